@@ -0,0 +1,101 @@
+// Compares serving a file through a BufReader against a memory mapping, and
+// concurrent readers of one file each opening it against sharing a mapping.
+// Run with `cargo bench --bench reader`.
+use fileserver::{open_file_source, FileSource, ServeDir};
+use std::{
+    fs,
+    io::{self, Read},
+    thread, time,
+};
+
+const ROOT_DIR: &str = "fileserver_reader_bench";
+const ROUNDS: u32 = 20;
+// clients downloading the same file at once
+const READERS: usize = 8;
+
+fn read_all(file: &str, mmap_threshold: Option<u64>) -> u64 {
+    read_source(open_file_source(file, ROOT_DIR, mmap_threshold).unwrap())
+}
+
+fn read_source(mut source: FileSource) -> u64 {
+    // same 1KB chunks the server streams in
+    let mut buf = [0u8; 1024];
+    let mut total = 0;
+    loop {
+        let read = source.read(&mut buf).unwrap();
+        if read == 0 {
+            return total;
+        }
+        total += read as u64;
+    }
+}
+
+fn bench(label: &str, file: &str, size: u64, mmap_threshold: Option<u64>) {
+    // warm the page cache so both runs measure the same thing
+    read_all(file, mmap_threshold);
+
+    let start = time::Instant::now();
+    for _ in 0..ROUNDS {
+        assert_eq!(size, read_all(file, mmap_threshold));
+    }
+    let elapsed = start.elapsed();
+    let throughput = (size * ROUNDS as u64) as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+    println!(
+        "{:<10} {:>9} bytes  {:>10.2?} per read  {:>8.1} MiB/s",
+        label,
+        size,
+        elapsed / ROUNDS,
+        throughput
+    );
+}
+
+// READERS threads read the whole file at once, opening and mapping it each or
+// sharing the first one's mapping the way the server does.
+fn bench_concurrent(label: &str, file: &'static str, size: u64, shared: bool) {
+    let start = time::Instant::now();
+    for _ in 0..ROUNDS {
+        let first = open_file_source(file, ROOT_DIR, Some(0)).unwrap();
+        let readers: Vec<_> = (1..READERS)
+            .map(|_| {
+                let source = match shared {
+                    true => first.share(),
+                    false => None,
+                };
+                thread::spawn(move || match source {
+                    Some(source) => read_source(source),
+                    None => read_all(file, Some(0)),
+                })
+            })
+            .collect();
+        assert_eq!(size, read_source(first));
+        for reader in readers {
+            assert_eq!(size, reader.join().unwrap());
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<10} {:>9} bytes  {:>10.2?} per {} readers",
+        label,
+        size,
+        elapsed / ROUNDS,
+        READERS
+    );
+}
+
+fn main() -> io::Result<()> {
+    let serve_dir = ServeDir::create(ROOT_DIR)?;
+    let dir = serve_dir.path();
+
+    for size in [64 * 1024, 1024 * 1024, 16 * 1024 * 1024, 128 * 1024 * 1024] {
+        let file = format!("bench_{}", size);
+        fs::write(dir.join(&file), vec![42u8; size as usize])?;
+        bench("bufreader", &file, size, None);
+        bench("mmap", &file, size, Some(0));
+        // the threads need the name for good, it is one string per size
+        let file: &'static str = Box::leak(file.into_boxed_str());
+        bench_concurrent("open each", file, size, false);
+        bench_concurrent("shared", file, size, true);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// any input may be rejected, none may panic
+fuzz_target!(|data: &[u8]| {
+    let _ = fileserver::parse_request(data);
+});
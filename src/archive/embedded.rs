@@ -0,0 +1,301 @@
+use super::BLOCK_SIZE;
+use crate::reader::{validate_file_name, ServedFile};
+use std::{collections::BTreeMap, fs, io, ops::Range, path::Path, sync::Arc, time};
+
+const BLOCK: usize = BLOCK_SIZE as usize;
+
+// A tar archive held in memory that the server serves files from instead of
+// its root dir, see FileServer::set_embedded_archive. Either compiled into
+// the binary,
+//
+//   EmbeddedArchive::from_static(include_bytes!("assets.tar"))
+//
+// or read once at startup with EmbeddedArchive::open. Only regular files at
+// the top of the archive are served, a leading "./" aside, the way only files
+// directly under the root dir are. Everything else is skipped.
+#[derive(Clone)]
+pub struct EmbeddedArchive {
+    bytes: ArchiveBytes,
+    files: Arc<BTreeMap<String, ArchivedFile>>,
+}
+
+#[derive(Clone)]
+enum ArchiveBytes {
+    Static(&'static [u8]),
+    Loaded(Arc<[u8]>),
+}
+
+impl AsRef<[u8]> for ArchiveBytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            ArchiveBytes::Static(bytes) => bytes,
+            ArchiveBytes::Loaded(bytes) => bytes,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ArchivedFile {
+    data: Range<usize>,
+    modified: time::SystemTime,
+}
+
+// One file's bytes inside the archive, read through an io::Cursor like the
+// hot file cache's.
+#[derive(Clone)]
+pub struct ArchivedBytes {
+    bytes: ArchiveBytes,
+    data: Range<usize>,
+}
+
+impl AsRef<[u8]> for ArchivedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes.as_ref()[self.data.clone()]
+    }
+}
+
+impl EmbeddedArchive {
+    pub fn from_static(bytes: &'static [u8]) -> io::Result<EmbeddedArchive> {
+        Self::index(ArchiveBytes::Static(bytes))
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> io::Result<EmbeddedArchive> {
+        Self::index(ArchiveBytes::Loaded(bytes.into()))
+    }
+
+    // Reads the whole archive at `path` into memory, the file is not looked
+    // at again.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<EmbeddedArchive> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    fn index(bytes: ArchiveBytes) -> io::Result<EmbeddedArchive> {
+        let files = read_entries(bytes.as_ref())?
+            .into_iter()
+            .filter(|(name, _)| validate_file_name(name).is_ok())
+            .collect();
+        Ok(EmbeddedArchive {
+            bytes,
+            files: Arc::new(files),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    // NotFound for names the archive does not serve.
+    pub fn file(&self, name: &str) -> io::Result<ServedFile> {
+        let file = self.lookup(name)?;
+        Ok(ServedFile {
+            name: name.to_owned(),
+            size: file.data.len() as u64,
+            modified: file.modified,
+        })
+    }
+
+    // Every served file, by name.
+    pub fn files(&self) -> impl Iterator<Item = ServedFile> + '_ {
+        self.files.iter().map(|(name, file)| ServedFile {
+            name: name.clone(),
+            size: file.data.len() as u64,
+            modified: file.modified,
+        })
+    }
+
+    pub fn bytes(&self, name: &str) -> io::Result<ArchivedBytes> {
+        let file = self.lookup(name)?;
+        Ok(ArchivedBytes {
+            bytes: self.bytes.clone(),
+            data: file.data.clone(),
+        })
+    }
+
+    fn lookup(&self, name: &str) -> io::Result<&ArchivedFile> {
+        validate_file_name(name)?;
+        self.files
+            .get(name)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+fn invalid(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+// Every regular file in the archive by its full name. Later entries of the
+// same name replace earlier ones, as they do when tar extracts. GNU long
+// names ('L') and pax paths ('x') are honoured, other extensions skipped.
+fn read_entries(archive: &[u8]) -> io::Result<BTreeMap<String, ArchivedFile>> {
+    let mut files = BTreeMap::new();
+    let mut long_name: Option<String> = None;
+    let mut at = 0;
+    loop {
+        let Some(header) = archive.get(at..at + BLOCK) else {
+            return Err(invalid(
+                "tar archive ends without its end blocks".to_owned(),
+            ));
+        };
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(files);
+        }
+        check_checksum(header, at)?;
+        let size = read_octal(&header[124..136])
+            .ok_or_else(|| invalid(format!("bad size in the tar header at {}", at)))?;
+        let start = at + BLOCK;
+        let end = usize::try_from(size)
+            .ok()
+            .and_then(|size| start.checked_add(size))
+            .filter(|end| *end <= archive.len())
+            .ok_or_else(|| invalid(format!("tar entry at {} runs past the archive", at)))?;
+        let data = &archive[start..end];
+
+        match header[156] {
+            b'L' => long_name = Some(field_str(data).to_owned()),
+            b'x' => long_name = pax_path(data).or(long_name),
+            b'0' | 0 => {
+                let name = long_name.take().unwrap_or_else(|| header_name(header));
+                let modified = time::UNIX_EPOCH
+                    + time::Duration::from_secs(read_octal(&header[136..148]).unwrap_or(0));
+                let name = name.strip_prefix("./").unwrap_or(&name).to_owned();
+                files.insert(
+                    name,
+                    ArchivedFile {
+                        data: start..end,
+                        modified,
+                    },
+                );
+            }
+            _ => long_name = None,
+        }
+        at = start + (end - start).div_ceil(BLOCK) * BLOCK;
+    }
+}
+
+fn check_checksum(header: &[u8], at: usize) -> io::Result<()> {
+    let stored = read_octal(&header[148..156]);
+    // taken with the checksum field itself read as spaces
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| match i {
+            148..156 => b' ' as u64,
+            _ => *byte as u64,
+        })
+        .sum();
+    match stored == Some(sum) {
+        true => Ok(()),
+        false => Err(invalid(format!("bad checksum in the tar header at {}", at))),
+    }
+}
+
+// ustar splits long names into a prefix and a name
+fn header_name(header: &[u8]) -> String {
+    let name = field_str(&header[..100]);
+    let prefix = match &header[257..262] {
+        b"ustar" => field_str(&header[345..500]),
+        _ => "",
+    };
+    match prefix {
+        "" => name.to_owned(),
+        prefix => format!("{}/{}", prefix, name),
+    }
+}
+
+// up to the first NUL, what is not UTF-8 as no name at all
+fn field_str(field: &[u8]) -> &str {
+    let end = field
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(field.len());
+    std::str::from_utf8(&field[..end]).unwrap_or("")
+}
+
+fn read_octal(field: &[u8]) -> Option<u64> {
+    let digits = field_str(field).trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+// pax records are "<length> <key>=<value>\n"
+fn pax_path(records: &[u8]) -> Option<String> {
+    std::str::from_utf8(records)
+        .ok()?
+        .lines()
+        .filter_map(|record| record.split_once(' ').map(|(_, record)| record))
+        .find_map(|record| record.strip_prefix("path="))
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{header, END_OF_ARCHIVE};
+    use super::*;
+    use std::io::Read;
+
+    fn tar(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (name, data) in entries {
+            let is_dir = name.ends_with('/');
+            archive.extend(header(name, data.len() as u64, 60, is_dir).unwrap());
+            archive.extend(*data);
+            archive.resize(archive.len().div_ceil(BLOCK) * BLOCK, 0);
+        }
+        archive.extend(END_OF_ARCHIVE);
+        archive
+    }
+
+    #[test]
+    fn test_serves_top_level_files_from_the_archive() {
+        let archive = EmbeddedArchive::from_bytes(tar(&[
+            ("./", b""),
+            ("./index.html", b"<h1>hi</h1>"),
+            ("css/", b""),
+            ("css/site.css", b"body {}"),
+            ("empty", b""),
+        ]))
+        .unwrap();
+
+        assert_eq!(2, archive.len());
+        let names: Vec<String> = archive.files().map(|file| file.name).collect();
+        assert_eq!(vec!["empty", "index.html"], names);
+        let index = archive.file("index.html").unwrap();
+        assert_eq!(11, index.size);
+        assert_eq!(
+            time::UNIX_EPOCH + time::Duration::from_secs(60),
+            index.modified
+        );
+
+        let mut served = String::new();
+        io::Cursor::new(archive.bytes("index.html").unwrap())
+            .read_to_string(&mut served)
+            .unwrap();
+        assert_eq!("<h1>hi</h1>", served);
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            archive.file("site.css").unwrap_err().kind()
+        );
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            archive.bytes("css/site.css").err().unwrap().kind()
+        );
+    }
+
+    #[test]
+    fn test_rejects_damaged_archives() {
+        let mut archive = tar(&[("a.txt", b"abc")]);
+        archive[0] = b'b';
+        assert!(EmbeddedArchive::from_bytes(archive).is_err());
+        let archive = tar(&[("a.txt", b"abc")]);
+        assert!(EmbeddedArchive::from_bytes(archive[..BLOCK].to_vec()).is_err());
+        assert!(EmbeddedArchive::from_bytes(END_OF_ARCHIVE.to_vec())
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::reader::{ServedFile, PARTIAL_SUFFIX, TRASH_DIR};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time,
+};
+
+// serving from a tar archive instead of the root dir
+pub mod embedded;
+
+// Builds ustar archives of served directories on the fly. Nothing is staged
+// on disk, the server writes each header followed by the file straight from
+// its source, so an archive costs no more than downloading its files.
+
+pub const BLOCK_SIZE: u64 = 512;
+// an archive ends with two zeroed blocks
+pub const END_OF_ARCHIVE: [u8; 2 * BLOCK_SIZE as usize] = [0; 2 * BLOCK_SIZE as usize];
+
+// One file or directory in an archive.
+pub struct TarEntry {
+    // '/' separated path inside the archive, directories end with '/'
+    pub name: String,
+    pub disk_path: PathBuf,
+    pub size: u64,
+    pub is_dir: bool,
+    pub header: [u8; BLOCK_SIZE as usize],
+    mtime: u64,
+}
+
+impl TarEntry {
+    fn new(name: String, disk_path: PathBuf, metadata: &fs::Metadata) -> io::Result<TarEntry> {
+        let is_dir = metadata.is_dir();
+        let size = if is_dir { 0 } else { metadata.len() };
+        Self::build(name, disk_path, size, metadata.modified().ok(), is_dir)
+    }
+
+    // A file that is not on disk, one out of an EmbeddedArchive say. It has
+    // no disk_path, its bytes come from wherever it is served from.
+    pub fn for_served_file(file: &ServedFile) -> io::Result<TarEntry> {
+        let name = file.name.clone();
+        Self::build(name, PathBuf::new(), file.size, Some(file.modified), false)
+    }
+
+    fn build(
+        name: String,
+        disk_path: PathBuf,
+        size: u64,
+        modified: Option<time::SystemTime>,
+        is_dir: bool,
+    ) -> io::Result<TarEntry> {
+        let mtime = modified
+            .and_then(|modified| modified.duration_since(time::UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        let header = header(&name, size, mtime, is_dir)?;
+        Ok(TarEntry {
+            name,
+            disk_path,
+            size,
+            is_dir,
+            header,
+            mtime,
+        })
+    }
+
+    // Gives the entry a size other than the one on disk, for files that are
+    // stored encrypted.
+    pub fn resize(&mut self, size: u64) -> io::Result<()> {
+        self.header = header(&self.name, size, self.mtime, self.is_dir)?;
+        self.size = size;
+        Ok(())
+    }
+
+    // zero bytes that pad the file's data to a whole block
+    pub fn padding(&self) -> usize {
+        ((BLOCK_SIZE - self.size % BLOCK_SIZE) % BLOCK_SIZE) as usize
+    }
+}
+
+// Everything under `dir`, named `prefix/...` in the archive (no prefix for an
+// empty one). Half uploaded .part files, symlinks and the trash are left out. Entries are
+// sorted so the same tree always gives the same archive.
+pub fn collect_entries(dir: &Path, prefix: &str) -> io::Result<Vec<TarEntry>> {
+    let mut entries = Vec::new();
+    if !prefix.is_empty() {
+        let metadata = fs::metadata(dir)?;
+        entries.push(TarEntry::new(
+            format!("{}/", prefix),
+            dir.to_path_buf(),
+            &metadata,
+        )?);
+    }
+    collect_into(dir, prefix, &mut entries)?;
+    Ok(entries)
+}
+
+fn collect_into(dir: &Path, prefix: &str, entries: &mut Vec<TarEntry>) -> io::Result<()> {
+    let mut children: Vec<fs::DirEntry> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    children.sort_by_key(|child| child.file_name());
+
+    for child in children {
+        let file_name = child.file_name().to_string_lossy().to_string();
+        // symlink_metadata so a link never takes us outside the served tree
+        let metadata = fs::symlink_metadata(child.path())?;
+        if metadata.file_type().is_symlink() || file_name.ends_with(PARTIAL_SUFFIX) {
+            continue;
+        }
+        // the trash only sits at the top of the served tree
+        if prefix.is_empty() && file_name == TRASH_DIR {
+            continue;
+        }
+        let name = match prefix {
+            "" => file_name,
+            prefix => format!("{}/{}", prefix, file_name),
+        };
+
+        if metadata.is_dir() {
+            entries.push(TarEntry::new(
+                format!("{}/", name),
+                child.path(),
+                &metadata,
+            )?);
+            collect_into(&child.path(), &name, entries)?;
+        } else if metadata.is_file() {
+            entries.push(TarEntry::new(name, child.path(), &metadata)?);
+        }
+    }
+    Ok(())
+}
+
+// Exact number of bytes the archive of `entries` takes.
+pub fn archive_size(entries: &[TarEntry]) -> u64 {
+    let data: u64 = entries
+        .iter()
+        .map(|entry| BLOCK_SIZE + entry.size + entry.padding() as u64)
+        .sum();
+    data + END_OF_ARCHIVE.len() as u64
+}
+
+fn header(name: &str, size: u64, mtime: u64, is_dir: bool) -> io::Result<[u8; 512]> {
+    let mut header = [0u8; BLOCK_SIZE as usize];
+    let (prefix, name) = split_name(name)?;
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], if is_dir { 0o755 } else { 0o644 })?;
+    write_octal(&mut header[108..116], 0)?;
+    write_octal(&mut header[116..124], 0)?;
+    write_octal(&mut header[124..136], size)?;
+    write_octal(&mut header[136..148], mtime)?;
+    header[156] = if is_dir { b'5' } else { b'0' };
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // the checksum is taken with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|byte| *byte as u64).sum();
+    write_octal(&mut header[148..155], checksum)?;
+    Ok(header)
+}
+
+// ustar keeps names in a 100 byte field plus a 155 byte prefix, split on a '/'.
+fn split_name(name: &str) -> io::Result<(&str, &str)> {
+    if name.len() <= 100 {
+        return Ok(("", name));
+    }
+    // a trailing '/' belongs to the name, never split on it
+    let searchable = name.strip_suffix('/').unwrap_or(name);
+    searchable
+        .match_indices('/')
+        .map(|(index, _)| (&name[..index], &name[index + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= 155 && rest.len() <= 100 && !rest.is_empty())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is too long for a tar archive", name),
+            )
+        })
+}
+
+// NUL terminated octal, as wide as the field allows
+fn write_octal(field: &mut [u8], value: u64) -> io::Result<()> {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() >= field.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} does not fit a tar header field", value),
+        ));
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_fields() {
+        let header = header("logs/a.log", 1000, 0, false).unwrap();
+        assert_eq!(b"logs/a.log\0", &header[..11]);
+        assert_eq!(b"00000001750\0", &header[124..136]);
+        assert_eq!(b"ustar\0", &header[257..263]);
+
+        let stored: u64 =
+            u64::from_str_radix(std::str::from_utf8(&header[148..154]).unwrap().trim(), 8).unwrap();
+        let mut blanked = header;
+        blanked[148..156].fill(b' ');
+        assert_eq!(stored, blanked.iter().map(|b| *b as u64).sum::<u64>());
+    }
+
+    #[test]
+    fn test_long_names_use_the_prefix() {
+        let name = format!("{}/{}", "d".repeat(120), "f".repeat(90));
+        let (prefix, rest) = split_name(&name).unwrap();
+        assert_eq!("d".repeat(120), prefix);
+        assert_eq!("f".repeat(90), rest);
+        assert!(split_name(&"x".repeat(101)).is_err());
+    }
+}
@@ -1 +1,529 @@
-fn main() {}
+use fileserver::protocol::stats::{FORMAT_JSON, FORMAT_V2};
+use fileserver::{
+    partial_path, CancellationToken, CommandType, FileClient, ListQuery, ListSort, RetryPolicy,
+    ServerConfig, StatsSnapshot,
+};
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    path::Path,
+    process,
+};
+
+static DEFAULT_CONFIG_PATH: &str = "fileserver.toml";
+// transfers smaller than this finish too fast for a progress bar to be useful
+const PROGRESS_BAR_THRESHOLD: u64 = 1024 * 1024;
+const PROGRESS_BAR_WIDTH: u64 = 30;
+// sent back after every stats report while following
+const HEARTBEAT: u8 = b'\n';
+// downloads cut off by the network are retried, resuming where they stopped
+const DEFAULT_RETRIES: u32 = 2;
+const RETRY_BACKOFF_MS: u64 = 500;
+
+const USAGE: &str =
+    "usage: fileserver-cli [--addr ADDRESS] [--port PORT] [--retries N] [--user NAME] <command>
+
+--user logs in with the password in FILESERVER_PASSWORD
+
+commands:
+    get <name> [-o <path>]    download a file (to ./<name> by default), picking up
+                              where an interrupted get of it stopped
+    pget <name> [-n <conns>]  download a big file over several connections (4 by default)
+    mget <glob> [-d <dir>]    download every file matching the glob, e.g. '*.log'
+    tar <dir> [-o <path>]     download a directory as a tar ('.' for everything)
+    put <path> [--name <n>]   upload a local file
+    sync [<dir>]              download whatever differs from the server into dir (. by default)
+    ls [--prefix <p>] [--sort name|mtime|size] [--desc]
+                              list served files, a page at a time with any option
+    transfers                 show the transfers in progress
+    caps                      show the server's version, commands and limits
+    watch <glob>              print changes to matching files as they happen
+    tail <name> [-c <bytes>]  print the end of a file (1024 bytes by default) and follow appends
+    stats [--follow] [--json] print server statistics, --follow keeps printing every tick,
+                              --json prints one JSON object per tick";
+
+// Progress bar on stderr, fed by the client's progress callbacks.
+#[derive(Default)]
+struct ProgressBar {
+    drawn_percent: Option<u64>,
+}
+
+impl ProgressBar {
+    fn update(&mut self, done: u64, total: u64) {
+        if total < PROGRESS_BAR_THRESHOLD {
+            return;
+        }
+        // only redraw when the percentage moves, not on every chunk
+        let percent = done * 100 / total;
+        if self.drawn_percent == Some(percent) {
+            return;
+        }
+        self.drawn_percent = Some(percent);
+
+        let filled = done * PROGRESS_BAR_WIDTH / total;
+        eprint!(
+            "\r[{}{}] {:>3}% {}/{} bytes",
+            "#".repeat(filled as usize),
+            ".".repeat((PROGRESS_BAR_WIDTH - filled.min(PROGRESS_BAR_WIDTH)) as usize),
+            percent,
+            done,
+            total
+        );
+        if done >= total {
+            eprintln!();
+        }
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("fileserver-cli: {}", message);
+    process::exit(1);
+}
+
+fn get(client: &mut FileClient, args: &[String]) {
+    let name = args.first().unwrap_or_else(|| fail(USAGE.to_owned()));
+    let output = match args.get(1).map(String::as_str) {
+        Some("-o") => args
+            .get(2)
+            .unwrap_or_else(|| fail("-o needs a path".to_owned())),
+        Some(other) => fail(format!("unexpected argument {:?}", other)),
+        None => name,
+    };
+
+    let output = Path::new(output);
+    client.set_queue_listener(|position| eprintln!("server busy, queued at position {}", position));
+    let mut bar = ProgressBar::default();
+    let result = client.download_resumable(
+        name,
+        output,
+        &CancellationToken::new(),
+        |received, total| bar.update(received, total),
+    );
+
+    match result {
+        Ok(bytes) => println!("saved {} ({} bytes) to {}", name, bytes, output.display()),
+        Err(err) => {
+            let partial = partial_path(output);
+            if fs::metadata(&partial).is_ok_and(|metadata| metadata.len() > 0) {
+                eprintln!(
+                    "kept what arrived in {}, get {} again to resume",
+                    partial.display(),
+                    name
+                );
+            }
+            fail(err.to_string());
+        }
+    }
+}
+
+fn pget(client: &mut FileClient, args: &[String]) {
+    let name = args.first().unwrap_or_else(|| fail(USAGE.to_owned()));
+    let connections = match args.get(1).map(String::as_str) {
+        Some("-n") => args
+            .get(2)
+            .and_then(|n| n.parse().ok())
+            .unwrap_or_else(|| fail("-n needs a number of connections".to_owned())),
+        Some(other) => fail(format!("unexpected argument {:?}", other)),
+        None => 4,
+    };
+
+    let path = std::path::Path::new(name);
+    match client.download_parallel(name, path, connections, &CancellationToken::new()) {
+        Ok(bytes) => println!(
+            "saved {} ({} bytes, {} connections)",
+            name, bytes, connections
+        ),
+        Err(err) => {
+            let _ = fs::remove_file(path);
+            fail(err.to_string());
+        }
+    }
+}
+
+fn mget(client: &mut FileClient, args: &[String]) {
+    let pattern = args.first().unwrap_or_else(|| fail(USAGE.to_owned()));
+    let dir = match args.get(1).map(String::as_str) {
+        Some("-d") => args
+            .get(2)
+            .unwrap_or_else(|| fail("-d needs a directory".to_owned())),
+        Some(other) => fail(format!("unexpected argument {:?}", other)),
+        None => ".",
+    };
+
+    match client.download_matching(
+        pattern,
+        std::path::Path::new(dir),
+        &CancellationToken::new(),
+    ) {
+        Ok(files) => {
+            for file in &files {
+                println!("saved {} ({} bytes)", file.name, file.size);
+            }
+            println!("{} files matched {}", files.len(), pattern);
+        }
+        Err(err) => fail(err.to_string()),
+    }
+}
+
+fn tar(client: &mut FileClient, args: &[String]) {
+    let directory = args.first().unwrap_or_else(|| fail(USAGE.to_owned()));
+    let default_output = match directory.trim_end_matches('/') {
+        "." => "served.tar".to_owned(),
+        directory => format!("{}.tar", directory.replace('/', "_")),
+    };
+    let output = match args.get(1).map(String::as_str) {
+        Some("-o") => args
+            .get(2)
+            .cloned()
+            .unwrap_or_else(|| fail("-o needs a path".to_owned())),
+        Some(other) => fail(format!("unexpected argument {:?}", other)),
+        None => default_output,
+    };
+
+    let file = fs::File::create(&output).unwrap_or_else(|err| fail(format!("{}: {}", output, err)));
+    let mut writer = io::BufWriter::new(file);
+    match client.download_archive(directory, &mut writer, &CancellationToken::new()) {
+        Ok(bytes) => println!("saved {} ({} bytes) to {}", directory, bytes, output),
+        Err(err) => {
+            drop(writer);
+            let _ = fs::remove_file(&output);
+            fail(err.to_string());
+        }
+    }
+}
+
+fn sync(client: &mut FileClient, args: &[String]) {
+    let dir = args.first().map_or(".", String::as_str);
+    match client.sync_dir(std::path::Path::new(dir), &CancellationToken::new()) {
+        Ok(files) => {
+            for file in &files {
+                println!("updated {} ({} bytes)", file.name, file.size);
+            }
+            println!("{} files updated in {}", files.len(), dir);
+        }
+        Err(err) => fail(err.to_string()),
+    }
+}
+
+fn put(client: &mut FileClient, args: &[String]) {
+    let path = args.first().unwrap_or_else(|| fail(USAGE.to_owned()));
+    let default_name = std::path::Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| fail(format!("{} has no file name", path)));
+    let name = match args.get(1).map(String::as_str) {
+        Some("--name") => args
+            .get(2)
+            .cloned()
+            .unwrap_or_else(|| fail("--name needs a value".to_owned())),
+        Some(other) => fail(format!("unexpected argument {:?}", other)),
+        None => default_name,
+    };
+
+    // streamed from disk, big files are not read into memory first
+    let file = fs::File::open(path).unwrap_or_else(|err| fail(format!("{}: {}", path, err)));
+    let len = file
+        .metadata()
+        .unwrap_or_else(|err| fail(format!("{}: {}", path, err)))
+        .len();
+    let mut bar = ProgressBar::default();
+    match client
+        .upload_from_reader_with_progress(&name, file, len, |sent, total| bar.update(sent, total))
+    {
+        Ok(()) => println!("uploaded {} ({} bytes) as {}", path, len, name),
+        Err(err) => fail(err.to_string()),
+    }
+}
+
+fn ls(client: &mut FileClient, args: &[String]) {
+    if args.is_empty() {
+        match client.list() {
+            Ok(files) => {
+                for file in files {
+                    println!("{:>12}  {}", file.size, file.name);
+                }
+            }
+            Err(err) => fail(err.to_string()),
+        }
+        return;
+    }
+
+    // any option pages through ListPage, servers before it only know List
+    let mut query = ListQuery::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prefix" => {
+                query.prefix = args
+                    .next()
+                    .unwrap_or_else(|| fail("--prefix needs a name prefix".to_owned()))
+                    .clone();
+            }
+            "--sort" => {
+                query.sort = args
+                    .next()
+                    .and_then(|sort| ListSort::parse(sort))
+                    .unwrap_or_else(|| fail("--sort needs name, mtime or size".to_owned()));
+            }
+            "--desc" => query.descending = true,
+            other => fail(format!("unexpected argument {:?}", other)),
+        }
+    }
+    loop {
+        let page = client
+            .list_page(&query)
+            .unwrap_or_else(|err| fail(err.to_string()));
+        for file in page.files {
+            println!("{:>12}  {}", file.size, file.name);
+        }
+        match page.next {
+            Some(next) => query.page = Some(next),
+            None => return,
+        }
+    }
+}
+
+fn transfers(client: &mut FileClient) {
+    match client.transfers() {
+        Ok(transfers) => {
+            for t in transfers {
+                println!(
+                    "{:>6}  {:>12}  {:>8.1}s  {:<22}  {}",
+                    t.id,
+                    t.bytes_sent,
+                    t.elapsed.as_secs_f64(),
+                    t.peer,
+                    t.file_name
+                );
+            }
+        }
+        Err(err) => fail(err.to_string()),
+    }
+}
+
+fn caps(client: &FileClient) {
+    match client.capabilities() {
+        Ok(caps) => {
+            println!("server version: {}", caps.server_version);
+            println!("protocol: {}", caps.protocol);
+            println!("commands: {:?}", caps.commands);
+            match caps.max_upload_bytes {
+                Some(max) => println!("max upload: {} bytes", max),
+                None => println!("max upload: unlimited"),
+            }
+            println!("compression: {}", caps.compression.join(", "));
+        }
+        Err(err) => fail(err.to_string()),
+    }
+}
+
+fn watch(client: &mut FileClient, args: &[String]) {
+    let pattern = args.first().unwrap_or_else(|| fail(USAGE.to_owned()));
+    let mut feed = client
+        .watch(pattern)
+        .unwrap_or_else(|err| fail(err.to_string()));
+    let token = CancellationToken::new();
+    loop {
+        match feed.next_change(&token) {
+            Ok(change) => println!("{:<8}  {}", change.kind.as_str(), change.file_name),
+            Err(err) => fail(err.to_string()),
+        }
+    }
+}
+
+fn tail(client: &mut FileClient, args: &[String]) {
+    let name = args.first().unwrap_or_else(|| fail(USAGE.to_owned()));
+    let last_bytes = match args.get(1).map(String::as_str) {
+        Some("-c") => args
+            .get(2)
+            .and_then(|bytes| bytes.parse().ok())
+            .unwrap_or_else(|| fail("-c needs a number of bytes".to_owned())),
+        Some(other) => fail(format!("unexpected argument {:?}", other)),
+        None => 1024,
+    };
+    let mut feed = client
+        .tail(name, last_bytes)
+        .unwrap_or_else(|err| fail(err.to_string()));
+    let token = CancellationToken::new();
+    let mut stdout = io::stdout();
+    loop {
+        match feed.next_chunk(&token) {
+            Ok(bytes) => {
+                let _ = stdout.write_all(&bytes);
+                let _ = stdout.flush();
+            }
+            Err(err) => fail(err.to_string()),
+        }
+    }
+}
+
+fn stats(address: &str, port: &str, login: Option<&(String, String)>, args: &[String]) {
+    let mut follow = false;
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--follow" => follow = true,
+            "--json" => json = true,
+            other => fail(format!("unexpected argument {:?}", other)),
+        }
+    }
+
+    let port: u16 = port
+        .parse()
+        .unwrap_or_else(|_| fail(format!("invalid port {:?}", port)));
+    // (host, port) also takes bare IPv6 literals like ::1
+    let host = address.trim_start_matches('[').trim_end_matches(']');
+    let mut stream = TcpStream::connect((host, port)).unwrap_or_else(|err| fail(err.to_string()));
+    if let Some((user, password)) = login {
+        // the Login prefix, the stats command follows on the same connection
+        let mut request = vec![CommandType::Login.into()];
+        request.extend_from_slice(format!("user={}|password={}|", user, password).as_bytes());
+        stream
+            .write_all(&request)
+            .unwrap_or_else(|err| fail(err.to_string()));
+    }
+    // StatisticsV2 to follow, StatsOnce for a single report, then the format
+    // byte
+    let command = if follow {
+        CommandType::StatisticsV2
+    } else {
+        CommandType::StatsOnce
+    };
+    let format = if json { FORMAT_JSON } else { FORMAT_V2 };
+    stream
+        .write_all(&[command.into(), format])
+        .unwrap_or_else(|err| fail(err.to_string()));
+    if json {
+        return stats_json(stream, follow);
+    }
+
+    loop {
+        let stats =
+            StatsSnapshot::from_stream_v2(&mut stream).unwrap_or_else(|err| fail(err.to_string()));
+        println!(
+            "clients: {}  most downloaded: {} ({} downloads)  bytes served: {}",
+            stats.number_of_clients,
+            stats.most_downloaded_file,
+            stats.file_downloaded_count,
+            stats.bytes_served
+        );
+        let dispatch_wait = &stats.pool.dispatch_wait;
+        println!(
+            "  workers: {}/{} busy, {} waiting, average dispatch wait {} us",
+            stats.number_of_clients,
+            stats.pool.workers,
+            stats.pool.waiting_connections,
+            dispatch_wait.sum_micros / dispatch_wait.count.max(1)
+        );
+        for (rank, (file_name, downloads)) in stats.top_files.iter().enumerate() {
+            println!("  #{} {}: {} downloads", rank + 1, file_name, downloads);
+        }
+        for transfer in &stats.transfers {
+            println!(
+                "  transfer {} {}: {} bytes at {} B/s",
+                transfer.id, transfer.file_name, transfer.bytes_sent, transfer.bytes_per_second
+            );
+        }
+        let cluster = &stats.cluster;
+        if cluster.nodes > 0 {
+            println!(
+                "  cluster: {} nodes ({} unreachable), {} clients, {} bytes served",
+                cluster.nodes, cluster.unreachable, cluster.clients, cluster.bytes_served
+            );
+            for (rank, (file_name, downloads)) in cluster.top_files.iter().enumerate() {
+                println!("    #{} {}: {} downloads", rank + 1, file_name, downloads);
+            }
+        }
+        if !follow {
+            return;
+        }
+        // heartbeat so a server evicting silent subscribers keeps us, any byte will do
+        stream
+            .write_all(&[HEARTBEAT])
+            .unwrap_or_else(|err| fail(err.to_string()));
+    }
+}
+
+// Echoes the JSON lines as they come, for piping into jq.
+fn stats_json(mut stream: TcpStream, follow: bool) {
+    let mut lines = BufReader::new(
+        stream
+            .try_clone()
+            .unwrap_or_else(|err| fail(err.to_string())),
+    );
+    loop {
+        let mut line = String::new();
+        match lines.read_line(&mut line) {
+            Ok(0) => fail("server closed the connection".to_owned()),
+            Ok(_) => print!("{}", line),
+            Err(err) => fail(err.to_string()),
+        }
+        if !follow {
+            return;
+        }
+        stream
+            .write_all(&[HEARTBEAT])
+            .unwrap_or_else(|err| fail(err.to_string()));
+    }
+}
+
+fn main() {
+    let config_path = env::var("FILESERVER_CONFIG").unwrap_or(DEFAULT_CONFIG_PATH.to_owned());
+    let config =
+        ServerConfig::load_or_default(&config_path).unwrap_or_else(|err| fail(err.to_string()));
+    let mut address = config.address;
+    let mut port = config.port.to_string();
+
+    let mut attempts = DEFAULT_RETRIES + 1;
+    let mut login = None;
+
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    while args.len() >= 2
+        && matches!(
+            args[0].as_str(),
+            "--addr" | "--port" | "--retries" | "--user"
+        )
+    {
+        let value = args.remove(1);
+        match args.remove(0).as_str() {
+            "--addr" => address = value,
+            "--port" => port = value,
+            "--user" => {
+                let password = env::var("FILESERVER_PASSWORD")
+                    .unwrap_or_else(|_| fail("--user needs FILESERVER_PASSWORD".to_owned()));
+                login = Some((value, password));
+            }
+            _ => {
+                let retries: u32 = value
+                    .parse()
+                    .unwrap_or_else(|_| fail(format!("invalid --retries {:?}", value)));
+                attempts = retries + 1;
+            }
+        }
+    }
+
+    let mut client = FileClient::new(&address, &port);
+    client.set_retry_policy(RetryPolicy::exponential(
+        attempts,
+        std::time::Duration::from_millis(RETRY_BACKOFF_MS),
+    ));
+    if let Some((user, password)) = &login {
+        client.set_login(user, password);
+    }
+    match args.first().map(String::as_str) {
+        Some("get") => get(&mut client, &args[1..]),
+        Some("pget") => pget(&mut client, &args[1..]),
+        Some("mget") => mget(&mut client, &args[1..]),
+        Some("tar") => tar(&mut client, &args[1..]),
+        Some("put") => put(&mut client, &args[1..]),
+        Some("sync") => sync(&mut client, &args[1..]),
+        Some("ls") => ls(&mut client, &args[1..]),
+        Some("transfers") => transfers(&mut client),
+        Some("caps") => caps(&client),
+        Some("watch") => watch(&mut client, &args[1..]),
+        Some("tail") => tail(&mut client, &args[1..]),
+        Some("stats") => stats(&address, &port, login.as_ref(), &args[1..]),
+        _ => fail(USAGE.to_owned()),
+    }
+}
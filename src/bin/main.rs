@@ -1,25 +1,84 @@
 use fileserver::CommandType as commands;
 use fileserver::FileServer as server;
+use fileserver::{FileServerBuilder, ListenerPolicy, ServeDir, ServerConfig};
+use std::env;
 
-static CONF_FOLDER_NAME: &str = "rust_file_server";
-static CONF_PORT: &str = "8089";
-static CONF_ADDRESS: &str = "127.0.0.1";
-fn main() {
-    fileserver::configure_directory_to_serve_file(CONF_FOLDER_NAME);
-    println!("Starting TCP server!!!");
-    let mut file_server = server::new(CONF_ADDRESS, CONF_PORT, 10, CONF_FOLDER_NAME).unwrap();
-    file_server.register_handlers(&[
-        (commands::Download, server::handle_incomming_file_request),
-        (commands::Statistics, server::no_op_handler),
-    ]);
+static DEFAULT_CONFIG_PATH: &str = "fileserver.toml";
 
-    file_server.start_metrics_report();
-    file_server.handle_incomming_connections();
+fn main() {
+    // panics are logged with the thread and request they happened on
+    fileserver::install_panic_hook();
+    // FILESERVER_CONFIG points at the TOML file, without it we look next to the binary's cwd
+    let config_path = env::var("FILESERVER_CONFIG").unwrap_or(DEFAULT_CONFIG_PATH.to_owned());
+    let config = ServerConfig::load_or_default(&config_path).unwrap();
 
-    let cleanup = || {
-        fileserver::cleanup_server_file(CONF_FOLDER_NAME);
+    if let Some(base_dir) = &config.base_dir {
+        fileserver::set_base_directory(base_dir);
+    }
+    // uploads and user homes live in the root, it outlives the server. A
+    // server with an embedded archive serves that instead and needs none
+    let _serve_dir = match config.embedded_archive {
+        Some(_) => None,
+        None => Some(ServeDir::create(&config.root_dir).unwrap().persistent()),
     };
+    println!("Starting TCP server!!!");
+    let file_server = FileServerBuilder::from_config(&config)
+        .handlers(&[
+            (commands::Download, server::handle_incomming_file_request),
+            (
+                commands::ConditionalDownload,
+                server::handle_conditional_download,
+            ),
+            (commands::Upload, server::handle_upload),
+            (commands::Delete, server::handle_delete),
+            (commands::List, server::handle_list),
+            (commands::ListPage, server::handle_list_page),
+            (commands::Transfers, server::handle_transfers),
+            (commands::BatchDownload, server::handle_batch_download),
+            (commands::Archive, server::handle_archive),
+            (commands::RangeDownload, server::handle_range_download),
+            (commands::ReliableDownload, server::handle_reliable_download),
+            (commands::FlashDownload, server::handle_flash_download),
+            (commands::Delta, server::handle_delta),
+            (commands::Stat, server::handle_stat),
+            (commands::Locate, server::handle_locate),
+            (commands::Watch, server::handle_watch),
+            (commands::Tail, server::handle_tail),
+            (commands::Sync, server::handle_sync),
+            (commands::Statistics, server::no_op_handler),
+            (commands::StatisticsV2, server::no_op_handler),
+            (commands::StatsOnce, server::no_op_handler),
+            (commands::KeepAlive, server::handle_keep_alive_session),
+            (commands::Ping, server::handle_ping),
+        ])
+        .build()
+        .unwrap();
 
+    file_server.start_metrics_report();
+    if let Some(tftp_port) = config.tftp_port {
+        file_server
+            .start_tftp(&config.address, &tftp_port.to_string())
+            .unwrap();
+    }
+    if let Some(webdav_port) = config.webdav_port {
+        file_server
+            .start_webdav_with_policy(
+                &config.address,
+                &webdav_port.to_string(),
+                ListenerPolicy {
+                    read_only: config.webdav_read_only,
+                },
+            )
+            .unwrap();
+    }
+    if let Some(admin_port) = config.admin_port {
+        file_server
+            .start_admin(&config.admin_address, &admin_port.to_string())
+            .unwrap();
+    }
+    match file_server.handle_incomming_connections() {
+        Ok(report) => println!("Server ran for {:?}", report.uptime),
+        Err(err) => println!("Server stopped: {}", err),
+    }
     // TODO: spawn a signal handler to allow shutdowns to cleanup gracefully
-    cleanup();
 }
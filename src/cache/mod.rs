@@ -0,0 +1,314 @@
+use crate::reader::SharedMapping;
+use memmap2::Mmap;
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time,
+};
+
+// A file has to be downloaded this many times before it is worth keeping in memory.
+pub const HOT_FILE_MIN_DOWNLOADS: i64 = 2;
+
+// What a served file is cached under. Names alone are not enough, user
+// accounts have a root each and two users can both have a `notes.txt`.
+pub fn cache_key(root_dir: &str, file_name: &str) -> String {
+    format!("{}/{}", root_dir, file_name)
+}
+
+// Keeps the bytes of small, frequently downloaded files in memory, evicting
+// the least recently served file once `capacity_bytes` is reached.
+// A capacity of 0 (the default) disables the cache.
+pub struct HotFileCache {
+    state: Mutex<CacheState>,
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+}
+
+struct CacheState {
+    capacity_bytes: u64,
+    max_file_size: u64,
+    used_bytes: u64,
+    entries: HashMap<String, Arc<[u8]>>,
+    // front is the least recently used file
+    recency: VecDeque<String>,
+}
+
+impl CacheState {
+    fn touch(&mut self, file_name: &str) {
+        if let Some(position) = self.recency.iter().position(|f| f == file_name) {
+            let file_name = self.recency.remove(position).unwrap();
+            self.recency.push_back(file_name);
+        }
+    }
+
+    fn remove(&mut self, file_name: &str) {
+        if let Some(bytes) = self.entries.remove(file_name) {
+            self.used_bytes -= bytes.len() as u64;
+            self.recency.retain(|f| f != file_name);
+        }
+    }
+}
+
+impl HotFileCache {
+    pub fn new(capacity_bytes: u64, max_file_size: u64) -> HotFileCache {
+        HotFileCache {
+            state: Mutex::new(CacheState {
+                capacity_bytes,
+                max_file_size,
+                used_bytes: 0,
+                entries: HashMap::new(),
+                recency: VecDeque::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Changes the limits and evicts whatever no longer fits.
+    pub fn configure(&self, capacity_bytes: u64, max_file_size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.capacity_bytes = capacity_bytes;
+        state.max_file_size = max_file_size;
+        let too_big: Vec<String> = state
+            .entries
+            .iter()
+            .filter(|(_, bytes)| bytes.len() as u64 > max_file_size)
+            .map(|(file_name, _)| file_name.clone())
+            .collect();
+        for file_name in too_big {
+            state.remove(&file_name);
+        }
+        while state.used_bytes > state.capacity_bytes {
+            let oldest = state.recency.front().cloned().unwrap();
+            state.remove(&oldest);
+        }
+    }
+
+    pub fn get(&self, file_name: &str) -> Option<Arc<[u8]>> {
+        let mut state = self.state.lock().unwrap();
+        if state.capacity_bytes == 0 {
+            return None;
+        }
+        match state.entries.get(file_name).cloned() {
+            Some(bytes) => {
+                state.touch(file_name);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(bytes)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    // Whether a file of `size` bytes downloaded `downloads` times should be cached.
+    pub fn admits(&self, size: u64, downloads: i64) -> bool {
+        let state = self.state.lock().unwrap();
+        downloads >= HOT_FILE_MIN_DOWNLOADS
+            && size <= state.max_file_size
+            && size <= state.capacity_bytes
+    }
+
+    pub fn insert(&self, file_name: &str, bytes: Arc<[u8]>) {
+        let mut state = self.state.lock().unwrap();
+        let size = bytes.len() as u64;
+        if size > state.max_file_size || size > state.capacity_bytes {
+            return;
+        }
+
+        state.remove(file_name);
+        while state.used_bytes + size > state.capacity_bytes {
+            let oldest = state.recency.front().cloned().unwrap();
+            state.remove(&oldest);
+        }
+        state.used_bytes += size;
+        state.entries.insert(file_name.to_owned(), bytes);
+        state.recency.push_back(file_name.to_owned());
+    }
+
+    // Drops a file whose content changed on disk (upload, delete).
+    pub fn invalidate(&self, file_name: &str) {
+        self.state.lock().unwrap().remove(file_name);
+    }
+
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.recency.clear();
+        state.used_bytes = 0;
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.state.lock().unwrap().used_bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for HotFileCache {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+// Mappings of the big files being downloaded right now, by cache_key. A
+// download of a file some other download already mapped reads that mapping
+// instead of opening and mapping the file again, so a burst of clients after
+// the same big file costs one open. Too big for the hot file cache, these are
+// only kept while someone reads them: entries are weak and a mapping goes
+// away with its last reader.
+#[derive(Default)]
+pub struct SharedMappings {
+    mappings: Mutex<HashMap<String, Weak<Mmap>>>,
+    // downloads that got a mapping another one opened
+    pub reuses: AtomicU64,
+}
+
+impl SharedMappings {
+    pub fn get(&self, file_name: &str) -> Option<SharedMapping> {
+        let mapping = SharedMapping::upgrade(self.mappings.lock().unwrap().get(file_name)?)?;
+        self.reuses.fetch_add(1, Ordering::Relaxed);
+        Some(mapping)
+    }
+
+    pub fn insert(&self, file_name: &str, mapping: &SharedMapping) {
+        let mut mappings = self.mappings.lock().unwrap();
+        // the ones nobody reads anymore go while we are here
+        mappings.retain(|_, weak| weak.strong_count() > 0);
+        mappings.insert(file_name.to_owned(), mapping.downgrade());
+    }
+
+    // Drops a file whose content changed on disk, readers of the old mapping
+    // finish with the old content.
+    pub fn invalidate(&self, file_name: &str) {
+        self.mappings.lock().unwrap().remove(file_name);
+    }
+
+    // Files with a mapping someone still reads.
+    pub fn len(&self) -> usize {
+        self.mappings
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|weak| weak.strong_count() > 0)
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// ETags of served files by cache_key: the lowercase hex SHA-256 of the bytes
+// a download sends, so the same sha256 the Checksums frames and sync
+// manifests carry. Each is kept along with the size and modification time the
+// file had when it was hashed, a file is only hashed again once either moved.
+#[derive(Default)]
+pub struct ETagCache {
+    entries: RwLock<HashMap<String, (u64, time::SystemTime, String)>>,
+    // ETags that had to be computed
+    pub misses: AtomicU64,
+}
+
+impl ETagCache {
+    // The ETag of the file whose size and mtime are `size` and `modified`,
+    // `hash` computes it when there is none for that version yet.
+    pub fn get_or_compute(
+        &self,
+        file_name: &str,
+        size: u64,
+        modified: time::SystemTime,
+        hash: impl FnOnce() -> io::Result<String>,
+    ) -> io::Result<String> {
+        if let Some((cached_size, cached_modified, etag)) =
+            self.entries.read().unwrap().get(file_name)
+        {
+            if (*cached_size, *cached_modified) == (size, modified) {
+                return Ok(etag.clone());
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let etag = hash()?;
+        self.entries
+            .write()
+            .unwrap()
+            .insert(file_name.to_owned(), (size, modified, etag.clone()));
+        Ok(etag)
+    }
+
+    // Forgets a file that was replaced or deleted.
+    pub fn invalidate(&self, file_name: &str) {
+        self.entries.write().unwrap().remove(file_name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(size: usize) -> Arc<[u8]> {
+        vec![1u8; size].into()
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = HotFileCache::new(30, 20);
+        cache.insert("a", bytes(10));
+        cache.insert("b", bytes(10));
+        cache.insert("c", bytes(10));
+        // touching a makes b the oldest
+        assert!(cache.get("a").is_some());
+        cache.insert("d", bytes(10));
+
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+        assert!(cache.get("d").is_some());
+        assert_eq!(30, cache.used_bytes());
+    }
+
+    #[test]
+    fn test_respects_size_limits_and_invalidation() {
+        let cache = HotFileCache::new(100, 20);
+        assert!(!cache.admits(10, HOT_FILE_MIN_DOWNLOADS - 1));
+        assert!(!cache.admits(21, HOT_FILE_MIN_DOWNLOADS));
+        assert!(cache.admits(20, HOT_FILE_MIN_DOWNLOADS));
+
+        cache.insert("big", bytes(21));
+        assert!(cache.is_empty());
+
+        cache.insert("a", bytes(5));
+        cache.invalidate("a");
+        assert!(cache.get("a").is_none());
+        assert_eq!(0, cache.used_bytes());
+    }
+
+    #[test]
+    fn test_etag_is_recomputed_once_the_file_moves() {
+        let cache = ETagCache::default();
+        let then = time::UNIX_EPOCH + time::Duration::from_secs(10);
+        let etag = |value: &str| {
+            let value = value.to_owned();
+            move || Ok(value)
+        };
+        assert_eq!("a", cache.get_or_compute("f", 1, then, etag("a")).unwrap());
+        assert_eq!("a", cache.get_or_compute("f", 1, then, etag("b")).unwrap());
+        // same size, newer mtime
+        let later = then + time::Duration::from_secs(1);
+        assert_eq!("c", cache.get_or_compute("f", 1, later, etag("c")).unwrap());
+        cache.invalidate("f");
+        assert_eq!("d", cache.get_or_compute("f", 1, later, etag("d")).unwrap());
+        assert_eq!(3, cache.misses.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,2817 @@
+use crate::protocol::crc32::crc32;
+use crate::protocol::frame::{
+    decode_crc_chunk_head, decode_frame_header, CRC_CHUNK_HEAD_LEN, FRAME_BUSY, FRAME_CHECKSUM,
+    FRAME_CHUNK, FRAME_CONTENT_TYPE, FRAME_COPY, FRAME_CRC_CHUNK, FRAME_DENIED, FRAME_ENCODING,
+    FRAME_END, FRAME_ERROR, FRAME_ETAG, FRAME_HEADER_LEN, FRAME_NOT_FOUND, FRAME_NOT_MODIFIED,
+    FRAME_OK, FRAME_QUEUED,
+};
+use crate::protocol::stats::{ActiveTransfer, StatsSnapshot, FORMAT_V2, V2_LENGTH_BYTES};
+use crate::protocol::{CommandType, PROTOCOL_VERSION, QUEUE_POSITION_VERSION};
+use crate::reader::{sha256_hex, validate_file_name, Hashing};
+use crate::server::delta;
+use crate::server::listener;
+use crate::server::reliable::{CHUNK_ACK, CHUNK_NACK};
+use crate::server::types::{
+    Capabilities, ChangeEvent, DownloadCondition, ListQuery, ManifestEntry,
+};
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+// how often a blocked read wakes up to look at the cancellation token
+const CANCEL_POLL_INTERVAL_MS: u64 = 100;
+// uploads report progress after every chunk this big
+const UPLOAD_CHUNK_BYTES: usize = 64 * 1024;
+// BUSY answers waited out per command unless set_busy_retries says otherwise
+const DEFAULT_BUSY_RETRIES: u32 = 3;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Connect(String),
+    Timeout(String),
+    Cancelled,
+    Io(String),
+    Server(String),
+    // the file asked for does not exist, unlike Server nothing is wrong with
+    // the server
+    NotFound(String),
+    // the file exists but the server does not serve it, asking again will
+    // not help
+    Denied(String),
+    // what arrived for the file does not match the server's checksum
+    ChecksumMismatch(String),
+    // the server shed the connection, try again after this long. Session
+    // commands other than uploads wait and resend on their own, see
+    // FileClient::set_busy_retries
+    Busy(time::Duration),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Connect(reason) => write!(f, "Could not connect to server: {}", reason),
+            ClientError::Timeout(operation) => write!(f, "Timed out while {}", operation),
+            ClientError::Cancelled => write!(f, "Operation was cancelled"),
+            ClientError::Io(reason) => write!(f, "Connection error: {}", reason),
+            ClientError::Server(reason) => write!(f, "Server reported an error: {}", reason),
+            ClientError::NotFound(reason) => write!(f, "{}", reason),
+            ClientError::Denied(reason) => write!(f, "{}", reason),
+            ClientError::Busy(retry_after) => write!(
+                f,
+                "Server is busy, retry after {} seconds",
+                retry_after.as_secs()
+            ),
+            ClientError::ChecksumMismatch(file) => {
+                write!(
+                    f,
+                    "Checksum mismatch for {}, it was corrupted in transit",
+                    file
+                )
+            }
+        }
+    }
+}
+
+// What the server answers to a Ping.
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub version: String,
+    pub uptime: time::Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+}
+
+// One page of a listing, see FileClient::list_page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListPage {
+    pub files: Vec<FileEntry>,
+    // what ListQuery::page takes to get the page after this one, None on the
+    // last page
+    pub next: Option<String>,
+}
+
+// What the server answers to a Stat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileStat {
+    pub size: u64,
+    pub modified: time::SystemTime,
+    // sha256 hex of the content, what DownloadCondition::NoneMatch takes.
+    // None from servers that predate ETags
+    pub etag: Option<String>,
+}
+
+// How a delta download went: the file's size and how much of it came over
+// the wire or was taken from the old copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaStats {
+    pub size: u64,
+    pub received: u64,
+    pub reused: u64,
+}
+
+// How often and how patiently an operation is retried after the connection
+// failed. Server errors (a missing file, say) are answers, not failures, and
+// are never retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // attempts in total, 1 means no retries
+    pub max_attempts: u32,
+    // wait before the first retry, doubled for every retry after that
+    pub initial_backoff: time::Duration,
+    pub max_backoff: time::Duration,
+}
+
+impl RetryPolicy {
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: time::Duration::ZERO,
+            max_backoff: time::Duration::ZERO,
+        }
+    }
+
+    pub fn exponential(max_attempts: u32, initial_backoff: time::Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            initial_backoff,
+            max_backoff: time::Duration::from_secs(30),
+        }
+    }
+
+    // How long to wait after the `failed`th failed attempt.
+    pub fn backoff(&self, failed: u32) -> time::Duration {
+        let doublings = failed.saturating_sub(1).min(31);
+        self.initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::none()
+    }
+}
+
+// A Watch subscription on a connection of its own, see FileClient::watch.
+// Dropping it ends the subscription.
+pub struct ChangeFeed {
+    stream: TcpStream,
+}
+
+impl ChangeFeed {
+    // Blocks until the next change comes in or the token is cancelled.
+    pub fn next_change(&mut self, token: &CancellationToken) -> Result<ChangeEvent, ClientError> {
+        let length = read_ok_frame(&mut self.stream, token, None)?;
+        let mut payload = vec![0; length as usize];
+        read_exact_cancellable(&mut self.stream, &mut payload, token, None)?;
+        let payload = String::from_utf8_lossy(&payload);
+        ChangeEvent::parse(&payload)
+            .ok_or_else(|| ClientError::Io(format!("malformed change event {:?}", payload)))
+    }
+}
+
+// A Tail of a served file on a connection of its own, see FileClient::tail.
+// Dropping it stops following the file.
+pub struct TailFeed {
+    stream: TcpStream,
+}
+
+impl TailFeed {
+    // The end of the file first, then the bytes appended to it as they come
+    // in. Blocks until there are some or the token is cancelled.
+    pub fn next_chunk(&mut self, token: &CancellationToken) -> Result<Vec<u8>, ClientError> {
+        let length = read_ok_frame(&mut self.stream, token, None)?;
+        let mut bytes = vec![0; length as usize];
+        read_exact_cancellable(&mut self.stream, &mut bytes, token, None)?;
+        Ok(bytes)
+    }
+}
+
+// What a StatsSubscriber yields, one Report per reporting tick of the server.
+// nearly every event is a Report, boxing it would only add an allocation
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum StatsEvent {
+    Report(StatsSnapshot),
+    // the subscription broke off and a new one was opened, reports in between
+    // were missed
+    Reconnected,
+}
+
+// A StatisticsV2 subscription on a connection of its own, see
+// FileClient::subscribe_stats. Iterating blocks until the next tick, a lost
+// connection is reopened following the client's RetryPolicy and the
+// iterator ends once it gives up or the subscription is closed.
+pub struct StatsSubscriber {
+    client: FileClient,
+    stream: Option<TcpStream>,
+    token: CancellationToken,
+    // a connection was lost, the next one opened is announced as Reconnected
+    reconnecting: bool,
+}
+
+impl StatsSubscriber {
+    // Token another thread can cancel to end the iteration, the same as close.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    // Hangs up, the iterator yields nothing after this.
+    pub fn close(&mut self) {
+        self.token.cancel();
+        if let Some(stream) = self.stream.take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn next_report(&mut self) -> Result<StatsSnapshot, ClientError> {
+        let stream = self.stream.as_mut().unwrap();
+        // reports are not framed, a u32 length and the v2 payload
+        let mut report = vec![0u8; V2_LENGTH_BYTES];
+        read_exact_cancellable(stream, &mut report, &self.token, None)?;
+        let mut length = [0u8; V2_LENGTH_BYTES];
+        length.copy_from_slice(&report);
+        report.resize(V2_LENGTH_BYTES + u32::from_be_bytes(length) as usize, 0);
+        read_exact_cancellable(stream, &mut report[V2_LENGTH_BYTES..], &self.token, None)?;
+        let snapshot = StatsSnapshot::from_stream_v2(&mut report.as_slice())
+            .map_err(|err| ClientError::Io(format!("malformed stats report: {}", err)))?;
+
+        // heartbeat so a server evicting silent subscribers keeps us
+        stream
+            .write_all(b"\n")
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        Ok(snapshot)
+    }
+
+    fn resubscribe(&mut self) -> Result<(), ClientError> {
+        let policy = self.client.retry_policy.clone();
+        let mut failed = 0;
+        loop {
+            match self.client.subscribe_stats_stream() {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    return Ok(());
+                }
+                Err(err) => {
+                    failed += 1;
+                    if failed >= policy.max_attempts {
+                        return Err(err);
+                    }
+                    thread::sleep(policy.backoff(failed));
+                    if self.token.is_cancelled() {
+                        return Err(ClientError::Cancelled);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for StatsSubscriber {
+    type Item = Result<StatsEvent, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.token.is_cancelled() {
+                return None;
+            }
+            if self.stream.is_none() {
+                if let Err(err) = self.resubscribe() {
+                    // given up, nothing more to yield after the error
+                    self.close();
+                    return match err {
+                        ClientError::Cancelled => None,
+                        err => Some(Err(err)),
+                    };
+                }
+                if self.reconnecting {
+                    self.reconnecting = false;
+                    return Some(Ok(StatsEvent::Reconnected));
+                }
+            }
+
+            match self.next_report() {
+                Ok(snapshot) => return Some(Ok(StatsEvent::Report(snapshot))),
+                Err(ClientError::Cancelled) => return None,
+                Err(_) => {
+                    self.stream = None;
+                    self.reconnecting = true;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for StatsSubscriber {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Shared flag a UI thread can flip to stop an operation running elsewhere.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+// Client for the file server protocol.
+// Downloads go over a single keep-alive session which is reused between calls,
+// the connection is only thrown away when a transfer is interrupted half way
+// because the rest of the frame would still be sitting in the socket.
+pub struct FileClient {
+    address: String,
+    port: String,
+    connect_timeout: time::Duration,
+    operation_timeout: Option<time::Duration>,
+    retry_policy: RetryPolicy,
+    // BUSY answers waited out before one is returned as ClientError::Busy
+    busy_retries: u32,
+    verify_checksums: bool,
+    // (user, password) sent ahead of every command, see set_login
+    login: Option<(String, String)>,
+    // told where a new session waits for a worker, see set_queue_listener
+    queue_listener: Option<Arc<dyn Fn(u64) + Send + Sync>>,
+    session: Option<TcpStream>,
+}
+
+impl FileClient {
+    pub fn new(address: &str, port: &str) -> FileClient {
+        FileClient {
+            address: address.to_owned(),
+            port: port.to_owned(),
+            connect_timeout: time::Duration::from_secs(5),
+            operation_timeout: None,
+            retry_policy: RetryPolicy::none(),
+            busy_retries: DEFAULT_BUSY_RETRIES,
+            verify_checksums: false,
+            login: None,
+            queue_listener: None,
+            session: None,
+        }
+    }
+
+    pub fn set_connect_timeout(&mut self, timeout: time::Duration) {
+        self.connect_timeout = timeout;
+    }
+
+    // upper bound for a whole operation, None waits forever. With retries
+    // every attempt gets the full timeout.
+    pub fn set_operation_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.operation_timeout = timeout;
+    }
+
+    // Retries downloads that lose their connection. An interrupted download
+    // picks up where it stopped with a ranged request instead of starting over.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    // How many times in a row a command the server sheds is sent again after
+    // the wait it asked for, 0 returns ClientError::Busy right away.
+    pub fn set_busy_retries(&mut self, retries: u32) {
+        self.busy_retries = retries;
+    }
+
+    // Has the server send a SHA-256 after every single file download and
+    // checks what arrived against it, see ClientError::ChecksumMismatch.
+    // Batch, archive and sync replies are not checked.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        if verify != self.verify_checksums {
+            // checksums are switched on per session
+            self.close();
+            self.verify_checksums = verify;
+        }
+    }
+
+    // Calls `listener` with the place in the queue (1 for next in line) about
+    // every second while a new session waits for a busy server to pick it
+    // up, for showing wait state instead of a stall. Sessions say Hello for
+    // it, servers older than Hello fail them, older ones with Hello never
+    // call the listener.
+    pub fn set_queue_listener(&mut self, listener: impl Fn(u64) + Send + Sync + 'static) {
+        self.close();
+        self.queue_listener = Some(Arc::new(listener));
+    }
+
+    // Logs in as `user` on servers with accounts, every connection opened from
+    // now on starts with it. Files are then relative to the user's home.
+    pub fn set_login(&mut self, user: &str, password: &str) {
+        self.close();
+        self.login = Some((user.to_owned(), password.to_owned()));
+    }
+
+    pub fn download(&mut self, file_name: &str) -> Result<Vec<u8>, ClientError> {
+        let mut buffer = Vec::new();
+        self.download_to(file_name, &mut buffer, &CancellationToken::new())?;
+        Ok(buffer)
+    }
+
+    // Streams `file_name` into `writer`, returns the number of bytes written.
+    pub fn download_to<W: Write>(
+        &mut self,
+        file_name: &str,
+        writer: &mut W,
+        token: &CancellationToken,
+    ) -> Result<u64, ClientError> {
+        self.download_with_progress(file_name, writer, token, |_, _| {})
+    }
+
+    // Like download_to, calling `progress(received, total)` once the size is
+    // known and after every chunk. Called on this thread, keep it cheap.
+    pub fn download_with_progress<W: Write>(
+        &mut self,
+        file_name: &str,
+        writer: &mut W,
+        token: &CancellationToken,
+        progress: impl FnMut(u64, u64),
+    ) -> Result<u64, ClientError> {
+        if token.is_cancelled() {
+            return Err(ClientError::Cancelled);
+        }
+
+        let mut writer = CountingWriter::new(writer, progress);
+        // known once the server started sending, a retry then only asks for the rest
+        let mut length = None;
+        let verify = self.verify_checksums;
+        self.with_retries(token, |client| match length {
+            Some(length) => client
+                .range_attempt(file_name, writer.written, length, &mut writer, token)
+                .map(|_| length),
+            None => client.on_session(|stream, deadline| {
+                send_request(
+                    stream,
+                    CommandType::Download,
+                    format!("filename={}|", file_name).as_bytes(),
+                )?;
+                let frame_length = read_ok_frame(stream, token, deadline)?;
+                length = Some(frame_length);
+                writer.start(frame_length);
+                copy_checked_payload(
+                    stream,
+                    frame_length,
+                    &mut writer,
+                    token,
+                    deadline,
+                    verify.then_some(file_name),
+                )?;
+                Ok(frame_length)
+            }),
+        })
+    }
+
+    // Downloads `file_name` along with its content type, e.g. `image/png`. Runs
+    // on a session of its own that asked for content types, the keep-alive
+    // session stays as it is.
+    pub fn download_with_content_type(
+        &self,
+        file_name: &str,
+    ) -> Result<(String, Vec<u8>), ClientError> {
+        let token = CancellationToken::new();
+        let deadline = self.operation_timeout.map(|t| time::Instant::now() + t);
+        let mut stream = self.connect()?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        send_request(
+            &mut stream,
+            CommandType::KeepAlive,
+            &[CommandType::ContentTypes.into()],
+        )?;
+        read_ok_frame(&mut stream, &token, deadline)?;
+        send_request(
+            &mut stream,
+            CommandType::Download,
+            format!("filename={}|", file_name).as_bytes(),
+        )?;
+
+        let content_type = match read_frame(&mut stream, &token, deadline)? {
+            (FRAME_CONTENT_TYPE, length) => {
+                let mut content_type = vec![0; length as usize];
+                read_exact_cancellable(&mut stream, &mut content_type, &token, deadline)?;
+                String::from_utf8_lossy(&content_type).to_string()
+            }
+            (other, _) => {
+                return Err(ClientError::Io(format!(
+                    "expected a content type frame, got status {}",
+                    other
+                )))
+            }
+        };
+        let length = read_ok_frame(&mut stream, &token, deadline)?;
+        let mut bytes = Vec::new();
+        copy_payload(&mut stream, length, &mut bytes, &token, deadline)?;
+        let _ = stream.write_all(&[CommandType::Quit.into()]);
+        Ok((content_type, bytes))
+    }
+
+    // Downloads `file_name` along with its ETag, to hand back later as
+    // DownloadCondition::NoneMatch. Runs on a session of its own like
+    // download_with_content_type.
+    pub fn download_with_etag(&self, file_name: &str) -> Result<(String, Vec<u8>), ClientError> {
+        let token = CancellationToken::new();
+        let deadline = self.operation_timeout.map(|t| time::Instant::now() + t);
+        let mut stream = self.connect()?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        send_request(
+            &mut stream,
+            CommandType::KeepAlive,
+            &[CommandType::ETags.into()],
+        )?;
+        read_ok_frame(&mut stream, &token, deadline)?;
+        send_request(
+            &mut stream,
+            CommandType::Download,
+            format!("filename={}|", file_name).as_bytes(),
+        )?;
+
+        let etag = match read_frame(&mut stream, &token, deadline)? {
+            (FRAME_ETAG, length) => {
+                let mut etag = vec![0; length as usize];
+                read_exact_cancellable(&mut stream, &mut etag, &token, deadline)?;
+                String::from_utf8_lossy(&etag).to_string()
+            }
+            (other, _) => {
+                return Err(ClientError::Io(format!(
+                    "expected an ETag frame, got status {}",
+                    other
+                )))
+            }
+        };
+        let length = read_ok_frame(&mut stream, &token, deadline)?;
+        let mut bytes = Vec::new();
+        copy_payload(&mut stream, length, &mut bytes, &token, deadline)?;
+        let _ = stream.write_all(&[CommandType::Quit.into()]);
+        Ok((etag, bytes))
+    }
+
+    // Downloads `file_name`, or its gzip precompressed sibling when the
+    // server has a fresh one and serves those (see Capabilities::compression).
+    // The bytes come back as sent, along with their encoding, `gzip` when they
+    // still need decompressing and None for the file itself. Runs on a
+    // session of its own like download_with_content_type.
+    pub fn download_accepting_gzip(
+        &self,
+        file_name: &str,
+    ) -> Result<(Option<String>, Vec<u8>), ClientError> {
+        let token = CancellationToken::new();
+        let deadline = self.operation_timeout.map(|t| time::Instant::now() + t);
+        let mut stream = self.connect()?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        send_request(
+            &mut stream,
+            CommandType::KeepAlive,
+            &[CommandType::AcceptGzip.into()],
+        )?;
+        read_ok_frame(&mut stream, &token, deadline)?;
+        send_request(
+            &mut stream,
+            CommandType::Download,
+            format!("filename={}|", file_name).as_bytes(),
+        )?;
+
+        let (encoding, length) = match read_frame(&mut stream, &token, deadline)? {
+            (FRAME_ENCODING, length) => {
+                let mut encoding = vec![0; length as usize];
+                read_exact_cancellable(&mut stream, &mut encoding, &token, deadline)?;
+                let encoding = String::from_utf8_lossy(&encoding).to_string();
+                (
+                    Some(encoding),
+                    read_ok_frame(&mut stream, &token, deadline)?,
+                )
+            }
+            (FRAME_OK, length) => (None, length),
+            (other, _) => {
+                return Err(ClientError::Io(format!(
+                    "expected an encoding or OK frame, got status {}",
+                    other
+                )))
+            }
+        };
+        let mut bytes = Vec::new();
+        copy_payload(&mut stream, length, &mut bytes, &token, deadline)?;
+        let _ = stream.write_all(&[CommandType::Quit.into()]);
+        Ok((encoding, bytes))
+    }
+
+    // Size, modification time and ETag of a served file.
+    pub fn stat(&mut self, file_name: &str) -> Result<FileStat, ClientError> {
+        let token = CancellationToken::new();
+        self.on_session(|stream, deadline| {
+            send_request(
+                stream,
+                CommandType::Stat,
+                format!("filename={}|", file_name).as_bytes(),
+            )?;
+            let length = read_ok_frame(stream, &token, deadline)?;
+            let mut reply = vec![0; length as usize];
+            read_exact_cancellable(stream, &mut reply, &token, deadline)?;
+
+            let reply = String::from_utf8_lossy(&reply);
+            let malformed = || ClientError::Io(format!("malformed stat reply {:?}", reply));
+            let mut fields = reply.split('\t');
+            let (Some(size), Some(modified)) = (fields.next(), fields.next()) else {
+                return Err(malformed());
+            };
+            Ok(FileStat {
+                size: size.parse().map_err(|_| malformed())?,
+                modified: time::UNIX_EPOCH
+                    + time::Duration::from_secs(modified.parse().map_err(|_| malformed())?),
+                etag: fields.next().map(str::to_owned),
+            })
+        })
+    }
+
+    // Streams bytes `start..end` of `file_name` into `writer`.
+    pub fn download_range<W: Write>(
+        &mut self,
+        file_name: &str,
+        start: u64,
+        end: u64,
+        writer: &mut W,
+        token: &CancellationToken,
+    ) -> Result<u64, ClientError> {
+        if token.is_cancelled() {
+            return Err(ClientError::Cancelled);
+        }
+
+        let mut writer = CountingWriter::new(writer, |_, _| {});
+        self.with_retries(token, |client| {
+            let resume_at = start + writer.written;
+            client.range_attempt(file_name, resume_at, end, &mut writer, token)
+        })?;
+        Ok(writer.written)
+    }
+
+    fn range_attempt<W: Write>(
+        &mut self,
+        file_name: &str,
+        start: u64,
+        end: u64,
+        writer: &mut W,
+        token: &CancellationToken,
+    ) -> Result<u64, ClientError> {
+        let verify = self.verify_checksums.then_some(file_name);
+        self.on_session(|stream, deadline| {
+            let request = format!("filename={}|range={}-{}|", file_name, start, end);
+            send_request(stream, CommandType::RangeDownload, request.as_bytes())?;
+            let length = read_ok_frame(stream, token, deadline)?;
+            copy_checked_payload(stream, length, writer, token, deadline, verify)?;
+            Ok(length)
+        })
+    }
+
+    // Runs `attempt` until it succeeds, fails for a reason retrying will not
+    // fix, or the retry policy runs out. Waits between attempts per the policy.
+    fn with_retries<T>(
+        &mut self,
+        token: &CancellationToken,
+        mut attempt: impl FnMut(&mut FileClient) -> Result<T, ClientError>,
+    ) -> Result<T, ClientError> {
+        let mut failed = 0;
+        loop {
+            let err = match attempt(self) {
+                Ok(result) => return Ok(result),
+                Err(err) => err,
+            };
+            failed += 1;
+            let retryable = matches!(err, ClientError::Connect(_) | ClientError::Io(_));
+            if !retryable || failed >= self.retry_policy.max_attempts {
+                return Err(err);
+            }
+
+            let resume = time::Instant::now() + self.retry_policy.backoff(failed);
+            while time::Instant::now() < resume {
+                if token.is_cancelled() {
+                    return Err(ClientError::Cancelled);
+                }
+                thread::sleep(
+                    resume
+                        .saturating_duration_since(time::Instant::now())
+                        .min(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)),
+                );
+            }
+        }
+    }
+
+    // Downloads `file_name` to `path` over `connections` connections, each
+    // fetching its own slice of the file. Returns the file size.
+    pub fn download_parallel(
+        &mut self,
+        file_name: &str,
+        path: &Path,
+        connections: usize,
+        token: &CancellationToken,
+    ) -> Result<u64, ClientError> {
+        let size = self.stat(file_name)?.size;
+        let file = fs::File::create(path).map_err(|err| ClientError::Io(err.to_string()))?;
+        file.set_len(size)
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+
+        let slice = size.div_ceil(connections.max(1) as u64).max(1);
+        let ranges: Vec<(u64, u64)> = (0..size)
+            .step_by(slice as usize)
+            .map(|start| (start, (start + slice).min(size)))
+            .collect();
+
+        thread::scope(|scope| {
+            let workers: Vec<_> = ranges
+                .into_iter()
+                .map(|(start, end)| {
+                    let mut client = self.sibling();
+                    scope.spawn(move || -> Result<(), ClientError> {
+                        let mut file = fs::OpenOptions::new()
+                            .write(true)
+                            .open(path)
+                            .map_err(|err| ClientError::Io(err.to_string()))?;
+                        file.seek(SeekFrom::Start(start))
+                            .map_err(|err| ClientError::Io(err.to_string()))?;
+                        let mut writer = io::BufWriter::new(file);
+                        client.download_range(file_name, start, end, &mut writer, token)?;
+                        writer
+                            .flush()
+                            .map_err(|err| ClientError::Io(err.to_string()))
+                    })
+                })
+                .collect();
+            // report the first failure, the other slices are wasted anyway
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker.join().unwrap_or_else(|_| {
+                        Err(ClientError::Io("download thread panicked".to_owned()))
+                    })
+                })
+                .collect::<Result<Vec<()>, ClientError>>()
+        })?;
+        Ok(size)
+    }
+
+    // Downloads `file_name` to `path` by way of `<path>.partial`, which holds
+    // what has arrived so far. A partial file left by an earlier run is picked
+    // up where it stopped with a range download. The whole file is checked
+    // against the server's checksum before it is renamed into place, a partial
+    // file that does not match is thrown away. `progress(received, total)`
+    // counts the bytes already there. Returns the file size.
+    pub fn download_resumable(
+        &mut self,
+        file_name: &str,
+        path: &Path,
+        token: &CancellationToken,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<u64, ClientError> {
+        let io_error = |err: io::Error| ClientError::Io(err.to_string());
+        let stat = self.stat(file_name)?;
+        let partial = partial_path(path);
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&partial)
+            .map_err(io_error)?;
+        let mut have = file.metadata().map_err(io_error)?.len();
+        // the file shrank on the server since, start over
+        if have > stat.size {
+            file.set_len(0).map_err(io_error)?;
+            have = 0;
+        }
+
+        // hash what is already there, the rest is hashed on its way in
+        let mut hashing = Hashing::new(&mut file);
+        io::copy(&mut (&mut hashing).take(have), &mut io::sink()).map_err(io_error)?;
+        let mut writer = CountingWriter::new(&mut hashing, |received, total| {
+            progress(have + received, total)
+        });
+        writer.start(stat.size);
+        if have < stat.size {
+            self.with_retries(token, |client| {
+                let resume_at = have + writer.written;
+                client.range_attempt(file_name, resume_at, stat.size, &mut writer, token)
+            })?;
+        }
+        writer.flush().map_err(io_error)?;
+
+        let sha256 = hashing.sha256_hex();
+        if stat.etag.is_some_and(|etag| etag != sha256) {
+            drop(file);
+            let _ = fs::remove_file(&partial);
+            return Err(ClientError::ChecksumMismatch(file_name.to_owned()));
+        }
+        file.sync_all().map_err(io_error)?;
+        drop(file);
+        fs::rename(&partial, path).map_err(io_error)?;
+        Ok(stat.size)
+    }
+
+    // Downloads `file_name` into `writer` in numbered chunks of `chunk_bytes`,
+    // acknowledging every `ack_every` of them. Chunks that go missing or
+    // arrive cut short are asked for again, so a relay on the way that drops
+    // or mangles data does not end the download. Like tail this gets a
+    // connection of its own. Returns the file size.
+    pub fn download_reliable<W: Write>(
+        &self,
+        file_name: &str,
+        chunk_bytes: u64,
+        ack_every: u64,
+        writer: &mut W,
+        token: &CancellationToken,
+    ) -> Result<u64, ClientError> {
+        let deadline = self.operation_timeout.map(|t| time::Instant::now() + t);
+        let mut stream = self.connect()?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        let request = format!(
+            "filename={}|chunks={},{}|",
+            file_name, chunk_bytes, ack_every
+        );
+        send_request(
+            &mut stream,
+            CommandType::ReliableDownload,
+            request.as_bytes(),
+        )?;
+
+        let length = read_ok_frame(&mut stream, token, deadline)?;
+        let mut reply = vec![0; length as usize];
+        read_exact_cancellable(&mut stream, &mut reply, token, deadline)?;
+        let reply = String::from_utf8_lossy(&reply);
+        let malformed = || ClientError::Io(format!("malformed reliable reply {:?}", reply));
+        let mut fields = reply.split('\t');
+        let (Some(size), Some(chunks), Some(etag)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(malformed());
+        };
+        let size: u64 = size.parse().map_err(|_| malformed())?;
+        let chunks: u64 = chunks.parse().map_err(|_| malformed())?;
+
+        let mut hashing = Hashing::new(writer);
+        // the server sends windows from where the last answer left off
+        let mut window_start = 0;
+        let mut expected = 0;
+        while expected < chunks {
+            let window_end = (window_start + ack_every).min(chunks);
+            let length = match read_frame(&mut stream, token, deadline)? {
+                (FRAME_CHUNK, length) if length >= 8 => length,
+                (other, _) => {
+                    return Err(ClientError::Io(format!(
+                        "expected a chunk frame, got status {}",
+                        other
+                    )))
+                }
+            };
+            let mut seq = [0u8; 8];
+            read_exact_cancellable(&mut stream, &mut seq, token, deadline)?;
+            let seq = u64::from_be_bytes(seq);
+            let mut chunk = vec![0; (length - 8) as usize];
+            read_exact_cancellable(&mut stream, &mut chunk, token, deadline)?;
+
+            let whole =
+                chunk.len() as u64 == chunk_bytes.min(size.saturating_sub(seq * chunk_bytes));
+            if seq == expected && whole {
+                hashing
+                    .write_all(&chunk)
+                    .map_err(|err| ClientError::Io(err.to_string()))?;
+                expected += 1;
+            }
+            let answer = if seq + 1 == window_end {
+                // the end of the window, say what is still missing
+                let kind = if expected == window_end {
+                    CHUNK_ACK
+                } else {
+                    CHUNK_NACK
+                };
+                window_start = expected;
+                Some((kind, expected))
+            } else if seq + 1 == window_start {
+                // the window before came again, the ack for it got lost
+                Some((CHUNK_ACK, window_start))
+            } else {
+                None
+            };
+            if let Some((kind, seq)) = answer {
+                stream
+                    .write_all(&[kind])
+                    .and_then(|_| stream.write_all(&seq.to_be_bytes()))
+                    .map_err(|err| ClientError::Io(err.to_string()))?;
+            }
+        }
+
+        if !etag.is_empty() && etag != hashing.sha256_hex() {
+            return Err(ClientError::ChecksumMismatch(file_name.to_owned()));
+        }
+        Ok(size)
+    }
+
+    // Downloads `file_name` from `offset` on in chunks of `chunk_bytes` that
+    // each come with their offset and CRC-32, and hands every chunk that
+    // checks out to `write_chunk` with its offset, the way a device writes
+    // to flash. A chunk that fails its check ends the download with
+    // ChecksumMismatch before any of it is handed on, ask again from its
+    // offset. Like tail this gets a connection of its own. Returns the file
+    // size.
+    pub fn download_flash(
+        &self,
+        file_name: &str,
+        chunk_bytes: u64,
+        offset: u64,
+        mut write_chunk: impl FnMut(u64, &[u8]) -> io::Result<()>,
+    ) -> Result<u64, ClientError> {
+        let token = CancellationToken::new();
+        let deadline = self.operation_timeout.map(|t| time::Instant::now() + t);
+        let mut stream = self.connect()?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        let request = format!("filename={}|flash={},{}|", file_name, chunk_bytes, offset);
+        send_request(&mut stream, CommandType::FlashDownload, request.as_bytes())?;
+
+        let length = read_ok_frame(&mut stream, &token, deadline)?;
+        if length != 8 {
+            return Err(ClientError::Io(format!(
+                "malformed flash reply of {} bytes",
+                length
+            )));
+        }
+        let mut size = [0u8; 8];
+        read_exact_cancellable(&mut stream, &mut size, &token, deadline)?;
+        let size = u64::from_be_bytes(size);
+
+        let mut expected = offset;
+        loop {
+            let length = match read_frame(&mut stream, &token, deadline)? {
+                (FRAME_END, _) => break,
+                (FRAME_CRC_CHUNK, length) if length >= CRC_CHUNK_HEAD_LEN as u64 => length,
+                (other, _) => {
+                    return Err(ClientError::Io(format!(
+                        "expected a flash chunk, got status {}",
+                        other
+                    )))
+                }
+            };
+            let mut head = [0u8; CRC_CHUNK_HEAD_LEN];
+            read_exact_cancellable(&mut stream, &mut head, &token, deadline)?;
+            let (chunk_offset, crc) = decode_crc_chunk_head(&head);
+            let mut chunk = vec![0; (length - CRC_CHUNK_HEAD_LEN as u64) as usize];
+            read_exact_cancellable(&mut stream, &mut chunk, &token, deadline)?;
+            if chunk_offset != expected {
+                return Err(ClientError::Io(format!(
+                    "expected the chunk at {}, got the one at {}",
+                    expected, chunk_offset
+                )));
+            }
+            if crc32(&chunk) != crc {
+                return Err(ClientError::ChecksumMismatch(format!(
+                    "{} at offset {}",
+                    file_name, chunk_offset
+                )));
+            }
+            write_chunk(chunk_offset, &chunk).map_err(|err| ClientError::Io(err.to_string()))?;
+            expected += chunk.len() as u64;
+        }
+        if expected != size {
+            return Err(ClientError::Io(format!(
+                "{} ended at {} of {} bytes",
+                file_name, expected, size
+            )));
+        }
+        Ok(size)
+    }
+
+    // Brings the file at `path` up to date with `file_name` on the server and
+    // fetches only what changed, like rsync: the server gets a signature of
+    // each `block_size` block of the local copy and answers with the bytes
+    // the copy lacks and which of its blocks to reuse. Without a local copy
+    // the whole file comes over. The new file is put together in
+    // `<path>.partial` and checked against the server's checksum before it
+    // replaces the old one.
+    pub fn download_delta(
+        &mut self,
+        file_name: &str,
+        path: &Path,
+        block_size: u64,
+        token: &CancellationToken,
+    ) -> Result<DeltaStats, ClientError> {
+        let io_error = |err: io::Error| ClientError::Io(err.to_string());
+        let mut old = match fs::File::open(path) {
+            Ok(file) => Some(file),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(io_error(err)),
+        };
+        let mut body = Vec::new();
+        if let Some(old) = &old {
+            for signature in
+                delta::signatures(io::BufReader::new(old), block_size as usize).map_err(io_error)?
+            {
+                signature.encode(&mut body);
+            }
+        }
+        let partial = partial_path(path);
+
+        let result = self.on_session(|stream, deadline| {
+            let request = format!("filename={}|blocks={}|", file_name, block_size);
+            send_request(stream, CommandType::Delta, request.as_bytes())?;
+            stream
+                .write_all(&(body.len() as u64).to_be_bytes())
+                .and_then(|_| stream.write_all(&body))
+                .map_err(|err| ClientError::Io(err.to_string()))?;
+
+            let length = read_ok_frame(stream, token, deadline)?;
+            let mut reply = vec![0; length as usize];
+            read_exact_cancellable(stream, &mut reply, token, deadline)?;
+            let reply = String::from_utf8_lossy(&reply).to_string();
+            let (size, etag) = reply
+                .split_once('\t')
+                .and_then(|(size, etag)| Some((size.parse().ok()?, etag)))
+                .ok_or_else(|| ClientError::Io(format!("malformed delta reply {:?}", reply)))?;
+
+            let file = fs::File::create(&partial).map_err(io_error)?;
+            let mut hashing = Hashing::new(io::BufWriter::new(file));
+            let mut stats = DeltaStats {
+                size,
+                received: 0,
+                reused: 0,
+            };
+            loop {
+                match read_frame(stream, token, deadline)? {
+                    (FRAME_OK, length) => {
+                        copy_payload(stream, length, &mut hashing, token, deadline)?;
+                        stats.received += length;
+                    }
+                    (FRAME_COPY, 16) => {
+                        let mut blocks = [0u8; 16];
+                        read_exact_cancellable(stream, &mut blocks, token, deadline)?;
+                        let (first, count) = blocks.split_at(8);
+                        let first = u64::from_be_bytes(first.try_into().unwrap());
+                        let count = u64::from_be_bytes(count.try_into().unwrap());
+                        let old = old.as_mut().ok_or_else(|| {
+                            ClientError::Io("server reused blocks of a file we lack".to_owned())
+                        })?;
+                        old.seek(SeekFrom::Start(first * block_size))
+                            .map_err(io_error)?;
+                        stats.reused += io::copy(&mut old.take(count * block_size), &mut hashing)
+                            .map_err(io_error)?;
+                    }
+                    (FRAME_END, _) => break,
+                    (other, _) => {
+                        return Err(ClientError::Io(format!(
+                            "unexpected frame status {} in delta reply",
+                            other
+                        )))
+                    }
+                }
+            }
+            hashing.flush().map_err(io_error)?;
+            if hashing.sha256_hex() != etag {
+                return Err(ClientError::ChecksumMismatch(file_name.to_owned()));
+            }
+            Ok(stats)
+        });
+
+        match result {
+            Ok(stats) => {
+                fs::File::open(&partial)
+                    .and_then(|file| file.sync_all())
+                    .and_then(|_| fs::rename(&partial, path))
+                    .map_err(io_error)?;
+                Ok(stats)
+            }
+            Err(err) => {
+                let _ = fs::remove_file(&partial);
+                Err(err)
+            }
+        }
+    }
+
+    // A client for the same server with the same settings and its own session.
+    fn sibling(&self) -> FileClient {
+        let mut client = FileClient::new(&self.address, &self.port);
+        client.connect_timeout = self.connect_timeout;
+        client.operation_timeout = self.operation_timeout;
+        client.retry_policy = self.retry_policy.clone();
+        client.login = self.login.clone();
+        client
+    }
+
+    // Like download_to, but the server skips the transfer when `condition`
+    // says the local copy is current. Returns None in that case.
+    pub fn download_if_changed<W: Write>(
+        &mut self,
+        file_name: &str,
+        condition: &DownloadCondition,
+        writer: &mut W,
+        token: &CancellationToken,
+    ) -> Result<Option<u64>, ClientError> {
+        if token.is_cancelled() {
+            return Err(ClientError::Cancelled);
+        }
+
+        let verify = self.verify_checksums.then_some(file_name);
+        self.on_session(|stream, deadline| {
+            let request = format!("filename={}|{}", file_name, condition.encode());
+            send_request(stream, CommandType::ConditionalDownload, request.as_bytes())?;
+            match read_frame(stream, token, deadline)? {
+                (FRAME_NOT_MODIFIED, _) => Ok(None),
+                (_, length) => {
+                    copy_checked_payload(stream, length, writer, token, deadline, verify)?;
+                    Ok(Some(length))
+                }
+            }
+        })
+    }
+
+    // Streams a tar of `directory` ("." for everything served) into `writer`,
+    // returns the archive size.
+    pub fn download_archive<W: Write>(
+        &mut self,
+        directory: &str,
+        writer: &mut W,
+        token: &CancellationToken,
+    ) -> Result<u64, ClientError> {
+        if token.is_cancelled() {
+            return Err(ClientError::Cancelled);
+        }
+
+        self.on_session(|stream, deadline| {
+            send_request(
+                stream,
+                CommandType::Archive,
+                format!("filename={}|", directory).as_bytes(),
+            )?;
+            let length = read_ok_frame(stream, token, deadline)?;
+            copy_payload(stream, length, writer, token, deadline)?;
+            Ok(length)
+        })
+    }
+
+    // Downloads every served file matching `pattern` (`*` and `?` wildcards)
+    // into `into_dir`, returns what was written.
+    pub fn download_matching(
+        &mut self,
+        pattern: &str,
+        into_dir: &Path,
+        token: &CancellationToken,
+    ) -> Result<Vec<FileEntry>, ClientError> {
+        if token.is_cancelled() {
+            return Err(ClientError::Cancelled);
+        }
+
+        self.on_session(|stream, deadline| {
+            send_request(
+                stream,
+                CommandType::BatchDownload,
+                format!("filename={}|", pattern).as_bytes(),
+            )?;
+            receive_batch(stream, into_dir, token, deadline)
+        })
+    }
+
+    // Brings `dir` up to date with the served directory: every served file
+    // `dir` is missing or holds a different copy of is downloaded into it,
+    // returns what was written. Local files the server does not have are kept.
+    pub fn sync_dir(
+        &mut self,
+        dir: &Path,
+        token: &CancellationToken,
+    ) -> Result<Vec<FileEntry>, ClientError> {
+        if token.is_cancelled() {
+            return Err(ClientError::Cancelled);
+        }
+        let manifest: String = local_manifest(dir)
+            .map_err(|err| ClientError::Io(err.to_string()))?
+            .iter()
+            .map(ManifestEntry::encode_line)
+            .collect();
+
+        self.on_session(|stream, deadline| {
+            let mut request = (manifest.len() as u64).to_be_bytes().to_vec();
+            request.extend_from_slice(manifest.as_bytes());
+            send_request(stream, CommandType::Sync, &request)?;
+            receive_batch(stream, dir, token, deadline)
+        })
+    }
+
+    pub fn upload(&mut self, file_name: &str, content: &[u8]) -> Result<(), ClientError> {
+        self.upload_with_progress(file_name, content, |_, _| {})
+    }
+
+    // Like upload, calling `progress(sent, total)` after every chunk.
+    pub fn upload_with_progress(
+        &mut self,
+        file_name: &str,
+        content: &[u8],
+        progress: impl FnMut(u64, u64),
+    ) -> Result<(), ClientError> {
+        self.upload_from_reader_with_progress(file_name, content, content.len() as u64, progress)
+    }
+
+    // Uploads the local file at `path` under its file name, streamed from disk.
+    pub fn upload_file(&mut self, path: impl AsRef<Path>) -> Result<(), ClientError> {
+        let path = path.as_ref();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| ClientError::Io(format!("{} has no file name", path.display())))?;
+        let file = fs::File::open(path)
+            .map_err(|err| ClientError::Io(format!("{}: {}", path.display(), err)))?;
+        let len = file
+            .metadata()
+            .map_err(|err| ClientError::Io(format!("{}: {}", path.display(), err)))?
+            .len();
+        self.upload_from_reader(&file_name, file, len)
+    }
+
+    // Uploads the next `len` bytes of `content` without holding them all in
+    // memory. Fails, and drops the session, if `content` ends before that.
+    pub fn upload_from_reader(
+        &mut self,
+        file_name: &str,
+        content: impl Read,
+        len: u64,
+    ) -> Result<(), ClientError> {
+        self.upload_from_reader_with_progress(file_name, content, len, |_, _| {})
+    }
+
+    pub fn upload_from_reader_with_progress(
+        &mut self,
+        file_name: &str,
+        content: impl Read,
+        len: u64,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<(), ClientError> {
+        let token = CancellationToken::new();
+        // the content can only be read once, a shed upload is not resent
+        let result = self.on_session_once(|stream, deadline| {
+            let mut request = format!("filename={}|", file_name).into_bytes();
+            request.extend_from_slice(&len.to_be_bytes());
+            send_request(stream, CommandType::Upload, &request)?;
+            progress(0, len);
+            let mut content = content.take(len);
+            let mut chunk = vec![0; UPLOAD_CHUNK_BYTES.min(len as usize)];
+            let mut sent = 0;
+            while sent < len {
+                let read = match content.read(&mut chunk) {
+                    Ok(0) => {
+                        // the server still waits for the rest, the session is lost
+                        return Err(ClientError::Io(format!(
+                            "upload source ended after {} of {} bytes",
+                            sent, len
+                        )));
+                    }
+                    Ok(read) => read,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(ClientError::Io(err.to_string())),
+                };
+                if let Err(err) = stream.write_all(&chunk[..read]) {
+                    // a refused upload is answered and hung up on before the
+                    // body is in, the answer says more than the broken pipe
+                    return Err(read_ok_frame(stream, &token, deadline)
+                        .err()
+                        .unwrap_or_else(|| ClientError::Io(err.to_string())));
+                }
+                sent += read as u64;
+                progress(sent, len);
+            }
+            read_ok_frame(stream, &token, deadline)?;
+            Ok(())
+        });
+        // the server hangs up on an upload it turns away, the body unread
+        if result.is_err() {
+            self.close();
+        }
+        result
+    }
+
+    // The "host:port" of the shard `file_name` belongs to, asked of a server
+    // with a hash ring, see HashRing. The file need not exist yet.
+    pub fn locate(&mut self, file_name: &str) -> Result<String, ClientError> {
+        let token = CancellationToken::new();
+        self.on_session(|stream, deadline| {
+            send_request(
+                stream,
+                CommandType::Locate,
+                format!("filename={}|", file_name).as_bytes(),
+            )?;
+            let length = read_ok_frame(stream, &token, deadline)?;
+            let mut node = vec![0; length as usize];
+            read_exact_cancellable(stream, &mut node, &token, deadline)?;
+            Ok(String::from_utf8_lossy(&node).to_string())
+        })
+    }
+
+    // Removes `file_name` from the server, NotFound when it has no such file.
+    pub fn delete(&mut self, file_name: &str) -> Result<(), ClientError> {
+        let token = CancellationToken::new();
+        self.on_session(|stream, deadline| {
+            send_request(
+                stream,
+                CommandType::Delete,
+                format!("filename={}|", file_name).as_bytes(),
+            )?;
+            read_ok_frame(stream, &token, deadline)?;
+            Ok(())
+        })
+    }
+
+    pub fn list(&mut self) -> Result<Vec<FileEntry>, ClientError> {
+        let token = CancellationToken::new();
+        self.on_session(|stream, deadline| {
+            send_request(stream, CommandType::List, &[])?;
+            let length = read_ok_frame(stream, &token, deadline)?;
+            let mut listing = vec![0; length as usize];
+            read_exact_cancellable(stream, &mut listing, &token, deadline)?;
+
+            let mut files = Vec::new();
+            for line in String::from_utf8_lossy(&listing).lines() {
+                let (name, size) = line
+                    .rsplit_once('\t')
+                    .ok_or_else(|| ClientError::Io(format!("malformed listing line {:?}", line)))?;
+                files.push(FileEntry {
+                    name: name.to_owned(),
+                    size: size.parse().unwrap_or(0),
+                });
+            }
+            Ok(files)
+        })
+    }
+
+    // One page of the files `query` asks for. Follow `next` into the query's
+    // page for the rest, a directory of any size costs a page at a time.
+    pub fn list_page(&mut self, query: &ListQuery) -> Result<ListPage, ClientError> {
+        let token = CancellationToken::new();
+        self.on_session(|stream, deadline| {
+            send_request(stream, CommandType::ListPage, query.encode().as_bytes())?;
+            let mut files = Vec::new();
+            loop {
+                let (status, length) = read_frame(stream, &token, deadline)?;
+                let mut payload = vec![0; length as usize];
+                read_exact_cancellable(stream, &mut payload, &token, deadline)?;
+                let payload = String::from_utf8_lossy(&payload);
+                match status {
+                    FRAME_OK => {
+                        let (name, size) = payload.rsplit_once('\t').ok_or_else(|| {
+                            ClientError::Io(format!("malformed listing line {:?}", payload))
+                        })?;
+                        files.push(FileEntry {
+                            name: name.to_owned(),
+                            size: size.parse().unwrap_or(0),
+                        });
+                    }
+                    FRAME_END => {
+                        return Ok(ListPage {
+                            files,
+                            next: (!payload.is_empty()).then(|| payload.to_string()),
+                        })
+                    }
+                    other => {
+                        return Err(ClientError::Io(format!(
+                            "unexpected frame status {}",
+                            other
+                        )))
+                    }
+                }
+            }
+        })
+    }
+
+    // Transfers the server is running right now, oldest first.
+    pub fn transfers(&mut self) -> Result<Vec<ActiveTransfer>, ClientError> {
+        let token = CancellationToken::new();
+        self.on_session(|stream, deadline| {
+            send_request(stream, CommandType::Transfers, &[])?;
+            let length = read_ok_frame(stream, &token, deadline)?;
+            let mut listing = vec![0; length as usize];
+            read_exact_cancellable(stream, &mut listing, &token, deadline)?;
+
+            String::from_utf8_lossy(&listing)
+                .lines()
+                .map(|line| {
+                    ActiveTransfer::parse_line(line).ok_or_else(|| {
+                        ClientError::Io(format!("malformed transfer line {:?}", line))
+                    })
+                })
+                .collect()
+        })
+    }
+
+    // Runs one request/reply exchange on the keep-alive session. The session is
+    // dropped if the exchange broke off half way, a server error frame is a
+    // complete reply so the session stays usable after one.
+    fn on_session<T>(
+        &mut self,
+        mut exchange: impl FnMut(&mut TcpStream, Option<time::Instant>) -> Result<T, ClientError>,
+    ) -> Result<T, ClientError> {
+        let mut shed = 0;
+        loop {
+            match self.on_session_once(&mut exchange) {
+                // nothing of the command ran, send it again once asked to
+                Err(ClientError::Busy(retry_after)) if shed < self.busy_retries => {
+                    shed += 1;
+                    thread::sleep(retry_after);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn on_session_once<T>(
+        &mut self,
+        exchange: impl FnOnce(&mut TcpStream, Option<time::Instant>) -> Result<T, ClientError>,
+    ) -> Result<T, ClientError> {
+        let deadline = self.operation_timeout.map(|t| time::Instant::now() + t);
+        let result = self.session().and_then(|stream| {
+            stream
+                .set_write_timeout(deadline.map(|d| {
+                    // a zero timeout is rejected by the OS, an expired deadline still needs a value
+                    d.saturating_duration_since(time::Instant::now())
+                        .max(time::Duration::from_millis(1))
+                }))
+                .map_err(|err| ClientError::Io(err.to_string()))?;
+            exchange(stream, deadline)
+        });
+
+        match &result {
+            Ok(_)
+            | Err(ClientError::Server(_))
+            | Err(ClientError::NotFound(_))
+            | Err(ClientError::Denied(_))
+            | Err(ClientError::ChecksumMismatch(_)) => {}
+            Err(_) => self.close(),
+        }
+        result
+    }
+
+    // Follows changes to served files matching `pattern` (`*` and `?`
+    // wildcards). The subscription lives on a connection of its own since it
+    // never ends, the keep-alive session stays free for downloads.
+    pub fn watch(&self, pattern: &str) -> Result<ChangeFeed, ClientError> {
+        let mut stream = self.connect()?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        send_request(
+            &mut stream,
+            CommandType::Watch,
+            format!("filename={}|", pattern).as_bytes(),
+        )?;
+
+        // an empty OK frame once the server is watching
+        let deadline = self.operation_timeout.map(|t| time::Instant::now() + t);
+        read_ok_frame(&mut stream, &CancellationToken::new(), deadline)?;
+        Ok(ChangeFeed { stream })
+    }
+
+    // Follows a served file like `tail -f`: its last `last_bytes` bytes, then
+    // whatever is appended to it. Like watch this gets a connection of its own.
+    pub fn tail(&self, name: &str, last_bytes: u64) -> Result<TailFeed, ClientError> {
+        let mut stream = self.connect()?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        send_request(
+            &mut stream,
+            CommandType::Tail,
+            format!("filename={}|tail={}|", name, last_bytes).as_bytes(),
+        )?;
+        Ok(TailFeed { stream })
+    }
+
+    // Follows the server's statistics, one StatsEvent::Report per reporting
+    // tick. Like watch this gets a connection of its own.
+    pub fn subscribe_stats(&self) -> Result<StatsSubscriber, ClientError> {
+        let mut client = FileClient::new(&self.address, &self.port);
+        client.connect_timeout = self.connect_timeout;
+        client.retry_policy = self.retry_policy.clone();
+        client.login = self.login.clone();
+        let stream = client.subscribe_stats_stream()?;
+        Ok(StatsSubscriber {
+            client,
+            stream: Some(stream),
+            token: CancellationToken::new(),
+            reconnecting: false,
+        })
+    }
+
+    fn subscribe_stats_stream(&self) -> Result<TcpStream, ClientError> {
+        let mut stream = self.connect()?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        // StatisticsV2 in the binary v2 format
+        send_request(&mut stream, CommandType::StatisticsV2, &[FORMAT_V2])?;
+        Ok(stream)
+    }
+
+    // Health check on a short lived connection of its own, pings are not part
+    // of the keep-alive session protocol.
+    pub fn ping(&self) -> Result<ServerInfo, ClientError> {
+        // no login, health checks are answered for anyone
+        let addr = self.resolve()?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.connect_timeout)
+            .map_err(|err| ClientError::Connect(err.to_string()))?;
+        stream
+            .set_read_timeout(self.operation_timeout)
+            .and_then(|_| stream.write_all(&[CommandType::Ping.into()]))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+
+        let mut reply = Vec::new();
+        stream
+            .read_to_end(&mut reply)
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        let version_end = 1 + *reply.first().unwrap_or(&0) as usize;
+        if reply.len() != version_end + 8 {
+            return Err(ClientError::Io("malformed ping reply".to_owned()));
+        }
+
+        let mut uptime = [0u8; 8];
+        uptime.copy_from_slice(&reply[version_end..]);
+        Ok(ServerInfo {
+            version: String::from_utf8_lossy(&reply[1..version_end]).to_string(),
+            uptime: time::Duration::from_secs(u64::from_be_bytes(uptime)),
+        })
+    }
+
+    // What the server supports, from the Hello handshake: the protocol version
+    // both sides speak, its command bytes, the upload cap and compression.
+    // Servers older than Hello answer with an error.
+    pub fn capabilities(&self) -> Result<Capabilities, ClientError> {
+        // no login, Hello comes before it and a ping needs none
+        let token = CancellationToken::new();
+        let deadline = self.operation_timeout.map(|t| time::Instant::now() + t);
+        let addr = self.resolve()?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.connect_timeout)
+            .map_err(|err| ClientError::Connect(err.to_string()))?;
+        stream
+            .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        send_request(
+            &mut stream,
+            CommandType::Hello,
+            format!("version={}|", PROTOCOL_VERSION).as_bytes(),
+        )?;
+        let length = read_ok_frame(&mut stream, &token, deadline)?;
+        let mut reply = vec![0; length as usize];
+        read_exact_cancellable(&mut stream, &mut reply, &token, deadline)?;
+        // the server still wants a command after Hello
+        let _ = stream.write_all(&[CommandType::Ping.into()]);
+
+        Capabilities::parse(&String::from_utf8_lossy(&reply))
+            .ok_or_else(|| ClientError::Io("malformed capability frame".to_owned()))
+    }
+
+    // Ends the keep-alive session, the next operation opens a fresh one.
+    pub fn close(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            let _ = session.write_all(&[CommandType::Quit.into()]);
+        }
+    }
+
+    fn session(&mut self) -> Result<&mut TcpStream, ClientError> {
+        if self.session.is_none() {
+            let hello = self.queue_listener.is_some();
+            let mut stream = self.connect_with(hello)?;
+            stream
+                .set_read_timeout(Some(time::Duration::from_millis(CANCEL_POLL_INTERVAL_MS)))
+                .map_err(|err| ClientError::Io(err.to_string()))?;
+            stream
+                .write_all(&[CommandType::KeepAlive.into()])
+                .map_err(|err| ClientError::Io(err.to_string()))?;
+            if let Some(listener) = &self.queue_listener {
+                wait_in_queue(&mut stream, &**listener)?;
+            }
+            if self.verify_checksums {
+                send_request(&mut stream, CommandType::Checksums, &[])?;
+                read_ok_frame(&mut stream, &CancellationToken::new(), None)?;
+            }
+            self.session = Some(stream);
+        }
+        Ok(self.session.as_mut().unwrap())
+    }
+
+    // A new connection, logged in when there is a login. The server only
+    // answers a login that failed, it shows up as the reply to the command.
+    fn connect(&self) -> Result<TcpStream, ClientError> {
+        self.connect_with(false)
+    }
+
+    // connect, saying Hello ahead of the login when `hello` is set. Its
+    // reply is left for the caller to read.
+    fn connect_with(&self, hello: bool) -> Result<TcpStream, ClientError> {
+        let addr = self.resolve()?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.connect_timeout)
+            .map_err(|err| ClientError::Connect(err.to_string()))?;
+        if hello {
+            send_request(
+                &mut stream,
+                CommandType::Hello,
+                format!("version={}|", PROTOCOL_VERSION).as_bytes(),
+            )?;
+        }
+        if let Some((user, password)) = &self.login {
+            send_request(
+                &mut stream,
+                CommandType::Login,
+                format!("user={}|password={}|", user, password).as_bytes(),
+            )?;
+        }
+        Ok(stream)
+    }
+
+    fn resolve(&self) -> Result<SocketAddr, ClientError> {
+        let port = self
+            .port
+            .parse()
+            .map_err(|_| ClientError::Connect(format!("invalid port {:?}", self.port)))?;
+        listener::resolve(&self.address, port)
+            .map_err(|err| ClientError::Connect(err.to_string()))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| ClientError::Connect(format!("{} did not resolve", self.address)))
+    }
+}
+
+impl Drop for FileClient {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn send_request(
+    stream: &mut TcpStream,
+    command: CommandType,
+    request: &[u8],
+) -> Result<(), ClientError> {
+    stream
+        .write_all(&[command.into()])
+        .and_then(|_| stream.write_all(request))
+        .map_err(|err| ClientError::Io(err.to_string()))
+}
+
+// Reads the Hello reply of a connection that said it and, when the server
+// reports queue positions, passes them to `listener` until a worker picked
+// the connection up.
+fn wait_in_queue(stream: &mut TcpStream, listener: &dyn Fn(u64)) -> Result<(), ClientError> {
+    let token = CancellationToken::new();
+    let length = read_ok_frame(stream, &token, None)?;
+    let mut reply = vec![0; length as usize];
+    read_exact_cancellable(stream, &mut reply, &token, None)?;
+    let capabilities = Capabilities::parse(&String::from_utf8_lossy(&reply))
+        .ok_or_else(|| ClientError::Io("malformed capability frame".to_owned()))?;
+    if capabilities.protocol < QUEUE_POSITION_VERSION {
+        return Ok(());
+    }
+    loop {
+        match read_frame(stream, &token, None)? {
+            (FRAME_QUEUED, 8) => {
+                let mut position = [0u8; 8];
+                read_exact_cancellable(stream, &mut position, &token, None)?;
+                match u64::from_be_bytes(position) {
+                    0 => return Ok(()),
+                    position => listener(position),
+                }
+            }
+            (other, _) => {
+                return Err(ClientError::Io(format!(
+                    "unexpected frame status {} while queued",
+                    other
+                )))
+            }
+        }
+    }
+}
+
+// Reads a frame header and returns the payload length of an OK frame, error
+// frames are read in full and turned into ClientError::Server.
+fn read_ok_frame(
+    stream: &mut TcpStream,
+    token: &CancellationToken,
+    deadline: Option<time::Instant>,
+) -> Result<u64, ClientError> {
+    match read_frame(stream, token, deadline)? {
+        (FRAME_OK, length) => Ok(length),
+        (other, _) => Err(ClientError::Io(format!(
+            "unexpected frame status {}",
+            other
+        ))),
+    }
+}
+
+// Reads a frame header and returns its status and payload length, error
+// frames are read in full and turned into ClientError::Server, NotFound or
+// Denied.
+fn read_frame(
+    stream: &mut TcpStream,
+    token: &CancellationToken,
+    deadline: Option<time::Instant>,
+) -> Result<(u8, u64), ClientError> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    read_exact_cancellable(stream, &mut header, token, deadline)?;
+    let (status, length) = decode_frame_header(&header);
+
+    match status {
+        FRAME_OK | FRAME_NOT_MODIFIED | FRAME_END | FRAME_CHECKSUM | FRAME_CONTENT_TYPE
+        | FRAME_ETAG | FRAME_CHUNK | FRAME_COPY | FRAME_ENCODING | FRAME_QUEUED
+        | FRAME_CRC_CHUNK => Ok((status, length)),
+        FRAME_ERROR => {
+            let mut reason = vec![0; length as usize];
+            read_exact_cancellable(stream, &mut reason, token, deadline)?;
+            Err(ClientError::Server(
+                String::from_utf8_lossy(&reason).to_string(),
+            ))
+        }
+        FRAME_NOT_FOUND => {
+            let mut reason = vec![0; length as usize];
+            read_exact_cancellable(stream, &mut reason, token, deadline)?;
+            Err(ClientError::NotFound(
+                String::from_utf8_lossy(&reason).to_string(),
+            ))
+        }
+        FRAME_DENIED => {
+            let mut reason = vec![0; length as usize];
+            read_exact_cancellable(stream, &mut reason, token, deadline)?;
+            Err(ClientError::Denied(
+                String::from_utf8_lossy(&reason).to_string(),
+            ))
+        }
+        FRAME_BUSY if length == 8 => {
+            let mut retry_after = [0u8; 8];
+            read_exact_cancellable(stream, &mut retry_after, token, deadline)?;
+            Err(ClientError::Busy(time::Duration::from_secs(
+                u64::from_be_bytes(retry_after),
+            )))
+        }
+        other => Err(ClientError::Io(format!("unknown frame status {}", other))),
+    }
+}
+
+// Counts what went through so a retried download knows where to pick up, and
+// reports it to a progress callback.
+struct CountingWriter<W, P> {
+    inner: W,
+    written: u64,
+    total: u64,
+    progress: P,
+}
+
+impl<W: Write, P: FnMut(u64, u64)> CountingWriter<W, P> {
+    fn new(inner: W, progress: P) -> CountingWriter<W, P> {
+        CountingWriter {
+            inner,
+            written: 0,
+            total: 0,
+            progress,
+        }
+    }
+
+    // the size came in with the frame header
+    fn start(&mut self, total: u64) {
+        self.total = total;
+        (self.progress)(self.written, total);
+    }
+}
+
+impl<W: Write, P: FnMut(u64, u64)> Write for CountingWriter<W, P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        (self.progress)(self.written, self.total);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Where download_resumable keeps a download until it is complete.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    PathBuf::from(partial)
+}
+
+// Reads a multi-file reply (BatchDownload, Sync) up to its END frame, writing
+// each file into `into_dir`.
+fn receive_batch(
+    stream: &mut TcpStream,
+    into_dir: &Path,
+    token: &CancellationToken,
+    deadline: Option<time::Instant>,
+) -> Result<Vec<FileEntry>, ClientError> {
+    let mut files = Vec::new();
+    loop {
+        let length = match read_frame(stream, token, deadline)? {
+            (FRAME_END, _) => return Ok(files),
+            (FRAME_OK, length) => length,
+            (other, _) => {
+                return Err(ClientError::Io(format!(
+                    "unexpected frame status {}",
+                    other
+                )))
+            }
+        };
+
+        let mut name_length = [0u8; 2];
+        read_exact_cancellable(stream, &mut name_length, token, deadline)?;
+        let mut name = vec![0; u16::from_be_bytes(name_length) as usize];
+        read_exact_cancellable(stream, &mut name, token, deadline)?;
+        let size = length
+            .checked_sub(2 + name.len() as u64)
+            .ok_or_else(|| ClientError::Io("malformed batch frame".to_owned()))?;
+        let name = String::from_utf8_lossy(&name).to_string();
+        // the name comes from the server, do not let it point outside into_dir
+        validate_file_name(&name).map_err(|err| ClientError::Io(err.to_string()))?;
+
+        let mut file = fs::File::create(into_dir.join(&name))
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        copy_payload(stream, size, &mut file, token, deadline)?;
+        files.push(FileEntry { name, size });
+    }
+}
+
+// What a Sync tells the server about the files directly under `dir`, names
+// the server would never serve are left out.
+fn local_manifest(dir: &Path) -> io::Result<Vec<ManifestEntry>> {
+    let mut manifest = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !metadata.is_file() || validate_file_name(&name).is_err() {
+            continue;
+        }
+        manifest.push(ManifestEntry {
+            size: metadata.len(),
+            sha256: sha256_hex(fs::File::open(entry.path())?)?,
+            name,
+        });
+    }
+    Ok(manifest)
+}
+
+// Copies an OK frame's payload into `writer`. With `verify` set to the file
+// name the checksum frame the server sends after it is read and checked.
+fn copy_checked_payload<W: Write>(
+    stream: &mut TcpStream,
+    length: u64,
+    writer: &mut W,
+    token: &CancellationToken,
+    deadline: Option<time::Instant>,
+    verify: Option<&str>,
+) -> Result<(), ClientError> {
+    let Some(file_name) = verify else {
+        return copy_payload(stream, length, writer, token, deadline);
+    };
+    let mut hashing = Hashing::new(writer);
+    copy_payload(stream, length, &mut hashing, token, deadline)?;
+
+    let length = match read_frame(stream, token, deadline)? {
+        (FRAME_CHECKSUM, length) => length,
+        (other, _) => {
+            return Err(ClientError::Io(format!(
+                "expected a checksum frame, got status {}",
+                other
+            )))
+        }
+    };
+    let mut expected = vec![0; length as usize];
+    read_exact_cancellable(stream, &mut expected, token, deadline)?;
+    if expected != hashing.sha256_hex().as_bytes() {
+        return Err(ClientError::ChecksumMismatch(file_name.to_owned()));
+    }
+    Ok(())
+}
+
+// Copies `length` payload bytes from the stream into `writer`.
+fn copy_payload<W: Write>(
+    stream: &mut TcpStream,
+    length: u64,
+    writer: &mut W,
+    token: &CancellationToken,
+    deadline: Option<time::Instant>,
+) -> Result<(), ClientError> {
+    let mut remaining = length;
+    let mut chunk = [0u8; 1024];
+    while remaining > 0 {
+        let size = remaining.min(chunk.len() as u64) as usize;
+        read_exact_cancellable(stream, &mut chunk[..size], token, deadline)?;
+        writer
+            .write_all(&chunk[..size])
+            .map_err(|err| ClientError::Io(err.to_string()))?;
+        remaining -= size as u64;
+    }
+    Ok(())
+}
+
+// Like read_exact but gives up when the token is cancelled or the deadline
+// passes. Relies on the stream having a short read timeout to wake up.
+fn read_exact_cancellable(
+    stream: &mut TcpStream,
+    buf: &mut [u8],
+    token: &CancellationToken,
+    deadline: Option<time::Instant>,
+) -> Result<(), ClientError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        if token.is_cancelled() {
+            return Err(ClientError::Cancelled);
+        }
+        if deadline.is_some_and(|d| time::Instant::now() >= d) {
+            return Err(ClientError::Timeout("reading from server".to_owned()));
+        }
+
+        match stream.read(&mut buf[filled..]) {
+            Ok(0) => return Err(ClientError::Io("server closed the connection".to_owned())),
+            Ok(read) => filled += read,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(ClientError::Io(err.to_string())),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::types::ChangeKind;
+    use crate::{reader, CommandType, FileServer, Limits};
+    use std::{fs, thread};
+
+    fn init_test_server(port: &str, root_dir: &'static str, files: &[(&str, &str)]) {
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        for (file_name, content) in files {
+            fs::write(path.join(file_name), content).unwrap();
+        }
+        let mut server = FileServer::new("127.0.0.1", port, 4, root_dir).unwrap();
+        server.register_handlers(&[
+            (
+                CommandType::KeepAlive,
+                FileServer::handle_keep_alive_session,
+            ),
+            (CommandType::Watch, FileServer::handle_watch),
+            (CommandType::Tail, FileServer::handle_tail),
+            (CommandType::Sync, FileServer::handle_sync),
+        ]);
+        server.spawn();
+    }
+
+    #[test]
+    fn test_denied_files_are_told_apart_from_missing_ones() {
+        let root_dir = "temp_test_client_denied_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("server.key"), "secret").unwrap();
+        fs::write(path.join("notes.txt"), "public").unwrap();
+        let mut server = FileServer::new("127.0.0.1", "8219", 2, root_dir).unwrap();
+        server.register_handlers(&[(
+            CommandType::KeepAlive,
+            FileServer::handle_keep_alive_session,
+        )]);
+        server.set_serve_patterns(Vec::new(), vec!["*.key".to_owned()]);
+        server.spawn();
+
+        let mut client = FileClient::new("127.0.0.1", "8219");
+        assert!(matches!(
+            client.download("server.key"),
+            Err(ClientError::Denied(_))
+        ));
+        assert!(matches!(
+            client.download("missing.key"),
+            Err(ClientError::Denied(_))
+        ));
+        assert!(matches!(
+            client.download("missing.txt"),
+            Err(ClientError::NotFound(_))
+        ));
+        assert_eq!(b"public".to_vec(), client.download("notes.txt").unwrap());
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_capabilities_handshake() {
+        let root_dir = "temp_test_client_capabilities_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+        let mut server = FileServer::new("127.0.0.1", "8239", 2, root_dir).unwrap();
+        server.register_handlers(&[
+            (CommandType::Ping, FileServer::handle_ping),
+            (
+                CommandType::KeepAlive,
+                FileServer::handle_keep_alive_session,
+            ),
+        ]);
+        server.set_limits(Limits {
+            max_upload_bytes: Some(4),
+            ..Default::default()
+        });
+        server.spawn();
+
+        let mut client = FileClient::new("127.0.0.1", "8239");
+        let capabilities = client.capabilities().unwrap();
+        assert_eq!(PROTOCOL_VERSION, capabilities.protocol);
+        assert_eq!(Some(4), capabilities.max_upload_bytes);
+        assert_eq!(vec![4, 6, 20, 23], capabilities.commands);
+        // the server still serves the command after Hello, and holds to the cap
+        assert!(client.ping().is_ok());
+        assert!(matches!(
+            client.upload("big", b"too big"),
+            Err(ClientError::Server(_))
+        ));
+        client.upload("small", b"ok").unwrap();
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_download_reuses_session() {
+        let root_dir = "temp_test_client_root_dir";
+        init_test_server("8049", root_dir, &[("a", "first"), ("b", "second")]);
+
+        let mut client = FileClient::new("127.0.0.1", "8049");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        assert_eq!(b"first".to_vec(), client.download("a").unwrap());
+        assert!(matches!(
+            client.download("missing"),
+            Err(ClientError::NotFound(_))
+        ));
+        assert_eq!(b"second".to_vec(), client.download("b").unwrap());
+
+        let token = CancellationToken::new();
+        token.cancel();
+        assert!(matches!(
+            client.download_to("a", &mut Vec::new(), &token),
+            Err(ClientError::Cancelled)
+        ));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_progress_callbacks() {
+        let root_dir = "temp_test_client_progress_root_dir";
+        init_test_server("8040", root_dir, &[("sized", &"x".repeat(3000))]);
+
+        let mut client = FileClient::new("127.0.0.1", "8040");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        let mut reports = Vec::new();
+        client
+            .download_with_progress(
+                "sized",
+                &mut Vec::new(),
+                &CancellationToken::new(),
+                |received, total| reports.push((received, total)),
+            )
+            .unwrap();
+        // the size is known before the first byte arrives
+        assert_eq!(Some(&(0, 3000)), reports.first());
+        assert_eq!(Some(&(3000, 3000)), reports.last());
+        assert!(reports.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        let mut last = None;
+        client
+            .upload_with_progress("up", &[1u8; 70_000], |sent, total| {
+                last = Some((sent, total))
+            })
+            .unwrap();
+        assert_eq!(Some((70_000, 70_000)), last);
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_download_parallel() {
+        let root_dir = "temp_test_client_parallel_root_dir";
+        let content: String = (0..1000)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        init_test_server("8045", root_dir, &[("big", &content)]);
+        let into = std::env::temp_dir().join("temp_test_client_parallel_out");
+
+        let mut client = FileClient::new("127.0.0.1", "8045");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        assert_eq!(1000, client.stat("big").unwrap().size);
+        let token = CancellationToken::new();
+        assert_eq!(
+            1000,
+            client.download_parallel("big", &into, 3, &token).unwrap()
+        );
+        assert_eq!(content, fs::read_to_string(&into).unwrap());
+        assert!(matches!(
+            client.download_range("big", 990, 1001, &mut Vec::new(), &token),
+            Err(ClientError::Server(_))
+        ));
+        assert!(matches!(
+            client.stat("missing"),
+            Err(ClientError::NotFound(_))
+        ));
+
+        fs::remove_file(&into).unwrap();
+        reader::cleanup_server_file(root_dir);
+    }
+
+    // Forwards `listen` to `target`, the first `flaky` connections are cut
+    // after `cut_after` bytes from the server.
+    fn flaky_proxy(listen: &str, target: &'static str, flaky: usize, cut_after: u64) {
+        let listener = std::net::TcpListener::bind(listen).unwrap();
+        thread::spawn(move || {
+            for (n, client) in listener.incoming().enumerate() {
+                let mut client = client.unwrap();
+                let mut server = TcpStream::connect(target).unwrap();
+                let (mut from_client, mut to_server) =
+                    (client.try_clone().unwrap(), server.try_clone().unwrap());
+                thread::spawn(move || io::copy(&mut from_client, &mut to_server));
+                let limit = if n < flaky { cut_after } else { u64::MAX };
+                thread::spawn(move || {
+                    let _ = io::copy(&mut (&mut server).take(limit), &mut client);
+                    let _ = client.shutdown(std::net::Shutdown::Both);
+                    let _ = server.shutdown(std::net::Shutdown::Both);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_download_resumes_after_dropped_connection() {
+        let root_dir = "temp_test_client_retry_root_dir";
+        let content: String = (0..1000)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        init_test_server("8041", root_dir, &[("big", &content)]);
+        // 9 bytes of frame header, then the first 291 bytes of the file
+        flaky_proxy("127.0.0.1:8042", "127.0.0.1:8041", 2, 300);
+
+        let mut client = FileClient::new("127.0.0.1", "8042");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        assert!(matches!(client.download("big"), Err(ClientError::Io(_))));
+
+        let mut client = FileClient::new("127.0.0.1", "8042");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        client.set_retry_policy(RetryPolicy::exponential(3, time::Duration::from_millis(10)));
+        assert_eq!(content.as_bytes(), client.download("big").unwrap());
+        // a missing file is an answer, not something to retry
+        assert!(matches!(
+            client.download("missing"),
+            Err(ClientError::NotFound(_))
+        ));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_reliable_download() {
+        let server = crate::testkit::TestServer::start();
+        let content = "0123456789".repeat(100);
+        server.add_file("firmware.bin", &content);
+
+        let client = server.client();
+        let mut received = Vec::new();
+        let size = client
+            .download_reliable(
+                "firmware.bin",
+                64,
+                4,
+                &mut received,
+                &CancellationToken::new(),
+            )
+            .unwrap();
+        assert_eq!(1000, size);
+        assert_eq!(content.as_bytes(), &received[..]);
+
+        // an empty file is a header and no chunks
+        server.add_file("empty", "");
+        let mut received = Vec::new();
+        let size = client
+            .download_reliable("empty", 64, 4, &mut received, &CancellationToken::new())
+            .unwrap();
+        assert_eq!((0, 0), (size, received.len()));
+        assert!(matches!(
+            client.download_reliable("nope", 64, 4, &mut received, &CancellationToken::new()),
+            Err(ClientError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_delta_download_reuses_the_local_copy() {
+        let server = crate::testkit::TestServer::start();
+        let old: String = (0..2000).map(|n| format!("line {}\n", n)).collect();
+        let new = old.replace("line 1000\n", "line one thousand\n");
+        server.add_file("big.txt", &new);
+        let path = std::env::temp_dir().join(format!("delta_{}_big.txt", std::process::id()));
+        fs::write(&path, &old).unwrap();
+
+        let mut client = server.client();
+        let token = CancellationToken::new();
+        let stats = client
+            .download_delta("big.txt", &path, 256, &token)
+            .unwrap();
+        assert_eq!(new, fs::read_to_string(&path).unwrap());
+        assert_eq!(new.len() as u64, stats.size);
+        assert_eq!(stats.size, stats.received + stats.reused);
+        assert!(stats.received < 1024, "{:?}", stats);
+        assert!(!partial_path(&path).exists());
+
+        // nothing to start from, the whole file comes over
+        fs::remove_file(&path).unwrap();
+        let stats = client
+            .download_delta("big.txt", &path, 256, &token)
+            .unwrap();
+        assert_eq!((new.len() as u64, 0), (stats.received, stats.reused));
+        assert_eq!(new, fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_download_resumes_from_partial_file() {
+        let root_dir = "temp_test_client_partial_root_dir";
+        let content: String = (0..5000)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        init_test_server("8259", root_dir, &[("big", &content)]);
+        flaky_proxy("127.0.0.1:8258", "127.0.0.1:8259", 1, 2000);
+        let path = Path::new("temp_test_client_partial_download");
+        let partial = partial_path(path);
+        let token = CancellationToken::new();
+
+        // cut off mid file, what arrived stays behind
+        let mut client = FileClient::new("127.0.0.1", "8258");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        assert!(client
+            .download_resumable("big", path, &token, |_, _| {})
+            .is_err());
+        let have = fs::metadata(&partial).unwrap().len();
+        assert!(have > 0 && have < 5000);
+        assert!(!path.exists());
+
+        let mut first = None;
+        let mut client = FileClient::new("127.0.0.1", "8259");
+        let size = client
+            .download_resumable("big", path, &token, |received, _| {
+                first.get_or_insert(received);
+            })
+            .unwrap();
+        assert_eq!(5000, size);
+        assert_eq!(Some(have), first);
+        assert_eq!(content, fs::read_to_string(path).unwrap());
+        assert!(!partial.exists());
+
+        // a partial file of something else fails the checksum and is dropped
+        fs::write(&partial, "not the start of big").unwrap();
+        assert!(matches!(
+            client.download_resumable("big", path, &token, |_, _| {}),
+            Err(ClientError::ChecksumMismatch(_))
+        ));
+        assert!(!partial.exists());
+
+        fs::remove_file(path).unwrap();
+        reader::cleanup_server_file(root_dir);
+    }
+
+    // Forwards `listen` to `target`, flipping the byte at `offset` of what
+    // the server sends on every connection.
+    fn corrupting_proxy(listen: &str, target: &'static str, offset: usize) {
+        let listener = std::net::TcpListener::bind(listen).unwrap();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let mut client = client.unwrap();
+                let mut server = TcpStream::connect(target).unwrap();
+                let (mut from_client, mut to_server) =
+                    (client.try_clone().unwrap(), server.try_clone().unwrap());
+                thread::spawn(move || io::copy(&mut from_client, &mut to_server));
+                thread::spawn(move || {
+                    let (mut seen, mut buf) = (0, [0u8; 1024]);
+                    while let Ok(read) = server.read(&mut buf) {
+                        if read == 0 {
+                            break;
+                        }
+                        if (seen..seen + read).contains(&offset) {
+                            buf[offset - seen] ^= 0xff;
+                        }
+                        seen += read;
+                        if client.write_all(&buf[..read]).is_err() {
+                            break;
+                        }
+                    }
+                    let _ = client.shutdown(std::net::Shutdown::Both);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_checksums_catch_corruption() {
+        let root_dir = "temp_test_client_checksum_root_dir";
+        init_test_server("7929", root_dir, &[("doc", "all the bytes intact")]);
+        // the empty reply to Checksums and the download's frame header, then
+        // the 6th byte of the file
+        corrupting_proxy("127.0.0.1:7928", "127.0.0.1:7929", 9 + 9 + 5);
+
+        let mut client = FileClient::new("127.0.0.1", "7929");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        client.set_verify_checksums(true);
+        assert_eq!(
+            b"all the bytes intact".to_vec(),
+            client.download("doc").unwrap()
+        );
+        let mut range = Vec::new();
+        let token = CancellationToken::new();
+        client
+            .download_range("doc", 4, 7, &mut range, &token)
+            .unwrap();
+        assert_eq!(b"the".to_vec(), range);
+
+        let mut client = FileClient::new("127.0.0.1", "7928");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        client.set_verify_checksums(true);
+        assert!(matches!(
+            client.download("doc"),
+            Err(ClientError::ChecksumMismatch(_))
+        ));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_retry_backoff_doubles_up_to_the_cap() {
+        let mut policy = RetryPolicy::exponential(5, time::Duration::from_millis(100));
+        policy.max_backoff = time::Duration::from_millis(300);
+        assert_eq!(time::Duration::from_millis(100), policy.backoff(1));
+        assert_eq!(time::Duration::from_millis(200), policy.backoff(2));
+        assert_eq!(time::Duration::from_millis(300), policy.backoff(3));
+        assert_eq!(time::Duration::from_millis(300), policy.backoff(40));
+    }
+
+    #[test]
+    fn test_watch_follows_uploads() {
+        let root_dir = "temp_test_client_watch_root_dir";
+        init_test_server("8044", root_dir, &[]);
+
+        let mut client = FileClient::new("127.0.0.1", "8044");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        let mut feed = client.watch("*.csv").unwrap();
+        client.upload("skipped.txt", b"not watched").unwrap();
+        client.upload("report.csv", b"a,b").unwrap();
+
+        let token = CancellationToken::new();
+        assert_eq!(
+            ChangeEvent {
+                kind: ChangeKind::Created,
+                file_name: "report.csv".to_owned(),
+            },
+            feed.next_change(&token).unwrap()
+        );
+        assert!(matches!(client.watch("../*"), Err(ClientError::Server(_))));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_tail_follows_appends() {
+        let root_dir = "temp_test_client_tail_root_dir";
+        init_test_server("8179", root_dir, &[("app.log", "booting\nready\n")]);
+
+        let client = FileClient::new("127.0.0.1", "8179");
+        let mut feed = client.tail("app.log", 6).unwrap();
+        let token = CancellationToken::new();
+        assert_eq!(b"ready\n".to_vec(), feed.next_chunk(&token).unwrap());
+
+        let path = reader::served_directory_path(root_dir).join("app.log");
+        let mut log = fs::OpenOptions::new().append(true).open(path).unwrap();
+        log.write_all(b"serving\n").unwrap();
+        assert_eq!(b"serving\n".to_vec(), feed.next_chunk(&token).unwrap());
+
+        let mut missing = client.tail("missing.log", 6).unwrap();
+        assert!(matches!(
+            missing.next_chunk(&token),
+            Err(ClientError::NotFound(_))
+        ));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_subscribe_stats_until_closed() {
+        let root_dir = "temp_test_client_stats_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+        let mut server = FileServer::new("127.0.0.1", "8129", 2, root_dir).unwrap();
+        server.register_handlers(&[(CommandType::StatisticsV2, FileServer::no_op_handler)]);
+        server.set_stats_interval(time::Duration::from_millis(50));
+        server.set_stats_heartbeat_timeout(Some(time::Duration::from_secs(1)));
+        server.start_metrics_report();
+        server.spawn();
+
+        let client = FileClient::new("127.0.0.1", "8129");
+        let mut subscriber = client.subscribe_stats().unwrap();
+        // outlives the heartbeat timeout, so heartbeats are going out
+        let mut last: Option<StatsSnapshot> = None;
+        for _ in 0..30 {
+            match subscriber.next() {
+                Some(Ok(StatsEvent::Report(snapshot))) => {
+                    assert_eq!(2, snapshot.pool.workers);
+                    // no tick missed on the way, and the clock never went back
+                    if let Some(last) = last {
+                        assert_eq!(last.seq + 1, snapshot.seq);
+                        assert!(snapshot.timestamp_ms >= last.timestamp_ms);
+                    }
+                    last = Some(snapshot);
+                }
+                other => panic!("expected a report, got {:?}", other),
+            }
+        }
+
+        subscriber.close();
+        assert!(subscriber.next().is_none());
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_download_with_content_type() {
+        let root_dir = "temp_test_client_content_type_root_dir";
+        init_test_server("8169", root_dir, &[("notes.txt", "plain")]);
+        // no extension, the PNG signature gives it away
+        fs::write(
+            reader::served_directory_path(root_dir).join("logo"),
+            b"\x89PNG\r\n\x1a\n",
+        )
+        .unwrap();
+
+        let client = FileClient::new("127.0.0.1", "8169");
+        assert_eq!(
+            ("text/plain; charset=utf-8".to_owned(), b"plain".to_vec()),
+            client.download_with_content_type("notes.txt").unwrap()
+        );
+        assert_eq!(
+            "image/png",
+            client.download_with_content_type("logo").unwrap().0
+        );
+        assert!(matches!(
+            client.download_with_content_type("missing"),
+            Err(ClientError::NotFound(_))
+        ));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_download_accepting_gzip() {
+        let server =
+            crate::testkit::TestServer::start_with(|builder| builder.serve_precompressed(true));
+        server.add_file("notes.txt", "plain");
+        server.add_file("notes.txt.gz", "squeezed");
+        server.add_file("other.txt", "no sibling");
+        let client = server.client();
+        assert_eq!(vec!["gzip"], client.capabilities().unwrap().compression);
+
+        assert_eq!(
+            (Some("gzip".to_owned()), b"squeezed".to_vec()),
+            client.download_accepting_gzip("notes.txt").unwrap()
+        );
+        assert_eq!(
+            (None, b"no sibling".to_vec()),
+            client.download_accepting_gzip("other.txt").unwrap()
+        );
+        // an edit since the sibling was made leaves it stale
+        let notes = fs::File::options()
+            .write(true)
+            .open(server.root().join("notes.txt"))
+            .unwrap();
+        notes
+            .set_modified(time::SystemTime::now() + time::Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(
+            (None, b"plain".to_vec()),
+            client.download_accepting_gzip("notes.txt").unwrap()
+        );
+        // sessions that did not ask keep getting the file itself
+        assert_eq!(
+            b"plain".to_vec(),
+            server.client().download("notes.txt").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sync_dir() {
+        let root_dir = "temp_test_client_sync_root_dir";
+        init_test_server("8043", root_dir, &[("a", "fresh"), ("b", "same")]);
+        let into = std::env::temp_dir().join("temp_test_client_sync_out");
+        fs::create_dir_all(&into).unwrap();
+        fs::write(into.join("a"), "stale").unwrap();
+        fs::write(into.join("b"), "same").unwrap();
+        fs::write(into.join("local"), "kept").unwrap();
+
+        let mut client = FileClient::new("127.0.0.1", "8043");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        let token = CancellationToken::new();
+        assert_eq!(
+            vec![FileEntry {
+                name: "a".to_owned(),
+                size: 5
+            }],
+            client.sync_dir(&into, &token).unwrap()
+        );
+        assert_eq!("fresh", fs::read_to_string(into.join("a")).unwrap());
+        assert_eq!("kept", fs::read_to_string(into.join("local")).unwrap());
+        // nothing left to fetch the second time around
+        assert!(client.sync_dir(&into, &token).unwrap().is_empty());
+
+        fs::remove_dir_all(&into).unwrap();
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_upload_then_list() {
+        let root_dir = "temp_test_client_upload_root_dir";
+        init_test_server("8048", root_dir, &[("existing", "12345")]);
+
+        let mut client = FileClient::new("127.0.0.1", "8048");
+        client.upload("uploaded", b"fresh content").unwrap();
+        assert!(matches!(
+            client.upload("../escape", b"nope"),
+            Err(ClientError::Server(_))
+        ));
+
+        assert_eq!(
+            vec![
+                FileEntry {
+                    name: "existing".to_owned(),
+                    size: 5
+                },
+                FileEntry {
+                    name: "uploaded".to_owned(),
+                    size: 13
+                },
+            ],
+            client.list().unwrap()
+        );
+        // biggest first, a file per page
+        let mut query = ListQuery {
+            sort: crate::ListSort::Size,
+            descending: true,
+            limit: 1,
+            ..Default::default()
+        };
+        let first = client.list_page(&query).unwrap();
+        assert_eq!("uploaded", first.files[0].name);
+        assert_eq!(1, first.files.len());
+        query.page = first.next;
+        let second = client.list_page(&query).unwrap();
+        assert_eq!("existing", second.files[0].name);
+        assert_eq!(1, second.files.len());
+        assert_eq!(None, second.next);
+        assert_eq!(
+            b"fresh content".to_vec(),
+            client.download("uploaded").unwrap()
+        );
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_locate() {
+        let nodes = ["10.0.0.1:7878", "10.0.0.2:7878", "10.0.0.3:7878"];
+        let server = crate::testkit::TestServer::start_with(|builder| builder.ring(&nodes, 64));
+        let ring = crate::HashRing::new(&nodes.map(str::to_owned), 64);
+        let mut client = server.client();
+
+        for file_name in ["a.txt", "b.txt", "not-uploaded-yet.bin"] {
+            assert_eq!(
+                ring.locate(file_name).unwrap(),
+                client.locate(file_name).unwrap()
+            );
+        }
+        assert!(matches!(
+            client.locate("../escape"),
+            Err(ClientError::Server(_))
+        ));
+        let unsharded = crate::testkit::TestServer::start();
+        assert!(matches!(
+            unsharded.client().locate("a.txt"),
+            Err(ClientError::Server(_))
+        ));
+    }
+
+    #[test]
+    fn test_delete() {
+        let server = crate::testkit::TestServer::start();
+        server.add_file("old.log", "stale");
+        let mut client = server.client();
+
+        client.delete("old.log").unwrap();
+        assert!(!server.root().join("old.log").exists());
+        assert!(matches!(
+            client.delete("old.log"),
+            Err(ClientError::NotFound(_))
+        ));
+        assert!(matches!(
+            client.delete("../escape"),
+            Err(ClientError::Server(_))
+        ));
+    }
+
+    #[test]
+    fn test_download_flash() {
+        let server = crate::testkit::TestServer::start();
+        server.add_file("fw.bin", "0123456789");
+        let client = server.client();
+        let capabilities = client.capabilities().unwrap();
+        assert!(capabilities
+            .commands
+            .contains(&CommandType::FlashDownload.into()));
+
+        let mut flash = vec![0u8; 10];
+        let mut writes = Vec::new();
+        let size = client
+            .download_flash("fw.bin", 4, 0, |offset, chunk| {
+                let offset = offset as usize;
+                flash[offset..offset + chunk.len()].copy_from_slice(chunk);
+                writes.push(offset);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(10, size);
+        assert_eq!(b"0123456789".to_vec(), flash);
+        assert_eq!(vec![0, 4, 8], writes);
+
+        // picking up after a power loss
+        let mut rest = Vec::new();
+        client
+            .download_flash("fw.bin", 4, 6, |offset, chunk| {
+                rest.push((offset, chunk.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(vec![(6, b"6789".to_vec())], rest);
+        assert!(matches!(
+            client.download_flash("fw.bin", 4, 11, |_, _| Ok(())),
+            Err(ClientError::Server(_))
+        ));
+    }
+
+    #[test]
+    fn test_queue_listener_hears_the_wait() {
+        let server = crate::testkit::TestServer::start_with(|builder| builder.thread_count(1));
+        server.add_file("a.txt", "queued");
+        // a session holds the only worker until it closes
+        let mut first = server.client();
+        first.download("a.txt").unwrap();
+
+        let (report, positions) = std::sync::mpsc::channel();
+        let mut second = server.client();
+        second.set_queue_listener(move |position| {
+            let _ = report.send(position);
+        });
+        let waiting = thread::spawn(move || second.download("a.txt"));
+        assert_eq!(1, positions.recv().unwrap());
+        first.close();
+        assert_eq!(b"queued".to_vec(), waiting.join().unwrap().unwrap());
+    }
+
+    #[test]
+    fn test_upload_from_reader_and_file() {
+        let root_dir = "temp_test_client_upload_reader_root_dir";
+        init_test_server("8209", root_dir, &[]);
+
+        let mut client = FileClient::new("127.0.0.1", "8209");
+        // more than one chunk, and only `len` bytes of a longer source
+        let len = UPLOAD_CHUNK_BYTES as u64 * 2 + 5;
+        client
+            .upload_from_reader("big", io::repeat(7), len)
+            .unwrap();
+        assert_eq!(vec![7; len as usize], client.download("big").unwrap());
+        assert!(matches!(
+            client.upload_from_reader("short", &b"abc"[..], 10),
+            Err(ClientError::Io(_))
+        ));
+
+        let local = std::env::temp_dir().join("temp_test_client_upload_file.txt");
+        fs::write(&local, "from disk").unwrap();
+        client.upload_file(&local).unwrap();
+        assert_eq!(
+            b"from disk".to_vec(),
+            client.download("temp_test_client_upload_file.txt").unwrap()
+        );
+
+        fs::remove_file(local).unwrap();
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_download_matching() {
+        let root_dir = "temp_test_client_batch_root_dir";
+        init_test_server(
+            "8046",
+            root_dir,
+            &[
+                ("a.log", "first"),
+                ("b.log", "second"),
+                ("c.txt", "skipped"),
+            ],
+        );
+        let into_dir = std::env::temp_dir().join("temp_test_client_batch_out");
+        fs::create_dir_all(&into_dir).unwrap();
+
+        let mut client = FileClient::new("127.0.0.1", "8046");
+        client.set_operation_timeout(Some(time::Duration::from_secs(5)));
+        let token = CancellationToken::new();
+        let files = client
+            .download_matching("*.log", &into_dir, &token)
+            .unwrap();
+        assert_eq!(
+            vec!["a.log", "b.log"],
+            files.iter().map(|f| f.name.as_str()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "second",
+            fs::read_to_string(into_dir.join("b.log")).unwrap()
+        );
+        assert!(client
+            .download_matching("*.none", &into_dir, &token)
+            .unwrap()
+            .is_empty());
+        // the session survives a batch
+        assert_eq!(b"skipped".to_vec(), client.download("c.txt").unwrap());
+
+        fs::remove_dir_all(&into_dir).unwrap();
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_conditional_download() {
+        let root_dir = "temp_test_client_conditional_root_dir";
+        init_test_server("8047", root_dir, &[("synced", "same bytes")]);
+
+        let mut client = FileClient::new("127.0.0.1", "8047");
+        let token = CancellationToken::new();
+        let hash = reader::sha256_hex(&b"same bytes"[..]).unwrap();
+        let mut out = Vec::new();
+
+        // Stat and the ETag frames hand out what NoneMatch takes
+        assert_eq!(Some(&hash), client.stat("synced").unwrap().etag.as_ref());
+        let (etag, bytes) = client.download_with_etag("synced").unwrap();
+        assert_eq!((&hash, b"same bytes".to_vec()), (&etag, bytes));
+
+        let current = DownloadCondition::NoneMatch(etag);
+        assert_eq!(
+            None,
+            client
+                .download_if_changed("synced", &current, &mut out, &token)
+                .unwrap()
+        );
+        let stale = DownloadCondition::NoneMatch("00".to_owned());
+        assert_eq!(
+            Some(10),
+            client
+                .download_if_changed("synced", &stale, &mut out, &token)
+                .unwrap()
+        );
+        assert_eq!(b"same bytes".to_vec(), out);
+
+        let future = DownloadCondition::ModifiedSince(
+            time::SystemTime::now() + time::Duration::from_secs(60),
+        );
+        assert_eq!(
+            None,
+            client
+                .download_if_changed("synced", &future, &mut Vec::new(), &token)
+                .unwrap()
+        );
+        let epoch = DownloadCondition::ModifiedSince(time::UNIX_EPOCH);
+        assert_eq!(
+            Some(10),
+            client
+                .download_if_changed("synced", &epoch, &mut Vec::new(), &token)
+                .unwrap()
+        );
+
+        reader::cleanup_server_file(root_dir);
+    }
+}
@@ -0,0 +1,748 @@
+use crate::protocol::{self, Limits};
+use crate::reader::AtRestKey;
+use crate::server::accounts::UserAccount;
+use crate::server::janitor::TrashPolicy;
+use crate::server::limit::OverflowPolicy;
+use crate::server::metrics::DEFAULT_TOP_FILES;
+use crate::server::pool::BusyPolicy;
+use crate::server::ring::RingConfig;
+use crate::server::threads::DEFAULT_THREAD_PREFIX;
+use crate::server::types::CommandType;
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, fmt, fs, time};
+
+// Env vars take precedence over whatever the TOML file says, handy for
+// containers where the file is baked into the image.
+pub const ENV_PREFIX: &str = "FILESERVER_";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String),
+    Parse(String),
+    InvalidValue(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(reason) => write!(f, "Could not read config file: {}", reason),
+            ConfigError::Parse(reason) => write!(f, "Could not parse config file: {}", reason),
+            ConfigError::InvalidValue(reason) => write!(f, "Invalid config value: {}", reason),
+        }
+    }
+}
+
+// Every field is optional in the file, missing ones fall back to the defaults
+// the binaries used to hardcode.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    pub thread_count: i32,
+    // accept threads for address/port, each on its own SO_REUSEPORT socket.
+    // More than 1 only works on Linux
+    pub acceptor_threads: usize,
+    pub root_dir: String,
+    // directory root_dir is created in, the system temp dir when unset
+    pub base_dir: Option<String>,
+    pub keep_alive_timeout_secs: u64,
+    pub drain_timeout_secs: u64,
+    // more "address:port" pairs served next to address/port, e.g. "[::]:8089"
+    pub extra_listeners: Vec<String>,
+    // like extra_listeners but uploads are refused on them, e.g. a public port
+    pub read_only_listeners: Vec<String>,
+    // globs for the files downloads may open: none matching a deny pattern,
+    // and once there are allow patterns only those matching one of them
+    pub allow_patterns: Vec<String>,
+    pub deny_patterns: Vec<String>,
+    // what to do with new clients while every worker is busy:
+    // "queue", "backpressure", "reject" or "shed"
+    pub busy_policy: String,
+    // the N in the "retry after N seconds" a rejected client is told
+    pub busy_retry_after_secs: u64,
+    // connections "shed" lets wait for a worker before turning clients away
+    pub busy_max_queue: usize,
+    // downloads of files under this many bytes wait for a worker ahead of
+    // larger ones, one queue for everyone when unset
+    pub small_transfer_bytes: Option<u64>,
+    // most client sockets open at once, stats subscribers included, no cap when unset
+    pub max_connections: Option<usize>,
+    // what to do with new clients past max_connections: "queue" or "reject"
+    pub connection_overflow: String,
+    // download speed caps in bytes per second, for each transfer and for all
+    // of them together, unlimited when unset
+    pub max_transfer_bytes_per_sec: Option<u64>,
+    pub max_total_bytes_per_sec: Option<u64>,
+    // bytes each client IP may download per UTC day, uncapped when unset
+    pub daily_ip_cap_bytes: Option<u64>,
+    // `[handler_timeout_secs]` table of command name (as in the stats'
+    // request durations, "Download", "Upload"...) to the longest a connection
+    // may spend on it before it is hung up on. Not overridable from the environment
+    pub handler_timeout_secs: BTreeMap<String, u64>,
+    // most clients following the statistics at once, no cap when unset
+    pub max_stats_subscribers: Option<usize>,
+    // evict stats subscribers silent for this long, off when unset
+    pub stats_heartbeat_timeout_secs: Option<u64>,
+    // how often stats subscribers get a tick
+    pub stats_interval_ms: u64,
+    // every tick is also appended as a JSON line to this file, written to
+    // whoever listens on this Unix socket, and printed on stdout. Each is off
+    // unless set
+    pub stats_file: Option<String>,
+    pub stats_socket: Option<String>,
+    pub stats_stdout: bool,
+    // how many of the most downloaded files each stats report lists
+    pub top_files: usize,
+    // server threads are named `<prefix>-worker-3`, `<prefix>-stats`...
+    pub thread_name_prefix: String,
+    // also serve read-only TFTP on address:tftp_port, off when unset
+    pub tftp_port: Option<u16>,
+    // also serve the root dir over WebDAV on address:webdav_port, off when unset
+    pub webdav_port: Option<u16>,
+    // PUT and DELETE are refused over WebDAV, like on read_only_listeners
+    pub webdav_read_only: bool,
+    // plain text admin commands on admin_address:admin_port, off when unset.
+    // Unauthenticated, so it gets its own address, loopback by default
+    pub admin_address: String,
+    pub admin_port: Option<u16>,
+    // SHA-256 of the password `auth` takes on the admin port, needed for the
+    // `metrics` commands, which are refused when unset
+    pub admin_password_sha256: Option<String>,
+    // uploads and deletes are appended to this file, not kept when unset
+    pub audit_log: Option<String>,
+    // "host:port" of file servers whose stats are added up with this one's
+    // in the cluster part of its reports, none when empty
+    pub cluster_peers: Vec<String>,
+    // "host:port" of file servers every upload and delete in root_dir is sent
+    // on to, none when empty
+    pub replicas: Vec<String>,
+    // what replicas could not take yet is kept here across restarts, only in
+    // memory when unset
+    pub replication_queue: Option<String>,
+    // 64 hex digits, uploads are stored AES-256-GCM encrypted with it and
+    // decrypted on the way out. Files already on disk must have been stored
+    // with the same key, they are not served otherwise
+    pub encryption_key: Option<String>,
+    // a tar archive read at startup and served in place of root_dir, which
+    // uploads and deletes are then refused for
+    pub embedded_archive: Option<String>,
+    // fsync uploads and the served directory before acknowledging them
+    pub durable_uploads: bool,
+    // serve `foo.txt.gz` to sessions that accept gzip when it is no older than foo.txt
+    pub serve_precompressed: bool,
+    // largest upload accepted in bytes, advertised to clients that send Hello,
+    // uncapped when unset
+    pub max_upload_bytes: Option<u64>,
+    // longest file name, request header segment and sync manifest accepted
+    pub max_file_name_bytes: usize,
+    pub max_header_bytes: usize,
+    pub max_manifest_bytes: u64,
+    // deletes move files to root_dir/.trash, kept until they are older than
+    // trash_max_age_secs or the trash outgrows trash_max_bytes, unset keeps them
+    pub trash: bool,
+    pub trash_max_age_secs: Option<u64>,
+    pub trash_max_bytes: Option<u64>,
+    // seconds between janitor sweeps for stale `.part` files, expired trash
+    // and empty directories, no sweeping when unset
+    pub janitor_interval_secs: Option<u64>,
+    // `[[users]]` tables, once there is one every client has to log in and
+    // is jailed to root_dir/<name>. Not overridable from the environment
+    pub users: Vec<UserAccount>,
+    // `[ring]` table of the shards Locate maps file names onto, see HashRing.
+    // Locate answers with an error while it lists no nodes
+    pub ring: RingConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: "127.0.0.1".to_owned(),
+            port: 8089,
+            thread_count: 10,
+            acceptor_threads: 1,
+            root_dir: "rust_file_server".to_owned(),
+            base_dir: None,
+            keep_alive_timeout_secs: 30,
+            drain_timeout_secs: 10,
+            extra_listeners: Vec::new(),
+            read_only_listeners: Vec::new(),
+            allow_patterns: Vec::new(),
+            deny_patterns: Vec::new(),
+            busy_policy: "queue".to_owned(),
+            busy_retry_after_secs: 5,
+            busy_max_queue: 64,
+            small_transfer_bytes: None,
+            max_connections: None,
+            connection_overflow: "queue".to_owned(),
+            max_transfer_bytes_per_sec: None,
+            max_total_bytes_per_sec: None,
+            daily_ip_cap_bytes: None,
+            handler_timeout_secs: BTreeMap::new(),
+            max_stats_subscribers: None,
+            stats_heartbeat_timeout_secs: None,
+            stats_interval_ms: 1000,
+            stats_file: None,
+            stats_socket: None,
+            stats_stdout: false,
+            top_files: DEFAULT_TOP_FILES,
+            thread_name_prefix: DEFAULT_THREAD_PREFIX.to_owned(),
+            tftp_port: None,
+            webdav_port: None,
+            webdav_read_only: false,
+            admin_address: "127.0.0.1".to_owned(),
+            admin_port: None,
+            admin_password_sha256: None,
+            audit_log: None,
+            cluster_peers: Vec::new(),
+            replicas: Vec::new(),
+            replication_queue: None,
+            encryption_key: None,
+            embedded_archive: None,
+            durable_uploads: false,
+            serve_precompressed: false,
+            max_upload_bytes: None,
+            max_file_name_bytes: protocol::MAX_FILE_NAME_BYTES,
+            max_header_bytes: protocol::MAX_HEADER_BYTES,
+            max_manifest_bytes: protocol::MAX_MANIFEST_BYTES,
+            trash: false,
+            trash_max_age_secs: None,
+            trash_max_bytes: None,
+            janitor_interval_secs: None,
+            users: Vec::new(),
+            ring: RingConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    // Loads the file at `path` and applies env overrides on top.
+    pub fn load(path: &str) -> Result<ServerConfig, ConfigError> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| ConfigError::Read(format!("{}: {}", path, err)))?;
+        let mut config = Self::from_toml_str(&content)?;
+        config.apply_env_overrides()?;
+        Ok(config)
+    }
+
+    // Like `load` but a missing file just means defaults plus env overrides.
+    pub fn load_or_default(path: &str) -> Result<ServerConfig, ConfigError> {
+        if fs::metadata(path).is_ok() {
+            return Self::load(path);
+        }
+        let mut config = ServerConfig::default();
+        config.apply_env_overrides()?;
+        Ok(config)
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<ServerConfig, ConfigError> {
+        let config: ServerConfig =
+            toml::from_str(content).map_err(|err| ConfigError::Parse(err.to_string()))?;
+        config.busy_policy()?;
+        config.connection_overflow()?;
+        config.check_stats_interval()?;
+        config.check_janitor_interval()?;
+        config.check_ring()?;
+        config.check_limits()?;
+        config.check_thread_name_prefix()?;
+        config.check_encryption_key()?;
+        config.handler_timeouts()?;
+        Ok(config)
+    }
+
+    pub fn busy_policy(&self) -> Result<BusyPolicy, ConfigError> {
+        match self.busy_policy.as_str() {
+            "queue" => Ok(BusyPolicy::Queue),
+            "backpressure" => Ok(BusyPolicy::Backpressure),
+            "reject" => Ok(BusyPolicy::Reject {
+                retry_after: time::Duration::from_secs(self.busy_retry_after_secs),
+            }),
+            "shed" => Ok(BusyPolicy::Shed {
+                max_queue: self.busy_max_queue,
+            }),
+            other => Err(ConfigError::InvalidValue(format!(
+                "busy_policy={:?}, expected queue, backpressure, reject or shed",
+                other
+            ))),
+        }
+    }
+
+    pub fn handler_timeouts(&self) -> Result<Vec<(CommandType, time::Duration)>, ConfigError> {
+        self.handler_timeout_secs
+            .iter()
+            .map(|(name, secs)| {
+                let command = (1..=u8::MAX)
+                    .map_while(|byte| CommandType::try_from(byte).ok())
+                    .find(|command| format!("{:?}", command) == *name)
+                    .ok_or_else(|| {
+                        ConfigError::InvalidValue(format!(
+                            "handler_timeout_secs.{}, expected a command name like Download",
+                            name
+                        ))
+                    })?;
+                match secs {
+                    0 => Err(ConfigError::InvalidValue(format!(
+                        "handler_timeout_secs.{}=0, expected at least 1",
+                        name
+                    ))),
+                    secs => Ok((command, time::Duration::from_secs(*secs))),
+                }
+            })
+            .collect()
+    }
+
+    fn check_stats_interval(&self) -> Result<(), ConfigError> {
+        match self.stats_interval_ms {
+            0 => Err(ConfigError::InvalidValue(
+                "stats_interval_ms=0, expected at least 1".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_janitor_interval(&self) -> Result<(), ConfigError> {
+        match self.janitor_interval_secs {
+            Some(0) => Err(ConfigError::InvalidValue(
+                "janitor_interval_secs=0, expected at least 1".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_ring(&self) -> Result<(), ConfigError> {
+        match self.ring.vnodes {
+            0 => Err(ConfigError::InvalidValue(
+                "ring.vnodes=0, expected at least 1".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_thread_name_prefix(&self) -> Result<(), ConfigError> {
+        if self.thread_name_prefix.is_empty() || self.thread_name_prefix.contains('\0') {
+            return Err(ConfigError::InvalidValue(format!(
+                "thread_name_prefix={:?}, expected a name without NUL bytes",
+                self.thread_name_prefix
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_limits(&self) -> Result<(), ConfigError> {
+        for (key, value) in [
+            ("max_file_name_bytes", self.max_file_name_bytes as u64),
+            ("max_header_bytes", self.max_header_bytes as u64),
+            ("max_manifest_bytes", self.max_manifest_bytes),
+        ] {
+            if value == 0 {
+                return Err(ConfigError::InvalidValue(format!(
+                    "{}=0, expected at least 1",
+                    key
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_encryption_key(&self) -> Result<(), ConfigError> {
+        match &self.encryption_key {
+            // the key itself stays out of the message
+            Some(key) if AtRestKey::from_hex(key).is_err() => Err(ConfigError::InvalidValue(
+                "encryption_key, expected 64 hex digits".to_owned(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn connection_overflow(&self) -> Result<OverflowPolicy, ConfigError> {
+        match self.connection_overflow.as_str() {
+            "queue" => Ok(OverflowPolicy::Queue),
+            "reject" => Ok(OverflowPolicy::Reject),
+            other => Err(ConfigError::InvalidValue(format!(
+                "connection_overflow={:?}, expected queue or reject",
+                other
+            ))),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_file_name_bytes: self.max_file_name_bytes,
+            max_header_bytes: self.max_header_bytes,
+            max_upload_bytes: self.max_upload_bytes,
+            max_manifest_bytes: self.max_manifest_bytes,
+        }
+    }
+
+    // None unless `trash` is on.
+    pub fn trash_policy(&self) -> Option<TrashPolicy> {
+        self.trash.then(|| TrashPolicy {
+            max_age: self.trash_max_age_secs.map(time::Duration::from_secs),
+            max_bytes: self.trash_max_bytes,
+        })
+    }
+
+    pub fn apply_env_overrides(&mut self) -> Result<(), ConfigError> {
+        if let Some(address) = env_var("ADDRESS") {
+            self.address = address;
+        }
+        if let Some(port) = env_var("PORT") {
+            self.port = parse_env("PORT", &port)?;
+        }
+        if let Some(thread_count) = env_var("THREAD_COUNT") {
+            self.thread_count = parse_env("THREAD_COUNT", &thread_count)?;
+        }
+        if let Some(count) = env_var("ACCEPTOR_THREADS") {
+            self.acceptor_threads = parse_env("ACCEPTOR_THREADS", &count)?;
+        }
+        if let Some(root_dir) = env_var("ROOT_DIR") {
+            self.root_dir = root_dir;
+        }
+        if let Some(base_dir) = env_var("BASE_DIR") {
+            self.base_dir = Some(base_dir);
+        }
+        if let Some(timeout) = env_var("KEEP_ALIVE_TIMEOUT_SECS") {
+            self.keep_alive_timeout_secs = parse_env("KEEP_ALIVE_TIMEOUT_SECS", &timeout)?;
+        }
+        if let Some(timeout) = env_var("DRAIN_TIMEOUT_SECS") {
+            self.drain_timeout_secs = parse_env("DRAIN_TIMEOUT_SECS", &timeout)?;
+        }
+        // comma separated
+        if let Some(listeners) = env_var("EXTRA_LISTENERS") {
+            self.extra_listeners = listeners
+                .split(',')
+                .map(|l| l.trim().to_owned())
+                .filter(|l| !l.is_empty())
+                .collect();
+        }
+        if let Some(listeners) = env_var("READ_ONLY_LISTENERS") {
+            self.read_only_listeners = listeners
+                .split(',')
+                .map(|l| l.trim().to_owned())
+                .filter(|l| !l.is_empty())
+                .collect();
+        }
+        if let Some(patterns) = env_var("ALLOW_PATTERNS") {
+            self.allow_patterns = patterns
+                .split(',')
+                .map(|p| p.trim().to_owned())
+                .filter(|p| !p.is_empty())
+                .collect();
+        }
+        if let Some(patterns) = env_var("DENY_PATTERNS") {
+            self.deny_patterns = patterns
+                .split(',')
+                .map(|p| p.trim().to_owned())
+                .filter(|p| !p.is_empty())
+                .collect();
+        }
+        if let Some(policy) = env_var("BUSY_POLICY") {
+            self.busy_policy = policy;
+            self.busy_policy()?;
+        }
+        if let Some(secs) = env_var("BUSY_RETRY_AFTER_SECS") {
+            self.busy_retry_after_secs = parse_env("BUSY_RETRY_AFTER_SECS", &secs)?;
+        }
+        if let Some(max) = env_var("BUSY_MAX_QUEUE") {
+            self.busy_max_queue = parse_env("BUSY_MAX_QUEUE", &max)?;
+        }
+        if let Some(bytes) = env_var("SMALL_TRANSFER_BYTES") {
+            self.small_transfer_bytes = Some(parse_env("SMALL_TRANSFER_BYTES", &bytes)?);
+        }
+        if let Some(max) = env_var("MAX_CONNECTIONS") {
+            self.max_connections = Some(parse_env("MAX_CONNECTIONS", &max)?);
+        }
+        if let Some(policy) = env_var("CONNECTION_OVERFLOW") {
+            self.connection_overflow = policy;
+            self.connection_overflow()?;
+        }
+        if let Some(rate) = env_var("MAX_TRANSFER_BYTES_PER_SEC") {
+            self.max_transfer_bytes_per_sec = Some(parse_env("MAX_TRANSFER_BYTES_PER_SEC", &rate)?);
+        }
+        if let Some(rate) = env_var("MAX_TOTAL_BYTES_PER_SEC") {
+            self.max_total_bytes_per_sec = Some(parse_env("MAX_TOTAL_BYTES_PER_SEC", &rate)?);
+        }
+        if let Some(cap) = env_var("DAILY_IP_CAP_BYTES") {
+            self.daily_ip_cap_bytes = Some(parse_env("DAILY_IP_CAP_BYTES", &cap)?);
+        }
+        if let Some(max) = env_var("MAX_STATS_SUBSCRIBERS") {
+            self.max_stats_subscribers = Some(parse_env("MAX_STATS_SUBSCRIBERS", &max)?);
+        }
+        if let Some(secs) = env_var("STATS_HEARTBEAT_TIMEOUT_SECS") {
+            self.stats_heartbeat_timeout_secs =
+                Some(parse_env("STATS_HEARTBEAT_TIMEOUT_SECS", &secs)?);
+        }
+        if let Some(interval) = env_var("STATS_INTERVAL_MS") {
+            self.stats_interval_ms = parse_env("STATS_INTERVAL_MS", &interval)?;
+            self.check_stats_interval()?;
+        }
+        if let Some(path) = env_var("STATS_FILE") {
+            self.stats_file = Some(path);
+        }
+        if let Some(path) = env_var("STATS_SOCKET") {
+            self.stats_socket = Some(path);
+        }
+        if let Some(stdout) = env_var("STATS_STDOUT") {
+            self.stats_stdout = parse_env("STATS_STDOUT", &stdout)?;
+        }
+        if let Some(count) = env_var("TOP_FILES") {
+            self.top_files = parse_env("TOP_FILES", &count)?;
+        }
+        if let Some(prefix) = env_var("THREAD_NAME_PREFIX") {
+            self.thread_name_prefix = prefix;
+            self.check_thread_name_prefix()?;
+        }
+        if let Some(port) = env_var("TFTP_PORT") {
+            self.tftp_port = Some(parse_env("TFTP_PORT", &port)?);
+        }
+        if let Some(port) = env_var("WEBDAV_PORT") {
+            self.webdav_port = Some(parse_env("WEBDAV_PORT", &port)?);
+        }
+        if let Some(read_only) = env_var("WEBDAV_READ_ONLY") {
+            self.webdav_read_only = parse_env("WEBDAV_READ_ONLY", &read_only)?;
+        }
+        if let Some(address) = env_var("ADMIN_ADDRESS") {
+            self.admin_address = address;
+        }
+        if let Some(port) = env_var("ADMIN_PORT") {
+            self.admin_port = Some(parse_env("ADMIN_PORT", &port)?);
+        }
+        if let Some(hash) = env_var("ADMIN_PASSWORD_SHA256") {
+            self.admin_password_sha256 = Some(hash);
+        }
+        if let Some(path) = env_var("AUDIT_LOG") {
+            self.audit_log = Some(path);
+        }
+        // comma separated
+        if let Some(peers) = env_var("CLUSTER_PEERS") {
+            self.cluster_peers = peers
+                .split(',')
+                .map(|p| p.trim().to_owned())
+                .filter(|p| !p.is_empty())
+                .collect();
+        }
+        if let Some(replicas) = env_var("REPLICAS") {
+            self.replicas = replicas
+                .split(',')
+                .map(|r| r.trim().to_owned())
+                .filter(|r| !r.is_empty())
+                .collect();
+        }
+        if let Some(path) = env_var("REPLICATION_QUEUE") {
+            self.replication_queue = Some(path);
+        }
+        // comma separated
+        if let Some(nodes) = env_var("RING_NODES") {
+            self.ring.nodes = nodes
+                .split(',')
+                .map(|n| n.trim().to_owned())
+                .filter(|n| !n.is_empty())
+                .collect();
+        }
+        if let Some(vnodes) = env_var("RING_VNODES") {
+            self.ring.vnodes = parse_env("RING_VNODES", &vnodes)?;
+            self.check_ring()?;
+        }
+        if let Some(key) = env_var("ENCRYPTION_KEY") {
+            self.encryption_key = Some(key);
+            self.check_encryption_key()?;
+        }
+        if let Some(path) = env_var("EMBEDDED_ARCHIVE") {
+            self.embedded_archive = Some(path);
+        }
+        if let Some(durable) = env_var("DURABLE_UPLOADS") {
+            self.durable_uploads = parse_env("DURABLE_UPLOADS", &durable)?;
+        }
+        if let Some(serve) = env_var("SERVE_PRECOMPRESSED") {
+            self.serve_precompressed = parse_env("SERVE_PRECOMPRESSED", &serve)?;
+        }
+        if let Some(max) = env_var("MAX_UPLOAD_BYTES") {
+            self.max_upload_bytes = Some(parse_env("MAX_UPLOAD_BYTES", &max)?);
+        }
+        if let Some(max) = env_var("MAX_FILE_NAME_BYTES") {
+            self.max_file_name_bytes = parse_env("MAX_FILE_NAME_BYTES", &max)?;
+            self.check_limits()?;
+        }
+        if let Some(max) = env_var("MAX_HEADER_BYTES") {
+            self.max_header_bytes = parse_env("MAX_HEADER_BYTES", &max)?;
+            self.check_limits()?;
+        }
+        if let Some(max) = env_var("MAX_MANIFEST_BYTES") {
+            self.max_manifest_bytes = parse_env("MAX_MANIFEST_BYTES", &max)?;
+            self.check_limits()?;
+        }
+        if let Some(trash) = env_var("TRASH") {
+            self.trash = parse_env("TRASH", &trash)?;
+        }
+        if let Some(secs) = env_var("TRASH_MAX_AGE_SECS") {
+            self.trash_max_age_secs = Some(parse_env("TRASH_MAX_AGE_SECS", &secs)?);
+        }
+        if let Some(max) = env_var("TRASH_MAX_BYTES") {
+            self.trash_max_bytes = Some(parse_env("TRASH_MAX_BYTES", &max)?);
+        }
+        if let Some(secs) = env_var("JANITOR_INTERVAL_SECS") {
+            self.janitor_interval_secs = Some(parse_env("JANITOR_INTERVAL_SECS", &secs)?);
+            self.check_janitor_interval()?;
+        }
+        Ok(())
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(format!("{}{}", ENV_PREFIX, name)).ok()
+}
+
+fn parse_env<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::InvalidValue(format!("{}{}={:?}", ENV_PREFIX, name, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_file_keeps_defaults() {
+        let config = ServerConfig::from_toml_str("port = 9000\nroot_dir = \"served\"\n").unwrap();
+        assert_eq!(9000, config.port);
+        assert_eq!("served", config.root_dir);
+        assert_eq!(ServerConfig::default().thread_count, config.thread_count);
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let config =
+            ServerConfig::from_toml_str("max_connections = 64\nconnection_overflow = \"reject\"\n")
+                .unwrap();
+        assert_eq!(Some(64), config.max_connections);
+        assert_eq!(
+            OverflowPolicy::Reject,
+            config.connection_overflow().unwrap()
+        );
+        assert!(ServerConfig::from_toml_str("connection_overflow = \"drop\"").is_err());
+    }
+
+    #[test]
+    fn test_handler_timeouts() {
+        let config =
+            ServerConfig::from_toml_str("[handler_timeout_secs]\nDownload = 600\nUpload = 1800\n")
+                .unwrap();
+        assert_eq!(
+            vec![
+                (CommandType::Download, time::Duration::from_secs(600)),
+                (CommandType::Upload, time::Duration::from_secs(1800)),
+            ],
+            config.handler_timeouts().unwrap()
+        );
+        assert!(matches!(
+            ServerConfig::from_toml_str("[handler_timeout_secs]\nDownlaod = 600\n"),
+            Err(ConfigError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_busy_policy() {
+        let config =
+            ServerConfig::from_toml_str("busy_policy = \"reject\"\nbusy_retry_after_secs = 3\n")
+                .unwrap();
+        assert_eq!(
+            BusyPolicy::Reject {
+                retry_after: time::Duration::from_secs(3)
+            },
+            config.busy_policy().unwrap()
+        );
+        let config =
+            ServerConfig::from_toml_str("busy_policy = \"shed\"\nbusy_max_queue = 8\n").unwrap();
+        assert_eq!(
+            BusyPolicy::Shed { max_queue: 8 },
+            config.busy_policy().unwrap()
+        );
+        assert!(matches!(
+            ServerConfig::from_toml_str("busy_policy = \"drop\""),
+            Err(ConfigError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_stats_interval() {
+        let config = ServerConfig::from_toml_str("stats_interval_ms = 250").unwrap();
+        assert_eq!(250, config.stats_interval_ms);
+        assert_eq!(None, config.stats_file);
+        let config =
+            ServerConfig::from_toml_str("stats_file = \"stats.jsonl\"\nstats_stdout = true")
+                .unwrap();
+        assert_eq!(Some("stats.jsonl"), config.stats_file.as_deref());
+        assert!(config.stats_stdout);
+        assert!(matches!(
+            ServerConfig::from_toml_str("stats_interval_ms = 0"),
+            Err(ConfigError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_thread_name_prefix() {
+        assert_eq!("fs", ServerConfig::default().thread_name_prefix);
+        let config = ServerConfig::from_toml_str("thread_name_prefix = \"edge\"").unwrap();
+        assert_eq!("edge", config.thread_name_prefix);
+        assert!(matches!(
+            ServerConfig::from_toml_str("thread_name_prefix = \"\""),
+            Err(ConfigError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_encryption_key() {
+        let key = "00".repeat(32);
+        let config =
+            ServerConfig::from_toml_str(&format!("encryption_key = \"{}\"\n", key)).unwrap();
+        assert_eq!(Some(key), config.encryption_key);
+        assert!(matches!(
+            ServerConfig::from_toml_str("encryption_key = \"abcd\"\n"),
+            Err(ConfigError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_users() {
+        let config = ServerConfig::from_toml_str(
+            "[[users]]\nname = \"alice\"\npassword_sha256 = \"2bb80d53\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![UserAccount {
+                name: "alice".to_owned(),
+                password_sha256: "2bb80d53".to_owned(),
+            }],
+            config.users
+        );
+        assert!(ServerConfig::from_toml_str("[[users]]\nname = \"bob\"\n").is_err());
+    }
+
+    #[test]
+    fn test_ring() {
+        let config =
+            ServerConfig::from_toml_str("[ring]\nnodes = [\"10.0.0.1:7878\", \"10.0.0.2:7878\"]\n")
+                .unwrap();
+        assert_eq!(
+            RingConfig {
+                nodes: vec!["10.0.0.1:7878".to_owned(), "10.0.0.2:7878".to_owned()],
+                vnodes: crate::server::ring::DEFAULT_VNODES,
+            },
+            config.ring
+        );
+        assert!(matches!(
+            ServerConfig::from_toml_str("[ring]\nvnodes = 0\n"),
+            Err(ConfigError::InvalidValue(_))
+        ));
+        assert!(ServerConfig::from_toml_str("[ring]\nweights = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(matches!(
+            ServerConfig::from_toml_str("prot = 9000"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+}
@@ -1,9 +1,83 @@
+// without the std feature only the wire format is left, for clients that
+// can not link std
+#![cfg_attr(not(feature = "std"), no_std)]
+
 // do not make public as a lib
+#[cfg(feature = "std")]
+mod archive;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "std")]
+mod client;
+#[cfg(feature = "std")]
+mod config;
+// the wire format on its own, for clients without std
+pub mod protocol;
+#[cfg(feature = "std")]
 mod reader;
+#[cfg(feature = "std")]
 mod server;
+// throwaway servers for integration tests, see testkit::TestServer
+#[cfg(all(feature = "std", any(test, feature = "testkit")))]
+pub mod testkit;
 // reexport only what I want
-pub use reader::{cleanup_server_file, configure_directory_to_serve_file};
-pub use server::{server::FileServer, types::CommandType};
+#[cfg(feature = "std")]
+pub use archive::embedded::EmbeddedArchive;
+#[cfg(feature = "std")]
+pub use cache::{HotFileCache, HOT_FILE_MIN_DOWNLOADS};
+#[cfg(feature = "std")]
+pub use client::{
+    partial_path, CancellationToken, ChangeFeed, ClientError, DeltaStats, FileClient, FileEntry,
+    FileStat, ListPage, RetryPolicy, ServerInfo, StatsEvent, StatsSubscriber, TailFeed,
+};
+#[cfg(feature = "std")]
+pub use config::{ConfigError, ServerConfig};
+#[cfg(feature = "std")]
+pub use protocol::{
+    parse_request,
+    stats::{ActiveTransfer, ClusterStats, Stats, StatsFormat, StatsSnapshot, TransferStats},
+    Limit, Limits, Request, PROTOCOL_VERSION, QUEUE_POSITION_VERSION,
+};
+#[cfg(feature = "std")]
+pub use reader::{
+    cleanup_server_file, configure_directory_to_serve_file, open_file_source,
+    served_directory_path, set_base_directory, sha256_hex, FileSource, ServeDir, ServedFile,
+    DEFAULT_MMAP_THRESHOLD,
+};
+#[cfg(feature = "std")]
+pub use server::{
+    accounts::UserAccount,
+    authorizer::{AuthRequest, Authorizer},
+    builder::FileServerBuilder,
+    clock::{Clock, MockClock, SystemClock},
+    connection::{duplex, Connection, MemoryConnection},
+    health::SERVER_VERSION,
+    janitor::{JanitorCounts, TrashPolicy},
+    limit::OverflowPolicy,
+    listener::ListenerPolicy,
+    metrics::{MetricsRegistry, ProgressHook, TransferUpdate, UploadHook, UploadMeta},
+    observer::{
+        ConnectionEvent, ErrorEvent, EventObserver, Observer, RequestEvent, ServerEvent,
+        TransferEvent,
+    },
+    pool::{BusyPolicy, TransferClass},
+    preflight::PreflightError,
+    request_id::RequestId,
+    ring::{HashRing, RingConfig},
+    router::{request_logger, Middleware, RequestContext, Router},
+    serve_policy::ServePolicy,
+    server::{FileServer, FileServerError, Handler},
+    shutdown::{ServerHandle, ShutdownHandle, ShutdownReport},
+    stats_sink::{FileSink, StatsSink, StdoutSink},
+    threads::{install_panic_hook, ThreadInfo, ThreadRegistry},
+    types::{
+        Capabilities, ChangeEvent, ChangeKind, CommandType, DownloadCondition, ListQuery, ListSort,
+        ManifestEntry,
+    },
+};
+
+#[cfg(all(unix, feature = "std"))]
+pub use server::stats_sink::UnixSocketSink;
 
 // reexport modules for external usage like so
 // use $crate_name::server::$file_server_type/trait/function;
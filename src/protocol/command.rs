@@ -0,0 +1,145 @@
+// The command byte a request starts with, the server's dispatch and every
+// client go through from_byte and u8::from.
+#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
+pub enum CommandType {
+    Upload,
+    Download,
+    Statistics,
+    // keeps the connection open and serves framed commands until Quit
+    KeepAlive,
+    Quit,
+    // answered without a worker, used for health checks
+    Ping,
+    List,
+    // subscribes to the length prefixed v2 stats frames
+    StatisticsV2,
+    // download that is skipped when the client's copy is still current
+    ConditionalDownload,
+    // one-off listing of the transfers in progress, who gets what and how far along
+    Transfers,
+    // every served file whose name matches a glob, in one reply
+    BatchDownload,
+    // tar of a directory under the root, built while it is sent
+    Archive,
+    // one byte range of a file, for fetching big files over several connections
+    RangeDownload,
+    // size and modification time of a served file
+    Stat,
+    // long-lived subscription to created/modified/deleted events for a glob
+    Watch,
+    // every served file that differs from the client's manifest, in one reply
+    Sync,
+    // keep-alive only: follow every single file download with a checksum frame
+    Checksums,
+    // a single stats report in the requested format, then the connection closes
+    StatsOnce,
+    // prefix naming the account the command after it runs as
+    Login,
+    // prefix asking for the connection's request id before the command after it
+    RequestId,
+    // keep-alive only: precede every single file download with a content type frame
+    ContentTypes,
+    // the last bytes of a file, then whatever is appended to it, like tail -f
+    Tail,
+    // keep-alive only: precede every single file download with an ETag frame
+    ETags,
+    // prefix naming the newest protocol version the client speaks, answered
+    // with the server's Capabilities before the command after it
+    Hello,
+    // one page of the listing, filtered by name prefix and sorted, see ListQuery
+    ListPage,
+    // a download in sequence numbered chunks the client acknowledges, with
+    // chunks sent again on request, for relays that may drop or mangle data
+    ReliableDownload,
+    // only the parts of a file the client's copy does not have, see
+    // BlockSignature
+    Delta,
+    // keep-alive only: single file downloads may come as the gzip
+    // precompressed sibling of the file, announced by an encoding frame
+    AcceptGzip,
+    // removes a served file, into the trash while the server keeps one
+    Delete,
+    // the "host:port" of the shard a file belongs to, see HashRing
+    Locate,
+    // a download in chunks that each carry their offset and CRC-32, for
+    // clients that write straight to flash without holding the whole file
+    FlashDownload,
+}
+
+impl CommandType {
+    // None for bytes outside the built-in protocol.
+    pub fn from_byte(byte: u8) -> Option<CommandType> {
+        match byte {
+            1 => Some(CommandType::Download),
+            2 => Some(CommandType::Upload),
+            3 => Some(CommandType::Statistics),
+            4 => Some(CommandType::KeepAlive),
+            5 => Some(CommandType::Quit),
+            6 => Some(CommandType::Ping),
+            7 => Some(CommandType::List),
+            8 => Some(CommandType::StatisticsV2),
+            9 => Some(CommandType::ConditionalDownload),
+            10 => Some(CommandType::Transfers),
+            11 => Some(CommandType::BatchDownload),
+            12 => Some(CommandType::Archive),
+            13 => Some(CommandType::RangeDownload),
+            14 => Some(CommandType::Stat),
+            15 => Some(CommandType::Watch),
+            16 => Some(CommandType::Sync),
+            17 => Some(CommandType::Checksums),
+            18 => Some(CommandType::StatsOnce),
+            19 => Some(CommandType::Login),
+            20 => Some(CommandType::RequestId),
+            21 => Some(CommandType::ContentTypes),
+            22 => Some(CommandType::Tail),
+            23 => Some(CommandType::Hello),
+            24 => Some(CommandType::ETags),
+            25 => Some(CommandType::ListPage),
+            26 => Some(CommandType::ReliableDownload),
+            27 => Some(CommandType::Delta),
+            28 => Some(CommandType::AcceptGzip),
+            29 => Some(CommandType::Delete),
+            30 => Some(CommandType::Locate),
+            31 => Some(CommandType::FlashDownload),
+            _ => None,
+        }
+    }
+}
+
+impl From<CommandType> for u8 {
+    fn from(command: CommandType) -> u8 {
+        match command {
+            CommandType::Download => 1,
+            CommandType::Upload => 2,
+            CommandType::Statistics => 3,
+            CommandType::KeepAlive => 4,
+            CommandType::Quit => 5,
+            CommandType::Ping => 6,
+            CommandType::List => 7,
+            CommandType::StatisticsV2 => 8,
+            CommandType::ConditionalDownload => 9,
+            CommandType::Transfers => 10,
+            CommandType::BatchDownload => 11,
+            CommandType::Archive => 12,
+            CommandType::RangeDownload => 13,
+            CommandType::Stat => 14,
+            CommandType::Watch => 15,
+            CommandType::Sync => 16,
+            CommandType::Checksums => 17,
+            CommandType::StatsOnce => 18,
+            CommandType::Login => 19,
+            CommandType::RequestId => 20,
+            CommandType::ContentTypes => 21,
+            CommandType::Tail => 22,
+            CommandType::Hello => 23,
+            CommandType::ETags => 24,
+            CommandType::ListPage => 25,
+            CommandType::ReliableDownload => 26,
+            CommandType::Delta => 27,
+            CommandType::AcceptGzip => 28,
+            CommandType::Delete => 29,
+            CommandType::Locate => 30,
+            CommandType::FlashDownload => 31,
+        }
+    }
+}
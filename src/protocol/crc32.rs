@@ -0,0 +1,49 @@
+// CRC-32 as zlib, PNG and most bootloaders compute it (IEEE, reflected,
+// polynomial 0xEDB88320), over a table built at compile time so clients
+// without an allocator or much code space can check FlashDownload chunks.
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    update(0, bytes)
+}
+
+// Carries `crc`, what crc32 returned for the bytes before, over `bytes`, for
+// checking data that arrives in pieces.
+pub fn update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc = TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+        assert_eq!(0, crc32(b""));
+        assert_eq!(crc32(b"123456789"), update(crc32(b"1234"), b"56789"));
+    }
+}
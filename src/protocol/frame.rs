@@ -0,0 +1,99 @@
+// Replies on a keep-alive connection are framed so the client knows where one
+// ends and the next begins:
+// [status: u8][payload length: u64 big endian][payload]
+pub const FRAME_HEADER_LEN: usize = 9;
+
+pub const FRAME_OK: u8 = 0;
+pub const FRAME_ERROR: u8 = 1;
+// empty reply to a ConditionalDownload whose condition says the client is current
+pub const FRAME_NOT_MODIFIED: u8 = 2;
+// empty frame closing a reply made of several OK frames, see BatchDownload
+pub const FRAME_END: u8 = 3;
+// lowercase hex SHA-256 of the file bytes in the OK frame just before it, sent
+// after single file downloads once a session asked for them (Checksums)
+pub const FRAME_CHECKSUM: u8 = 4;
+// content type of the file in the OK frame right after it, e.g. `image/png`,
+// sent ahead of single file downloads once a session asked for them (ContentTypes)
+pub const FRAME_CONTENT_TYPE: u8 = 5;
+// the file a request named does not exist, the payload says which. Apart from
+// FRAME_ERROR so clients can tell "ask for another file" from "the server is
+// broken"
+pub const FRAME_NOT_FOUND: u8 = 6;
+// the file exists but the server's allow/deny patterns keep it from being
+// served, the payload says which. Asking again will not help
+pub const FRAME_DENIED: u8 = 7;
+// ETag of the file in the OK frame right after it, the sha256 hex of its bytes,
+// sent ahead of single file downloads once a session asked for them (ETags)
+pub const FRAME_ETAG: u8 = 8;
+// the server is too busy to run the command and hangs up, the payload is how
+// many seconds to wait before trying again as a u64 big endian
+pub const FRAME_BUSY: u8 = 9;
+// one chunk of a ReliableDownload, the payload is the chunk's sequence number
+// as a u64 big endian followed by its bytes
+pub const FRAME_CHUNK: u8 = 10;
+// part of a Delta reply: blocks of the client's own copy to put in next, the
+// payload is the first block's number and how many as two u64 big endian
+pub const FRAME_COPY: u8 = 11;
+// the OK frame right after it holds the file encoded this way, `gzip` for the
+// precompressed sibling of the file, sent once a session asked for them
+// (AcceptGzip). Checksum frames cover the encoded bytes, ETags the file's own
+pub const FRAME_ENCODING: u8 = 12;
+// the connection waits for a worker, the payload is its place in the queue as
+// a u64 big endian, 1 for next in line. Sent about every second to clients
+// that said Hello with QUEUE_POSITION_VERSION or later, ahead of the reply to
+// downloads, uploads, sessions and the other commands that wait for a worker
+// (stats aside), and once with 0 when a worker picks the connection up,
+// queued or not
+pub const FRAME_QUEUED: u8 = 13;
+// one chunk of a FlashDownload, the payload is the offset of its first byte
+// in the file as a u64 big endian and the CRC-32 of its bytes as a u32 big
+// endian (see encode_crc_chunk_head), followed by the bytes
+pub const FRAME_CRC_CHUNK: u8 = 14;
+pub const CRC_CHUNK_HEAD_LEN: usize = 12;
+
+pub fn encode_frame_header(status: u8, length: u64) -> [u8; FRAME_HEADER_LEN] {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    header[0] = status;
+    header[1..].copy_from_slice(&length.to_be_bytes());
+    header
+}
+
+// (status, payload length)
+pub fn decode_frame_header(header: &[u8; FRAME_HEADER_LEN]) -> (u8, u64) {
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&header[1..]);
+    (header[0], u64::from_be_bytes(length))
+}
+
+pub fn encode_crc_chunk_head(offset: u64, crc: u32) -> [u8; CRC_CHUNK_HEAD_LEN] {
+    let mut head = [0u8; CRC_CHUNK_HEAD_LEN];
+    head[..8].copy_from_slice(&offset.to_be_bytes());
+    head[8..].copy_from_slice(&crc.to_be_bytes());
+    head
+}
+
+// (offset, crc)
+pub fn decode_crc_chunk_head(head: &[u8; CRC_CHUNK_HEAD_LEN]) -> (u64, u32) {
+    let mut offset = [0u8; 8];
+    let mut crc = [0u8; 4];
+    offset.copy_from_slice(&head[..8]);
+    crc.copy_from_slice(&head[8..]);
+    (u64::from_be_bytes(offset), u32::from_be_bytes(crc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_header_round_trip() {
+        let header = encode_frame_header(FRAME_QUEUED, 0x0102_0304_0506_0708);
+        assert_eq!([13, 1, 2, 3, 4, 5, 6, 7, 8], header);
+        assert_eq!(
+            (FRAME_QUEUED, 0x0102_0304_0506_0708),
+            decode_frame_header(&header)
+        );
+        let head = encode_crc_chunk_head(4096, 0xCBF4_3926);
+        assert_eq!((4096, 0xCBF4_3926), decode_crc_chunk_head(&head));
+    }
+}
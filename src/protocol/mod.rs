@@ -0,0 +1,32 @@
+// The wire format shared by the server, FileClient and clients that can not
+// link std: command bytes, frame statuses and headers, the layout of stats
+// reports and the parsing of request heads. Apart from the parsing and the
+// stats reports themselves, which need the `std` feature, nothing in here
+// uses more than `core` or allocates, so embedded clients (devices pulling
+// firmware files, say) build it with `--no-default-features` under
+// #![no_std] and speak the exact same frames. Reading and writing the
+// connection is left to the caller.
+#![deny(
+    clippy::std_instead_of_core,
+    clippy::std_instead_of_alloc,
+    clippy::alloc_instead_of_core
+)]
+
+mod command;
+pub mod crc32;
+pub mod frame;
+#[cfg(feature = "std")]
+mod request;
+pub mod stats;
+
+pub use command::CommandType;
+#[cfg(feature = "std")]
+pub use request::*;
+
+// What Hello answers with when the client speaks it too, bumped whenever a
+// change to the wire format could trip up older clients.
+pub const PROTOCOL_VERSION: u16 = 2;
+
+// Connections that said Hello with this version or later hear where they
+// stand while they wait for a worker, see frame::FRAME_QUEUED.
+pub const QUEUE_POSITION_VERSION: u16 = 2;
@@ -0,0 +1,719 @@
+use super::stats::StatsFormat;
+use crate::server::delta::BlockSignature;
+use crate::server::server::FileServerError;
+use crate::server::types::{CommandType, DownloadCondition, ListQuery, ManifestEntry};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+// Parsing of what a client sends before any payload, kept free of I/O so it can
+// be unit tested and fuzzed (see fuzz/fuzz_targets/parse_request.rs). Handlers
+// read from their stream segment by segment and hand the bytes to the
+// functions below.
+
+// Biggest Sync manifest a client may send by default, about 100k files worth
+// of lines.
+pub const MAX_MANIFEST_BYTES: u64 = 16 * 1024 * 1024;
+// Longest `key=value|` segment of a request head by default, a file name or
+// glob never needs more. Anything longer is someone feeding us garbage.
+pub const MAX_HEADER_BYTES: usize = 4096;
+// Longest file name, glob or directory a request may name by default, what
+// most filesystems allow for a single name.
+pub const MAX_FILE_NAME_BYTES: usize = 255;
+// How long a client may take to send the head of a request once it started.
+pub const HEADER_TIMEOUT: core::time::Duration = core::time::Duration::from_secs(5);
+// Biggest chunk a ReliableDownload or FlashDownload may ask for, each is read
+// into memory.
+pub const MAX_CHUNK_BYTES: u64 = 1024 * 1024;
+// Smallest block a Delta may ask for, smaller blocks cost more in signatures
+// than they save.
+pub const MIN_DELTA_BLOCK_BYTES: u64 = 64;
+
+static FILE_MATCHER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"filename=([^|]+)\|").unwrap()
+    // allowed filename: filename=a_file_name|
+});
+
+// One of the byte limits in Limits, named in the error a client gets for
+// going over it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    FileName,
+    Header,
+    Upload,
+    Manifest,
+}
+
+impl Limit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Limit::FileName => "file name",
+            Limit::Header => "request header",
+            Limit::Upload => "upload",
+            Limit::Manifest => "manifest",
+        }
+    }
+}
+
+// Every cap on the bytes a client may send, in one place. The server holds
+// one (FileServerBuilder::limits) and every handler reads request heads and
+// bodies against it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_file_name_bytes: usize,
+    // a single `key=value|` segment, the longest a file name can be is a bit
+    // under this
+    pub max_header_bytes: usize,
+    // None lets uploads be any size
+    pub max_upload_bytes: Option<u64>,
+    pub max_manifest_bytes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_file_name_bytes: MAX_FILE_NAME_BYTES,
+            max_header_bytes: MAX_HEADER_BYTES,
+            max_upload_bytes: None,
+            max_manifest_bytes: MAX_MANIFEST_BYTES,
+        }
+    }
+}
+
+impl Limits {
+    pub fn max(&self, limit: Limit) -> Option<u64> {
+        match limit {
+            Limit::FileName => Some(self.max_file_name_bytes as u64),
+            Limit::Header => Some(self.max_header_bytes as u64),
+            Limit::Upload => self.max_upload_bytes,
+            Limit::Manifest => Some(self.max_manifest_bytes),
+        }
+    }
+
+    // TooLarge when `bytes` is over `limit`.
+    pub fn check(&self, limit: Limit, bytes: u64) -> Result<(), FileServerError> {
+        match self.max(limit) {
+            Some(max) if bytes > max => Err(FileServerError::TooLarge { limit, max }),
+            _ => Ok(()),
+        }
+    }
+
+    // The file name of a `filename=a_file_name|` segment, no longer than
+    // max_file_name_bytes.
+    pub fn parse_file_name(&self, segment: &[u8]) -> Result<String, FileServerError> {
+        let file_name = parse_file_name(segment)?;
+        self.check(Limit::FileName, file_name.len() as u64)?;
+        Ok(file_name)
+    }
+}
+
+// A request head: the command byte plus the segments that follow it.
+// Upload bodies and other payloads come after the head and are not part of it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Download {
+        file_name: String,
+    },
+    Upload {
+        file_name: String,
+    },
+    Delete {
+        file_name: String,
+    },
+    Locate {
+        file_name: String,
+    },
+    Statistics,
+    KeepAlive,
+    Quit,
+    Ping,
+    List,
+    StatisticsV2 {
+        format: StatsFormat,
+    },
+    ConditionalDownload {
+        file_name: String,
+        condition: DownloadCondition,
+    },
+    Transfers,
+    BatchDownload {
+        pattern: String,
+    },
+    Archive {
+        directory: String,
+    },
+    RangeDownload {
+        file_name: String,
+        start: u64,
+        end: u64,
+    },
+    Stat {
+        file_name: String,
+    },
+    Watch {
+        pattern: String,
+    },
+    // the manifest follows the head, see parse_manifest
+    Sync,
+    Checksums,
+    StatsOnce {
+        format: StatsFormat,
+    },
+    // the command to run as this user follows
+    Login {
+        user: String,
+        password: String,
+    },
+    // the id comes back in an OK frame, the command follows
+    RequestId,
+    ContentTypes,
+    ETags,
+    AcceptGzip,
+    Tail {
+        file_name: String,
+        bytes: u64,
+    },
+    // the capabilities come back in an OK frame, the command follows
+    Hello {
+        version: u16,
+    },
+    ListPage {
+        query: ListQuery,
+    },
+    ReliableDownload {
+        file_name: String,
+        chunk_bytes: u64,
+        ack_every: u64,
+    },
+    // the signatures follow the head, see parse_signatures
+    Delta {
+        file_name: String,
+        block_size: u64,
+    },
+    FlashDownload {
+        file_name: String,
+        chunk_bytes: u64,
+        offset: u64,
+    },
+}
+
+// `segment` is one `key=value|` segment as read off the wire.
+pub fn parse_file_name(segment: &[u8]) -> Result<String, FileServerError> {
+    let segment = core::str::from_utf8(segment)
+        .map_err(|_| FileServerError::bad_frame("file name is not valid utf-8"))?;
+    FILE_MATCHER
+        .captures(segment)
+        .and_then(|capture| capture.get(1))
+        .map(|file_name| file_name.as_str().to_owned())
+        .ok_or_else(|| FileServerError::bad_frame("file name not found"))
+}
+
+pub fn parse_condition(segment: &[u8]) -> Result<DownloadCondition, FileServerError> {
+    core::str::from_utf8(segment)
+        .ok()
+        .and_then(DownloadCondition::parse)
+        .ok_or_else(|| {
+            FileServerError::bad_frame(format!(
+                "invalid condition {:?}",
+                String::from_utf8_lossy(segment)
+            ))
+        })
+}
+
+// The two segments of a Login: `user=<name>|password=<password>|`. A password
+// can hold anything but `|`.
+pub fn parse_login(user: &[u8], password: &[u8]) -> Result<(String, String), FileServerError> {
+    let field = |segment: &[u8], key: &str| {
+        core::str::from_utf8(segment)
+            .ok()
+            .and_then(|segment| segment.strip_prefix(key))
+            .and_then(|value| value.strip_suffix('|'))
+            .map(str::to_owned)
+            .ok_or_else(|| FileServerError::bad_frame(format!("missing {}...|", key)))
+    };
+    Ok((field(user, "user=")?, field(password, "password=")?))
+}
+
+// Second segment of a RangeDownload: `range=<start>-<end>|`, end exclusive.
+pub fn parse_range(segment: &[u8]) -> Result<(u64, u64), FileServerError> {
+    let invalid = || {
+        FileServerError::bad_frame(format!(
+            "invalid range {:?}",
+            String::from_utf8_lossy(segment)
+        ))
+    };
+    let range = core::str::from_utf8(segment)
+        .ok()
+        .and_then(|segment| segment.strip_prefix("range="))
+        .and_then(|range| range.strip_suffix('|'))
+        .ok_or_else(invalid)?;
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+    match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => Ok((start, end)),
+        _ => Err(invalid()),
+    }
+}
+
+// Second segment of a Tail: `tail=<bytes>|`, how much of the end of the file
+// to send before following it.
+pub fn parse_tail(segment: &[u8]) -> Result<u64, FileServerError> {
+    core::str::from_utf8(segment)
+        .ok()
+        .and_then(|segment| segment.strip_prefix("tail="))
+        .and_then(|bytes| bytes.strip_suffix('|'))
+        .and_then(|bytes| bytes.parse().ok())
+        .ok_or_else(|| {
+            FileServerError::bad_frame(format!(
+                "invalid tail {:?}",
+                String::from_utf8_lossy(segment)
+            ))
+        })
+}
+
+// The four segments of a ListPage, see ListQuery::encode.
+pub fn parse_list_query(segments: [&[u8]; 4]) -> Result<ListQuery, FileServerError> {
+    let invalid = || {
+        FileServerError::bad_frame(format!(
+            "invalid list query {:?}",
+            String::from_utf8_lossy(&segments.concat())
+        ))
+    };
+    let mut text = [""; 4];
+    for (text, segment) in text.iter_mut().zip(segments) {
+        *text = core::str::from_utf8(segment).map_err(|_| invalid())?;
+    }
+    ListQuery::parse(text).ok_or_else(invalid)
+}
+
+// Second segment of a ReliableDownload: `chunks=<bytes>,<ack every>|`, how
+// big each chunk is and after how many the client acknowledges. Both at
+// least 1, chunks no bigger than MAX_CHUNK_BYTES.
+pub fn parse_chunking(segment: &[u8]) -> Result<(u64, u64), FileServerError> {
+    core::str::from_utf8(segment)
+        .ok()
+        .and_then(|segment| segment.strip_prefix("chunks="))
+        .and_then(|chunking| chunking.strip_suffix('|'))
+        .and_then(|chunking| chunking.split_once(','))
+        .and_then(|(bytes, every)| Some((bytes.parse().ok()?, every.parse().ok()?)))
+        .filter(|(bytes, every)| (1..=MAX_CHUNK_BYTES).contains(bytes) && *every > 0)
+        .ok_or_else(|| {
+            FileServerError::bad_frame(format!(
+                "invalid chunking {:?}",
+                String::from_utf8_lossy(segment)
+            ))
+        })
+}
+
+// Second segment of a FlashDownload: `flash=<chunk bytes>,<offset>|`, how
+// big each chunk is, 1 up to MAX_CHUNK_BYTES, and where in the file the
+// first one starts, so a device that lost power picks up where it was.
+pub fn parse_flash(segment: &[u8]) -> Result<(u64, u64), FileServerError> {
+    core::str::from_utf8(segment)
+        .ok()
+        .and_then(|segment| segment.strip_prefix("flash="))
+        .and_then(|flash| flash.strip_suffix('|'))
+        .and_then(|flash| flash.split_once(','))
+        .and_then(|(bytes, offset)| Some((bytes.parse().ok()?, offset.parse().ok()?)))
+        .filter(|(bytes, _)| (1..=MAX_CHUNK_BYTES).contains(bytes))
+        .ok_or_else(|| {
+            FileServerError::bad_frame(format!(
+                "invalid flash chunking {:?}",
+                String::from_utf8_lossy(segment)
+            ))
+        })
+}
+
+// Second segment of a Delta: `blocks=<bytes>|`, the size of the blocks the
+// client's signatures are of, MIN_DELTA_BLOCK_BYTES up to MAX_CHUNK_BYTES.
+pub fn parse_block_size(segment: &[u8]) -> Result<u64, FileServerError> {
+    core::str::from_utf8(segment)
+        .ok()
+        .and_then(|segment| segment.strip_prefix("blocks="))
+        .and_then(|bytes| bytes.strip_suffix('|'))
+        .and_then(|bytes| bytes.parse().ok())
+        .filter(|bytes| (MIN_DELTA_BLOCK_BYTES..=MAX_CHUNK_BYTES).contains(bytes))
+        .ok_or_else(|| {
+            FileServerError::bad_frame(format!(
+                "invalid block size {:?}",
+                String::from_utf8_lossy(segment)
+            ))
+        })
+}
+
+// Body of a Delta request, BlockSignature::ENCODED_LEN bytes per block.
+pub fn parse_signatures(bytes: &[u8]) -> Result<Vec<BlockSignature>, FileServerError> {
+    let signatures = bytes.chunks_exact(BlockSignature::ENCODED_LEN);
+    if !signatures.remainder().is_empty() {
+        return Err(FileServerError::bad_frame("signatures cut short"));
+    }
+    Ok(signatures
+        .map(|signature| BlockSignature::decode(signature.try_into().unwrap()))
+        .collect())
+}
+
+// `version=<n>|`, the newest protocol version the client speaks, 1 or more.
+pub fn parse_hello(segment: &[u8]) -> Result<u16, FileServerError> {
+    core::str::from_utf8(segment)
+        .ok()
+        .and_then(|segment| segment.strip_prefix("version="))
+        .and_then(|version| version.strip_suffix('|'))
+        .and_then(|version| version.parse().ok())
+        .filter(|version| *version > 0)
+        .ok_or_else(|| {
+            FileServerError::bad_frame(format!(
+                "invalid hello {:?}",
+                String::from_utf8_lossy(segment)
+            ))
+        })
+}
+
+// Body of a Sync request, one ManifestEntry line per file the client has.
+pub fn parse_manifest(bytes: &[u8]) -> Result<Vec<ManifestEntry>, FileServerError> {
+    let manifest = core::str::from_utf8(bytes)
+        .map_err(|_| FileServerError::bad_frame("manifest is not valid utf-8"))?;
+    manifest
+        .lines()
+        .map(|line| {
+            ManifestEntry::parse_line(line).ok_or_else(|| {
+                FileServerError::bad_frame(format!("invalid manifest line {:?}", line))
+            })
+        })
+        .collect()
+}
+
+pub fn parse_stats_format(format_byte: u8) -> Result<StatsFormat, FileServerError> {
+    StatsFormat::from_v2_format_byte(format_byte)
+        .ok_or_else(|| FileServerError::bad_frame(format!("unknown stats format {}", format_byte)))
+}
+
+// The format byte following StatisticsV2 and StatsOnce.
+fn stats_format_segment(segment: &[u8]) -> Result<StatsFormat, FileServerError> {
+    let format_byte = segment
+        .first()
+        .copied()
+        .ok_or_else(|| FileServerError::bad_frame("missing stats format"))?;
+    parse_stats_format(format_byte)
+}
+
+// Parses a whole request head held in memory, the same way the handlers parse
+// it off a stream.
+pub fn parse_request(bytes: &[u8]) -> Result<Request, FileServerError> {
+    let (&command_byte, mut rest) = bytes
+        .split_first()
+        .ok_or_else(|| FileServerError::bad_frame("empty request"))?;
+    let mut next_segment = || {
+        // a segment runs up to and including `|`, or to the end of the input
+        let end = rest
+            .iter()
+            .position(|byte| *byte == b'|')
+            .map_or(rest.len(), |pos| pos + 1);
+        let (segment, remaining) = rest.split_at(end);
+        rest = remaining;
+        segment
+    };
+
+    Ok(match CommandType::try_from(command_byte)? {
+        CommandType::Download => Request::Download {
+            file_name: parse_file_name(next_segment())?,
+        },
+        CommandType::Upload => Request::Upload {
+            file_name: parse_file_name(next_segment())?,
+        },
+        CommandType::Delete => Request::Delete {
+            file_name: parse_file_name(next_segment())?,
+        },
+        CommandType::Locate => Request::Locate {
+            file_name: parse_file_name(next_segment())?,
+        },
+        CommandType::ConditionalDownload => {
+            let file_name = parse_file_name(next_segment())?;
+            Request::ConditionalDownload {
+                file_name,
+                condition: parse_condition(next_segment())?,
+            }
+        }
+        CommandType::StatisticsV2 => Request::StatisticsV2 {
+            format: stats_format_segment(next_segment())?,
+        },
+        CommandType::StatsOnce => Request::StatsOnce {
+            format: stats_format_segment(next_segment())?,
+        },
+        CommandType::Statistics => Request::Statistics,
+        CommandType::KeepAlive => Request::KeepAlive,
+        CommandType::Quit => Request::Quit,
+        CommandType::Ping => Request::Ping,
+        CommandType::List => Request::List,
+        CommandType::Transfers => Request::Transfers,
+        CommandType::BatchDownload => Request::BatchDownload {
+            pattern: parse_file_name(next_segment())?,
+        },
+        CommandType::Archive => Request::Archive {
+            directory: parse_file_name(next_segment())?,
+        },
+        CommandType::RangeDownload => {
+            let file_name = parse_file_name(next_segment())?;
+            let (start, end) = parse_range(next_segment())?;
+            Request::RangeDownload {
+                file_name,
+                start,
+                end,
+            }
+        }
+        CommandType::Stat => Request::Stat {
+            file_name: parse_file_name(next_segment())?,
+        },
+        CommandType::Watch => Request::Watch {
+            pattern: parse_file_name(next_segment())?,
+        },
+        CommandType::Sync => Request::Sync,
+        CommandType::Checksums => Request::Checksums,
+        CommandType::Login => {
+            let user = next_segment();
+            let (user, password) = parse_login(user, next_segment())?;
+            Request::Login { user, password }
+        }
+        CommandType::RequestId => Request::RequestId,
+        CommandType::ContentTypes => Request::ContentTypes,
+        CommandType::ETags => Request::ETags,
+        CommandType::AcceptGzip => Request::AcceptGzip,
+        CommandType::Tail => {
+            let file_name = parse_file_name(next_segment())?;
+            Request::Tail {
+                file_name,
+                bytes: parse_tail(next_segment())?,
+            }
+        }
+        CommandType::Hello => Request::Hello {
+            version: parse_hello(next_segment())?,
+        },
+        CommandType::ListPage => Request::ListPage {
+            query: parse_list_query([
+                next_segment(),
+                next_segment(),
+                next_segment(),
+                next_segment(),
+            ])?,
+        },
+        CommandType::ReliableDownload => {
+            let file_name = parse_file_name(next_segment())?;
+            let (chunk_bytes, ack_every) = parse_chunking(next_segment())?;
+            Request::ReliableDownload {
+                file_name,
+                chunk_bytes,
+                ack_every,
+            }
+        }
+        CommandType::Delta => {
+            let file_name = parse_file_name(next_segment())?;
+            Request::Delta {
+                file_name,
+                block_size: parse_block_size(next_segment())?,
+            }
+        }
+        CommandType::FlashDownload => {
+            let file_name = parse_file_name(next_segment())?;
+            let (chunk_bytes, offset) = parse_flash(next_segment())?;
+            Request::FlashDownload {
+                file_name,
+                chunk_bytes,
+                offset,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::types::ListSort;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            Request::Download {
+                file_name: "notes.txt".to_owned()
+            },
+            parse_request(b"\x01filename=notes.txt|").unwrap()
+        );
+        assert_eq!(
+            Request::ConditionalDownload {
+                file_name: "notes.txt".to_owned(),
+                condition: DownloadCondition::NoneMatch("ab12".to_owned()),
+            },
+            parse_request(b"\x09filename=notes.txt|if-none-match=AB12|").unwrap()
+        );
+        assert_eq!(
+            Request::StatisticsV2 {
+                format: StatsFormat::V2
+            },
+            parse_request(&[8, 0]).unwrap()
+        );
+        assert_eq!(
+            Request::StatisticsV2 {
+                format: StatsFormat::Json
+            },
+            parse_request(&[8, 1]).unwrap()
+        );
+        assert_eq!(Request::Ping, parse_request(&[6]).unwrap());
+        assert_eq!(Request::Checksums, parse_request(&[17]).unwrap());
+        assert_eq!(
+            Request::Login {
+                user: "alice".to_owned(),
+                password: "s=cr t".to_owned(),
+            },
+            parse_request(b"\x13user=alice|password=s=cr t|").unwrap()
+        );
+        assert!(parse_request(b"\x13user=alice|").is_err());
+        assert_eq!(Request::RequestId, parse_request(&[20]).unwrap());
+        assert_eq!(Request::ContentTypes, parse_request(&[21]).unwrap());
+        assert_eq!(Request::ETags, parse_request(&[24]).unwrap());
+        assert_eq!(Request::AcceptGzip, parse_request(&[28]).unwrap());
+        assert_eq!(
+            Request::Hello { version: 3 },
+            parse_request(b"\x17version=3|").unwrap()
+        );
+        assert!(parse_request(b"\x17version=0|").is_err());
+        assert_eq!(
+            Request::StatsOnce {
+                format: StatsFormat::Json
+            },
+            parse_request(&[18, 1]).unwrap()
+        );
+        assert_eq!(
+            Request::RangeDownload {
+                file_name: "big.iso".to_owned(),
+                start: 0,
+                end: 104857600,
+            },
+            parse_request(b"\x0dfilename=big.iso|range=0-104857600|").unwrap()
+        );
+        assert_eq!(
+            Request::Watch {
+                pattern: "*.log".to_owned()
+            },
+            parse_request(b"\x0ffilename=*.log|").unwrap()
+        );
+        assert_eq!(
+            Request::Tail {
+                file_name: "app.log".to_owned(),
+                bytes: 4096,
+            },
+            parse_request(b"\x16filename=app.log|tail=4096|").unwrap()
+        );
+        assert!(parse_request(b"\x16filename=app.log|tail=-1|").is_err());
+        let query = ListQuery {
+            prefix: "log-".to_owned(),
+            sort: ListSort::Size,
+            descending: true,
+            limit: 50,
+            page: Some("7/log-3".to_owned()),
+        };
+        assert_eq!(
+            Request::ListPage {
+                query: query.clone()
+            },
+            parse_request(&[&[25][..], query.encode().as_bytes()].concat()).unwrap()
+        );
+        assert!(parse_request(b"\x19prefix=|sort=age|limit=0|page=|").is_err());
+        assert_eq!(
+            Request::ReliableDownload {
+                file_name: "fw.bin".to_owned(),
+                chunk_bytes: 4096,
+                ack_every: 8,
+            },
+            parse_request(b"\x1afilename=fw.bin|chunks=4096,8|").unwrap()
+        );
+        assert!(parse_request(b"\x1afilename=fw.bin|chunks=0,8|").is_err());
+        assert!(parse_request(b"\x1afilename=fw.bin|chunks=4096,0|").is_err());
+        assert_eq!(
+            Request::Delta {
+                file_name: "disk.img".to_owned(),
+                block_size: 8192,
+            },
+            parse_request(b"\x1bfilename=disk.img|blocks=8192|").unwrap()
+        );
+        assert_eq!(
+            Request::FlashDownload {
+                file_name: "fw.bin".to_owned(),
+                chunk_bytes: 512,
+                offset: 1024,
+            },
+            parse_request(b"\x1ffilename=fw.bin|flash=512,1024|").unwrap()
+        );
+        assert!(parse_request(b"\x1ffilename=fw.bin|flash=0,0|").is_err());
+        assert!(parse_request(b"\x1bfilename=disk.img|blocks=1|").is_err());
+        assert_eq!(
+            Request::Delete {
+                file_name: "old.log".to_owned(),
+            },
+            parse_request(b"\x1dfilename=old.log|").unwrap()
+        );
+        assert_eq!(
+            Request::Locate {
+                file_name: "a.bin".to_owned(),
+            },
+            parse_request(b"\x1efilename=a.bin|").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_request_rejects_garbage() {
+        assert!(parse_request(b"").is_err());
+        assert!(matches!(
+            parse_request(&[42]),
+            Err(FileServerError::UnknownCommand { byte: 42 })
+        ));
+        assert!(parse_request(b"\x01filename=|").is_err());
+        assert!(matches!(
+            parse_request(b"\x01filename=notes.txt"),
+            Err(FileServerError::BadFrame { .. })
+        ));
+        // used to panic in from_utf8().unwrap()
+        assert!(parse_request(b"\x01filename=\xff\xfe|").is_err());
+        assert!(parse_request(b"\x09filename=a|if-none-match=\xff|").is_err());
+        assert!(parse_request(&[8]).is_err());
+        assert!(parse_request(b"\x0dfilename=a|range=9-3|").is_err());
+        assert!(parse_request(b"\x0dfilename=a|range=-3|").is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_upload_bytes: Some(10),
+            ..Default::default()
+        };
+        assert!(limits.check(Limit::Upload, 10).is_ok());
+        assert!(matches!(
+            limits.check(Limit::Upload, 11),
+            Err(FileServerError::TooLarge {
+                limit: Limit::Upload,
+                max: 10
+            })
+        ));
+        assert!(Limits::default().check(Limit::Upload, u64::MAX).is_ok());
+        let name = format!("filename={}|", "a".repeat(MAX_FILE_NAME_BYTES + 1));
+        assert!(matches!(
+            limits.parse_file_name(name.as_bytes()),
+            Err(FileServerError::TooLarge {
+                limit: Limit::FileName,
+                ..
+            })
+        ));
+        assert_eq!("a", limits.parse_file_name(b"filename=a|").unwrap());
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        assert_eq!(
+            vec![ManifestEntry {
+                name: "notes.txt".to_owned(),
+                size: 5,
+                sha256: "ab12".to_owned(),
+            }],
+            parse_manifest(b"notes.txt\t5\tAB12\n").unwrap()
+        );
+        assert!(parse_manifest(b"").unwrap().is_empty());
+        assert!(parse_manifest(b"notes.txt\tfive\tab12\n").is_err());
+        assert!(parse_manifest(b"notes.txt\t5\n").is_err());
+        assert!(parse_manifest(b"\xff\t5\tab12\n").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+// The bytes of StatisticsV2 and StatsOnce reports.
+
+#[cfg(feature = "std")]
+mod report;
+
+#[cfg(feature = "std")]
+pub use report::*;
+
+// the format byte a StatisticsV2 or StatsOnce request carries right after
+// the command
+pub const FORMAT_V2: u8 = 0;
+// one JSON object per line
+pub const FORMAT_JSON: u8 = 1;
+
+// A v2 report is [payload length: u32 big endian][payload], the payload's
+// fields in the order StatsSnapshot::encode_v2 writes them.
+pub const V2_LENGTH_BYTES: usize = 4;
+
+// The payload of a v2 report was shorter than its fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truncated;
+
+// Reads the fields of a v2 payload front to back without copying: integers
+// are big endian, strings [length: u16][utf-8 bytes] and histograms
+// [bucket count: u32]([bound: u64][count: u64])*[count: u64][sum: u64].
+// Fields are only ever appended, a reader that finds the payload empty
+// before the ones it knows about talks to an older server.
+pub struct Cursor<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(payload: &'a [u8]) -> Cursor<'a> {
+        Cursor { bytes: payload }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Truncated> {
+        let mut value = [0u8; 4];
+        value.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(value))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Truncated> {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(value))
+    }
+
+    // a string's bytes, not checked to be utf-8
+    pub fn str(&mut self) -> Result<&'a [u8], Truncated> {
+        let mut length = [0u8; 2];
+        length.copy_from_slice(self.take(2)?);
+        self.take(u16::from_be_bytes(length) as usize)
+    }
+
+    pub fn histogram(&mut self) -> Result<Histogram<'a>, Truncated> {
+        let buckets = self.u32()? as usize;
+        let buckets = self.take(buckets.checked_mul(16).ok_or(Truncated)?)?;
+        Ok(Histogram {
+            buckets,
+            count: self.u64()?,
+            sum_micros: self.u64()?,
+        })
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Truncated> {
+        if length > self.bytes.len() {
+            return Err(Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+}
+
+// A histogram as a v2 payload holds it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Histogram<'a> {
+    buckets: &'a [u8],
+    pub count: u64,
+    pub sum_micros: u64,
+}
+
+impl<'a> Histogram<'a> {
+    // (upper bound, count) of every bucket
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + 'a {
+        self.buckets.chunks_exact(16).map(|bucket| {
+            let mut bound = [0u8; 8];
+            let mut count = [0u8; 8];
+            bound.copy_from_slice(&bucket[..8]);
+            count.copy_from_slice(&bucket[8..]);
+            (u64::from_be_bytes(bound), u64::from_be_bytes(count))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_reads_fields_and_stops_short() {
+        let payload = [
+            0, 0, 0, 7, // u32
+            0, 2, b'h', b'i', // str
+            0, 0, 0, 1, // one bucket
+            0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 3, // (10, 3)
+            0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 20, // count, sum
+            0, 0, // cut short
+        ];
+        let mut cursor = Cursor::new(&payload);
+        assert_eq!(Ok(7), cursor.u32());
+        assert_eq!(Ok(&b"hi"[..]), cursor.str());
+        let histogram = cursor.histogram().unwrap();
+        assert_eq!((3, 20), (histogram.count, histogram.sum_micros));
+        assert_eq!(Some((10, 3)), histogram.buckets().next());
+        assert_eq!(Err(Truncated), cursor.u64());
+        assert!(!cursor.is_empty());
+    }
+}
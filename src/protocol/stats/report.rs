@@ -0,0 +1,676 @@
+// Stats reports as the server builds and sends them and FileClient reads them
+// back, in every format a subscriber can ask for. Unlike the rest of the
+// module this needs std, it is left out of no_std builds.
+
+use super::{Cursor, Truncated, FORMAT_JSON, FORMAT_V2, V2_LENGTH_BYTES};
+use crate::server::request_id::RequestId;
+use core::time::Duration;
+use std::io::{self, Read};
+
+// Layout a subscriber asked for when it subscribed.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum StatsFormat {
+    // the original [clients][name length][name][count] bytes
+    V1,
+    // [payload length: u32][payload], see StatsSnapshot::encode_v2
+    V2,
+    // one JSON object per line, see StatsSnapshot::encode_json
+    Json,
+}
+
+impl StatsFormat {
+    // the byte a StatisticsV2 subscriber sends right after the command
+    pub fn from_v2_format_byte(byte: u8) -> Option<StatsFormat> {
+        match byte {
+            FORMAT_V2 => Some(StatsFormat::V2),
+            FORMAT_JSON => Some(StatsFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+// One transfer in progress as reported by the Transfers command, sent as
+// one `id\tfile name\tpeer\tbytes sent\tmilliseconds running\n` line.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ActiveTransfer {
+    pub id: u64,
+    pub file_name: String,
+    pub peer: String,
+    pub bytes_sent: u64,
+    pub elapsed: Duration,
+    // the connection it runs on, shown on the admin port, not on the wire
+    pub request_id: Option<RequestId>,
+}
+
+impl ActiveTransfer {
+    pub fn encode_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            self.id,
+            self.file_name,
+            self.peer,
+            self.bytes_sent,
+            self.elapsed.as_millis()
+        )
+    }
+
+    pub fn parse_line(line: &str) -> Option<ActiveTransfer> {
+        let mut fields = line.split('\t');
+        let transfer = ActiveTransfer {
+            id: fields.next()?.parse().ok()?,
+            file_name: fields.next()?.to_owned(),
+            peer: fields.next()?.to_owned(),
+            bytes_sent: fields.next()?.parse().ok()?,
+            elapsed: Duration::from_millis(fields.next()?.parse().ok()?),
+            request_id: None,
+        };
+        match fields.next() {
+            None => Some(transfer),
+            Some(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransferStats {
+    pub id: u64,
+    pub file_name: String,
+    pub bytes_sent: u64,
+    pub bytes_per_second: u64,
+}
+
+// Cumulative counts, buckets[i] is (upper bound in microseconds, how many
+// took at most that long). `count` also has the ones past the last bound.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<(u64, u64)>,
+    pub count: u64,
+    pub sum_micros: u64,
+}
+
+// How saturated the worker pool is. Connections keep waiting for a worker
+// while every one is busy, a dispatch wait that grows means thread_count
+// is too low.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoolStats {
+    pub workers: u32,
+    pub waiting_connections: u32,
+    // from the command being read to a worker picking the connection up
+    pub dispatch_wait: HistogramSnapshot,
+    // the same per size class, see TransferClass. Last in a v2 frame
+    pub queues: Vec<QueueStats>,
+}
+
+// Downloads of one size class waiting for a worker and how long they waited.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueStats {
+    pub class: String,
+    pub waiting: u32,
+    pub dispatch_wait: HistogramSnapshot,
+}
+
+// This server and the peers it follows the stats of, see Cluster. All
+// zero without peers. Last in a v2 frame
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClusterStats {
+    // this server plus the peers it currently hears from
+    pub nodes: u32,
+    // peers configured but not reporting
+    pub unreachable: u32,
+    pub clients: u32,
+    pub bytes_served: u64,
+    // most downloaded files across the nodes as (file, downloads), summed
+    // from each node's own top files, so a file just outside every node's
+    // list is missed
+    pub top_files: Vec<(String, u64)>,
+}
+
+// Everything a stats tick reports, built once per tick and encoded for
+// each subscriber in the format it asked for.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StatsSnapshot {
+    // busy workers, every client being served holds one
+    pub number_of_clients: u32,
+    pub most_downloaded_file: String,
+    pub file_downloaded_count: u64,
+    pub bytes_served: u64,
+    pub bytes_per_file: Vec<(String, u64)>,
+    pub transfers: Vec<TransferStats>,
+    pub pool: PoolStats,
+    // most downloaded files first as (file, downloads), ties by name
+    pub top_files: Vec<(String, u64)>,
+    // how long requests took, per command by name, commands never run left out
+    pub request_durations: Vec<(String, HistogramSnapshot)>,
+    // bytes served per client IP since the start or the last flush
+    pub bytes_per_ip: Vec<(String, u64)>,
+    // number of the reporting tick, one up from the last one; a gap means
+    // ticks were missed. 0 for a report outside the ticks (StatsOnce)
+    pub seq: u64,
+    // server clock when the report was taken, ms since the Unix epoch, to
+    // tell a late tick from a skewed clock
+    pub timestamp_ms: u64,
+    // the node's own figures above, the cluster's here
+    pub cluster: ClusterStats,
+}
+
+impl StatsSnapshot {
+    pub fn encode(&self, format: StatsFormat) -> Vec<u8> {
+        match format {
+            StatsFormat::V1 => self.encode_v1(),
+            StatsFormat::V2 => self.encode_v2(),
+            StatsFormat::Json => self.encode_json().into_bytes(),
+        }
+    }
+
+    pub fn encode_v1(&self) -> Vec<u8> {
+        let mut frame = vec![self.number_of_clients as u8];
+        frame.push(self.most_downloaded_file.len() as u8);
+        frame.extend_from_slice(self.most_downloaded_file.as_bytes());
+        frame.push(self.file_downloaded_count as u8);
+        frame
+    }
+
+    // Fields are written in a fixed order, new fields only ever get
+    // appended so older readers can skip what they do not know about
+    // using the payload length.
+    pub fn encode_v2(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&self.number_of_clients.to_be_bytes());
+        push_str(&mut payload, &self.most_downloaded_file);
+        payload.extend_from_slice(&self.file_downloaded_count.to_be_bytes());
+        payload.extend_from_slice(&self.bytes_served.to_be_bytes());
+
+        payload.extend_from_slice(&(self.bytes_per_file.len() as u32).to_be_bytes());
+        for (file_name, bytes) in &self.bytes_per_file {
+            push_str(&mut payload, file_name);
+            payload.extend_from_slice(&bytes.to_be_bytes());
+        }
+
+        payload.extend_from_slice(&(self.transfers.len() as u32).to_be_bytes());
+        for transfer in &self.transfers {
+            payload.extend_from_slice(&transfer.id.to_be_bytes());
+            push_str(&mut payload, &transfer.file_name);
+            payload.extend_from_slice(&transfer.bytes_sent.to_be_bytes());
+            payload.extend_from_slice(&transfer.bytes_per_second.to_be_bytes());
+        }
+
+        payload.extend_from_slice(&self.pool.workers.to_be_bytes());
+        payload.extend_from_slice(&self.pool.waiting_connections.to_be_bytes());
+        push_histogram(&mut payload, &self.pool.dispatch_wait);
+
+        payload.extend_from_slice(&(self.top_files.len() as u32).to_be_bytes());
+        for (file_name, downloads) in &self.top_files {
+            push_str(&mut payload, file_name);
+            payload.extend_from_slice(&downloads.to_be_bytes());
+        }
+
+        payload.extend_from_slice(&(self.request_durations.len() as u32).to_be_bytes());
+        for (command, durations) in &self.request_durations {
+            push_str(&mut payload, command);
+            push_histogram(&mut payload, durations);
+        }
+
+        payload.extend_from_slice(&(self.bytes_per_ip.len() as u32).to_be_bytes());
+        for (ip, bytes) in &self.bytes_per_ip {
+            push_str(&mut payload, ip);
+            payload.extend_from_slice(&bytes.to_be_bytes());
+        }
+
+        payload.extend_from_slice(&(self.pool.queues.len() as u32).to_be_bytes());
+        for queue in &self.pool.queues {
+            push_str(&mut payload, &queue.class);
+            payload.extend_from_slice(&queue.waiting.to_be_bytes());
+            push_histogram(&mut payload, &queue.dispatch_wait);
+        }
+
+        payload.extend_from_slice(&self.seq.to_be_bytes());
+        payload.extend_from_slice(&self.timestamp_ms.to_be_bytes());
+
+        payload.extend_from_slice(&self.cluster.nodes.to_be_bytes());
+        payload.extend_from_slice(&self.cluster.unreachable.to_be_bytes());
+        payload.extend_from_slice(&self.cluster.clients.to_be_bytes());
+        payload.extend_from_slice(&self.cluster.bytes_served.to_be_bytes());
+        payload.extend_from_slice(&(self.cluster.top_files.len() as u32).to_be_bytes());
+        for (file_name, downloads) in &self.cluster.top_files {
+            push_str(&mut payload, file_name);
+            payload.extend_from_slice(&downloads.to_be_bytes());
+        }
+
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    // A single line, newline included, so a stream of them can be piped
+    // straight into jq. Keys follow the v2 fields.
+    pub fn encode_json(&self) -> String {
+        let bytes_per_file: Vec<String> = self
+            .bytes_per_file
+            .iter()
+            .map(|(file_name, bytes)| format!("{}:{}", json_str(file_name), bytes))
+            .collect();
+        let transfers: Vec<String> = self
+            .transfers
+            .iter()
+            .map(|transfer| {
+                format!(
+                    "{{\"id\":{},\"file\":{},\"bytes_sent\":{},\"bytes_per_second\":{}}}",
+                    transfer.id,
+                    json_str(&transfer.file_name),
+                    transfer.bytes_sent,
+                    transfer.bytes_per_second
+                )
+            })
+            .collect();
+        let top_files: Vec<String> = self
+            .top_files
+            .iter()
+            .map(|(file_name, downloads)| {
+                format!(
+                    "{{\"file\":{},\"downloads\":{}}}",
+                    json_str(file_name),
+                    downloads
+                )
+            })
+            .collect();
+        let request_durations: Vec<String> = self
+            .request_durations
+            .iter()
+            .map(|(command, durations)| {
+                format!("{}:{}", json_str(command), json_histogram(durations))
+            })
+            .collect();
+        let bytes_per_ip: Vec<String> = self
+            .bytes_per_ip
+            .iter()
+            .map(|(ip, bytes)| format!("{}:{}", json_str(ip), bytes))
+            .collect();
+        let queues: Vec<String> = self
+            .pool
+            .queues
+            .iter()
+            .map(|queue| {
+                format!(
+                    "{}:{{\"waiting\":{},\"dispatch_wait_us\":{}}}",
+                    json_str(&queue.class),
+                    queue.waiting,
+                    json_histogram(&queue.dispatch_wait)
+                )
+            })
+            .collect();
+        let cluster_top_files: Vec<String> = self
+            .cluster
+            .top_files
+            .iter()
+            .map(|(file_name, downloads)| {
+                format!(
+                    "{{\"file\":{},\"downloads\":{}}}",
+                    json_str(file_name),
+                    downloads
+                )
+            })
+            .collect();
+        format!(
+            "{{\"clients\":{},\"top_file\":{},\"count\":{},\"bytes_served\":{},\"bytes_per_file\":{{{}}},\"transfers\":[{}],\"workers\":{},\"waiting\":{},\"dispatch_wait_us\":{},\"top_files\":[{}],\"request_durations_us\":{{{}}},\"bytes_per_ip\":{{{}}},\"queues\":{{{}}},\"seq\":{},\"timestamp_ms\":{},\"cluster\":{{\"nodes\":{},\"unreachable\":{},\"clients\":{},\"bytes_served\":{},\"top_files\":[{}]}}}}\n",
+            self.number_of_clients,
+            json_str(&self.most_downloaded_file),
+            self.file_downloaded_count,
+            self.bytes_served,
+            bytes_per_file.join(","),
+            transfers.join(","),
+            self.pool.workers,
+            self.pool.waiting_connections,
+            json_histogram(&self.pool.dispatch_wait),
+            top_files.join(","),
+            request_durations.join(","),
+            bytes_per_ip.join(","),
+            queues.join(","),
+            self.seq,
+            self.timestamp_ms,
+            self.cluster.nodes,
+            self.cluster.unreachable,
+            self.cluster.clients,
+            self.cluster.bytes_served,
+            cluster_top_files.join(",")
+        )
+    }
+
+    pub fn from_stream_v2(stream: &mut impl Read) -> io::Result<StatsSnapshot> {
+        let mut length = [0u8; V2_LENGTH_BYTES];
+        stream.read_exact(&mut length)?;
+        let mut payload = vec![0; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut payload)?;
+
+        let mut cursor = Cursor::new(&payload);
+        let mut snapshot = StatsSnapshot {
+            number_of_clients: read_u32(&mut cursor)?,
+            most_downloaded_file: read_str(&mut cursor)?,
+            file_downloaded_count: read_u64(&mut cursor)?,
+            bytes_served: read_u64(&mut cursor)?,
+            ..StatsSnapshot::default()
+        };
+        for _ in 0..read_u32(&mut cursor)? {
+            snapshot
+                .bytes_per_file
+                .push((read_str(&mut cursor)?, read_u64(&mut cursor)?));
+        }
+        for _ in 0..read_u32(&mut cursor)? {
+            snapshot.transfers.push(TransferStats {
+                id: read_u64(&mut cursor)?,
+                file_name: read_str(&mut cursor)?,
+                bytes_sent: read_u64(&mut cursor)?,
+                bytes_per_second: read_u64(&mut cursor)?,
+            });
+        }
+        // older servers end here, before the pool stats, the top files
+        // or the request durations
+        if cursor.is_empty() {
+            return Ok(snapshot);
+        }
+        snapshot.pool.workers = read_u32(&mut cursor)?;
+        snapshot.pool.waiting_connections = read_u32(&mut cursor)?;
+        snapshot.pool.dispatch_wait = read_histogram(&mut cursor)?;
+        if cursor.is_empty() {
+            return Ok(snapshot);
+        }
+        for _ in 0..read_u32(&mut cursor)? {
+            snapshot
+                .top_files
+                .push((read_str(&mut cursor)?, read_u64(&mut cursor)?));
+        }
+        if cursor.is_empty() {
+            return Ok(snapshot);
+        }
+        for _ in 0..read_u32(&mut cursor)? {
+            snapshot
+                .request_durations
+                .push((read_str(&mut cursor)?, read_histogram(&mut cursor)?));
+        }
+        if cursor.is_empty() {
+            return Ok(snapshot);
+        }
+        for _ in 0..read_u32(&mut cursor)? {
+            snapshot
+                .bytes_per_ip
+                .push((read_str(&mut cursor)?, read_u64(&mut cursor)?));
+        }
+        if cursor.is_empty() {
+            return Ok(snapshot);
+        }
+        for _ in 0..read_u32(&mut cursor)? {
+            snapshot.pool.queues.push(QueueStats {
+                class: read_str(&mut cursor)?,
+                waiting: read_u32(&mut cursor)?,
+                dispatch_wait: read_histogram(&mut cursor)?,
+            });
+        }
+        if cursor.is_empty() {
+            return Ok(snapshot);
+        }
+        snapshot.seq = read_u64(&mut cursor)?;
+        snapshot.timestamp_ms = read_u64(&mut cursor)?;
+        if cursor.is_empty() {
+            return Ok(snapshot);
+        }
+        snapshot.cluster.nodes = read_u32(&mut cursor)?;
+        snapshot.cluster.unreachable = read_u32(&mut cursor)?;
+        snapshot.cluster.clients = read_u32(&mut cursor)?;
+        snapshot.cluster.bytes_served = read_u64(&mut cursor)?;
+        for _ in 0..read_u32(&mut cursor)? {
+            snapshot
+                .cluster
+                .top_files
+                .push((read_str(&mut cursor)?, read_u64(&mut cursor)?));
+        }
+        Ok(snapshot)
+    }
+}
+
+// `value` as a quoted JSON string
+fn json_str(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// {"buckets":{"<bound>":<count>,...},"count":<count>,"sum":<sum>}
+fn json_histogram(histogram: &HistogramSnapshot) -> String {
+    let buckets: Vec<String> = histogram
+        .buckets
+        .iter()
+        .map(|(bound, count)| format!("\"{}\":{}", bound, count))
+        .collect();
+    format!(
+        "{{\"buckets\":{{{}}},\"count\":{},\"sum\":{}}}",
+        buckets.join(","),
+        histogram.count,
+        histogram.sum_micros
+    )
+}
+
+// [bucket count: u32]([bound: u64][count: u64])*[count: u64][sum: u64]
+fn push_histogram(payload: &mut Vec<u8>, histogram: &HistogramSnapshot) {
+    payload.extend_from_slice(&(histogram.buckets.len() as u32).to_be_bytes());
+    for (bound, count) in &histogram.buckets {
+        payload.extend_from_slice(&bound.to_be_bytes());
+        payload.extend_from_slice(&count.to_be_bytes());
+    }
+    payload.extend_from_slice(&histogram.count.to_be_bytes());
+    payload.extend_from_slice(&histogram.sum_micros.to_be_bytes());
+}
+
+fn read_histogram(cursor: &mut Cursor) -> io::Result<HistogramSnapshot> {
+    let histogram = cursor.histogram()?;
+    Ok(HistogramSnapshot {
+        buckets: histogram.buckets().collect(),
+        count: histogram.count,
+        sum_micros: histogram.sum_micros,
+    })
+}
+
+fn push_str(payload: &mut Vec<u8>, value: &str) {
+    payload.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    payload.extend_from_slice(value.as_bytes());
+}
+
+impl From<Truncated> for io::Error {
+    fn from(_: Truncated) -> io::Error {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "stats report cut short")
+    }
+}
+
+fn read_u32(cursor: &mut Cursor) -> io::Result<u32> {
+    Ok(cursor.u32()?)
+}
+
+fn read_u64(cursor: &mut Cursor) -> io::Result<u64> {
+    Ok(cursor.u64()?)
+}
+
+fn read_str(cursor: &mut Cursor) -> io::Result<String> {
+    Ok(String::from_utf8_lossy(cursor.str()?).to_string())
+}
+
+// The figures of a v1 report, also read out of a v2 one by from_stream_v2.
+// A v1 frame has no room for seq and timestamp_ms, they stay 0 there.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub number_of_clients: u8,
+    pub most_downloaded_file: String,
+    pub file_downloaded_count: u8,
+    // see StatsSnapshot::seq
+    pub seq: u64,
+    // see StatsSnapshot::timestamp_ms
+    pub timestamp_ms: u64,
+}
+
+impl From<&StatsSnapshot> for Stats {
+    // cut down to bytes like encode_v1 does
+    fn from(snapshot: &StatsSnapshot) -> Stats {
+        Stats {
+            number_of_clients: snapshot.number_of_clients as u8,
+            most_downloaded_file: snapshot.most_downloaded_file.clone(),
+            file_downloaded_count: snapshot.file_downloaded_count as u8,
+            seq: snapshot.seq,
+            timestamp_ms: snapshot.timestamp_ms,
+        }
+    }
+}
+
+impl Stats {
+    pub fn stats_from_stream(stream: &mut impl Read) -> Stats {
+        let mut client_count: [u8; 1] = [11];
+        stream.read_exact(client_count.as_mut_slice()).unwrap();
+
+        let mut most_accessed_file_name_length: [u8; 1] = [1];
+        stream
+            .read_exact(most_accessed_file_name_length.as_mut_slice())
+            .unwrap();
+
+        let mut vec = vec![0; most_accessed_file_name_length[0] as usize];
+        let file_name: &mut [u8] = &mut vec[..];
+        stream.read_exact(file_name).unwrap();
+
+        let mut file_downloaded_stat: [u8; 1] = [11];
+        stream
+            .read_exact(file_downloaded_stat.as_mut_slice())
+            .unwrap();
+
+        Stats {
+            number_of_clients: client_count[0],
+            most_downloaded_file: String::from_utf8_lossy(file_name).to_string(),
+            file_downloaded_count: file_downloaded_stat[0],
+            ..Stats::default()
+        }
+    }
+
+    pub fn from_stream_v2(stream: &mut impl Read) -> io::Result<Stats> {
+        Ok(Stats::from(&StatsSnapshot::from_stream_v2(stream)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_json_line() {
+        let snapshot = StatsSnapshot {
+            number_of_clients: 2,
+            most_downloaded_file: "a \"quoted\"\tname".to_owned(),
+            file_downloaded_count: 3,
+            bytes_served: 10,
+            bytes_per_file: vec![("a".to_owned(), 4), ("b".to_owned(), 6)],
+            transfers: vec![TransferStats {
+                id: 7,
+                file_name: "b".to_owned(),
+                bytes_sent: 6,
+                bytes_per_second: 12,
+            }],
+            pool: PoolStats {
+                workers: 4,
+                waiting_connections: 1,
+                dispatch_wait: HistogramSnapshot {
+                    buckets: vec![(1000, 2), (10000, 3)],
+                    count: 3,
+                    sum_micros: 6500,
+                },
+                queues: vec![QueueStats {
+                    class: "small".to_owned(),
+                    waiting: 1,
+                    dispatch_wait: HistogramSnapshot {
+                        buckets: vec![(1000, 1)],
+                        count: 1,
+                        sum_micros: 400,
+                    },
+                }],
+            },
+            top_files: vec![("b".to_owned(), 3), ("a".to_owned(), 1)],
+            request_durations: vec![(
+                "Download".to_owned(),
+                HistogramSnapshot {
+                    buckets: vec![(1000, 1)],
+                    count: 1,
+                    sum_micros: 800,
+                },
+            )],
+            bytes_per_ip: vec![("10.0.0.1".to_owned(), 10)],
+            seq: 42,
+            timestamp_ms: 1_700_000_000_123,
+            cluster: ClusterStats {
+                nodes: 2,
+                unreachable: 1,
+                clients: 5,
+                bytes_served: 30,
+                top_files: vec![("b".to_owned(), 7)],
+            },
+        };
+        assert_eq!(
+            concat!(
+                r#"{"clients":2,"top_file":"a \"quoted\"\tname","count":3,"bytes_served":10,"#,
+                r#""bytes_per_file":{"a":4,"b":6},"#,
+                r#""transfers":[{"id":7,"file":"b","bytes_sent":6,"bytes_per_second":12}],"#,
+                r#""workers":4,"waiting":1,"#,
+                r#""dispatch_wait_us":{"buckets":{"1000":2,"10000":3},"count":3,"sum":6500},"#,
+                r#""top_files":[{"file":"b","downloads":3},{"file":"a","downloads":1}],"#,
+                r#""request_durations_us":{"Download":{"buckets":{"1000":1},"count":1,"sum":800}},"#,
+                r#""bytes_per_ip":{"10.0.0.1":10},"#,
+                r#""queues":{"small":{"waiting":1,"dispatch_wait_us":{"buckets":{"1000":1},"count":1,"sum":400}}},"#,
+                r#""seq":42,"timestamp_ms":1700000000123,"#,
+                r#""cluster":{"nodes":2,"unreachable":1,"clients":5,"bytes_served":30,"#,
+                r#""top_files":[{"file":"b","downloads":7}]}}"#,
+                "\n"
+            ),
+            snapshot.encode_json()
+        );
+
+        let v2 = snapshot.encode_v2();
+        assert_eq!(
+            snapshot,
+            StatsSnapshot::from_stream_v2(&mut v2.as_slice()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_stats_round_trip_keeps_seq_and_timestamp() {
+        let snapshot = StatsSnapshot {
+            number_of_clients: 2,
+            most_downloaded_file: "notes".to_owned(),
+            file_downloaded_count: 3,
+            seq: 7,
+            timestamp_ms: 1_700_000_000_123,
+            ..StatsSnapshot::default()
+        };
+        let stats = Stats {
+            number_of_clients: 2,
+            most_downloaded_file: "notes".to_owned(),
+            file_downloaded_count: 3,
+            seq: 7,
+            timestamp_ms: 1_700_000_000_123,
+        };
+        let v2 = snapshot.encode_v2();
+        assert_eq!(stats, Stats::from_stream_v2(&mut v2.as_slice()).unwrap());
+
+        // v1 has no room for them
+        let v1 = snapshot.encode_v1();
+        assert_eq!(
+            Stats {
+                seq: 0,
+                timestamp_ms: 0,
+                ..stats
+            },
+            Stats::stats_from_stream(&mut v1.as_slice())
+        );
+    }
+}
@@ -0,0 +1,323 @@
+use aes_gcm::{
+    aead::{consts::U12, Aead, Generate, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use std::{
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+// Files encrypted at rest are AES-256-GCM in chunks, so a download can start
+// before the whole file is decrypted and a range only decrypts the chunks it
+// touches:
+//
+//   [MAGIC: 8][nonce prefix: 8][chunk 0]...[chunk n]
+//
+// Every chunk is CHUNK_SIZE bytes of plaintext (the last one up to that)
+// sealed with a 16 byte tag. A chunk's nonce is the file's random prefix
+// followed by its index, and the last chunk is sealed with different
+// associated data, so chunks can not be reordered, swapped between files or
+// cut off the end without decryption failing.
+
+const MAGIC: &[u8; 8] = b"FSENC\0\0\x01";
+const HEADER_SIZE: u64 = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+const SEALED_CHUNK_SIZE: u64 = CHUNK_SIZE as u64 + TAG_SIZE;
+
+// Key files are encrypted with, 32 bytes given as 64 hex digits.
+pub struct AtRestKey {
+    cipher: Aes256Gcm,
+}
+
+impl AtRestKey {
+    pub fn from_hex(hex: &str) -> io::Result<AtRestKey> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                "the encryption key must be 64 hex digits",
+            )
+        };
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+            .collect::<io::Result<Vec<u8>>>()?;
+        let cipher = Aes256Gcm::new_from_slice(&bytes).map_err(|_| invalid())?;
+        Ok(AtRestKey { cipher })
+    }
+
+    fn seal(&self, prefix: &[u8; 8], index: u32, last: bool, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.cipher
+            .encrypt(
+                &nonce(prefix, index),
+                Payload {
+                    msg: chunk,
+                    aad: &[last as u8],
+                },
+            )
+            .map_err(|_| io::Error::other("could not encrypt file chunk"))
+    }
+
+    fn open(&self, prefix: &[u8; 8], index: u32, last: bool, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        self.cipher
+            .decrypt(
+                &nonce(prefix, index),
+                Payload {
+                    msg: sealed,
+                    aad: &[last as u8],
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "could not decrypt file, wrong key or corrupted file",
+                )
+            })
+    }
+}
+
+fn nonce(prefix: &[u8; 8], index: u32) -> Nonce<U12> {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&index.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+// Size of the plaintext of an encrypted file `disk_len` bytes long, 0 for
+// anything too short to be one.
+pub fn plaintext_len(disk_len: u64) -> u64 {
+    let body = disk_len.saturating_sub(HEADER_SIZE);
+    let chunks = body.div_ceil(SEALED_CHUNK_SIZE);
+    body.saturating_sub(chunks * TAG_SIZE)
+}
+
+// Encrypts what is written to it into `inner`. Nothing is complete until
+// finish() seals the last chunk, dropping the writer leaves a file that fails
+// to decrypt.
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    key: Arc<AtRestKey>,
+    prefix: [u8; 8],
+    index: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub fn new(mut inner: W, key: Arc<AtRestKey>) -> io::Result<EncryptingWriter<W>> {
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&Nonce::<U12>::generate()[..8]);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&prefix)?;
+        Ok(EncryptingWriter {
+            inner,
+            key,
+            prefix,
+            index: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    // Seals what is left as the last chunk and hands back the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let chunk = std::mem::take(&mut self.buffer);
+        self.write_chunk(&chunk, true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_chunk(&mut self, chunk: &[u8], last: bool) -> io::Result<()> {
+        let sealed = self.key.seal(&self.prefix, self.index, last, chunk)?;
+        self.inner.write_all(&sealed)?;
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("file too big to encrypt"))?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a full chunk is only sealed once more data shows up, the last
+        // chunk is never empty unless the whole file is
+        if self.buffer.len() == CHUNK_SIZE && !buf.is_empty() {
+            let chunk = std::mem::take(&mut self.buffer);
+            self.write_chunk(&chunk, false)?;
+        }
+        let taken = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..taken]);
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Reads the plaintext of an encrypted file, a chunk at a time.
+pub struct DecryptingReader<R: Read + Seek> {
+    inner: R,
+    key: Arc<AtRestKey>,
+    prefix: [u8; 8],
+    disk_len: u64,
+    len: u64,
+    position: u64,
+    // index and plaintext of the chunk read last
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> DecryptingReader<R> {
+    pub fn new(
+        mut inner: R,
+        disk_len: u64,
+        key: Arc<AtRestKey>,
+    ) -> io::Result<DecryptingReader<R>> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        inner.read_exact(&mut header).map_err(|_| not_encrypted())?;
+        if &header[..8] != MAGIC || disk_len < HEADER_SIZE + TAG_SIZE {
+            return Err(not_encrypted());
+        }
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&header[8..]);
+        Ok(DecryptingReader {
+            inner,
+            key,
+            prefix,
+            disk_len,
+            len: plaintext_len(disk_len),
+            position: 0,
+            chunk: None,
+        })
+    }
+
+    // Size of the plaintext.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn load_chunk(&mut self, index: u64) -> io::Result<()> {
+        let start = HEADER_SIZE + index * SEALED_CHUNK_SIZE;
+        let sealed_len = SEALED_CHUNK_SIZE.min(self.disk_len - start);
+        let last = start + sealed_len == self.disk_len;
+        let mut sealed = vec![0u8; sealed_len as usize];
+        self.inner.seek(SeekFrom::Start(start))?;
+        self.inner.read_exact(&mut sealed)?;
+        let index32 = u32::try_from(index).map_err(|_| not_encrypted())?;
+        let plaintext = self.key.open(&self.prefix, index32, last, &sealed)?;
+        self.chunk = Some((index, plaintext));
+        Ok(())
+    }
+}
+
+fn not_encrypted() -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        "file is not encrypted with the at-rest key format",
+    )
+}
+
+impl<R: Read + Seek> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.position / CHUNK_SIZE as u64;
+        if !matches!(&self.chunk, Some((loaded, _)) if *loaded == index) {
+            self.load_chunk(index)?;
+        }
+        let Some((_, plaintext)) = &self.chunk else {
+            unreachable!("the chunk was just loaded")
+        };
+        let offset = (self.position % CHUNK_SIZE as u64) as usize;
+        let read = buf.len().min(plaintext.len() - offset);
+        buf[..read].copy_from_slice(&plaintext[offset..offset + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for DecryptingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "seek before the start of the file")
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn encrypt(key: &Arc<AtRestKey>, plaintext: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), key.clone()).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_and_seek() {
+        let key = Arc::new(AtRestKey::from_hex(KEY).unwrap());
+        for size in [0, 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE - 5] {
+            let plaintext: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt(&key, &plaintext);
+            assert_eq!(size as u64, plaintext_len(encrypted.len() as u64));
+            assert!(size < 4 || !encrypted.windows(4).any(|w| w == &plaintext[..4]));
+
+            let mut reader =
+                DecryptingReader::new(Cursor::new(&encrypted), encrypted.len() as u64, key.clone())
+                    .unwrap();
+            let mut decrypted = Vec::new();
+            reader.read_to_end(&mut decrypted).unwrap();
+            assert_eq!(plaintext, decrypted);
+
+            if size >= CHUNK_SIZE + 2 {
+                reader.seek(SeekFrom::Start(CHUNK_SIZE as u64 - 2)).unwrap();
+                let mut middle = [0u8; 4];
+                reader.read_exact(&mut middle).unwrap();
+                assert_eq!(&plaintext[CHUNK_SIZE - 2..CHUNK_SIZE + 2], &middle);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let key = Arc::new(AtRestKey::from_hex(KEY).unwrap());
+        let plaintext = vec![9u8; 2 * CHUNK_SIZE];
+        let encrypted = encrypt(&key, &plaintext);
+        let read_all = |bytes: &[u8], key: &Arc<AtRestKey>| {
+            let mut reader =
+                DecryptingReader::new(Cursor::new(bytes), bytes.len() as u64, key.clone())?;
+            let mut decrypted = Vec::new();
+            reader.read_to_end(&mut decrypted).map(|_| decrypted)
+        };
+
+        let mut flipped = encrypted.clone();
+        flipped[HEADER_SIZE as usize + 3] ^= 1;
+        assert!(read_all(&flipped, &key).is_err());
+        // dropping the last chunk leaves a file whose new last chunk was not sealed as one
+        let truncated = &encrypted[..(HEADER_SIZE + SEALED_CHUNK_SIZE) as usize];
+        assert!(read_all(truncated, &key).is_err());
+        let other_key = Arc::new(AtRestKey::from_hex(&KEY.replace('0', "f")).unwrap());
+        assert!(read_all(&encrypted, &other_key).is_err());
+        assert!(read_all(b"plain text on disk", &key).is_err());
+
+        assert!(AtRestKey::from_hex("00").is_err());
+        assert!(AtRestKey::from_hex(&KEY.replace('0', "g")).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// Content type detection for served files, by extension first and by the
+// first bytes of the file for names that say nothing.
+
+// how many bytes of a file content_type_of_bytes wants to see
+pub const SNIFF_BYTES: usize = 512;
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+// lowercase extension and its type, the common ones only
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("txt", "text/plain; charset=utf-8"),
+    ("log", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("toml", "application/toml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("7z", "application/x-7z-compressed"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("wasm", "application/wasm"),
+    ("iso", "application/x-iso9660-image"),
+];
+
+// leading bytes and the type they give away
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\x00asm", "application/wasm"),
+    (b"\x7fELF", "application/x-executable"),
+];
+
+// By the extension of `file_name`, None when it has none or an unknown one.
+pub fn content_type_by_extension(file_name: &str) -> Option<&'static str> {
+    let (_, extension) = file_name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map(|(_, content_type)| *content_type)
+}
+
+// By the first bytes of a file, up to SNIFF_BYTES of them. Anything without a
+// known signature is text if it is valid utf-8 without control characters
+// other than whitespace, octet-stream otherwise.
+pub fn content_type_of_bytes(head: &[u8]) -> &'static str {
+    if let Some((_, content_type)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return content_type;
+    }
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    // a multi-byte character cut off at the end of `head` is still text
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&head[..err.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return OCTET_STREAM,
+    };
+    if head.is_empty()
+        || text
+            .chars()
+            .any(|c| c.is_control() && !c.is_ascii_whitespace())
+    {
+        return OCTET_STREAM;
+    }
+    "text/plain; charset=utf-8"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type_detection() {
+        assert_eq!(Some("image/png"), content_type_by_extension("logo.PNG"));
+        assert_eq!(
+            Some("application/gzip"),
+            content_type_by_extension("backup.tar.gz")
+        );
+        assert_eq!(None, content_type_by_extension("Makefile"));
+        assert_eq!(None, content_type_by_extension("data.unknown"));
+
+        assert_eq!("image/png", content_type_of_bytes(b"\x89PNG\r\n\x1a\n...."));
+        assert_eq!("application/pdf", content_type_of_bytes(b"%PDF-1.7\n"));
+        assert_eq!(
+            "text/plain; charset=utf-8",
+            content_type_of_bytes("all: build\n\tcargo build — done\n".as_bytes())
+        );
+        // cut off in the middle of the dash
+        assert_eq!(
+            "text/plain; charset=utf-8",
+            content_type_of_bytes(&"a — b".as_bytes()[..3])
+        );
+        assert_eq!(OCTET_STREAM, content_type_of_bytes(&[0, 1, 2, 0xff]));
+        assert_eq!(OCTET_STREAM, content_type_of_bytes(b""));
+    }
+}
@@ -1,21 +1,718 @@
+mod encryption;
+mod mime;
+mod trash;
+
+use crate::archive::embedded::ArchivedBytes;
+pub use encryption::{plaintext_len, AtRestKey, DecryptingReader, EncryptingWriter};
+use memmap2::Mmap;
+pub use mime::{content_type_by_extension, content_type_of_bytes, OCTET_STREAM, SNIFF_BYTES};
+use sha2::{Digest, Sha256};
 use std::{
+    env,
     fs::{self, File},
-    io::{self, BufReader},
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, Weak},
+    time,
 };
+pub use trash::{purge_trash, restore_trashed_file, trash_file, trashed_files, TRASH_DIR};
+
+// Uploads are written to `<name>.part` and renamed once complete, so a
+// half received file never shows up under its real name.
+pub const PARTIAL_SUFFIX: &str = ".part";
+
+// Directory the served directories live in, the system temp dir unless set.
+static BASE_DIRECTORY: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+// Below this size a BufReader is as fast as a mapping and much cheaper to set up.
+pub const DEFAULT_MMAP_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+// A served file, read either through a BufReader or straight out of a memory
+// mapping for big files.
+pub enum FileSource {
+    Buffered(BufReader<File>, u64),
+    Mapped(Cursor<SharedMapping>),
+    // bytes already held by the hot file cache
+    Cached(Cursor<Arc<[u8]>>),
+    // a file encrypted at rest, decrypted as it is read
+    Decrypted(DecryptingReader<File>),
+    // a file out of the server's embedded archive
+    Embedded(Cursor<ArchivedBytes>),
+}
+
+impl FileSource {
+    // Size of the file when it was opened.
+    pub fn len(&self) -> u64 {
+        match self {
+            FileSource::Buffered(_, len) => *len,
+            FileSource::Mapped(mapping) => mapping.get_ref().as_ref().len() as u64,
+            FileSource::Cached(bytes) => bytes.get_ref().len() as u64,
+            FileSource::Decrypted(reader) => reader.len(),
+            FileSource::Embedded(bytes) => bytes.get_ref().as_ref().len() as u64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, FileSource::Mapped(_))
+    }
+
+    // Another reader of the same mapping, from the start, without opening the
+    // file again. None for sources that are not mapped.
+    pub fn share(&self) -> Option<FileSource> {
+        self.mapping()
+            .map(|mapping| FileSource::Mapped(Cursor::new(mapping.clone())))
+    }
+
+    pub(crate) fn mapping(&self) -> Option<&SharedMapping> {
+        match self {
+            FileSource::Mapped(mapping) => Some(mapping.get_ref()),
+            _ => None,
+        }
+    }
+}
+
+// A memory mapping every concurrent reader of a big file can read through, it
+// is unmapped once the last of them is done.
+#[derive(Clone)]
+pub struct SharedMapping(Arc<Mmap>);
+
+impl SharedMapping {
+    pub(crate) fn downgrade(&self) -> Weak<Mmap> {
+        Arc::downgrade(&self.0)
+    }
+
+    pub(crate) fn upgrade(weak: &Weak<Mmap>) -> Option<SharedMapping> {
+        weak.upgrade().map(SharedMapping)
+    }
+
+    // Whether both read the very same mapping.
+    pub fn ptr_eq(&self, other: &SharedMapping) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl AsRef<[u8]> for SharedMapping {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Read for FileSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            FileSource::Buffered(reader, _) => reader.read(buf),
+            FileSource::Mapped(mapping) => mapping.read(buf),
+            FileSource::Cached(bytes) => bytes.read(buf),
+            FileSource::Decrypted(reader) => reader.read(buf),
+            FileSource::Embedded(bytes) => bytes.read(buf),
+        }
+    }
+}
+
+impl Seek for FileSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            FileSource::Buffered(reader, _) => reader.seek(pos),
+            FileSource::Mapped(mapping) => mapping.seek(pos),
+            FileSource::Cached(bytes) => bytes.seek(pos),
+            FileSource::Decrypted(reader) => reader.seek(pos),
+            FileSource::Embedded(bytes) => bytes.seek(pos),
+        }
+    }
+}
+
+// Serves directories from under `base` instead of the system temp dir. Set it
+// before starting the server, paths handed out earlier keep the old base.
+pub fn set_base_directory(base: impl Into<PathBuf>) {
+    *BASE_DIRECTORY.write().unwrap() = Some(base.into());
+}
+
+pub fn base_directory() -> PathBuf {
+    BASE_DIRECTORY
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(env::temp_dir)
+}
+
+pub fn served_directory_path(dir: &str) -> PathBuf {
+    base_directory().join(dir)
+}
+
+fn served_file_path(file: &str, dir: &str) -> PathBuf {
+    served_directory_path(dir).join(file)
+}
 
-pub fn configure_directory_to_serve_file(dir: &str) -> String {
-    let path = format!("/tmp/{dir}");
-    fs::create_dir_all(path.clone()).unwrap();
+pub fn configure_directory_to_serve_file(dir: &str) -> PathBuf {
+    let path = served_directory_path(dir);
+    fs::create_dir_all(&path).unwrap();
     path
 }
 
-pub fn fetch_file_buffer(file: &str, dir: &str) -> Result<BufReader<File>, io::Error> {
-    // todo handle rust_file_server as a config passed from main
-    let f = File::open(format!("/tmp/{dir}/{file}"))?;
-    let reader = BufReader::new(f);
-    Ok(reader)
+// Opens a served file, mapping it into memory when it is at least
+// `mmap_threshold` bytes. None always uses a BufReader.
+pub fn open_file_source(
+    file: &str,
+    dir: &str,
+    mmap_threshold: Option<u64>,
+) -> Result<FileSource, io::Error> {
+    validate_file_name(file)?;
+    let f = File::open(served_file_path(file, dir))?;
+    let len = f.metadata()?.len();
+    match mmap_threshold {
+        // empty files can not be mapped
+        Some(threshold) if len >= threshold && len > 0 => {
+            // SAFETY: the mapping is only read from. If another process
+            // truncates the file while it is mapped reads can fault, uploads
+            // from this server replace whole files so that is not expected.
+            let mapping = unsafe { Mmap::map(&f)? };
+            Ok(FileSource::Mapped(Cursor::new(SharedMapping(Arc::new(
+                mapping,
+            )))))
+        }
+        _ => Ok(FileSource::Buffered(BufReader::new(f), len)),
+    }
+}
+
+// Opens a served file as it is on disk, for reading it from an offset while
+// it grows.
+pub fn open_growing_file(file: &str, dir: &str) -> Result<File, io::Error> {
+    validate_file_name(file)?;
+    File::open(served_file_path(file, dir))
+}
+
+// Opens a served file that is encrypted at rest with `key`. Chunks are read
+// whole, so there is no BufReader in between, and never mapped.
+pub fn open_encrypted_file_source(
+    file: &str,
+    dir: &str,
+    key: Arc<AtRestKey>,
+) -> Result<FileSource, io::Error> {
+    validate_file_name(file)?;
+    let f = File::open(served_file_path(file, dir))?;
+    let len = f.metadata()?.len();
+    Ok(FileSource::Decrypted(DecryptingReader::new(f, len, key)?))
+}
+
+// Lowercase hex SHA-256 of everything `reader` yields.
+pub fn sha256_hex(reader: impl Read) -> Result<String, io::Error> {
+    let mut hashing = Hashing::new(reader);
+    io::copy(&mut hashing, &mut io::sink())?;
+    Ok(hashing.sha256_hex())
+}
+
+// Hashes whatever is read or written through it, so a checksum can be had
+// while the bytes are streamed somewhere instead of in a pass of its own.
+pub struct Hashing<T> {
+    inner: T,
+    hasher: Sha256,
+}
+
+impl<T> Hashing<T> {
+    pub fn new(inner: T) -> Hashing<T> {
+        Hashing {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    // Lowercase hex SHA-256 of everything read so far.
+    pub fn sha256_hex(self) -> String {
+        self.hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Names come straight off the wire, anything that could step outside the
+// served directory is refused.
+pub fn validate_file_name(file: &str) -> Result<(), io::Error> {
+    if file.is_empty()
+        || file == "."
+        || file == ".."
+        || file.contains(['/', '\\'])
+        || file.ends_with(PARTIAL_SUFFIX)
+        || file == TRASH_DIR
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid file name {:?}", file),
+        ));
+    }
+    Ok(())
+}
+
+fn partial_file_path(file: &str, dir: &str) -> PathBuf {
+    served_directory_path(dir).join(format!("{file}{PARTIAL_SUFFIX}"))
+}
+
+// Creates `<file>.part` for an upload. Fails if another upload of the same
+// file is still running.
+pub fn create_partial_file(file: &str, dir: &str) -> Result<File, io::Error> {
+    validate_file_name(file)?;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(partial_file_path(file, dir))
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::AlreadyExists => io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("an upload of {:?} is already in progress", file),
+            ),
+            _ => err,
+        })
+}
+
+// An upload being written to `<file>.part`, encrypted when the server
+// encrypts at rest.
+pub enum PartialFile {
+    Plain(File),
+    Encrypted(EncryptingWriter<File>),
+}
+
+impl PartialFile {
+    // Gets every byte to the file, call before commit_partial_file. The file
+    // is handed back for syncing.
+    pub fn finish(self) -> io::Result<File> {
+        match self {
+            PartialFile::Plain(mut file) => file.flush().map(|_| file),
+            PartialFile::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for PartialFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PartialFile::Plain(file) => file.write(buf),
+            PartialFile::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PartialFile::Plain(file) => file.flush(),
+            PartialFile::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+// Moves a finished upload to its real name, replacing the old file in one step.
+pub fn commit_partial_file(file: &str, dir: &str) -> Result<(), io::Error> {
+    fs::rename(partial_file_path(file, dir), served_file_path(file, dir))
+}
+
+// Makes renames and new names in the served directory survive a crash.
+// Windows can not open a directory to sync it, NTFS journals names anyway.
+pub fn sync_directory(dir: &str) -> Result<(), io::Error> {
+    if cfg!(windows) {
+        return Ok(());
+    }
+    File::open(served_directory_path(dir))?.sync_all()
+}
+
+pub fn file_metadata(file: &str, dir: &str) -> Result<fs::Metadata, io::Error> {
+    validate_file_name(file)?;
+    fs::metadata(served_file_path(file, dir))
+}
+
+pub fn delete_file(file: &str, dir: &str) -> Result<(), io::Error> {
+    validate_file_name(file)?;
+    fs::remove_file(served_file_path(file, dir))
+}
+
+pub fn discard_partial_file(file: &str, dir: &str) {
+    let _ = fs::remove_file(partial_file_path(file, dir));
+}
+
+// Deletes `.part` files left behind by uploads a previous run never finished.
+pub fn remove_partial_files(dir: &str) -> Result<usize, io::Error> {
+    let mut removed = 0;
+    for entry in fs::read_dir(served_directory_path(dir))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file()
+            && entry
+                .file_name()
+                .to_string_lossy()
+                .ends_with(PARTIAL_SUFFIX)
+        {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+// Deletes `.part` files anywhere under the served directory that no upload
+// wrote to for `max_idle`, left by clients that went away mid upload.
+pub fn remove_stale_partial_files(dir: &str, max_idle: time::Duration) -> io::Result<usize> {
+    fn sweep(path: &Path, max_idle: time::Duration, removed: &mut usize) -> io::Result<()> {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = fs::symlink_metadata(entry.path())?;
+            if metadata.is_dir() {
+                sweep(&entry.path(), max_idle, removed)?;
+            } else if metadata.is_file()
+                && entry
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with(PARTIAL_SUFFIX)
+                && metadata
+                    .modified()?
+                    .elapsed()
+                    .is_ok_and(|idle| idle > max_idle)
+            {
+                fs::remove_file(entry.path())?;
+                *removed += 1;
+            }
+        }
+        Ok(())
+    }
+    let mut removed = 0;
+    sweep(&served_directory_path(dir), max_idle, &mut removed)?;
+    Ok(removed)
+}
+
+// Removes the empty directories below the served one, deepest first, apart
+// from those in `keep`. The served directory itself always stays.
+pub fn remove_empty_directories(dir: &str, keep: &[PathBuf]) -> io::Result<usize> {
+    // whether `path` is empty once its children were swept
+    fn sweep(path: &Path, keep: &[PathBuf], removed: &mut usize) -> io::Result<bool> {
+        let mut empty = true;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = fs::symlink_metadata(entry.path())?;
+            if !metadata.is_dir() || !sweep(&entry.path(), keep, removed)? {
+                empty = false;
+                continue;
+            }
+            if keep.contains(&entry.path()) {
+                empty = false;
+                continue;
+            }
+            fs::remove_dir(entry.path())?;
+            *removed += 1;
+        }
+        Ok(empty)
+    }
+    let mut removed = 0;
+    sweep(&served_directory_path(dir), keep, &mut removed)?;
+    Ok(removed)
+}
+
+// `*` matches any run of characters, `?` exactly one, everything else itself.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was and how much of the name it has eaten so far
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, eaten)) => {
+                    backtrack = Some((star, eaten + 1));
+                    p = star + 1;
+                    n = eaten + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// The listed files whose name matches `pattern`, see glob_matches.
+pub fn matching_files(
+    mut files: Vec<(String, u64)>,
+    pattern: &str,
+) -> Result<Vec<(String, u64)>, io::Error> {
+    validate_pattern(pattern)?;
+    files.retain(|(name, _)| glob_matches(pattern, name));
+    Ok(files)
+}
+
+// Globs only match names directly under the served directory.
+pub fn validate_pattern(pattern: &str) -> Result<(), io::Error> {
+    if pattern.is_empty() || pattern.contains(['/', '\\']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid pattern {:?}", pattern),
+        ));
+    }
+    Ok(())
+}
+
+// A '/' separated directory below the served one, "." being the served
+// directory itself. Every part is checked like a file name.
+pub fn validate_directory_name(directory: &str) -> Result<(), io::Error> {
+    if directory == "." {
+        return Ok(());
+    }
+    directory
+        .trim_end_matches('/')
+        .split('/')
+        .try_for_each(validate_file_name)
+}
+
+pub fn served_subdirectory_path(dir: &str, directory: &str) -> PathBuf {
+    match directory {
+        "." => served_directory_path(dir),
+        // joined a part at a time, the separator differs between platforms
+        directory => directory
+            .trim_end_matches('/')
+            .split('/')
+            .fold(served_directory_path(dir), |path, part| path.join(part)),
+    }
+}
+
+// Regular files directly under the served directory as (name, size), sorted by name.
+pub fn list_files(dir: &str) -> Result<Vec<(String, u64)>, io::Error> {
+    let mut files = served_files(dir)?
+        .map(|file| file.map(|file| (file.name, file.size)))
+        .collect::<Result<Vec<_>, _>>()?;
+    files.sort();
+    Ok(files)
+}
+
+// One file directly under a served directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServedFile {
+    pub name: String,
+    pub size: u64,
+    pub modified: time::SystemTime,
+}
+
+// The files directly under `dir`, uploads in progress left out, one directory
+// entry at a time and in no particular order.
+pub fn served_files(dir: &str) -> io::Result<impl Iterator<Item = io::Result<ServedFile>>> {
+    let entries = fs::read_dir(served_directory_path(dir))?;
+    Ok(entries.filter_map(|entry| {
+        let served = entry.and_then(|entry| {
+            let metadata = entry.metadata()?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !metadata.is_file() || name.ends_with(PARTIAL_SUFFIX) {
+                return Ok(None);
+            }
+            Ok(Some(ServedFile {
+                name,
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            }))
+        });
+        served.transpose()
+    }))
+}
+
+// The directories directly under `dir` with their modification times, the
+// trash left out, sorted by name.
+pub fn served_subdirectories(dir: &str) -> io::Result<Vec<(String, time::SystemTime)>> {
+    let mut directories = Vec::new();
+    for entry in fs::read_dir(served_directory_path(dir))? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if metadata.is_dir() && name != TRASH_DIR {
+            directories.push((name, metadata.modified()?));
+        }
+    }
+    directories.sort();
+    Ok(directories)
 }
 
 pub fn cleanup_server_file(dir: &str) {
-    let _ = fs::remove_dir_all(format!("/tmp/{dir}"));
+    let _ = fs::remove_dir_all(served_directory_path(dir));
+}
+
+// A served directory that is created with the guard and removed with it, so a
+// panic on the way out does not leave it behind like a missed
+// cleanup_server_file would. A directory that was there before the guard is
+// never removed.
+pub struct ServeDir {
+    name: String,
+    path: PathBuf,
+    persistent: bool,
+}
+
+impl ServeDir {
+    // Creates `dir` under the base directory, see served_directory_path.
+    // If it exists already it is only borrowed and stays on drop, with
+    // whatever is in it.
+    pub fn create(dir: &str) -> Result<ServeDir, io::Error> {
+        let path = served_directory_path(dir);
+        let existed = path.exists();
+        fs::create_dir_all(&path)?;
+        Ok(ServeDir {
+            name: dir.to_owned(),
+            path,
+            persistent: existed,
+        })
+    }
+
+    // Leaves the directory in place on drop, for roots that outlive the server.
+    pub fn persistent(mut self) -> Self {
+        self.persistent = true;
+        self
+    }
+
+    // What the handlers know the directory by, the root_dir of FileServer::new.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ServeDir {
+    fn drop(&mut self) {
+        if !self.persistent {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_serve_dir_is_removed_on_drop() {
+        let dir = ServeDir::create("temp_test_reader_serve_dir").unwrap();
+        fs::write(dir.path().join("file"), b"gone with the guard").unwrap();
+        let path = dir.path().to_path_buf();
+        assert_eq!(served_directory_path("temp_test_reader_serve_dir"), path);
+        drop(dir);
+        assert!(!path.exists());
+
+        let dir = ServeDir::create("temp_test_reader_persistent_dir")
+            .unwrap()
+            .persistent();
+        let path = dir.path().to_path_buf();
+        drop(dir);
+        assert!(path.is_dir());
+        cleanup_server_file("temp_test_reader_persistent_dir");
+    }
+
+    #[test]
+    fn test_serve_dir_leaves_a_directory_it_did_not_create() {
+        let path = configure_directory_to_serve_file("temp_test_reader_existing_dir");
+        fs::write(path.join("file"), b"was here first").unwrap();
+        drop(ServeDir::create("temp_test_reader_existing_dir").unwrap());
+        assert!(path.join("file").is_file());
+        cleanup_server_file("temp_test_reader_existing_dir");
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("*.log", "server.log"));
+        assert!(glob_matches("*.log", ".log"));
+        assert!(!glob_matches("*.log", "server.log.1"));
+        assert!(glob_matches("a?c*", "abc"));
+        assert!(glob_matches("*a*b", "xxaxxab"));
+        assert!(!glob_matches("a?c", "ac"));
+        assert!(glob_matches("*", "anything"));
+    }
+
+    #[test]
+    fn test_open_file_source_maps_above_threshold() {
+        let dir = "temp_test_reader_mmap_root_dir";
+        let path = configure_directory_to_serve_file(dir);
+        fs::write(path.join("small"), b"tiny").unwrap();
+        fs::write(path.join("big"), [7u8; 4096]).unwrap();
+
+        let mut small = open_file_source("small", dir, Some(1024)).unwrap();
+        let mut big = open_file_source("big", dir, Some(1024)).unwrap();
+        assert!(!small.is_mapped());
+        assert!(big.is_mapped());
+        assert!(!open_file_source("big", dir, None).unwrap().is_mapped());
+
+        let mut content = Vec::new();
+        small.read_to_end(&mut content).unwrap();
+        assert_eq!(b"tiny".to_vec(), content);
+        content.clear();
+        big.read_to_end(&mut content).unwrap();
+        assert_eq!(vec![7u8; 4096], content);
+        assert_eq!(4096, big.len());
+
+        cleanup_server_file(dir);
+    }
+
+    #[test]
+    fn test_partial_upload_is_hidden_until_committed() {
+        let dir = "temp_test_reader_partial_root_dir";
+        configure_directory_to_serve_file(dir);
+
+        let mut part = create_partial_file("upload", dir).unwrap();
+        part.write_all(b"half").unwrap();
+        assert!(create_partial_file("upload", dir).is_err());
+        assert!(list_files(dir).unwrap().is_empty());
+        assert!(open_file_source("upload", dir, None).is_err());
+
+        commit_partial_file("upload", dir).unwrap();
+        assert_eq!(vec![("upload".to_owned(), 4)], list_files(dir).unwrap());
+
+        create_partial_file("orphan", dir).unwrap();
+        assert_eq!(1, remove_partial_files(dir).unwrap());
+        assert!(validate_file_name("orphan.part").is_err());
+
+        cleanup_server_file(dir);
+    }
+
+    #[test]
+    fn test_served_paths_are_joined_under_the_temp_dir() {
+        let root = env::temp_dir().join("temp_test_reader_paths_root_dir");
+        assert_eq!(
+            root,
+            served_directory_path("temp_test_reader_paths_root_dir")
+        );
+        assert_eq!(
+            root.join("logs").join("old"),
+            served_subdirectory_path("temp_test_reader_paths_root_dir", "logs/old/")
+        );
+        assert_eq!(
+            root,
+            served_subdirectory_path("temp_test_reader_paths_root_dir", ".")
+        );
+        assert_eq!(
+            root.join(format!("upload{PARTIAL_SUFFIX}")),
+            partial_file_path("upload", "temp_test_reader_paths_root_dir")
+        );
+    }
 }
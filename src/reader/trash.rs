@@ -0,0 +1,170 @@
+// Soft deletes. A trashed file is moved to `<root>/.trash/<unix millis>-<name>`
+// rather than unlinked, and stays there until it is restored or purged. The
+// trash directory is not a valid file name, so it is never listed, served,
+// archived or uploaded over.
+
+use super::{served_directory_path, served_file_path, validate_file_name};
+use std::{fs, io, path::PathBuf, time};
+
+pub const TRASH_DIR: &str = ".trash";
+
+// One file in the trash.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrashedFile {
+    // name inside the trash directory, what restore_trashed_file takes
+    pub name: String,
+    pub original: String,
+    pub deleted_at: time::SystemTime,
+    pub size: u64,
+}
+
+fn trash_path(dir: &str) -> PathBuf {
+    served_directory_path(dir).join(TRASH_DIR)
+}
+
+// Moves `file` to the trash, returns the name it got there.
+pub fn trash_file(file: &str, dir: &str) -> io::Result<String> {
+    validate_file_name(file)?;
+    // nothing to trash, fail like remove_file before creating the directory
+    fs::metadata(served_file_path(file, dir))?;
+    fs::create_dir_all(trash_path(dir))?;
+    let millis = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis());
+    let trashed = format!("{}-{}", millis, file);
+    fs::rename(served_file_path(file, dir), trash_path(dir).join(&trashed))?;
+    Ok(trashed)
+}
+
+// Everything in the trash, oldest first. Entries not named by trash_file are
+// left out.
+pub fn trashed_files(dir: &str) -> io::Result<Vec<TrashedFile>> {
+    let entries = match fs::read_dir(trash_path(dir)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut trashed = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some((millis, original)) = name.split_once('-') else {
+            continue;
+        };
+        let Ok(millis) = millis.parse() else {
+            continue;
+        };
+        if !metadata.is_file() || validate_file_name(original).is_err() {
+            continue;
+        }
+        trashed.push(TrashedFile {
+            original: original.to_owned(),
+            deleted_at: time::UNIX_EPOCH + time::Duration::from_millis(millis),
+            size: metadata.len(),
+            name,
+        });
+    }
+    trashed.sort_by(|a, b| (a.deleted_at, &a.name).cmp(&(b.deleted_at, &b.name)));
+    Ok(trashed)
+}
+
+// Moves `trashed` back under its original name, which it returns. Fails
+// rather than overwrite a file uploaded since.
+pub fn restore_trashed_file(trashed: &str, dir: &str) -> io::Result<String> {
+    let entry = trashed_files(dir)?
+        .into_iter()
+        .find(|entry| entry.name == trashed)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{:?} is not in the trash", trashed),
+            )
+        })?;
+    if served_file_path(&entry.original, dir).exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists again, delete it first", entry.original),
+        ));
+    }
+    fs::rename(
+        trash_path(dir).join(trashed),
+        served_file_path(&entry.original, dir),
+    )?;
+    Ok(entry.original)
+}
+
+// Deletes for good whatever was trashed more than `max_age` ago, then the
+// oldest entries until the rest fit in `max_bytes`. Returns what it deleted.
+pub fn purge_trash(
+    dir: &str,
+    max_age: Option<time::Duration>,
+    max_bytes: Option<u64>,
+) -> io::Result<Vec<TrashedFile>> {
+    let now = time::SystemTime::now();
+    let mut kept = trashed_files(dir)?;
+    let mut total: u64 = kept.iter().map(|entry| entry.size).sum();
+    let mut purged = Vec::new();
+    while let Some(oldest) = kept.first() {
+        let expired = max_age.is_some_and(|max_age| {
+            now.duration_since(oldest.deleted_at)
+                .is_ok_and(|age| age > max_age)
+        });
+        if !expired && max_bytes.is_none_or(|max_bytes| total <= max_bytes) {
+            break;
+        }
+        let oldest = kept.remove(0);
+        fs::remove_file(trash_path(dir).join(&oldest.name))?;
+        total -= oldest.size;
+        purged.push(oldest);
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ServeDir;
+    use super::*;
+
+    #[test]
+    fn test_trash_restore_and_purge() {
+        let dir = ServeDir::create("temp_test_reader_trash_dir").unwrap();
+        let root = "temp_test_reader_trash_dir";
+        fs::write(dir.path().join("a.txt"), b"first").unwrap();
+        fs::write(dir.path().join("b.txt"), b"second").unwrap();
+
+        let trashed = trash_file("a.txt", root).unwrap();
+        assert!(!dir.path().join("a.txt").exists());
+        assert!(trash_file("a.txt", root).is_err());
+        assert!(validate_file_name(TRASH_DIR).is_err());
+
+        // a newer upload under the same name is not overwritten
+        fs::write(dir.path().join("a.txt"), b"newer").unwrap();
+        assert!(restore_trashed_file(&trashed, root).is_err());
+        fs::remove_file(dir.path().join("a.txt")).unwrap();
+        assert_eq!("a.txt", restore_trashed_file(&trashed, root).unwrap());
+        assert_eq!(
+            b"first".to_vec(),
+            fs::read(dir.path().join("a.txt")).unwrap()
+        );
+
+        trash_file("a.txt", root).unwrap();
+        // within the same millisecond the name breaks the tie, a.txt is
+        // still the oldest
+        trash_file("b.txt", root).unwrap();
+        // over the size cap, the oldest goes first
+        let purged = purge_trash(root, None, Some(6)).unwrap();
+        assert_eq!(vec!["a.txt"], originals(&purged));
+        assert!(purge_trash(root, None, Some(6)).unwrap().is_empty());
+        let purged = purge_trash(root, Some(time::Duration::ZERO), None).unwrap();
+        assert_eq!(vec!["b.txt"], originals(&purged));
+        assert!(trashed_files(root).unwrap().is_empty());
+    }
+
+    fn originals(entries: &[TrashedFile]) -> Vec<&str> {
+        entries
+            .iter()
+            .map(|entry| entry.original.as_str())
+            .collect()
+    }
+}
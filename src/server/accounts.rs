@@ -0,0 +1,175 @@
+use super::connection::Connection;
+use super::header::HeaderReader;
+use super::keep_alive::write_closing_error_frame;
+use super::request_id::log_prefix;
+use super::server::{FileServer, FileServerError};
+use crate::protocol::{self, Limits};
+use crate::reader::{configure_directory_to_serve_file, sha256_hex, validate_file_name};
+use serde::Deserialize;
+use std::{collections::HashMap, io};
+
+// One configured user, a `[[users]]` table in the config file. Only the
+// SHA-256 of the password is kept, `printf %s 'secret' | sha256sum` makes one.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UserAccount {
+    pub name: String,
+    pub password_sha256: String,
+}
+
+// Users a client can log in as. Each one is jailed to root_dir/<name>, every
+// handler gets that directory as its root once the user logged in.
+pub struct Accounts {
+    // name -> (password sha256, home served as a root)
+    users: HashMap<String, (String, &'static str)>,
+}
+
+impl Accounts {
+    // Creates the missing home directories under `root_dir`.
+    pub fn new(users: &[UserAccount], root_dir: &str) -> io::Result<Accounts> {
+        let mut accounts = HashMap::new();
+        for user in users {
+            // the name becomes a directory under the root, no paths allowed
+            validate_file_name(&user.name)?;
+            // handlers take the root as &'static str, like the server's own
+            // root the few homes live as long as the program
+            let home: &'static str = Box::leak(format!("{}/{}", root_dir, user.name).into());
+            configure_directory_to_serve_file(home);
+            accounts.insert(
+                user.name.clone(),
+                (user.password_sha256.to_ascii_lowercase(), home),
+            );
+        }
+        Ok(Accounts { users: accounts })
+    }
+
+    // The user names, each one also a directory directly under the root.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.users.keys().map(String::as_str)
+    }
+
+    pub fn homes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.users.values().map(|(_, home)| *home)
+    }
+
+    // The user's home when the password is right. An unknown user and a
+    // wrong password fail the same way so names can not be probed.
+    pub fn log_in(&self, user: &str, password: &str) -> Result<&'static str, FileServerError> {
+        let hash = sha256_hex(password.as_bytes()).map_err(FileServerError::Io)?;
+        match self.users.get(user) {
+            Some((expected, home)) if same_bytes(expected.as_bytes(), hash.as_bytes()) => Ok(home),
+            _ => Err(FileServerError::LoginFailed {
+                user: user.to_owned(),
+            }),
+        }
+    }
+}
+
+// Compares every byte whatever the first difference, so timing says nothing
+// about how much of a hash matched.
+pub(crate) fn same_bytes(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl FileServer {
+    // Request: user=<name>|password=<password>| right after the Login byte,
+    // followed on the same connection by the command to run as that user.
+    // Nothing is sent back on success, a failed login gets an error frame.
+    // Both segments come out of `head`, under the deadline of the rest of the
+    // request head.
+    pub(crate) fn read_login(
+        stream: &dyn Connection,
+        head: &mut HeaderReader,
+        accounts: &Accounts,
+        limits: &Limits,
+    ) -> Result<&'static str, FileServerError> {
+        let user = head.read_segment(limits.max_header_bytes)?;
+        let password = head.read_segment(limits.max_header_bytes)?;
+        let (user, password) = protocol::parse_login(&user, &password)?;
+        let home = accounts.log_in(&user, &password)?;
+        println!(
+            "{}{} logged in as {}",
+            log_prefix(stream),
+            stream.peer(),
+            user
+        );
+        Ok(home)
+    }
+
+    // Sends `error` and closes. Clients send the command right behind the
+    // login, see write_closing_error_frame.
+    pub(crate) fn refuse_login(stream: &dyn Connection, error: FileServerError) {
+        println!(
+            "{}...Refused {}: {}",
+            log_prefix(stream),
+            stream.peer(),
+            error
+        );
+        let _ = write_closing_error_frame(stream, error.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::types::CommandType;
+    use super::*;
+    use crate::protocol::HEADER_TIMEOUT;
+    use crate::reader;
+    use crate::testkit::TestServer;
+    use std::{fs, io::Write, net::TcpStream, time};
+
+    #[test]
+    fn test_log_in_returns_the_users_home() {
+        let root_dir = "temp_test_accounts_root_dir";
+        let accounts = Accounts::new(
+            &[UserAccount {
+                name: "alice".to_owned(),
+                password_sha256: sha256_hex(&b"secret"[..]).unwrap().to_uppercase(),
+            }],
+            root_dir,
+        )
+        .unwrap();
+
+        let home = accounts.log_in("alice", "secret").unwrap();
+        assert_eq!("temp_test_accounts_root_dir/alice", home);
+        assert!(reader::served_directory_path(home).is_dir());
+        assert!(matches!(
+            accounts.log_in("alice", "wrong"),
+            Err(FileServerError::LoginFailed { .. })
+        ));
+        assert!(matches!(
+            accounts.log_in("bob", "secret"),
+            Err(FileServerError::LoginFailed { .. })
+        ));
+        assert!(Accounts::new(
+            &[UserAccount {
+                name: "../escape".to_owned(),
+                password_sha256: String::new(),
+            }],
+            root_dir,
+        )
+        .is_err());
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_a_stalled_login_holds_up_nobody() {
+        let alice = UserAccount {
+            name: "alice".to_owned(),
+            password_sha256: sha256_hex(&b"secret"[..]).unwrap(),
+        };
+        let server = TestServer::start_with(|builder| builder.accounts(&[alice]));
+        let mut stalled = TcpStream::connect(server.addr()).unwrap();
+        stalled.write_all(&[u8::from(CommandType::Login)]).unwrap();
+        stalled.write_all(b"user=ali").unwrap();
+
+        let started = time::Instant::now();
+        fs::write(server.root().join("alice").join("notes"), "mine").unwrap();
+        let mut client = server.client();
+        client.set_login("alice", "secret");
+        assert_eq!(b"mine".to_vec(), client.download("notes").unwrap());
+        assert!(started.elapsed() < HEADER_TIMEOUT);
+        drop(stalled);
+    }
+}
@@ -0,0 +1,492 @@
+use super::accounts::same_bytes;
+use super::limit::ConnectionLimit;
+use super::listener;
+use super::metrics::MetricsRegistry;
+use super::server::{FileServer, FileServerError};
+use super::shutdown::{self, ShutdownHandle};
+use crate::cache::cache_key;
+use crate::reader::{self, sha256_hex};
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+// What the admin commands act on, cloned out of the server when the admin
+// port is opened.
+#[derive(Clone)]
+pub(crate) struct AdminContext {
+    metrics: Arc<MetricsRegistry>,
+    connection_limit: Arc<ConnectionLimit>,
+    shutdown: ShutdownHandle,
+    root_dir: &'static str,
+    // see FileServer::set_admin_password_sha256
+    password_sha256: Option<String>,
+}
+
+impl FileServer {
+    // Starts the management port on address:port. It speaks plain text, one
+    // command per line, so `nc` is enough of a client:
+    //
+    //   connections          request id, peer and seconds open of every client socket
+    //   transfers            id, file, peer, bytes sent and request id of every download
+    //   bandwidth            bytes served per client IP, in total and today
+    //   threads              name, seconds running and request id of every
+    //                        server thread, then how many of them panicked
+    //   kill <id>            cancel a transfer, its client is disconnected
+    //   flush-metrics        start the counters over
+    //   read-only on|off     refuse uploads and deletes, or accept them again
+    //   shutdown             stop accepting and drain, like ShutdownHandle
+    //   drain                turn new connections away, shut down once idle
+    //   audit                follow the audit log, see below
+    //   trash                name, original name, bytes and seconds since
+    //                        deletion of every trashed file, oldest first
+    //   restore <name>       move a trashed file back under its original name
+    //   auth <password>      unlock the metrics commands for this connection
+    //   metrics snapshot <path>
+    //                        write the downloads of every file to path
+    //   metrics reset [path] start the download counts over, writing what
+    //                        they were to path first if given
+    //
+    // Every reply ends with a line that is either `ok` or `error: <reason>`,
+    // list rows come before it, tab separated. `audit` answers `ok` and then
+    // sends every upload and delete as it happens, one audit log line each,
+    // until the connection is closed. Only the metrics commands need `auth`,
+    // and only once an admin password is set, so keep the port on a loopback
+    // or management address all the same.
+    pub fn start_admin(&self, address: &str, port: &str) -> Result<(), FileServerError> {
+        let port: u16 = port.parse().map_err(|_| FileServerError::InvalidPort {
+            port: port.to_owned(),
+        })?;
+        let listener = listener::resolve(address, port)
+            .and_then(|addrs| listener::bind_first(&addrs[..]))
+            .map_err(|source| FileServerError::Bind {
+                address: format!("{}:{}", address, port),
+                source,
+            })?;
+
+        let context = AdminContext {
+            metrics: self.metrics.clone(),
+            connection_limit: self.connection_limit.clone(),
+            shutdown: self.shutdown_handle(),
+            root_dir: self.root_dir,
+            password_sha256: self.admin_password_sha256.clone(),
+        };
+        self.metrics.threads.spawn("admin", move || {
+            for stream in listener.incoming().flatten() {
+                let context = context.clone();
+                let threads = context.metrics.threads.clone();
+                threads.spawn("admin-client", move || serve_admin(stream, &context));
+            }
+        });
+        Ok(())
+    }
+}
+
+fn serve_admin(stream: TcpStream, context: &AdminContext) {
+    let peer = stream
+        .peer_addr()
+        .map_or("unknown peer".to_owned(), |addr| addr.to_string());
+    let mut reply_stream = match stream.try_clone() {
+        Ok(reply_stream) => reply_stream,
+        Err(_) => return,
+    };
+    let mut authenticated = false;
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        // no passwords in the log
+        let logged = match line.split_whitespace().next() {
+            Some("auth") => "auth ...",
+            _ => line.trim(),
+        };
+        println!("Admin command from {}: {}", peer, logged);
+        if line.trim() == "audit" {
+            return follow_audit(reply_stream, context);
+        }
+        if reply_stream
+            .write_all(run_command(&line, context, &mut authenticated).as_bytes())
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+// The connection belongs to the audit log from here on, a failed write
+// means the admin went away.
+fn follow_audit(mut stream: TcpStream, context: &AdminContext) {
+    let entries = context.metrics.audit.follow();
+    if stream.write_all(b"ok\n").is_err() {
+        return;
+    }
+    for entry in entries {
+        if stream.write_all(entry.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+// `authenticated` is the connection's, `auth` sets it.
+pub(crate) fn run_command(line: &str, context: &AdminContext, authenticated: &mut bool) -> String {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or("");
+    let argument = words.next();
+    let path = words.next();
+    let mut reply = String::new();
+
+    let outcome = match (command, argument) {
+        ("connections", None) => {
+            for connection in context.connection_limit.connections() {
+                let _ = writeln!(
+                    reply,
+                    "{}\t{}\t{}",
+                    connection.id,
+                    connection.peer,
+                    connection.open_for.as_secs()
+                );
+            }
+            Ok(())
+        }
+        ("transfers", None) => {
+            for transfer in context.metrics.active_transfers() {
+                let request_id = transfer
+                    .request_id
+                    .map_or("-".to_owned(), |id| id.to_string());
+                let _ = writeln!(
+                    reply,
+                    "{}\t{}\t{}\t{}\t{}",
+                    transfer.id, transfer.file_name, transfer.peer, transfer.bytes_sent, request_id
+                );
+            }
+            Ok(())
+        }
+        ("bandwidth", None) => {
+            for row in context.metrics.bandwidth.usage() {
+                let _ = writeln!(reply, "{}\t{}\t{}", row.ip, row.total, row.today);
+            }
+            Ok(())
+        }
+        ("threads", None) => {
+            for thread in context.metrics.threads.live() {
+                let request_id = thread
+                    .request_id
+                    .map_or("-".to_owned(), |id| id.to_string());
+                let _ = writeln!(
+                    reply,
+                    "{}\t{}\t{}",
+                    thread.name,
+                    thread.running_for.as_secs(),
+                    request_id
+                );
+            }
+            let _ = writeln!(reply, "panicked\t{}", context.metrics.threads.panics());
+            Ok(())
+        }
+        ("kill", Some(id)) => match id.parse() {
+            Ok(id) if context.metrics.cancel_transfer(id) => Ok(()),
+            Ok(id) => Err(format!("no transfer {}", id)),
+            Err(_) => Err(format!("{:?} is not a transfer id", id)),
+        },
+        ("flush-metrics", None) => {
+            context.metrics.flush();
+            Ok(())
+        }
+        ("read-only", Some("on")) => {
+            context.metrics.set_read_only(true);
+            Ok(())
+        }
+        ("read-only", Some("off")) => {
+            context.metrics.set_read_only(false);
+            Ok(())
+        }
+        ("shutdown", None) => {
+            context.shutdown.shutdown();
+            Ok(())
+        }
+        ("drain", None) => {
+            if shutdown::start_draining(&context.metrics, &context.shutdown) {
+                Ok(())
+            } else {
+                Err("already draining".to_owned())
+            }
+        }
+        ("trash", None) => reader::trashed_files(context.root_dir)
+            .map(|trashed| {
+                for entry in trashed {
+                    let age = entry.deleted_at.elapsed().unwrap_or_default();
+                    let _ = writeln!(
+                        reply,
+                        "{}\t{}\t{}\t{}",
+                        entry.name,
+                        entry.original,
+                        entry.size,
+                        age.as_secs()
+                    );
+                }
+            })
+            .map_err(|err| err.to_string()),
+        ("restore", Some(trashed)) => restore(trashed, context),
+        ("auth", Some(password)) => {
+            *authenticated = check_password(password, context);
+            if *authenticated {
+                Ok(())
+            } else {
+                Err("wrong password".to_owned())
+            }
+        }
+        ("metrics", Some("snapshot")) if path.is_some() => {
+            check_authenticated(*authenticated, context)
+                .and_then(|_| write_file_stat(&context.metrics.file_stat_snapshot(), path))
+        }
+        ("metrics", Some("reset")) => check_authenticated(*authenticated, context)
+            .and_then(|_| write_file_stat(&context.metrics.take_file_stat(), path)),
+        _ => Err(format!("unknown command {:?}", line.trim())),
+    };
+
+    match outcome {
+        Ok(()) => reply.push_str("ok\n"),
+        Err(reason) => {
+            let _ = writeln!(reply, "error: {}", reason);
+        }
+    }
+    reply
+}
+
+// A wrong password also takes back an earlier `auth`, and with no admin
+// password set nothing is right.
+fn check_password(password: &str, context: &AdminContext) -> bool {
+    let Some(expected) = &context.password_sha256 else {
+        return false;
+    };
+    sha256_hex(password.as_bytes())
+        .is_ok_and(|hash| same_bytes(expected.as_bytes(), hash.as_bytes()))
+}
+
+fn check_authenticated(authenticated: bool, context: &AdminContext) -> Result<(), String> {
+    match (&context.password_sha256, authenticated) {
+        (None, _) => Err("no admin password is set".to_owned()),
+        (Some(_), false) => Err("auth first".to_owned()),
+        (Some(_), true) => Ok(()),
+    }
+}
+
+// One `file\tdownloads` line per file, most downloaded first, written to a
+// temp file renamed over `path` so a reader never sees half a snapshot.
+// Nothing is written without a path.
+fn write_file_stat(stats: &HashMap<String, i64>, path: Option<&str>) -> Result<(), String> {
+    let Some(path) = path.map(Path::new) else {
+        return Ok(());
+    };
+    let mut rows: Vec<_> = stats.iter().collect();
+    rows.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then(a_name.cmp(b_name)));
+    let mut lines = String::new();
+    for (file_name, downloads) in rows {
+        let _ = writeln!(lines, "{}\t{}", file_name, downloads);
+    }
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, lines)
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|err| format!("writing {}: {}", path.display(), err))
+}
+
+// Puts a trashed file back, under the write lock an upload of its name takes
+// so no download sees it half moved.
+fn restore(trashed: &str, context: &AdminContext) -> Result<(), String> {
+    let original = trashed
+        .split_once('-')
+        .map_or(trashed, |(_, original)| original);
+    let key = cache_key(context.root_dir, original);
+    let _writing = context.metrics.file_locks.write(&key);
+    reader::restore_trashed_file(trashed, context.root_dir).map_err(|err| err.to_string())?;
+    context.metrics.hot_files.invalidate(&key);
+    context.metrics.shared_mappings.invalidate(&key);
+    context.metrics.etags.invalidate(&key);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::super::limit::OverflowPolicy;
+    use super::super::pool::WorkerPool;
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    fn context() -> AdminContext {
+        context_serving("unused")
+    }
+
+    fn context_serving(root_dir: &'static str) -> AdminContext {
+        AdminContext {
+            metrics: Arc::new(MetricsRegistry::new()),
+            connection_limit: Arc::new(ConnectionLimit::new(None, OverflowPolicy::Queue)),
+            // nothing listens there, the wake up poke just fails
+            shutdown: ShutdownHandle::new(
+                Arc::new(AtomicBool::new(false)),
+                "127.0.0.1:1".parse().unwrap(),
+            ),
+            root_dir,
+            password_sha256: None,
+        }
+    }
+
+    #[test]
+    fn test_admin_commands() {
+        let context = context();
+        let (client, _server) = duplex();
+        let counted = context
+            .connection_limit
+            .admit(Box::new(client))
+            .ok()
+            .unwrap();
+        let request_id = counted.request_id().unwrap();
+        assert_eq!(
+            format!("{}\tin-memory peer\t0\nok\n", request_id),
+            run_command("connections", &context, &mut false)
+        );
+
+        let transfer = context
+            .metrics
+            .begin_transfer("big.iso", "10.0.0.1:4000", Some(request_id));
+        transfer.record_bytes_sent(7);
+        assert_eq!(
+            format!("0\tbig.iso\t10.0.0.1:4000\t7\t{}\nok\n", request_id),
+            run_command("transfers", &context, &mut false)
+        );
+        assert_eq!(
+            "10.0.0.1\t7\t7\nok\n",
+            run_command("bandwidth", &context, &mut false)
+        );
+        assert_eq!(
+            "panicked\t0\nok\n",
+            run_command("threads", &context, &mut false)
+        );
+        assert_eq!("ok\n", run_command("kill 0", &context, &mut false));
+        assert!(transfer.is_cancelled());
+        assert_eq!(
+            "error: no transfer 9\n",
+            run_command("kill 9", &context, &mut false)
+        );
+        drop(transfer);
+
+        context.metrics.record_download("big.iso".to_owned());
+        assert_eq!("ok\n", run_command("flush-metrics", &context, &mut false));
+        assert_eq!(0, context.metrics.download_count("big.iso"));
+        assert!(context
+            .metrics
+            .snapshot(&WorkerPool::new(1))
+            .bytes_per_file
+            .is_empty());
+
+        assert_eq!("ok\n", run_command("read-only on", &context, &mut false));
+        assert!(context.metrics.is_read_only());
+        assert_eq!(
+            "ok\n",
+            run_command(" read-only  off ", &context, &mut false)
+        );
+        assert!(!context.metrics.is_read_only());
+
+        assert!(run_command("read-only maybe", &context, &mut false).starts_with("error: "));
+        assert_eq!("ok\n", run_command("drain", &context, &mut false));
+        assert!(context.metrics.is_draining());
+        assert_eq!(
+            "error: already draining\n",
+            run_command("drain", &context, &mut false)
+        );
+        assert_eq!("ok\n", run_command("shutdown", &context, &mut false));
+        assert!(context.shutdown.is_requested());
+    }
+
+    #[test]
+    fn test_admin_trash_and_restore() {
+        let root_dir = "temp_test_admin_trash_root_dir";
+        let dir = reader::ServeDir::create(root_dir).unwrap();
+        let context = context_serving(root_dir);
+        std::fs::write(dir.path().join("notes.txt"), b"kept").unwrap();
+        let trashed = reader::trash_file("notes.txt", root_dir).unwrap();
+
+        assert_eq!(
+            format!("{}\tnotes.txt\t4\t0\nok\n", trashed),
+            run_command("trash", &context, &mut false)
+        );
+        assert_eq!(
+            "ok\n",
+            run_command(&format!("restore {}", trashed), &context, &mut false)
+        );
+        assert_eq!(
+            b"kept".to_vec(),
+            std::fs::read(dir.path().join("notes.txt")).unwrap()
+        );
+        assert_eq!("ok\n", run_command("trash", &context, &mut false));
+        assert!(
+            run_command(&format!("restore {}", trashed), &context, &mut false)
+                .starts_with("error: ")
+        );
+    }
+
+    #[test]
+    fn test_admin_metrics_snapshot_and_reset() {
+        let path = std::env::temp_dir().join("fileserver_test_admin_metrics.tsv");
+        let command = |operation: &str| format!("metrics {} {}", operation, path.display());
+        let mut context = context();
+        let mut authenticated = false;
+        assert_eq!(
+            "error: no admin password is set\n",
+            run_command(&command("snapshot"), &context, &mut authenticated)
+        );
+
+        context.password_sha256 = Some(sha256_hex(&b"hunter2"[..]).unwrap());
+        context.metrics.record_download("a.txt".to_owned());
+        context.metrics.record_download("b.txt".to_owned());
+        context.metrics.record_download("b.txt".to_owned());
+        assert_eq!(
+            "error: auth first\n",
+            run_command("metrics reset", &context, &mut authenticated)
+        );
+        assert_eq!(
+            "error: wrong password\n",
+            run_command("auth hunter3", &context, &mut authenticated)
+        );
+        assert_eq!(
+            "ok\n",
+            run_command("auth hunter2", &context, &mut authenticated)
+        );
+        assert!(
+            run_command("metrics snapshot", &context, &mut authenticated).starts_with("error: ")
+        );
+
+        assert_eq!(
+            "ok\n",
+            run_command(&command("snapshot"), &context, &mut authenticated)
+        );
+        assert_eq!(
+            "b.txt\t2\na.txt\t1\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert_eq!(2, context.metrics.download_count("b.txt"));
+
+        assert_eq!(
+            "ok\n",
+            run_command(&command("reset"), &context, &mut authenticated)
+        );
+        assert_eq!(
+            "b.txt\t2\na.txt\t1\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+        assert!(context.metrics.file_stat_snapshot().is_empty());
+        assert_eq!(
+            "ok\n",
+            run_command("metrics reset", &context, &mut authenticated)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use super::connection::Connection;
+use super::keep_alive::{write_error_frame, write_frame_header, FRAME_OK};
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use super::types::CommandType;
+use crate::archive::{archive_size, collect_entries, TarEntry, END_OF_ARCHIVE};
+use crate::reader::{
+    served_subdirectory_path, validate_directory_name, AtRestKey, DecryptingReader,
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, ErrorKind, Write},
+    sync::Arc,
+};
+
+impl FileServer {
+    // Request: filename=<directory>|, "." for everything that is served
+    // Reply: an OK frame holding a tar of the directory, its entries named
+    // `<directory>/...` (or bare names for ".").
+    pub fn handle_archive(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_archive(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_archive(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let directory = match Self::read_file_request(stream, &metrics_registry.limits()) {
+            Ok(directory) => directory,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        if let Err(err) = validate_directory_name(&directory) {
+            return write_error_frame(stream, err.to_string());
+        }
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::Archive, &directory) {
+            return write_error_frame(stream, err.to_string());
+        }
+
+        let prefix = match directory.trim_end_matches('/') {
+            "." => "",
+            directory => directory,
+        };
+        let embedded = metrics_registry.embedded_archive();
+        // every header is built before the first byte goes out, a name tar
+        // can not hold still gets a clean error frame
+        let collected = match &embedded {
+            // the embedded archive only has files at the top, no directories
+            Some(embedded) if prefix.is_empty() => embedded
+                .files()
+                .map(|file| TarEntry::for_served_file(&file))
+                .collect(),
+            Some(_) => Err(io::Error::new(ErrorKind::NotFound, "no such directory")),
+            None => collect_entries(&served_subdirectory_path(root_dir, &directory), prefix),
+        };
+        let mut entries = match collected {
+            Ok(entries) => entries,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        entries.retain(|entry| {
+            entry.is_dir
+                || metrics_registry
+                    .authorize(stream, CommandType::Archive, &entry.name)
+                    .is_ok()
+        });
+        // files out of the embedded archive are never encrypted
+        let key = metrics_registry
+            .at_rest_key()
+            .filter(|_| embedded.is_none());
+        if key.is_some() {
+            for entry in entries.iter_mut().filter(|entry| !entry.is_dir) {
+                let size = metrics_registry.served_len(entry.size);
+                if let Err(err) = entry.resize(size) {
+                    return write_error_frame(stream, err.to_string());
+                }
+            }
+        }
+
+        write_frame_header(stream, FRAME_OK, archive_size(&entries))?;
+        for entry in &entries {
+            stream.write_all(&entry.header)?;
+            if entry.is_dir {
+                continue;
+            }
+            // the frame length promised exactly entry.size bytes, anything
+            // else breaks the archive and the framing with it
+            let sent = if embedded.is_some() {
+                let mut file_reader =
+                    Self::open_stored_file(&entry.name, root_dir, metrics_registry)?;
+                Self::stream_file(&mut file_reader, stream, metrics_registry, &entry.name)?
+            } else {
+                Self::stream_disk_entry(entry, stream, key.as_ref(), metrics_registry)?
+            };
+            if sent != entry.size {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "file size changed mid transfer",
+                ));
+            }
+            stream.write_all(&vec![0; entry.padding()])?;
+        }
+        stream.write_all(&END_OF_ARCHIVE)
+    }
+
+    // Sends the file of `entry` from disk, decrypted with `key` when the
+    // server encrypts at rest.
+    fn stream_disk_entry(
+        entry: &TarEntry,
+        stream: &dyn Connection,
+        key: Option<&Arc<AtRestKey>>,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<u64> {
+        let file = File::open(&entry.disk_path)?;
+        match key {
+            Some(key) => {
+                let disk_len = file.metadata()?.len();
+                let mut file_reader = DecryptingReader::new(file, disk_len, key.clone())?;
+                Self::stream_file(&mut file_reader, stream, metrics_registry, &entry.name)
+            }
+            None => {
+                let mut file_reader = BufReader::new(file);
+                Self::stream_file(&mut file_reader, stream, metrics_registry, &entry.name)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::*;
+    use crate::reader::{cleanup_server_file, configure_directory_to_serve_file};
+    use std::{fs, io::Read};
+
+    #[test]
+    fn test_archive_of_a_subdirectory() {
+        let root_dir = "temp_test_archive_root_dir";
+        let path = configure_directory_to_serve_file(root_dir);
+        fs::create_dir_all(path.join("logs").join("old")).unwrap();
+        fs::write(path.join("logs").join("a.log"), "first").unwrap();
+        fs::write(path.join("logs").join("old").join("b.log"), vec![7u8; 600]).unwrap();
+        fs::write(path.join("logs").join("c.log.part"), "half").unwrap();
+        fs::write(path.join("outside"), "not included").unwrap();
+
+        let (client, server) = duplex();
+        (&client as &dyn Connection)
+            .write_all(b"filename=logs|")
+            .unwrap();
+        FileServer::handle_archive(&server, root_dir, Arc::new(MetricsRegistry::new()));
+        drop(server);
+        let mut reply = Vec::new();
+        (&client as &dyn Connection)
+            .read_to_end(&mut reply)
+            .unwrap();
+
+        assert_eq!(FRAME_OK, reply[0]);
+        let tar = &reply[9..];
+        assert_eq!(
+            u64::from_be_bytes(reply[1..9].try_into().unwrap()),
+            tar.len() as u64
+        );
+
+        // walk the headers: name, then skip the data rounded up to a block
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while tar[offset] != 0 {
+            let header = &tar[offset..offset + 512];
+            let name_end = header.iter().position(|b| *b == 0).unwrap();
+            names.push(String::from_utf8_lossy(&header[..name_end]).to_string());
+            let size = u64::from_str_radix(std::str::from_utf8(&header[124..135]).unwrap(), 8)
+                .unwrap() as usize;
+            if names.last().unwrap() == "logs/a.log" {
+                assert_eq!(b"first", &tar[offset + 512..offset + 517]);
+            }
+            offset += 512 + size.div_ceil(512) * 512;
+        }
+        assert_eq!(
+            vec!["logs/", "logs/a.log", "logs/old/", "logs/old/b.log"],
+            names
+        );
+        assert_eq!(tar.len(), offset + 1024);
+
+        cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_archive_refuses_to_leave_the_root() {
+        let (client, server) = duplex();
+        (&client as &dyn Connection)
+            .write_all(b"filename=../etc|")
+            .unwrap();
+        FileServer::handle_archive(&server, "unused", Arc::new(MetricsRegistry::new()));
+        drop(server);
+        let mut reply = Vec::new();
+        (&client as &dyn Connection)
+            .read_to_end(&mut reply)
+            .unwrap();
+        assert_eq!(super::super::keep_alive::FRAME_ERROR, reply[0]);
+    }
+}
@@ -0,0 +1,136 @@
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    time,
+};
+
+// One operation that changed the served files.
+pub struct AuditEntry<'a> {
+    // client address
+    pub peer: &'a str,
+    // the directory the operation ran against, a user's home for logged in clients
+    pub root_dir: &'a str,
+    // upload, delete, webdav-put or webdav-delete
+    pub operation: &'static str,
+    pub file_name: &'a str,
+    // bytes written, 0 for deletes and refused operations
+    pub bytes: u64,
+    pub result: Result<(), String>,
+}
+
+// A line per entry: unix seconds, peer, root, operation, file, bytes and `ok`
+// or `error: <reason>`, tab separated like the admin port's rows.
+impl fmt::Display for AuditEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t",
+            now, self.peer, self.root_dir, self.operation, self.file_name, self.bytes
+        )?;
+        match &self.result {
+            Ok(()) => write!(f, "ok"),
+            // a reason spanning lines would break the one line per entry rule
+            Err(reason) => write!(f, "error: {}", reason.replace(['\n', '\t'], " ")),
+        }
+    }
+}
+
+// Record of every upload and delete, kept apart from the console log. Lines
+// are only ever appended to the file, and handed to whoever follows the log
+// from the admin port. Without a file entries still reach the followers.
+#[derive(Default)]
+pub struct AuditLog {
+    file: Mutex<Option<File>>,
+    followers: Mutex<Vec<Sender<String>>>,
+}
+
+impl AuditLog {
+    // Appends to `path` from now on, creating it if needed.
+    pub fn open(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        *self.file.lock().unwrap() = Some(file);
+        Ok(())
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        let line = format!("{}\n", entry);
+        if let Some(file) = self.file.lock().unwrap().as_mut() {
+            // the operation already happened, a full disk only costs its record
+            if let Err(err) = file.write_all(line.as_bytes()) {
+                println!("...Error writing audit log:{err}");
+            }
+        }
+        self.followers
+            .lock()
+            .unwrap()
+            .retain(|follower| follower.send(line.clone()).is_ok());
+    }
+
+    // Entries recorded from now on, a line each. Dropping the receiver stops them.
+    pub fn follow(&self) -> Receiver<String> {
+        let (sender, receiver) = mpsc::channel();
+        self.followers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_entries_are_appended_and_followed() {
+        let path = std::env::temp_dir().join("fileserver_test_audit.log");
+        fs::write(&path, "earlier run\n").unwrap();
+        let audit = AuditLog::default();
+        audit.open(&path).unwrap();
+        let follower = audit.follow();
+
+        audit.record(AuditEntry {
+            peer: "10.0.0.1:4000",
+            root_dir: "files",
+            operation: "upload",
+            file_name: "a.txt",
+            bytes: 5,
+            result: Ok(()),
+        });
+        audit.record(AuditEntry {
+            peer: "10.0.0.1:4000",
+            root_dir: "files",
+            operation: "webdav-delete",
+            file_name: "b.txt",
+            bytes: 0,
+            result: Err("File not found:\nb.txt".to_owned()),
+        });
+
+        let written = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(3, lines.len());
+        assert_eq!("earlier run", lines[0]);
+        assert!(lines[1].ends_with("\t10.0.0.1:4000\tfiles\tupload\ta.txt\t5\tok"));
+        assert!(lines[2].ends_with("\twebdav-delete\tb.txt\t0\terror: File not found: b.txt"));
+        assert_eq!(format!("{}\n", lines[1]), follower.recv().unwrap());
+
+        drop(follower);
+        audit.record(AuditEntry {
+            peer: "10.0.0.1:4000",
+            root_dir: "files",
+            operation: "upload",
+            file_name: "c.txt",
+            bytes: 1,
+            result: Ok(()),
+        });
+        assert!(audit.followers.lock().unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
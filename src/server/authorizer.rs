@@ -0,0 +1,21 @@
+use super::types::CommandType;
+use std::{net::SocketAddr, sync::Arc};
+
+// What an Authorizer gets asked about.
+pub struct AuthRequest<'a> {
+    // None when the client is not on TCP, a Unix socket say
+    pub peer: Option<SocketAddr>,
+    // the client certificate's subject on mutually authenticated TLS, see
+    // Connection::peer_identity
+    pub identity: Option<&'a str>,
+    pub command: CommandType,
+    // the file the command is about, the directory for Archive and the
+    // pattern for Watch. BatchDownload, List and Sync ask once per file and
+    // leave out the ones that are denied.
+    pub file_name: &'a str,
+}
+
+// Decides per request whether it may go ahead, on top of logins and read-only
+// mode. Policies like "only 10.0.0.0/8 may upload" or "deny *.secret" fit in
+// one closure. Runs on the worker serving the request, keep it quick.
+pub type Authorizer = Arc<dyn Fn(&AuthRequest) -> bool + Send + Sync>;
@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time,
+};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Bytes served per client IP, since the start (or the last flush) and since
+// midnight UTC, the latter checked against an optional daily cap. Transfers
+// hold on to their client's IpUsage so chunks are counted without the map
+// lock.
+#[derive(Default)]
+pub struct IpBandwidth {
+    clients: RwLock<HashMap<IpAddr, Arc<IpUsage>>>,
+    // bytes per IP per day, 0 is uncapped
+    daily_cap: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct IpUsage {
+    total: AtomicU64,
+    today: AtomicU64,
+    // days since the epoch `today` counts for
+    day: AtomicU64,
+}
+
+// One row of IpBandwidth::usage.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpBytes {
+    pub ip: IpAddr,
+    pub total: u64,
+    pub today: u64,
+}
+
+fn current_day() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / SECONDS_PER_DAY)
+}
+
+impl IpUsage {
+    pub fn record(&self, bytes: u64) {
+        self.roll_over(current_day());
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+        self.today.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn today(&self) -> u64 {
+        self.roll_over(current_day());
+        self.today.load(Ordering::Relaxed)
+    }
+
+    // starts `today` over once the day has changed, whoever swaps the day in
+    // does the reset
+    fn roll_over(&self, day: u64) {
+        let seen = self.day.load(Ordering::Relaxed);
+        if seen != day
+            && self
+                .day
+                .compare_exchange(seen, day, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            self.today.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl IpBandwidth {
+    // The counters of `ip`, added on first use.
+    pub fn client(&self, ip: IpAddr) -> Arc<IpUsage> {
+        if let Some(usage) = self.clients.read().unwrap().get(&ip) {
+            return usage.clone();
+        }
+        self.clients
+            .write()
+            .unwrap()
+            .entry(ip)
+            .or_insert_with(|| {
+                Arc::new(IpUsage {
+                    day: AtomicU64::new(current_day()),
+                    ..IpUsage::default()
+                })
+            })
+            .clone()
+    }
+
+    // None lifts the cap.
+    pub fn set_daily_cap(&self, bytes: Option<u64>) {
+        self.daily_cap.store(bytes.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub fn daily_cap(&self) -> Option<u64> {
+        match self.daily_cap.load(Ordering::Relaxed) {
+            0 => None,
+            cap => Some(cap),
+        }
+    }
+
+    // Whether `usage` has had its share for today.
+    pub fn exhausted(&self, usage: &IpUsage) -> bool {
+        self.daily_cap().is_some_and(|cap| usage.today() >= cap)
+    }
+
+    // Whether `ip` has had its share for today, false for unknown clients.
+    pub fn is_capped(&self, ip: IpAddr) -> bool {
+        self.daily_cap().is_some()
+            && self
+                .clients
+                .read()
+                .unwrap()
+                .get(&ip)
+                .is_some_and(|usage| self.exhausted(usage))
+    }
+
+    // How long until today's counts start over, at midnight UTC.
+    pub fn until_reset(&self) -> time::Duration {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        time::Duration::from_secs(SECONDS_PER_DAY - now % SECONDS_PER_DAY)
+    }
+
+    // Every client served so far, by IP.
+    pub fn usage(&self) -> Vec<IpBytes> {
+        let mut usage: Vec<IpBytes> = self
+            .clients
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(ip, usage)| IpBytes {
+                ip: *ip,
+                total: usage.total.load(Ordering::Relaxed),
+                today: usage.today(),
+            })
+            .collect();
+        usage.sort_by_key(|row| row.ip);
+        usage
+    }
+
+    // Forgets the totals, today's counts stay so a flush does not lift caps.
+    pub fn clear_totals(&self) {
+        for usage in self.clients.read().unwrap().values() {
+            usage.total.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_daily_cap_per_ip() {
+        let bandwidth = IpBandwidth::default();
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+        bandwidth.client(first).record(600);
+        bandwidth.client(second).record(100);
+        assert!(!bandwidth.is_capped(first));
+
+        bandwidth.set_daily_cap(Some(500));
+        assert!(bandwidth.is_capped(first));
+        assert!(!bandwidth.is_capped(second));
+        assert!(!bandwidth.is_capped("10.0.0.3".parse().unwrap()));
+
+        // a new day starts every client over
+        let usage = bandwidth.client(first);
+        usage.day.fetch_sub(1, Ordering::Relaxed);
+        assert!(!bandwidth.is_capped(first));
+
+        bandwidth.clear_totals();
+        usage.record(5);
+        assert_eq!(
+            vec![
+                IpBytes {
+                    ip: first,
+                    total: 5,
+                    today: 5
+                },
+                IpBytes {
+                    ip: second,
+                    total: 0,
+                    today: 100
+                },
+            ],
+            bandwidth.usage()
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use super::connection::Connection;
+use super::keep_alive::{write_error_frame, write_frame_header, FRAME_END, FRAME_OK};
+use super::metrics::MetricsRegistry;
+use super::request_id::log_prefix;
+use super::server::FileServer;
+use super::types::CommandType;
+use crate::reader::matching_files;
+use std::{
+    io::{self, ErrorKind, Write},
+    sync::Arc,
+};
+
+impl FileServer {
+    // Request: filename=<glob>|, e.g. filename=*.log|
+    // Reply: one OK frame per matching file holding
+    // [name length: u16][name][file bytes], then an empty END frame.
+    // An error frame instead of END means the batch was cut short.
+    pub fn handle_batch_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_batch_download(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_batch_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let pattern = match Self::read_file_request(stream, &metrics_registry.limits()) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        let files = match Self::list_stored_files(root_dir, metrics_registry)
+            .and_then(|files| matching_files(files, &pattern))
+        {
+            Ok(files) => files,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+
+        for (file_name, _) in files {
+            if metrics_registry
+                .authorize(stream, CommandType::BatchDownload, &file_name)
+                .is_err()
+            {
+                continue;
+            }
+            Self::send_batch_entry(stream, &file_name, root_dir, metrics_registry)?;
+        }
+        write_frame_header(stream, FRAME_END, 0)
+    }
+
+    // One file of a multi-file reply as an OK frame holding
+    // [name length: u16][name][file bytes]. A file that can not be opened is
+    // left out, the rest of the reply is still good.
+    pub(crate) fn send_batch_entry(
+        mut stream: &dyn Connection,
+        file_name: &str,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let mut file_reader = match Self::open_served_file(file_name, root_dir, metrics_registry) {
+            Ok(file_reader) => file_reader,
+            Err(err) => {
+                // deleted since we listed it
+                println!(
+                    "{}...Skipping {} in batch reply: {}",
+                    log_prefix(stream),
+                    file_name,
+                    err
+                );
+                return Ok(());
+            }
+        };
+        let length = file_reader.len();
+        metrics_registry.record_download(file_name.to_owned());
+
+        write_frame_header(stream, FRAME_OK, 2 + file_name.len() as u64 + length)?;
+        stream.write_all(&(file_name.len() as u16).to_be_bytes())?;
+        stream.write_all(file_name.as_bytes())?;
+        let sent = Self::stream_file(&mut file_reader, stream, metrics_registry, file_name)?;
+        if sent != length {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "file size changed mid transfer",
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,564 @@
+use super::accounts::UserAccount;
+use super::authorizer::Authorizer;
+use super::clock::Clock;
+use super::janitor::TrashPolicy;
+use super::limit::OverflowPolicy;
+use super::listener::ListenerPolicy;
+use super::metrics::{ProgressHook, UploadHook, UploadMeta};
+use super::observer::Observer;
+use super::pool::BusyPolicy;
+use super::ring::HashRing;
+use super::router::{Middleware, Router};
+use super::server::{FileServer, FileServerError, Handler};
+#[cfg(unix)]
+use super::stats_sink::UnixSocketSink;
+use super::stats_sink::{FileSink, StatsSink, StdoutSink};
+use super::types::CommandType;
+use crate::archive::embedded::EmbeddedArchive;
+use crate::config::ServerConfig;
+use crate::protocol::stats::StatsFormat;
+use crate::protocol::Limits;
+use std::{path::Path, sync::Arc, time};
+
+// Collects everything needed to start a FileServer so callers do not have to
+// chain `new` with a handful of setters, and so a ServerConfig loaded from disk
+// can be turned into a server in one call.
+pub struct FileServerBuilder {
+    address: String,
+    port: String,
+    thread_count: i32,
+    acceptor_threads: usize,
+    root_dir: String,
+    keep_alive_timeout: time::Duration,
+    drain_timeout: time::Duration,
+    mmap_threshold: Option<u64>,
+    rate_limits: (Option<u64>, Option<u64>),
+    daily_ip_cap: Option<u64>,
+    handler_timeouts: Vec<(CommandType, time::Duration)>,
+    hot_cache: (u64, u64),
+    reserved_workers: Vec<(CommandType, i32)>,
+    extra_listeners: Vec<(String, ListenerPolicy)>,
+    serve_patterns: (Vec<String>, Vec<String>),
+    busy_policy: BusyPolicy,
+    small_transfer_bytes: Option<u64>,
+    max_connections: Option<usize>,
+    connection_overflow: OverflowPolicy,
+    max_stats_subscribers: Option<usize>,
+    stats_heartbeat_timeout: Option<time::Duration>,
+    stats_interval: time::Duration,
+    stats_sinks: Vec<Box<dyn StatsSink>>,
+    top_files: usize,
+    thread_name_prefix: String,
+    users: Vec<UserAccount>,
+    admin_password_sha256: Option<String>,
+    audit_log: Option<String>,
+    cluster_peers: Vec<String>,
+    ring: HashRing,
+    replicas: Vec<String>,
+    replication_queue: Option<String>,
+    encryption_key: Option<String>,
+    // an archive handed over, else one to read from the path at build
+    embedded_archive: Option<EmbeddedArchive>,
+    embedded_archive_path: Option<String>,
+    durable_uploads: bool,
+    serve_precompressed: bool,
+    limits: Limits,
+    trash: Option<TrashPolicy>,
+    janitor_interval: Option<time::Duration>,
+    handlers: Vec<(CommandType, Handler)>,
+    router: Option<Router>,
+    middleware: Vec<Middleware>,
+    progress_hook: Option<ProgressHook>,
+    observers: Vec<Observer>,
+    upload_hooks: Vec<UploadHook>,
+    authorizer: Option<Authorizer>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl Default for FileServerBuilder {
+    fn default() -> Self {
+        Self::from_config(&ServerConfig::default())
+    }
+}
+
+impl FileServerBuilder {
+    pub fn new() -> FileServerBuilder {
+        FileServerBuilder::default()
+    }
+
+    pub fn from_config(config: &ServerConfig) -> FileServerBuilder {
+        FileServerBuilder {
+            address: config.address.clone(),
+            port: config.port.to_string(),
+            thread_count: config.thread_count,
+            acceptor_threads: config.acceptor_threads,
+            root_dir: config.root_dir.clone(),
+            keep_alive_timeout: time::Duration::from_secs(config.keep_alive_timeout_secs),
+            drain_timeout: time::Duration::from_secs(config.drain_timeout_secs),
+            mmap_threshold: None,
+            rate_limits: (
+                config.max_transfer_bytes_per_sec,
+                config.max_total_bytes_per_sec,
+            ),
+            daily_ip_cap: config.daily_ip_cap_bytes,
+            // load and from_toml_str already rejected unknown commands
+            handler_timeouts: config.handler_timeouts().unwrap_or_default(),
+            hot_cache: (0, 0),
+            reserved_workers: Vec::new(),
+            extra_listeners: config
+                .extra_listeners
+                .iter()
+                .map(|address| (address.clone(), ListenerPolicy::default()))
+                .chain(
+                    config
+                        .read_only_listeners
+                        .iter()
+                        .map(|address| (address.clone(), ListenerPolicy::read_only())),
+                )
+                .collect(),
+            serve_patterns: (config.allow_patterns.clone(), config.deny_patterns.clone()),
+            // load and from_toml_str already rejected unknown policies
+            busy_policy: config.busy_policy().unwrap_or_default(),
+            small_transfer_bytes: config.small_transfer_bytes,
+            max_connections: config.max_connections,
+            connection_overflow: config.connection_overflow().unwrap_or_default(),
+            max_stats_subscribers: config.max_stats_subscribers,
+            stats_heartbeat_timeout: config
+                .stats_heartbeat_timeout_secs
+                .map(time::Duration::from_secs),
+            stats_interval: time::Duration::from_millis(config.stats_interval_ms),
+            stats_sinks: Self::configured_stats_sinks(config),
+            top_files: config.top_files,
+            thread_name_prefix: config.thread_name_prefix.clone(),
+            users: config.users.clone(),
+            admin_password_sha256: config.admin_password_sha256.clone(),
+            audit_log: config.audit_log.clone(),
+            cluster_peers: config.cluster_peers.clone(),
+            ring: HashRing::from_config(&config.ring),
+            replicas: config.replicas.clone(),
+            replication_queue: config.replication_queue.clone(),
+            encryption_key: config.encryption_key.clone(),
+            embedded_archive: None,
+            embedded_archive_path: config.embedded_archive.clone(),
+            durable_uploads: config.durable_uploads,
+            serve_precompressed: config.serve_precompressed,
+            limits: config.limits(),
+            trash: config.trash_policy(),
+            janitor_interval: config.janitor_interval_secs.map(time::Duration::from_secs),
+            handlers: Vec::new(),
+            router: None,
+            middleware: Vec::new(),
+            progress_hook: None,
+            observers: Vec::new(),
+            upload_hooks: Vec::new(),
+            authorizer: None,
+            clock: None,
+        }
+    }
+
+    // The sinks the config turns on, all writing JSON lines.
+    fn configured_stats_sinks(config: &ServerConfig) -> Vec<Box<dyn StatsSink>> {
+        let mut sinks: Vec<Box<dyn StatsSink>> = Vec::new();
+        if let Some(path) = &config.stats_file {
+            sinks.push(Box::new(FileSink::new(path, StatsFormat::Json)));
+        }
+        #[cfg(unix)]
+        if let Some(path) = &config.stats_socket {
+            sinks.push(Box::new(UnixSocketSink::new(path, StatsFormat::Json)));
+        }
+        if config.stats_stdout {
+            sinks.push(Box::new(StdoutSink::new(StatsFormat::Json)));
+        }
+        sinks
+    }
+
+    pub fn address(mut self, address: &str) -> Self {
+        self.address = address.to_owned();
+        self
+    }
+
+    pub fn port(mut self, port: &str) -> Self {
+        self.port = port.to_owned();
+        self
+    }
+
+    pub fn thread_count(mut self, thread_count: i32) -> Self {
+        self.thread_count = thread_count;
+        self
+    }
+
+    // Accept on `count` SO_REUSEPORT sockets, see FileServer::set_acceptor_threads.
+    pub fn acceptor_threads(mut self, count: usize) -> Self {
+        self.acceptor_threads = count;
+        self
+    }
+
+    pub fn root_dir(mut self, root_dir: &str) -> Self {
+        self.root_dir = root_dir.to_owned();
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: time::Duration) -> Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: time::Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    // Memory map files of at least this many bytes, see
+    // reader::DEFAULT_MMAP_THRESHOLD for a sensible value.
+    pub fn mmap_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.mmap_threshold = threshold;
+        self
+    }
+
+    // Cap each download at `per_transfer` and all of them together at `global`
+    // bytes per second.
+    pub fn rate_limits(mut self, per_transfer: Option<u64>, global: Option<u64>) -> Self {
+        self.rate_limits = (per_transfer, global);
+        self
+    }
+
+    // Cap what each client IP may download per UTC day at `bytes`.
+    pub fn daily_ip_cap(mut self, bytes: Option<u64>) -> Self {
+        self.daily_ip_cap = bytes;
+        self
+    }
+
+    // Hang up on connections still serving `command` after `budget`.
+    pub fn handler_timeout(mut self, command: CommandType, budget: time::Duration) -> Self {
+        self.handler_timeouts.push((command, budget));
+        self
+    }
+
+    // Keep up to `capacity_bytes` of frequently downloaded files no bigger
+    // than `max_file_size` in memory.
+    pub fn hot_cache(mut self, capacity_bytes: u64, max_file_size: u64) -> Self {
+        self.hot_cache = (capacity_bytes, max_file_size);
+        self
+    }
+
+    // Keep `count` of the `thread_count` workers for `command` alone.
+    pub fn reserved_workers(mut self, command: CommandType, count: i32) -> Self {
+        self.reserved_workers.push((command, count));
+        self
+    }
+
+    // Also listen on `address` ("0.0.0.0:8089", "[::]:8089"...), see
+    // FileServer::add_listener.
+    pub fn extra_listener(mut self, address: &str) -> Self {
+        self.extra_listeners
+            .push((address.to_owned(), ListenerPolicy::default()));
+        self
+    }
+
+    // Same with a policy for its connections, see
+    // FileServer::add_listener_with_policy.
+    pub fn extra_listener_with_policy(mut self, address: &str, policy: ListenerPolicy) -> Self {
+        self.extra_listeners.push((address.to_owned(), policy));
+        self
+    }
+
+    // Only serve files matching one of `allow` (everything when empty) and
+    // none of `deny`, see FileServer::set_serve_patterns.
+    pub fn serve_patterns(mut self, allow: Vec<String>, deny: Vec<String>) -> Self {
+        self.serve_patterns = (allow, deny);
+        self
+    }
+
+    pub fn busy_policy(mut self, policy: BusyPolicy) -> Self {
+        self.busy_policy = policy;
+        self
+    }
+
+    // Let downloads of files under `bytes` ahead of larger ones, see
+    // FileServer::set_small_transfer_bytes.
+    pub fn small_transfer_bytes(mut self, bytes: Option<u64>) -> Self {
+        self.small_transfer_bytes = bytes;
+        self
+    }
+
+    // Cap the client sockets open at once, see FileServer::set_connection_limit.
+    pub fn max_connections(mut self, max: Option<usize>, overflow: OverflowPolicy) -> Self {
+        self.max_connections = max;
+        self.connection_overflow = overflow;
+        self
+    }
+
+    pub fn max_stats_subscribers(mut self, max: Option<usize>) -> Self {
+        self.max_stats_subscribers = max;
+        self
+    }
+
+    pub fn stats_heartbeat_timeout(mut self, timeout: Option<time::Duration>) -> Self {
+        self.stats_heartbeat_timeout = timeout;
+        self
+    }
+
+    pub fn stats_interval(mut self, interval: time::Duration) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    // Can be called more than once, see FileServer::add_stats_sink.
+    pub fn stats_sink(mut self, sink: Box<dyn StatsSink>) -> Self {
+        self.stats_sinks.push(sink);
+        self
+    }
+
+    // Length of the most downloaded files list in stats reports.
+    pub fn top_files(mut self, count: usize) -> Self {
+        self.top_files = count;
+        self
+    }
+
+    // See FileServer::set_thread_name_prefix.
+    pub fn thread_name_prefix(mut self, prefix: &str) -> Self {
+        self.thread_name_prefix = prefix.to_owned();
+        self
+    }
+
+    // Clients have to log in as one of `users`, see FileServer::set_accounts.
+    pub fn accounts(mut self, users: &[UserAccount]) -> Self {
+        self.users = users.to_vec();
+        self
+    }
+
+    // Appends uploads and deletes to the file at `path`.
+    pub fn audit_log(mut self, path: &str) -> Self {
+        self.audit_log = Some(path.to_owned());
+        self
+    }
+
+    // Shards Locate maps file names onto, see FileServer::set_ring.
+    pub fn ring(mut self, nodes: &[&str], vnodes: u32) -> Self {
+        let nodes: Vec<String> = nodes.iter().map(|n| n.to_string()).collect();
+        self.ring = HashRing::new(&nodes, vnodes);
+        self
+    }
+
+    // Adds the stats of `peers` to the cluster part of the reports, see
+    // FileServer::set_cluster_peers.
+    pub fn cluster_peers(mut self, peers: &[&str]) -> Self {
+        self.cluster_peers = peers.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    // Sends uploads and deletes on to `replicas`, see FileServer::set_replicas.
+    pub fn replicas(mut self, replicas: &[&str]) -> Self {
+        self.replicas = replicas.iter().map(|r| r.to_string()).collect();
+        self
+    }
+
+    // Keeps what replicas could not take yet in the file at `path`.
+    pub fn replication_queue(mut self, path: &str) -> Self {
+        self.replication_queue = Some(path.to_owned());
+        self
+    }
+
+    // Lets the admin port's `metrics` commands through after `auth`, see
+    // FileServer::set_admin_password_sha256.
+    pub fn admin_password_sha256(mut self, hex_hash: &str) -> Self {
+        self.admin_password_sha256 = Some(hex_hash.to_owned());
+        self
+    }
+
+    // Serves `archive` in place of the root dir, see
+    // FileServer::set_embedded_archive.
+    pub fn embedded_archive(mut self, archive: EmbeddedArchive) -> Self {
+        self.embedded_archive = Some(archive);
+        self
+    }
+
+    // Same with the tar archive at `path`, read when the server is built.
+    pub fn embedded_archive_file(mut self, path: &str) -> Self {
+        self.embedded_archive_path = Some(path.to_owned());
+        self
+    }
+
+    // Stores uploads encrypted with `hex_key`, see FileServer::set_encryption_key.
+    pub fn encryption_key(mut self, hex_key: &str) -> Self {
+        self.encryption_key = Some(hex_key.to_owned());
+        self
+    }
+
+    // Syncs uploads to disk before acknowledging them, see
+    // FileServer::set_durable_uploads.
+    pub fn durable_uploads(mut self, durable: bool) -> Self {
+        self.durable_uploads = durable;
+        self
+    }
+
+    // Serves fresh gzip siblings to sessions that accept them, see
+    // FileServer::set_serve_precompressed.
+    pub fn serve_precompressed(mut self, serve: bool) -> Self {
+        self.serve_precompressed = serve;
+        self
+    }
+
+    // Byte caps on file names, headers, uploads and manifests, see
+    // FileServer::set_limits.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Soft deletes, see FileServer::set_trash.
+    pub fn trash(mut self, policy: Option<TrashPolicy>) -> Self {
+        self.trash = policy;
+        self
+    }
+
+    // Sweep the root for leftovers, see FileServer::set_janitor_interval.
+    pub fn janitor_interval(mut self, interval: Option<time::Duration>) -> Self {
+        self.janitor_interval = interval;
+        self
+    }
+
+    pub fn handlers(mut self, handlers: &[(CommandType, Handler)]) -> Self {
+        self.handlers.extend_from_slice(handlers);
+        self
+    }
+
+    // Start from a custom dispatch table, `handlers` are added on top of it.
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    // Added after the router's own middleware, in call order.
+    pub fn middleware(mut self, middleware: Middleware) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub fn progress_hook(mut self, hook: ProgressHook) -> Self {
+        self.progress_hook = Some(hook);
+        self
+    }
+
+    // Can be called more than once, every observer hears about every event.
+    pub fn observer(mut self, observer: Observer) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    // See FileServer::on_upload_complete, can be called more than once.
+    pub fn on_upload_complete(
+        mut self,
+        hook: impl Fn(&Path, &UploadMeta) + Send + Sync + 'static,
+    ) -> Self {
+        self.upload_hooks.push(Arc::new(hook));
+        self
+    }
+
+    // See FileServer::set_authorizer.
+    pub fn authorizer(mut self, authorizer: Authorizer) -> Self {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    // See FileServer::set_clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    // Fails when the handlers can not serve the protocol, see FileServer::validate.
+    pub fn build(self) -> Result<FileServer, FileServerError> {
+        // handlers take the root dir as &'static str, the server lives for the
+        // rest of the program anyway so leaking the one string is fine
+        let root_dir: &'static str = Box::leak(self.root_dir.into_boxed_str());
+        let archive = match (self.embedded_archive, &self.embedded_archive_path) {
+            (Some(archive), _) => Some(archive),
+            (None, Some(path)) => Some(EmbeddedArchive::open(path)?),
+            (None, None) => None,
+        };
+        // served from the archive, the root dir need not exist
+        let mut file_server = FileServer::open(
+            &self.address,
+            &self.port,
+            self.thread_count,
+            root_dir,
+            archive.is_none(),
+        )?;
+        // first, the rate limits and the janitor below start out on it
+        if let Some(clock) = self.clock {
+            file_server.set_clock(clock);
+        }
+        file_server.set_acceptor_threads(self.acceptor_threads)?;
+        file_server.set_keep_alive_timeout(self.keep_alive_timeout);
+        file_server.set_drain_timeout(self.drain_timeout);
+        file_server.set_mmap_threshold(self.mmap_threshold);
+        file_server.set_rate_limits(self.rate_limits.0, self.rate_limits.1);
+        file_server.set_daily_ip_cap(self.daily_ip_cap);
+        for (command, budget) in self.handler_timeouts {
+            file_server.set_handler_timeout(command, Some(budget));
+        }
+        file_server.set_hot_cache(self.hot_cache.0, self.hot_cache.1);
+        file_server.set_serve_patterns(self.serve_patterns.0, self.serve_patterns.1);
+        file_server.set_busy_policy(self.busy_policy);
+        file_server.set_small_transfer_bytes(self.small_transfer_bytes);
+        file_server.set_connection_limit(self.max_connections, self.connection_overflow);
+        file_server.set_max_stats_subscribers(self.max_stats_subscribers);
+        file_server.set_stats_heartbeat_timeout(self.stats_heartbeat_timeout);
+        file_server.set_stats_interval(self.stats_interval);
+        for sink in self.stats_sinks {
+            file_server.add_stats_sink(sink);
+        }
+        file_server.set_top_files(self.top_files);
+        file_server.set_thread_name_prefix(&self.thread_name_prefix);
+        file_server.set_accounts(&self.users)?;
+        file_server.set_admin_password_sha256(self.admin_password_sha256.as_deref());
+        if let Some(path) = &self.audit_log {
+            file_server.set_audit_log(path)?;
+        }
+        file_server.set_cluster_peers(&self.cluster_peers);
+        file_server.set_ring(self.ring);
+        file_server.set_replicas(
+            &self.replicas,
+            self.replication_queue.as_deref().map(Path::new),
+        )?;
+        if let Some(hex_key) = &self.encryption_key {
+            file_server.set_encryption_key(hex_key)?;
+        }
+        if let Some(archive) = archive {
+            file_server.set_embedded_archive(archive);
+        }
+        file_server.set_durable_uploads(self.durable_uploads);
+        file_server.set_serve_precompressed(self.serve_precompressed);
+        file_server.set_limits(self.limits);
+        file_server.set_trash(self.trash);
+        file_server.set_janitor_interval(self.janitor_interval);
+        for (address, policy) in &self.extra_listeners {
+            file_server.add_listener_with_policy(address.as_str(), *policy)?;
+        }
+        for (command, count) in self.reserved_workers {
+            file_server.reserve_workers(command, count)?;
+        }
+        if let Some(router) = self.router {
+            file_server.set_router(router);
+        }
+        file_server.register_handlers(&self.handlers);
+        for middleware in self.middleware {
+            file_server.use_middleware(middleware);
+        }
+        if let Some(hook) = self.progress_hook {
+            file_server.set_progress_hook(hook);
+        }
+        for observer in self.observers {
+            file_server.add_observer(observer);
+        }
+        for hook in self.upload_hooks {
+            file_server.metrics.add_upload_hook(hook);
+        }
+        if let Some(authorizer) = self.authorizer {
+            file_server.set_authorizer(authorizer);
+        }
+        file_server.validate()?;
+        Ok(file_server)
+    }
+}
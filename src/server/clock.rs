@@ -0,0 +1,164 @@
+use std::{
+    sync::{Arc, Condvar, Mutex, RwLock},
+    thread, time,
+};
+
+// Where the server's interval-driven parts (the stats ticks, heartbeat
+// timeouts, rate limiters, the watchdog and the janitor) get the time and
+// wait it out. SystemClock everywhere unless a test swaps in a MockClock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> time::Instant;
+    fn sleep(&self, duration: time::Duration);
+}
+
+// The wall clock, std's Instant and thread::sleep.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+
+    fn sleep(&self, duration: time::Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+// The clock the registry and its background threads share, swapped in one
+// place by MetricsRegistry::set_clock. Threads already asleep finish their
+// sleep on the clock they started it on.
+#[derive(Clone)]
+pub(crate) struct SharedClock(Arc<RwLock<Arc<dyn Clock>>>);
+
+impl SharedClock {
+    pub(crate) fn get(&self) -> Arc<dyn Clock> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub(crate) fn set(&self, clock: Arc<dyn Clock>) {
+        *self.0.write().unwrap() = clock;
+    }
+
+    pub(crate) fn now(&self) -> time::Instant {
+        self.get().now()
+    }
+
+    pub(crate) fn sleep(&self, duration: time::Duration) {
+        self.get().sleep(duration);
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> SharedClock {
+        SharedClock(Arc::new(RwLock::new(system_clock())))
+    }
+}
+
+// A clock that only moves when told to. Sleepers wake once `advance` took
+// the time past their deadline, so a test can step a stats interval or a
+// watchdog budget without waiting for it.
+pub struct MockClock {
+    state: Mutex<MockState>,
+    changed: Condvar,
+}
+
+struct MockState {
+    now: time::Instant,
+    // when each thread inside sleep wakes up
+    wake_ups: Vec<time::Instant>,
+}
+
+impl MockState {
+    fn sleepers(&self) -> usize {
+        self.wake_ups.iter().filter(|at| **at > self.now).count()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            state: Mutex::new(MockState {
+                now: time::Instant::now(),
+                wake_ups: Vec::new(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn advance(&self, by: time::Duration) {
+        self.state.lock().unwrap().now += by;
+        self.changed.notify_all();
+    }
+
+    // Threads asleep on this clock that the time has not caught up with yet,
+    // one woken by `advance` no longer counts even before it is running.
+    pub fn sleepers(&self) -> usize {
+        self.state.lock().unwrap().sleepers()
+    }
+
+    // Waits, in real time, up to `timeout` for `count` threads to be asleep
+    // on this clock and reports whether they are. Advance after this returns
+    // and the sleepers are sure to see it. A loop that sleeps between rounds
+    // is back to sleep, its round done, once this returns after an advance.
+    pub fn wait_for_sleepers(&self, count: usize, timeout: time::Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.sleepers() < count)
+            .unwrap();
+        state.sleepers() >= count
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> time::Instant {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: time::Duration) {
+        let mut state = self.state.lock().unwrap();
+        let wake_at = state.now + duration;
+        state.wake_ups.push(wake_at);
+        self.changed.notify_all();
+        let mut state = self
+            .changed
+            .wait_while(state, |state| state.now < wake_at)
+            .unwrap();
+        let mine = state.wake_ups.iter().position(|at| *at == wake_at);
+        state.wake_ups.swap_remove(mine.unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_sleepers_wake_when_advanced_past_their_deadline() {
+        let clock = Arc::new(MockClock::new());
+        let started = clock.now();
+        let sleeper = {
+            let clock = clock.clone();
+            thread::spawn(move || clock.sleep(time::Duration::from_secs(60)))
+        };
+        assert!(clock.wait_for_sleepers(1, time::Duration::from_secs(5)));
+
+        clock.advance(time::Duration::from_secs(59));
+        assert_eq!(1, clock.sleepers());
+        clock.advance(time::Duration::from_secs(1));
+        sleeper.join().unwrap();
+        assert_eq!(0, clock.sleepers());
+        assert_eq!(time::Duration::from_secs(60), clock.now() - started);
+    }
+}
@@ -0,0 +1,206 @@
+use super::threads::ThreadRegistry;
+use crate::client::{FileClient, StatsEvent};
+use crate::protocol::stats::{ClusterStats, StatsSnapshot};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock, Weak},
+    thread, time,
+};
+
+// wait before subscribing again to a peer that could not be reached
+const RECONNECT: time::Duration = time::Duration::from_secs(5);
+// a peer whose last report is older than this counts as unreachable, it may
+// hold the connection open without sending anything
+const STALE: time::Duration = time::Duration::from_secs(30);
+
+// Follows the stats of peer file servers so this server's reports carry the
+// figures of the whole cluster too, see ClusterStats. A thread per peer keeps
+// a StatisticsV2 subscription open and the peer's latest report. Only each
+// peer's own figures are added up, never the cluster part of its reports, so
+// peers may follow each other.
+#[derive(Default)]
+pub struct Cluster {
+    state: Arc<ClusterState>,
+    threads: ThreadRegistry,
+}
+
+#[derive(Default)]
+struct ClusterState {
+    // "host:port"
+    peers: RwLock<Vec<String>>,
+    // the latest report of every peer that sent one and is still subscribed
+    reports: RwLock<HashMap<String, (time::Instant, StatsSnapshot)>>,
+}
+
+impl Cluster {
+    // A cluster whose threads are named and tracked by `threads`.
+    pub(crate) fn with_threads(threads: &ThreadRegistry) -> Cluster {
+        Cluster {
+            threads: threads.clone(),
+            ..Cluster::default()
+        }
+    }
+
+    // Follows `peers` from now on. Peers no longer listed are let go of at
+    // their next report.
+    pub fn set_peers(&self, peers: &[String]) {
+        let mut current = self.state.peers.write().unwrap();
+        for peer in peers.iter().filter(|peer| !current.contains(peer)) {
+            let state = Arc::downgrade(&self.state);
+            let peer = peer.clone();
+            self.threads
+                .spawn("cluster-peer", move || follow(state, peer));
+        }
+        *current = peers.to_vec();
+        self.state
+            .reports
+            .write()
+            .unwrap()
+            .retain(|peer, _| current.contains(peer));
+    }
+
+    pub fn peers(&self) -> Vec<String> {
+        self.state.peers.read().unwrap().clone()
+    }
+
+    // `own`, this server's report, added up with the latest one of every
+    // peer. The top files are cut to `top_files` like the server's own.
+    pub(crate) fn stats(&self, own: &StatsSnapshot, top_files: usize) -> ClusterStats {
+        let peers = self.state.peers.read().unwrap();
+        if peers.is_empty() {
+            return ClusterStats::default();
+        }
+        let reports = self.state.reports.read().unwrap();
+        let fresh: Vec<&StatsSnapshot> = reports
+            .values()
+            .filter(|(received, _)| received.elapsed() < STALE)
+            .map(|(_, report)| report)
+            .collect();
+
+        let mut downloads: HashMap<&str, u64> = HashMap::new();
+        let mut cluster = ClusterStats {
+            nodes: 1 + fresh.len() as u32,
+            unreachable: peers.len().saturating_sub(fresh.len()) as u32,
+            ..ClusterStats::default()
+        };
+        for report in fresh.into_iter().chain([own]) {
+            cluster.clients += report.number_of_clients;
+            cluster.bytes_served += report.bytes_served;
+            for (file_name, count) in &report.top_files {
+                *downloads.entry(file_name).or_default() += count;
+            }
+        }
+        let mut downloads: Vec<(String, u64)> = downloads
+            .into_iter()
+            .map(|(file_name, count)| (file_name.to_owned(), count))
+            .collect();
+        downloads.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        downloads.truncate(top_files);
+        cluster.top_files = downloads;
+        cluster
+    }
+}
+
+// Subscribes to `peer` until it is no peer any more or the cluster is gone,
+// subscribing again whenever the subscription breaks off.
+fn follow(state: Weak<ClusterState>, peer: String) {
+    let (address, port) = peer.rsplit_once(':').unwrap_or((&peer, ""));
+    let client = FileClient::new(address, port);
+    let mut reachable = true;
+    loop {
+        let subscribed = client.subscribe_stats();
+        if let Err(err) = &subscribed {
+            if reachable {
+                println!("...Cluster peer {} is unreachable:{err}", peer);
+            }
+            reachable = false;
+        }
+        for event in subscribed.into_iter().flatten() {
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            if !state.peers.read().unwrap().contains(&peer) {
+                return;
+            }
+            match event {
+                Ok(StatsEvent::Report(report)) => {
+                    if !reachable {
+                        println!("...Cluster peer {} is reporting again", peer);
+                        reachable = true;
+                    }
+                    state
+                        .reports
+                        .write()
+                        .unwrap()
+                        .insert(peer.clone(), (time::Instant::now(), report));
+                }
+                Ok(StatsEvent::Reconnected) => {}
+                Err(err) => {
+                    println!("...Lost the stats of cluster peer {}:{err}", peer);
+                    reachable = false;
+                }
+            }
+        }
+
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        state.reports.write().unwrap().remove(&peer);
+        if !state.peers.read().unwrap().contains(&peer) {
+            return;
+        }
+        drop(state);
+        thread::sleep(RECONNECT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestServer;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_reports_add_up_the_peers() {
+        let interval = time::Duration::from_millis(50);
+        let peer = TestServer::start_with(|builder| builder.stats_interval(interval));
+        peer.add_file("hot.bin", "hot");
+        peer.download("hot.bin");
+        peer.download("hot.bin");
+        // nothing listens there once the listener is dropped
+        let down = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let peers = [peer.addr().to_string(), down];
+        let server = TestServer::start_with(|builder| {
+            builder
+                .stats_interval(interval)
+                .cluster_peers(&[&peers[0], &peers[1]])
+        });
+        server.add_file("hot.bin", "hot");
+        server.add_file("cold.bin", "cold");
+        server.download("hot.bin");
+        server.download("cold.bin");
+
+        let deadline = time::Instant::now() + time::Duration::from_secs(10);
+        let mut reports = server.client().subscribe_stats().unwrap();
+        let cluster = loop {
+            assert!(time::Instant::now() < deadline, "the peer never reported");
+            if let Some(Ok(StatsEvent::Report(report))) = reports.next() {
+                if report.cluster.nodes == 2 {
+                    break report.cluster;
+                }
+            }
+        };
+        assert_eq!(1, cluster.unreachable);
+        // a stats subscriber on each node holds a worker
+        assert_eq!(2, cluster.clients);
+        assert_eq!(
+            vec![("hot.bin".to_owned(), 3), ("cold.bin".to_owned(), 1)],
+            cluster.top_files
+        );
+        assert_eq!(3 + 3 + 3 + 4, cluster.bytes_served);
+    }
+}
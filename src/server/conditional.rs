@@ -0,0 +1,94 @@
+use super::connection::Connection;
+use super::keep_alive::{
+    write_error_frame, write_frame_header, write_open_error_frame, DownloadExtras,
+    FRAME_NOT_MODIFIED,
+};
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use super::types::{CommandType, DownloadCondition};
+use crate::protocol;
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+    time,
+};
+
+impl FileServer {
+    // Request: filename=a_file_name|if-modified-since=<unix seconds>|
+    //      or: filename=a_file_name|if-none-match=<etag>|, the sha256 hex Stat
+    //          and the ETags frames hand out
+    // Reply: an empty NOT_MODIFIED frame when the condition holds, otherwise
+    // the file in an OK frame like a keep-alive download.
+    pub fn handle_conditional_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_conditional_download(
+            stream,
+            root_dir,
+            &metrics_registry,
+            DownloadExtras::default(),
+        );
+    }
+
+    pub(crate) fn framed_conditional_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+        extras: DownloadExtras,
+    ) -> io::Result<()> {
+        let request =
+            Self::read_file_request(stream, &metrics_registry.limits()).and_then(|file_name| {
+                let segment = Self::read_request_segment(stream, &metrics_registry.limits())?;
+                Ok((file_name, segment))
+            });
+        let (file_name, segment) = match request {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        let condition = match protocol::parse_condition(&segment) {
+            Ok(condition) => condition,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        if let Err(err) =
+            metrics_registry.authorize(stream, CommandType::ConditionalDownload, &file_name)
+        {
+            return write_error_frame(stream, err.to_string());
+        }
+
+        match Self::is_unchanged(&file_name, root_dir, &condition, metrics_registry) {
+            Err(err) => write_open_error_frame(stream, &file_name, err),
+            Ok(true) => write_frame_header(stream, FRAME_NOT_MODIFIED, 0),
+            Ok(false) => {
+                Self::send_framed_file(stream, &file_name, root_dir, metrics_registry, extras)
+            }
+        }
+    }
+
+    // Whether the client's copy described by `condition` is still current.
+    fn is_unchanged(
+        file_name: &str,
+        root_dir: &'static str,
+        condition: &DownloadCondition,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<bool> {
+        match condition {
+            DownloadCondition::ModifiedSince(since) => {
+                let modified = Self::stored_file(file_name, root_dir, metrics_registry)?.modified;
+                // the wire only carries whole seconds
+                let secs = |t: time::SystemTime| {
+                    t.duration_since(time::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs())
+                };
+                Ok(secs(modified) <= secs(*since))
+            }
+            DownloadCondition::NoneMatch(etag) => {
+                Ok(&Self::served_etag(file_name, root_dir, metrics_registry)? == etag)
+            }
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use super::listener::ListenerPolicy;
+use super::request_id::RequestId;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    time,
+};
+
+// A client connection, whatever carries it. Handlers only ever see this so the
+// same protocol can be served over TCP, Unix sockets or anything else.
+// Methods take &self like they do on &TcpStream, `let mut stream = stream;`
+// followed by the usual Read/Write calls works on a `&dyn Connection`.
+pub trait Connection: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&self, buf: &[u8]) -> io::Result<usize>;
+    fn flush(&self) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()>;
+    fn read_timeout(&self) -> io::Result<Option<time::Duration>>;
+    // who is on the other end, for logs
+    fn peer(&self) -> String;
+    // the same as an address, None when it is not a TCP peer
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+    // who the client proved to be, the subject of its certificate on a TLS
+    // connection with client certificates. None for everything else.
+    fn peer_identity(&self) -> Option<String> {
+        None
+    }
+    // hang up one or both directions, the peer sees EOF on its reads
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    // set once the server accepted the connection, see RequestId
+    fn request_id(&self) -> Option<RequestId> {
+        None
+    }
+    // what the listener it came in on allows, see ListenerPolicy
+    fn policy(&self) -> ListenerPolicy {
+        ListenerPolicy::default()
+    }
+}
+
+// The peer with its identity in front when it has one, for log lines.
+pub fn describe_peer(stream: &dyn Connection) -> String {
+    match stream.peer_identity() {
+        Some(identity) => format!("{} ({})", identity, stream.peer()),
+        None => stream.peer(),
+    }
+}
+
+impl Read for &dyn Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Connection::read(*self, buf)
+    }
+}
+
+impl Write for &dyn Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Connection::write(*self, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Connection::flush(*self)
+    }
+}
+
+impl Connection for TcpStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Write::flush(&mut &*self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        TcpStream::read_timeout(self)
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr()
+            .map_or("unknown peer".to_owned(), |addr| addr.to_string())
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(&mut &*self, buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut &*self, buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Write::flush(&mut &*self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        UnixStream::read_timeout(self)
+    }
+
+    fn peer(&self) -> String {
+        // clients of a listening socket are almost always unnamed
+        "unix socket peer".to_owned()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+}
+
+// A connection with bytes already read off it put back in front, so a
+// handler sees the request whole. The accept side reads a download's file
+// name early to queue it by size, see FileServer::set_small_transfer_bytes.
+pub struct PrefixedConnection {
+    inner: Box<dyn Connection>,
+    prefix: Mutex<VecDeque<u8>>,
+}
+
+impl PrefixedConnection {
+    pub fn new(inner: Box<dyn Connection>, prefix: Vec<u8>) -> PrefixedConnection {
+        PrefixedConnection {
+            inner,
+            prefix: Mutex::new(prefix.into()),
+        }
+    }
+}
+
+impl Connection for PrefixedConnection {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut prefix = self.prefix.lock().unwrap();
+        if prefix.is_empty() {
+            drop(prefix);
+            return self.inner.read(buf);
+        }
+        let len = buf.len().min(prefix.len());
+        for (slot, byte) in buf.iter_mut().zip(prefix.drain(..len)) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        self.inner.read_timeout()
+    }
+
+    fn peer(&self) -> String {
+        self.inner.peer()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.inner.peer_identity()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn request_id(&self) -> Option<RequestId> {
+        self.inner.request_id()
+    }
+
+    fn policy(&self) -> ListenerPolicy {
+        self.inner.policy()
+    }
+}
+
+// One direction of a MemoryConnection.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Default)]
+struct PipeState {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+// In-memory end of a duplex pair, see `duplex`. Lets handlers be driven from
+// tests without opening sockets.
+pub struct MemoryConnection {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<time::Duration>>,
+    identity: Option<String>,
+}
+
+impl MemoryConnection {
+    // Makes this end look like a client that presented a certificate for
+    // `identity`, see Connection::peer_identity.
+    pub fn with_identity(mut self, identity: &str) -> Self {
+        self.identity = Some(identity.to_owned());
+        self
+    }
+}
+
+// Two connected ends, what one writes the other reads.
+pub fn duplex() -> (MemoryConnection, MemoryConnection) {
+    let a_to_b = Arc::new(Pipe::default());
+    let b_to_a = Arc::new(Pipe::default());
+    (
+        MemoryConnection {
+            incoming: b_to_a.clone(),
+            outgoing: a_to_b.clone(),
+            read_timeout: Mutex::new(None),
+            identity: None,
+        },
+        MemoryConnection {
+            incoming: a_to_b,
+            outgoing: b_to_a,
+            read_timeout: Mutex::new(None),
+            identity: None,
+        },
+    )
+}
+
+impl Connection for MemoryConnection {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+        let mut state = self.incoming.state.lock().unwrap();
+        while state.buf.is_empty() && !state.closed && !buf.is_empty() {
+            state = match deadline {
+                None => self.incoming.readable.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = time::Instant::now();
+                    if now >= deadline {
+                        // same kind a socket read timeout reports on unix
+                        return Err(io::Error::from(ErrorKind::WouldBlock));
+                    }
+                    self.incoming
+                        .readable
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+
+        let count = buf.len().min(state.buf.len());
+        for (slot, byte) in buf.iter_mut().zip(state.buf.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.outgoing.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        state.buf.extend(buf);
+        self.outgoing.readable.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        if timeout == Some(time::Duration::ZERO) {
+            // mirror TcpStream, which refuses a zero timeout
+            return Err(io::Error::from(ErrorKind::InvalidInput));
+        }
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    fn peer(&self) -> String {
+        "in-memory peer".to_owned()
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.identity.clone()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.incoming.close();
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.outgoing.close();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MemoryConnection {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplex_round_trip_and_eof() {
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        let mut server_end: &dyn Connection = &server;
+
+        client_end.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let mut received = String::new();
+        server_end.read_to_string(&mut received).unwrap();
+        assert_eq!("ping", received);
+
+        server_end.write_all(b"pong").unwrap();
+        drop(server);
+        let mut reply = String::new();
+        client_end.read_to_string(&mut reply).unwrap();
+        assert_eq!("pong", reply);
+        assert!(client_end.write_all(b"late").is_err());
+    }
+
+    #[test]
+    fn test_duplex_read_timeout() {
+        let (client, _server) = duplex();
+        client
+            .set_read_timeout(Some(time::Duration::from_millis(20)))
+            .unwrap();
+        let err = Connection::read(&client, &mut [0; 1]).unwrap_err();
+        assert_eq!(ErrorKind::WouldBlock, err.kind());
+    }
+
+    #[test]
+    fn test_prefixed_connection_replays_before_reading_on() {
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"|rest").unwrap();
+        drop(client);
+
+        let prefixed = PrefixedConnection::new(Box::new(server), b"filename=a".to_vec());
+        let mut server_end: &dyn Connection = &prefixed;
+        let mut received = String::new();
+        server_end.read_to_string(&mut received).unwrap();
+        assert_eq!("filename=a|rest", received);
+    }
+}
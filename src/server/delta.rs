@@ -0,0 +1,448 @@
+use super::connection::Connection;
+use super::keep_alive::{
+    write_error_frame, write_frame_header, write_open_error_frame, FRAME_COPY, FRAME_END, FRAME_OK,
+};
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use super::types::CommandType;
+use crate::protocol::{self, Limit};
+use crate::reader::validate_file_name;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read, Write},
+    sync::{atomic::Ordering, Arc},
+};
+
+// biggest literal frame, a long run of new bytes goes out as several
+const MAX_LITERAL_BYTES: usize = 64 * 1024;
+// how much of the file is read at a time while looking for matches
+const READ_BYTES: usize = 256 * 1024;
+
+// What a Delta client sends for each block of its copy of a file: a rolling
+// checksum cheap enough to try at every offset of the served file, and the
+// start of the block's SHA-256 to make sure a match is one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: [u8; 16],
+}
+
+impl BlockSignature {
+    // bytes of a signature on the wire, weak checksum first, big endian
+    pub const ENCODED_LEN: usize = 20;
+
+    pub fn of(block: &[u8]) -> BlockSignature {
+        BlockSignature {
+            weak: RollingChecksum::new(block).digest(),
+            strong: strong_checksum(block),
+        }
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.weak.to_be_bytes());
+        out.extend_from_slice(&self.strong);
+    }
+
+    pub fn decode(bytes: &[u8; Self::ENCODED_LEN]) -> BlockSignature {
+        let mut weak = [0u8; 4];
+        weak.copy_from_slice(&bytes[..4]);
+        let mut strong = [0u8; 16];
+        strong.copy_from_slice(&bytes[4..]);
+        BlockSignature {
+            weak: u32::from_be_bytes(weak),
+            strong,
+        }
+    }
+}
+
+// The signature of every `block_size` block of `reader`, the last one may be
+// shorter.
+pub fn signatures(mut reader: impl Read, block_size: usize) -> io::Result<Vec<BlockSignature>> {
+    let mut signatures = Vec::new();
+    let mut block = Vec::with_capacity(block_size);
+    loop {
+        block.clear();
+        Read::by_ref(&mut reader)
+            .take(block_size as u64)
+            .read_to_end(&mut block)?;
+        if block.is_empty() {
+            return Ok(signatures);
+        }
+        signatures.push(BlockSignature::of(&block));
+    }
+}
+
+fn strong_checksum(block: &[u8]) -> [u8; 16] {
+    let mut strong = [0u8; 16];
+    strong.copy_from_slice(&Sha256::digest(block)[..16]);
+    strong
+}
+
+// rsync's weak checksum: two sums of the window that can be moved along by a
+// byte without going over the window again.
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(window: &[u8]) -> RollingChecksum {
+        let len = window.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        RollingChecksum { a, b, len }
+    }
+
+    // Moves the window a byte on, `out` leaving at the front and `inn` joining
+    // at the back.
+    fn roll(&mut self, out: u8, inn: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inn as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+// What the new file is made of, in order.
+#[derive(Debug, PartialEq)]
+pub enum DeltaOp<'a> {
+    // bytes the client does not have
+    Literal(&'a [u8]),
+    // `count` blocks of the client's copy starting with block `first`
+    Copy { first: u64, count: u64 },
+}
+
+// Walks `reader` looking for the blocks of `signatures` at every offset and
+// hands what it finds to `emit`, runs of blocks in a row as a single Copy.
+// Only whole blocks are matched, bytes after the last one go out as literals.
+pub fn encode_delta(
+    mut reader: impl Read,
+    block_size: usize,
+    signatures: &[BlockSignature],
+    mut emit: impl FnMut(DeltaOp) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut blocks: HashMap<u32, Vec<(usize, [u8; 16])>> = HashMap::new();
+    for (index, signature) in signatures.iter().enumerate() {
+        blocks
+            .entry(signature.weak)
+            .or_default()
+            .push((index, signature.strong));
+    }
+
+    let mut buf: Vec<u8> = Vec::new();
+    // the window starts at `start`, bytes from `literal` up to it are not sent yet
+    let (mut start, mut literal) = (0, 0);
+    let mut eof = false;
+    let mut rolling: Option<RollingChecksum> = None;
+    let mut copy: Option<(u64, u64)> = None;
+    loop {
+        if buf.len() - start <= block_size && !eof {
+            // sent bytes are dropped before reading more
+            buf.drain(..literal);
+            start -= literal;
+            literal = 0;
+            let read = Read::by_ref(&mut reader)
+                .take(READ_BYTES as u64)
+                .read_to_end(&mut buf)?;
+            eof = read == 0;
+            continue;
+        }
+        if buf.len() - start < block_size {
+            break;
+        }
+
+        let window = &buf[start..start + block_size];
+        let checksum = rolling.get_or_insert_with(|| RollingChecksum::new(window));
+        let matched = blocks.get(&checksum.digest()).and_then(|candidates| {
+            let strong = strong_checksum(window);
+            candidates
+                .iter()
+                .find(|(_, candidate)| *candidate == strong)
+                .map(|(index, _)| *index as u64)
+        });
+        if let Some(index) = matched {
+            if literal < start {
+                flush_copy(&mut copy, &mut emit)?;
+                emit(DeltaOp::Literal(&buf[literal..start]))?;
+            }
+            match &mut copy {
+                Some((first, count)) if *first + *count == index => *count += 1,
+                _ => {
+                    flush_copy(&mut copy, &mut emit)?;
+                    copy = Some((index, 1));
+                }
+            }
+            start += block_size;
+            literal = start;
+            rolling = None;
+            continue;
+        }
+
+        match buf.get(start + block_size) {
+            Some(next) => checksum.roll(buf[start], *next),
+            None => rolling = None,
+        }
+        start += 1;
+        if start - literal >= MAX_LITERAL_BYTES {
+            flush_copy(&mut copy, &mut emit)?;
+            emit(DeltaOp::Literal(&buf[literal..start]))?;
+            literal = start;
+        }
+    }
+
+    flush_copy(&mut copy, &mut emit)?;
+    for piece in buf[literal..].chunks(MAX_LITERAL_BYTES) {
+        emit(DeltaOp::Literal(piece))?;
+    }
+    Ok(())
+}
+
+fn flush_copy(
+    copy: &mut Option<(u64, u64)>,
+    emit: &mut impl FnMut(DeltaOp) -> io::Result<()>,
+) -> io::Result<()> {
+    match copy.take() {
+        Some((first, count)) => emit(DeltaOp::Copy { first, count }),
+        None => Ok(()),
+    }
+}
+
+impl FileServer {
+    // Request: filename=a_file_name|blocks=<block size>|, then
+    // [signatures length: u64 big endian][signatures], a BlockSignature for
+    // each block of the client's copy of the file in order.
+    // Reply: an OK frame holding `<size>\t<etag>` of the served file, then
+    // what it is made of: OK frames of bytes the client does not have and
+    // COPY frames naming blocks it does, then an empty END frame.
+    pub fn handle_delta(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_delta(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_delta(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let request =
+            Self::read_file_request(stream, &metrics_registry.limits()).and_then(|file_name| {
+                let segment = Self::read_request_segment(stream, &metrics_registry.limits())?;
+                Ok((file_name, protocol::parse_block_size(&segment)?))
+            });
+        let (file_name, block_size) = match request {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        let mut length = [0u8; 8];
+        stream.read_exact(&mut length)?;
+        let length = u64::from_be_bytes(length);
+        // signatures are held to the manifest limit, the biggest body a
+        // download takes
+        if let Err(err) = metrics_registry.limits().check(Limit::Manifest, length) {
+            let _ = write_error_frame(stream, err.to_string());
+            return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+        }
+        metrics_registry.record_request_body(length);
+        let mut signatures = vec![0; length as usize];
+        stream.read_exact(&mut signatures)?;
+        let signatures = match protocol::parse_signatures(&signatures) {
+            Ok(signatures) => signatures,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+
+        if let Err(err) = validate_file_name(&file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::Delta, &file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        let file_reader = match Self::open_served_file(&file_name, root_dir, metrics_registry) {
+            Ok(file_reader) => file_reader,
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+        let etag = match Self::served_etag(&file_name, root_dir, metrics_registry) {
+            Ok(etag) => etag,
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+        metrics_registry.record_download(file_name.clone());
+        let reply = format!("{}\t{}", file_reader.len(), etag);
+        write_frame_header(stream, FRAME_OK, reply.len() as u64)?;
+        stream.write_all(reply.as_bytes())?;
+
+        let transfer =
+            metrics_registry.begin_transfer(&file_name, &stream.peer(), stream.request_id());
+        let throttle = metrics_registry.throttle();
+        encode_delta(
+            file_reader,
+            block_size as usize,
+            &signatures,
+            |op| match op {
+                DeltaOp::Literal(bytes) => {
+                    if transfer.is_cancelled() {
+                        return Err(io::Error::new(
+                            ErrorKind::ConnectionAborted,
+                            format!("transfer {} was cancelled", transfer.id()),
+                        ));
+                    }
+                    throttle.pace(bytes.len() as u64);
+                    write_frame_header(stream, FRAME_OK, bytes.len() as u64)?;
+                    stream.write_all(bytes)?;
+                    transfer.record_bytes_sent(bytes.len() as u64);
+                    Ok(())
+                }
+                DeltaOp::Copy { first, count } => {
+                    metrics_registry
+                        .delta_bytes_copied
+                        .fetch_add(count * block_size, Ordering::Relaxed);
+                    write_frame_header(stream, FRAME_COPY, 16)?;
+                    stream.write_all(&first.to_be_bytes())?;
+                    stream.write_all(&count.to_be_bytes())
+                }
+            },
+        )?;
+        transfer.finish();
+        write_frame_header(stream, FRAME_END, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::*;
+    use crate::reader;
+    use std::fs;
+
+    // Runs the encoder and puts the file back together from `old` the way a
+    // client does.
+    fn round_trip(old: &[u8], new: &[u8], block_size: usize) -> (Vec<u8>, usize) {
+        let signatures = signatures(old, block_size).unwrap();
+        let mut rebuilt = Vec::new();
+        let mut literal_bytes = 0;
+        encode_delta(new, block_size, &signatures, |op| {
+            match op {
+                DeltaOp::Literal(bytes) => {
+                    literal_bytes += bytes.len();
+                    rebuilt.extend_from_slice(bytes);
+                }
+                DeltaOp::Copy { first, count } => {
+                    let start = first as usize * block_size;
+                    let end = (start + count as usize * block_size).min(old.len());
+                    rebuilt.extend_from_slice(&old[start..end]);
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+        (rebuilt, literal_bytes)
+    }
+
+    #[test]
+    fn test_rolling_checksum_rolls() {
+        let data = b"the quick brown fox jumps over the lazy dog";
+        let mut rolling = RollingChecksum::new(&data[..8]);
+        for start in 1..data.len() - 8 {
+            rolling.roll(data[start - 1], data[start + 7]);
+            assert_eq!(
+                RollingChecksum::new(&data[start..start + 8]).digest(),
+                rolling.digest()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta_sends_only_what_changed() {
+        let old: Vec<u8> = (0..100_000u32).flat_map(|n| n.to_le_bytes()).collect();
+        let mut new = old.clone();
+        // an insert shifts everything after it, an edit changes a block in place
+        new.splice(1000..1000, b"inserted".iter().copied());
+        new[300_000] ^= 0xff;
+        new.extend_from_slice(b"appended");
+
+        let (rebuilt, literal_bytes) = round_trip(&old, &new, 1024);
+        assert_eq!(new, rebuilt);
+        assert!(literal_bytes < 4 * 1024, "{} literal bytes", literal_bytes);
+
+        // nothing in common, or nothing to start from
+        assert_eq!(new, round_trip(b"unrelated", &new, 1024).0);
+        assert_eq!(new, round_trip(b"", &new, 1024).0);
+        assert_eq!(b"".to_vec(), round_trip(&old, b"", 1024).0);
+    }
+
+    #[test]
+    fn test_framed_delta() {
+        let root_dir = "temp_test_delta_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        let block = |byte: u8| vec![byte; 64];
+        let served = [
+            block(b'a'),
+            block(b'b'),
+            block(b'c'),
+            block(b'd'),
+            b"!".to_vec(),
+        ];
+        fs::write(path.join("doc"), served.concat()).unwrap();
+        let metrics = MetricsRegistry::new();
+
+        let mut body = Vec::new();
+        let local = [block(b'a'), block(b'X'), block(b'c'), block(b'd')].concat();
+        for signature in signatures(&local[..], 64).unwrap() {
+            signature.encode(&mut body);
+        }
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=doc|blocks=64|").unwrap();
+        client_end
+            .write_all(&(body.len() as u64).to_be_bytes())
+            .unwrap();
+        client_end.write_all(&body).unwrap();
+        FileServer::framed_delta(&server, root_dir, &metrics).unwrap();
+
+        let mut frames = Vec::new();
+        loop {
+            let mut header = [0u8; 9];
+            client_end.read_exact(&mut header).unwrap();
+            let mut length = [0u8; 8];
+            length.copy_from_slice(&header[1..]);
+            let mut payload = vec![0; u64::from_be_bytes(length) as usize];
+            client_end.read_exact(&mut payload).unwrap();
+            if header[0] == FRAME_END {
+                break;
+            }
+            frames.push((header[0], payload));
+        }
+        assert!(String::from_utf8_lossy(&frames[0].1).starts_with("257\t"));
+        let copy = |first: u64, count: u64| {
+            (
+                FRAME_COPY,
+                [first.to_be_bytes(), count.to_be_bytes()].concat(),
+            )
+        };
+        assert_eq!(
+            vec![
+                copy(0, 1),
+                (FRAME_OK, block(b'b')),
+                copy(2, 2),
+                (FRAME_OK, b"!".to_vec()),
+            ],
+            frames[1..]
+        );
+        assert_eq!(192, metrics.delta_bytes_copied.load(Ordering::Relaxed));
+        reader::cleanup_server_file(root_dir);
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::HashMap,
+    sync::{Condvar, Mutex},
+};
+
+// Per-file read/write coordination between downloads and uploads.
+// Uploads land in `<name>.part` and are renamed over the served file, so a
+// handle opened before the rename keeps reading the version it opened. What
+// needs ordering is the moment of opening (and of filling the hot file cache)
+// against the rename: readers hold a read lock while they open, writers a
+// write lock while they swap the file in and invalidate the cache. Writers
+// are preferred, a steady stream of downloads can not starve an upload.
+#[derive(Default)]
+pub struct FileLocks {
+    files: Mutex<HashMap<String, LockState>>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct LockState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+    waiting_readers: usize,
+}
+
+impl LockState {
+    fn idle(&self) -> bool {
+        self.readers == 0 && !self.writer && self.waiting_writers == 0 && self.waiting_readers == 0
+    }
+}
+
+impl FileLocks {
+    // Blocks while `key` is being written or a writer is waiting for it.
+    pub fn read(&self, key: &str) -> ReadGuard<'_> {
+        let mut files = self.files.lock().unwrap();
+        let mut waited = false;
+        loop {
+            let state = files.entry(key.to_owned()).or_default();
+            if !state.writer && state.waiting_writers == 0 {
+                state.readers += 1;
+                if waited {
+                    state.waiting_readers -= 1;
+                }
+                break;
+            }
+            if !waited {
+                waited = true;
+                state.waiting_readers += 1;
+                self.changed.notify_all();
+            }
+            files = self.changed.wait(files).unwrap();
+        }
+        ReadGuard {
+            locks: self,
+            key: key.to_owned(),
+        }
+    }
+
+    // Blocks until nobody reads or writes `key`.
+    pub fn write(&self, key: &str) -> WriteGuard<'_> {
+        let mut files = self.files.lock().unwrap();
+        files.entry(key.to_owned()).or_default().waiting_writers += 1;
+        self.changed.notify_all();
+        loop {
+            let state = files.get_mut(key).unwrap();
+            if !state.writer && state.readers == 0 {
+                state.waiting_writers -= 1;
+                state.writer = true;
+                break;
+            }
+            files = self.changed.wait(files).unwrap();
+        }
+        WriteGuard {
+            locks: self,
+            key: key.to_owned(),
+        }
+    }
+
+    // How many files are locked or waited on right now.
+    pub fn len(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Waits up to `timeout` until `count` readers and writers, together,
+    // wait for `key`, and reports whether they do. For tests to know a thread
+    // is blocked without sleeping on it.
+    #[cfg(test)]
+    fn wait_for_waiters(&self, key: &str, count: usize, timeout: std::time::Duration) -> bool {
+        let waiters = |files: &HashMap<String, LockState>| {
+            files
+                .get(key)
+                .map_or(0, |state| state.waiting_readers + state.waiting_writers)
+        };
+        let files = self.files.lock().unwrap();
+        let (files, _) = self
+            .changed
+            .wait_timeout_while(files, timeout, |files| waiters(files) < count)
+            .unwrap();
+        waiters(&files) >= count
+    }
+
+    fn release(&self, key: &str, update: impl FnOnce(&mut LockState)) {
+        let mut files = self.files.lock().unwrap();
+        if let Some(state) = files.get_mut(key) {
+            update(state);
+            if state.idle() {
+                files.remove(key);
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+pub struct ReadGuard<'a> {
+    locks: &'a FileLocks,
+    key: String,
+}
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.release(&self.key, |state| state.readers -= 1);
+    }
+}
+
+pub struct WriteGuard<'a> {
+    locks: &'a FileLocks,
+    key: String,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.locks.release(&self.key, |state| state.writer = false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{mpsc, Arc},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_writer_waits_for_readers_and_blocks_new_ones() {
+        let locks = Arc::new(FileLocks::default());
+        let first_reader = locks.read("a");
+        // other files are not held up
+        drop(locks.write("b"));
+
+        let (events, received) = mpsc::channel();
+        let writer = {
+            let locks = locks.clone();
+            let events = events.clone();
+            thread::spawn(move || {
+                let _guard = locks.write("a");
+                events.send("write").unwrap();
+            })
+        };
+        assert!(locks.wait_for_waiters("a", 1, Duration::from_secs(5)));
+        assert!(received.try_recv().is_err());
+
+        // the writer is waiting, a new reader queues behind it
+        let reader = {
+            let locks = locks.clone();
+            thread::spawn(move || {
+                let _guard = locks.read("a");
+                events.send("read").unwrap();
+            })
+        };
+        assert!(locks.wait_for_waiters("a", 2, Duration::from_secs(5)));
+        assert!(received.try_recv().is_err());
+
+        drop(first_reader);
+        writer.join().unwrap();
+        reader.join().unwrap();
+        assert_eq!(vec!["write", "read"], received.iter().collect::<Vec<_>>());
+        assert!(locks.is_empty());
+    }
+}
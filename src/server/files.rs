@@ -0,0 +1,210 @@
+use super::audit::AuditEntry;
+use super::connection::{describe_peer, Connection};
+use super::keep_alive::{
+    write_closing_error_frame, write_error_frame, write_frame_header, write_open_error_frame,
+    FRAME_OK,
+};
+use super::metrics::MetricsRegistry;
+use super::request_id::log_prefix;
+use super::server::FileServer;
+use super::types::CommandType;
+use crate::protocol::Limit;
+use crate::reader::discard_partial_file;
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    sync::Arc,
+};
+
+// Upload, Delete and List reply with the same frames keep-alive sessions use, so the
+// handlers below work the same on a one-shot connection and inside a session.
+impl FileServer {
+    // Request: filename=a_file_name|[length: u64 big endian][file bytes]
+    // Reply: an empty OK frame once the file is on disk, an error frame otherwise.
+    pub fn handle_upload(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_upload(stream, root_dir, &metrics_registry);
+    }
+
+    // Request: filename=a_file_name|
+    // Reply: an empty OK frame once the file is gone, a not found frame when
+    // there was none to remove.
+    pub fn handle_delete(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_delete(stream, root_dir, &metrics_registry);
+    }
+
+    // Reply: an OK frame holding one `name\tsize\n` line per served file.
+    pub fn handle_list(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_list(stream, root_dir, &metrics_registry);
+    }
+
+    // Answers an upload turned away before its body was read. The body can be
+    // as big as the client says, so instead of reading past it the
+    // connection is closed, a keep-alive session with it.
+    fn refuse_upload(stream: &dyn Connection, reason: String) -> io::Result<()> {
+        write_closing_error_frame(stream, reason.clone())?;
+        Err(io::Error::new(ErrorKind::ConnectionAborted, reason))
+    }
+
+    pub(crate) fn framed_upload(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let file_name = match Self::read_file_request(stream, &metrics_registry.limits()) {
+            Ok(file_name) => file_name,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+
+        let mut length = [0u8; 8];
+        stream.read_exact(&mut length)?;
+        let length = u64::from_be_bytes(length);
+        let audit = |bytes: u64, result: Result<(), String>| {
+            metrics_registry.audit.record(AuditEntry {
+                peer: &describe_peer(stream),
+                root_dir,
+                operation: "upload",
+                file_name: &file_name,
+                bytes,
+                result,
+            })
+        };
+        if let Err(err) = metrics_registry.limits().check(Limit::Upload, length) {
+            audit(0, Err(err.to_string()));
+            return Self::refuse_upload(stream, err.to_string());
+        }
+        if metrics_registry.is_read_only() || stream.policy().read_only {
+            audit(0, Err("server is read-only".to_owned()));
+            return Self::refuse_upload(stream, "server is read-only".to_owned());
+        }
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::Upload, &file_name) {
+            audit(0, Err(err.to_string()));
+            return Self::refuse_upload(stream, err.to_string());
+        }
+
+        // bytes land in `<name>.part` and only get the real name once complete
+        let mut file = match Self::create_stored_file(&file_name, root_dir, metrics_registry) {
+            Ok(file) => file,
+            Err(err) => {
+                audit(0, Err(err.to_string()));
+                return Self::refuse_upload(stream, err.to_string());
+            }
+        };
+
+        metrics_registry.record_request_body(length);
+        // io::copy goes through a fixed size buffer, however big the upload
+        // only that much of it (a 64 KiB chunk when encrypting) is in memory
+        let received = match io::copy(&mut stream.take(length), &mut file) {
+            Ok(received) => received,
+            Err(err) => {
+                discard_partial_file(&file_name, root_dir);
+                audit(0, Err(err.to_string()));
+                return Err(err);
+            }
+        };
+        if received != length {
+            discard_partial_file(&file_name, root_dir);
+            audit(
+                0,
+                Err("upload ended before the announced length".to_owned()),
+            );
+            let _ = write_error_frame(
+                stream,
+                "upload ended before the announced length".to_owned(),
+            );
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "short upload"));
+        }
+        if let Err(err) = Self::commit_stored_file(file, &file_name, root_dir, metrics_registry) {
+            discard_partial_file(&file_name, root_dir);
+            audit(0, Err(err.to_string()));
+            return write_error_frame(stream, err.to_string());
+        }
+        audit(received, Ok(()));
+
+        println!(
+            "{}Received {} bytes for {}",
+            log_prefix(stream),
+            received,
+            file_name
+        );
+        write_frame_header(stream, FRAME_OK, 0)
+    }
+
+    pub(crate) fn framed_delete(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let file_name = match Self::read_file_request(stream, &metrics_registry.limits()) {
+            Ok(file_name) => file_name,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        let audit = |result: Result<(), String>| {
+            metrics_registry.audit.record(AuditEntry {
+                peer: &describe_peer(stream),
+                root_dir,
+                operation: "delete",
+                file_name: &file_name,
+                bytes: 0,
+                result,
+            })
+        };
+        if metrics_registry.is_read_only() || stream.policy().read_only {
+            audit(Err("server is read-only".to_owned()));
+            return write_error_frame(stream, "server is read-only".to_owned());
+        }
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::Delete, &file_name) {
+            audit(Err(err.to_string()));
+            return write_error_frame(stream, err.to_string());
+        }
+        if let Err(err) = Self::remove_stored_file(&file_name, root_dir, metrics_registry) {
+            audit(Err(err.to_string()));
+            return write_open_error_frame(stream, &file_name, err);
+        }
+        audit(Ok(()));
+
+        println!("{}Deleted {}", log_prefix(stream), file_name);
+        write_frame_header(stream, FRAME_OK, 0)
+    }
+
+    pub(crate) fn framed_list(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let files = match Self::list_stored_files(root_dir, metrics_registry) {
+            Ok(files) => files,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+
+        let mut listing = String::new();
+        for (name, size) in files {
+            if metrics_registry
+                .authorize(stream, CommandType::List, &name)
+                .is_err()
+            {
+                continue;
+            }
+            let size = metrics_registry.served_len(size);
+            listing.push_str(&format!("{}\t{}\n", name, size));
+        }
+        write_frame_header(stream, FRAME_OK, listing.len() as u64)?;
+        stream.write_all(listing.as_bytes())
+    }
+}
@@ -0,0 +1,120 @@
+use super::connection::Connection;
+use super::keep_alive::{
+    write_error_frame, write_frame_header, write_open_error_frame, FRAME_CRC_CHUNK, FRAME_END,
+    FRAME_OK,
+};
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use super::types::CommandType;
+use crate::protocol;
+use crate::protocol::crc32::crc32;
+use crate::protocol::frame::{encode_crc_chunk_head, CRC_CHUNK_HEAD_LEN};
+use crate::reader::validate_file_name;
+use std::{
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+
+impl FileServer {
+    // For devices that write a download straight to flash: they can not hold
+    // the whole file to check it at the end, so every chunk comes with where
+    // it goes and a CRC-32 to check before writing it. A chunk that fails
+    // the check, or a device that lost power, asks again from that chunk's
+    // offset. Clients find the command in the Hello capabilities.
+    //
+    // Request: filename=a_file_name|flash=<chunk bytes>,<offset>|
+    // Reply: an OK frame holding the file size as a u64 big endian, then the
+    // file from `offset` on in FRAME_CRC_CHUNK frames of `chunk bytes` (the
+    // last one shorter), then an empty FRAME_END.
+    pub fn handle_flash_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_flash_download(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_flash_download(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let request =
+            Self::read_file_request(stream, &metrics_registry.limits()).and_then(|file_name| {
+                let segment = Self::read_request_segment(stream, &metrics_registry.limits())?;
+                Ok((file_name, segment))
+            });
+        let (file_name, segment) = match request {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        let (chunk_bytes, offset) = match protocol::parse_flash(&segment) {
+            Ok(flash) => flash,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        if let Err(err) = validate_file_name(&file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::FlashDownload, &file_name)
+        {
+            return write_error_frame(stream, err.to_string());
+        }
+
+        let mut file_reader = match Self::open_served_file(&file_name, root_dir, metrics_registry) {
+            Ok(file_reader) => file_reader,
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+        let size = file_reader.len();
+        if offset > size {
+            return write_error_frame(
+                stream,
+                format!("offset {} is past the end of {}", offset, file_name),
+            );
+        }
+        file_reader.seek(SeekFrom::Start(offset))?;
+        metrics_registry.record_download(file_name.clone());
+        write_frame_header(stream, FRAME_OK, 8)?;
+        stream.write_all(&size.to_be_bytes())?;
+
+        let transfer =
+            metrics_registry.begin_transfer(&file_name, &stream.peer(), stream.request_id());
+        let throttle = metrics_registry.throttle();
+        let mut at = offset;
+        while at < size {
+            if transfer.is_cancelled() {
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    format!("transfer {} was cancelled", transfer.id()),
+                ));
+            }
+            if transfer.is_over_daily_cap() {
+                return Err(io::Error::new(
+                    ErrorKind::ConnectionAborted,
+                    format!("daily transfer cap reached for {}", stream.peer()),
+                ));
+            }
+            let length = chunk_bytes.min(size - at);
+            let mut chunk = Vec::with_capacity(length as usize);
+            Read::by_ref(&mut file_reader)
+                .take(length)
+                .read_to_end(&mut chunk)?;
+            if chunk.len() as u64 != length {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "file size changed mid transfer",
+                ));
+            }
+            throttle.pace(length);
+            write_frame_header(stream, FRAME_CRC_CHUNK, CRC_CHUNK_HEAD_LEN as u64 + length)?;
+            stream.write_all(&encode_crc_chunk_head(at, crc32(&chunk)))?;
+            stream.write_all(&chunk)?;
+            transfer.record_bytes_sent(length);
+            at += length;
+        }
+        transfer.finish();
+        write_frame_header(stream, FRAME_END, 0)
+    }
+}
@@ -0,0 +1,150 @@
+use super::connection::Connection;
+use super::server::FileServerError;
+use crate::protocol::{Limit, HEADER_TIMEOUT};
+use std::{
+    io::{self, ErrorKind},
+    time,
+};
+
+// Reads the head of a request under a deadline and a size cap (see
+// Limits::max_header_bytes), so a client that stops halfway through a file
+// name, or never sends the `|`, ties up a thread for HEADER_TIMEOUT at most
+// and a few KB of memory. Every read through one reader shares its deadline,
+// determine_request reads the prefixes, the login and the command byte with
+// one. The accept loop never reads, see FileServer::serve_connection.
+// Bytes are still read one at a time, a buffered reader could swallow the
+// start of the next command on a keep-alive connection. Whatever read timeout
+// the stream had is put back when the reader is dropped.
+pub(crate) struct HeaderReader<'a> {
+    stream: &'a dyn Connection,
+    deadline: time::Instant,
+    previous_timeout: Option<time::Duration>,
+}
+
+impl<'a> HeaderReader<'a> {
+    pub fn new(stream: &'a dyn Connection) -> io::Result<HeaderReader<'a>> {
+        Ok(HeaderReader {
+            previous_timeout: stream.read_timeout()?,
+            stream,
+            deadline: time::Instant::now() + HEADER_TIMEOUT,
+        })
+    }
+
+    // A single byte (a command, a stats format), EOF before it is an error.
+    pub fn read_byte(&mut self) -> Result<u8, FileServerError> {
+        match self.next_byte()? {
+            Some(byte) => Ok(byte),
+            None => Err(FileServerError::Io(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a request",
+            ))),
+        }
+    }
+
+    // Up to and including the next `|`, or to EOF, at most `max_bytes`.
+    pub fn read_segment(&mut self, max_bytes: usize) -> Result<Vec<u8>, FileServerError> {
+        let mut buffer = Vec::new();
+        while let Some(byte) = self.next_byte()? {
+            buffer.push(byte);
+            if byte == b'|' {
+                break;
+            }
+            if buffer.len() >= max_bytes {
+                return Err(FileServerError::TooLarge {
+                    limit: Limit::Header,
+                    max: max_bytes as u64,
+                });
+            }
+        }
+        Ok(buffer)
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, FileServerError> {
+        let remaining = self
+            .deadline
+            .saturating_duration_since(time::Instant::now());
+        // a stream's own shorter timeout (a keep-alive idle timeout) still applies
+        let timeout = match self.previous_timeout {
+            Some(previous) => previous.min(remaining),
+            None => remaining,
+        };
+        if timeout.is_zero() {
+            return Err(Self::timed_out());
+        }
+        self.stream.set_read_timeout(Some(timeout))?;
+
+        let mut byte = [0u8; 1];
+        match self.stream.read(&mut byte) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(byte[0])),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(Self::timed_out())
+            }
+            Err(err) => Err(FileServerError::Io(err)),
+        }
+    }
+
+    fn timed_out() -> FileServerError {
+        FileServerError::bad_frame("timed out waiting for the rest of the request header")
+    }
+}
+
+impl Drop for HeaderReader<'_> {
+    fn drop(&mut self) {
+        let _ = self.stream.set_read_timeout(self.previous_timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::*;
+    use crate::protocol::MAX_HEADER_BYTES;
+    use std::io::Write;
+
+    #[test]
+    fn test_segment_over_the_cap_is_too_large() {
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end
+            .write_all(&vec![b'a'; MAX_HEADER_BYTES + 10])
+            .unwrap();
+
+        let err = HeaderReader::new(&server)
+            .unwrap()
+            .read_segment(MAX_HEADER_BYTES)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            FileServerError::TooLarge {
+                limit: Limit::Header,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_unfinished_segment_times_out_and_restores_timeout() {
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=never_ends").unwrap();
+        let idle_timeout = Some(time::Duration::from_millis(50));
+        server.set_read_timeout(idle_timeout).unwrap();
+
+        let started = time::Instant::now();
+        let err = HeaderReader::new(&server)
+            .unwrap()
+            .read_segment(MAX_HEADER_BYTES)
+            .unwrap_err();
+        assert!(matches!(err, FileServerError::BadFrame { .. }));
+        assert!(started.elapsed() < HEADER_TIMEOUT);
+        assert_eq!(idle_timeout, server.read_timeout().unwrap());
+
+        client_end.write_all(b"|").unwrap();
+        let mut reader = HeaderReader::new(&server).unwrap();
+        assert_eq!(
+            b"|".to_vec(),
+            reader.read_segment(MAX_HEADER_BYTES).unwrap()
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use super::connection::Connection;
+use super::http::{spawn_http_listener, HttpResponse};
+use super::metrics::MetricsRegistry;
+use super::request_id::log_prefix;
+use super::server::{FileServer, FileServerError};
+use std::{
+    io::Write,
+    sync::{atomic::Ordering, Arc},
+};
+
+pub const SERVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+impl FileServer {
+    // Replies with [version length: u8][version][uptime in seconds: u64 big endian]
+    pub fn handle_ping(
+        mut stream: &dyn Connection,
+        _root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let uptime = metrics_registry.started_at.elapsed().as_secs();
+        let mut reply = vec![SERVER_VERSION.len() as u8];
+        reply.extend_from_slice(SERVER_VERSION.as_bytes());
+        reply.extend_from_slice(&uptime.to_be_bytes());
+        stream.write_all(&reply).unwrap_or_else(|error| {
+            println!(
+                "{}...Error while answering ping:{error}",
+                log_prefix(stream)
+            );
+        });
+    }
+
+    // Starts a tiny HTTP listener that answers every request with 200 while the
+    // server accepts connections and 503 once it is draining or shutdown has
+    // been requested, for load balancers and orchestrators that only speak HTTP.
+    pub fn start_readiness_probe(&self, address: &str, port: &str) -> Result<(), FileServerError> {
+        let shutdown_requested = self.shutdown_requested.clone();
+        let metrics = self.metrics.clone();
+        spawn_http_listener(address, port, move |_request_line| {
+            if shutdown_requested.load(Ordering::SeqCst) || metrics.is_draining() {
+                HttpResponse {
+                    status: 503,
+                    reason: "Service Unavailable",
+                    content_type: "text/plain",
+                    body: "draining".to_owned(),
+                }
+            } else {
+                HttpResponse::ok("text/plain", "ready".to_owned())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reader, CommandType};
+    use std::{io::Read, net::TcpStream};
+
+    #[test]
+    fn test_ping_and_readiness_probe() {
+        let root_dir = "temp_test_health_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+        let mut server = FileServer::new("127.0.0.1", "8039", 1, root_dir).unwrap();
+        server.register_handlers(&[(CommandType::Ping, FileServer::handle_ping)]);
+        server.start_readiness_probe("127.0.0.1", "8038").unwrap();
+        server.spawn();
+
+        let mut stream = TcpStream::connect("127.0.0.1:8039").unwrap();
+        stream.write_all(&[6]).unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        assert_eq!(SERVER_VERSION.len(), reply[0] as usize);
+        assert_eq!(
+            SERVER_VERSION.as_bytes(),
+            &reply[1..1 + SERVER_VERSION.len()]
+        );
+        assert_eq!(1 + SERVER_VERSION.len() + 8, reply.len());
+
+        let mut probe = TcpStream::connect("127.0.0.1:8038").unwrap();
+        probe.write_all(b"GET /ready HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        probe.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        reader::cleanup_server_file(root_dir);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::protocol::stats::HistogramSnapshot;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time,
+};
+
+// Counts how long something took into fixed buckets, lock free so recording
+// costs the hot path no more than the other counters do.
+pub struct Histogram {
+    // upper bounds in microseconds, ascending
+    bounds: &'static [u64],
+    // one count per bound, not cumulative, anything past the last bound is
+    // only in `count`
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, elapsed: time::Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        if let Some(bucket) = self.bounds.iter().position(|bound| micros <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let mut below = 0;
+        let buckets = self
+            .bounds
+            .iter()
+            .zip(&self.buckets)
+            .map(|(bound, bucket)| {
+                below += bucket.load(Ordering::Relaxed);
+                (*bound, below)
+            })
+            .collect();
+        HistogramSnapshot {
+            buckets,
+            count: self.count.load(Ordering::Relaxed),
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[1_000, 10_000]);
+        histogram.observe(time::Duration::from_micros(1_000));
+        histogram.observe(time::Duration::from_millis(5));
+        histogram.observe(time::Duration::from_secs(1));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(vec![(1_000, 1), (10_000, 2)], snapshot.buckets);
+        assert_eq!(3, snapshot.count);
+        assert_eq!(1_006_000, snapshot.sum_micros);
+    }
+}
@@ -0,0 +1,73 @@
+use super::listener;
+use super::server::FileServerError;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    thread, time,
+};
+
+// Bare minimum HTTP/1.1 for the side listeners (readiness, metrics), enough for
+// curl, load balancers and scrapers. Every connection gets one response and is
+// closed.
+pub struct HttpResponse {
+    pub status: u16,
+    pub reason: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn ok(content_type: &'static str, body: String) -> HttpResponse {
+        HttpResponse {
+            status: 200,
+            reason: "OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.reason,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+// Binds `address:port` and answers every request on a background thread with
+// whatever `respond` returns for the request line (e.g. "GET /metrics HTTP/1.1").
+pub fn spawn_http_listener<F>(address: &str, port: &str, respond: F) -> Result<(), FileServerError>
+where
+    F: Fn(&str) -> HttpResponse + Send + 'static,
+{
+    let port: u16 = port.parse().map_err(|_| FileServerError::InvalidPort {
+        port: port.to_owned(),
+    })?;
+    let listener = listener::resolve(address, port)
+        .and_then(|addrs| listener::bind_first(&addrs[..]))
+        .map_err(|source| FileServerError::Bind {
+            address: format!("{}:{}", address, port),
+            source,
+        })?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            answer(stream, &respond);
+        }
+    });
+    Ok(())
+}
+
+fn answer<F: Fn(&str) -> HttpResponse>(mut stream: TcpStream, respond: &F) {
+    let _ = stream.set_read_timeout(Some(time::Duration::from_millis(500)));
+    let mut request = [0u8; 1024];
+    let read = stream.read(&mut request).unwrap_or(0);
+    let request = String::from_utf8_lossy(&request[..read]);
+    let request_line = request.lines().next().unwrap_or("");
+    let _ = stream.write_all(&respond(request_line).to_bytes());
+}
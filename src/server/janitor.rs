@@ -0,0 +1,220 @@
+use super::clock::SharedClock;
+use super::threads::ThreadRegistry;
+use crate::reader;
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time,
+};
+
+// how often the trash is purged while sweeping is off
+const DEFAULT_JANITOR_INTERVAL_SECS: u64 = 60;
+// a `.part` file no upload wrote to for this long was given up on
+const STALE_PARTIAL_SECS: u64 = 3600;
+
+// How long trashed files are kept, see reader::trash_file. Unset limits keep
+// them until an admin restores them or empties the trash by hand.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrashPolicy {
+    pub max_age: Option<time::Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+// What the janitor removed since the server started, by kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct JanitorCounts {
+    pub partial_files: u64,
+    pub trash_entries: u64,
+    pub directories: u64,
+}
+
+// Background clean up of the served directory. While a trash policy is set
+// deletes go to the trash and the janitor purges what the policy no longer
+// keeps. Once sweeping is on it also removes `.part` files left by uploads
+// that stalled and empty directories below the root, user homes aside.
+#[derive(Default)]
+pub struct Janitor {
+    state: Arc<JanitorState>,
+    threads: ThreadRegistry,
+}
+
+#[derive(Default)]
+struct JanitorState {
+    trash: RwLock<Option<TrashPolicy>>,
+    // time between sweeps, None while sweeping is off
+    interval: RwLock<Option<time::Duration>>,
+    // directories never removed for being empty
+    keep: RwLock<Vec<PathBuf>>,
+    started: AtomicBool,
+    partial_files: AtomicU64,
+    trash_entries: AtomicU64,
+    directories: AtomicU64,
+    clock: SharedClock,
+}
+
+impl Janitor {
+    // A janitor whose thread is named and tracked by `threads` and waits
+    // between sweeps on `clock`.
+    pub(crate) fn with_threads(threads: &ThreadRegistry, clock: &SharedClock) -> Janitor {
+        Janitor {
+            threads: threads.clone(),
+            state: Arc::new(JanitorState {
+                clock: clock.clone(),
+                ..JanitorState::default()
+            }),
+        }
+    }
+
+    // None unlinks deleted files again, what is in the trash stays there.
+    pub fn set_trash(&self, root_dir: &'static str, policy: Option<TrashPolicy>) {
+        *self.state.trash.write().unwrap() = policy;
+        if policy.is_some() {
+            self.start(root_dir);
+        }
+    }
+
+    pub fn trash(&self) -> Option<TrashPolicy> {
+        *self.state.trash.read().unwrap()
+    }
+
+    // Sweeps every `interval`, None stops sweeping. The trash is still purged
+    // every minute while there is a trash policy.
+    pub fn set_interval(&self, root_dir: &'static str, interval: Option<time::Duration>) {
+        *self.state.interval.write().unwrap() = interval;
+        if interval.is_some() {
+            self.start(root_dir);
+        }
+    }
+
+    pub fn keep_directory(&self, path: PathBuf) {
+        self.state.keep.write().unwrap().push(path);
+    }
+
+    pub fn counts(&self) -> JanitorCounts {
+        JanitorCounts {
+            partial_files: self.state.partial_files.load(Ordering::Relaxed),
+            trash_entries: self.state.trash_entries.load(Ordering::Relaxed),
+            directories: self.state.directories.load(Ordering::Relaxed),
+        }
+    }
+
+    // One sweep right away, on the calling thread.
+    pub fn sweep(&self, root_dir: &str) -> JanitorCounts {
+        self.state.sweep(root_dir)
+    }
+
+    fn start(&self, root_dir: &'static str) {
+        if self.state.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let state = Arc::downgrade(&self.state);
+        self.threads.spawn("janitor", move || loop {
+            // ends with the registry the janitor belongs to
+            let Some((interval, clock)) = state
+                .upgrade()
+                .map(|state| (state.interval(), state.clock.clone()))
+            else {
+                return;
+            };
+            clock.sleep(interval);
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            state.sweep(root_dir);
+        });
+    }
+}
+
+impl JanitorState {
+    fn interval(&self) -> time::Duration {
+        self.interval
+            .read()
+            .unwrap()
+            .unwrap_or(time::Duration::from_secs(DEFAULT_JANITOR_INTERVAL_SECS))
+    }
+
+    // What this sweep removed.
+    fn sweep(&self, root_dir: &str) -> JanitorCounts {
+        let mut swept = JanitorCounts::default();
+        if let Some(policy) = *self.trash.read().unwrap() {
+            match reader::purge_trash(root_dir, policy.max_age, policy.max_bytes) {
+                Ok(purged) => swept.trash_entries = purged.len() as u64,
+                Err(err) => println!("...Janitor could not purge the trash:{err}"),
+            }
+        }
+        if self.interval.read().unwrap().is_some() {
+            let max_idle = time::Duration::from_secs(STALE_PARTIAL_SECS);
+            match reader::remove_stale_partial_files(root_dir, max_idle) {
+                Ok(removed) => swept.partial_files = removed as u64,
+                Err(err) => println!("...Janitor could not remove stale uploads:{err}"),
+            }
+            let keep = self.keep.read().unwrap();
+            match reader::remove_empty_directories(root_dir, &keep) {
+                Ok(removed) => swept.directories = removed as u64,
+                Err(err) => println!("...Janitor could not remove empty directories:{err}"),
+            }
+        }
+
+        self.partial_files
+            .fetch_add(swept.partial_files, Ordering::Relaxed);
+        self.trash_entries
+            .fetch_add(swept.trash_entries, Ordering::Relaxed);
+        self.directories
+            .fetch_add(swept.directories, Ordering::Relaxed);
+        if swept != JanitorCounts::default() {
+            println!(
+                "...Janitor removed {} stale upload(s), {} trashed file(s) and {} empty directories",
+                swept.partial_files, swept.trash_entries, swept.directories
+            );
+        }
+        swept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_sweep_spares_homes_and_live_uploads() {
+        let root_dir = "temp_test_janitor_root_dir";
+        let dir = reader::ServeDir::create(root_dir).unwrap();
+        fs::create_dir_all(dir.path().join("old").join("empty")).unwrap();
+        fs::create_dir_all(dir.path().join("alice")).unwrap();
+        fs::write(dir.path().join("upload.bin.part"), b"in progress").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"trashed").unwrap();
+        reader::trash_file("notes.txt", root_dir).unwrap();
+
+        let janitor = Janitor::default();
+        janitor.keep_directory(dir.path().join("alice"));
+        // sweeping is off, only the trash is looked after
+        *janitor.state.trash.write().unwrap() = Some(TrashPolicy {
+            max_age: None,
+            max_bytes: Some(0),
+        });
+        let swept = janitor.sweep(root_dir);
+        assert_eq!(1, swept.trash_entries);
+        assert!(dir.path().join("old").exists());
+
+        *janitor.state.interval.write().unwrap() = Some(time::Duration::from_secs(60));
+        let swept = janitor.sweep(root_dir);
+        // old/empty, old and the emptied trash, not the home
+        assert_eq!(3, swept.directories);
+        assert!(dir.path().join("alice").is_dir());
+        // written to just now
+        assert_eq!(0, swept.partial_files);
+        assert!(dir.path().join("upload.bin.part").exists());
+        assert_eq!(
+            JanitorCounts {
+                partial_files: 0,
+                trash_entries: 1,
+                directories: 3,
+            },
+            janitor.counts()
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use super::connection::Connection;
+use super::metrics::MetricsRegistry;
+use super::request_id::{log_prefix, with_request_id};
+use super::serve_policy::is_denial;
+use super::server::{FileServer, FileServerError};
+use super::types::CommandType;
+use crate::protocol::frame::encode_frame_header;
+use crate::protocol::MAX_HEADER_BYTES;
+use crate::reader::{FileSource, Hashing};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::Shutdown,
+    sync::{atomic::Ordering, Arc},
+    time,
+};
+
+// Replies on a keep-alive connection are framed, the statuses and header
+// layout live with the rest of the wire format in crate::protocol.
+pub use crate::protocol::frame::{
+    FRAME_BUSY, FRAME_CHECKSUM, FRAME_CHUNK, FRAME_CONTENT_TYPE, FRAME_COPY, FRAME_CRC_CHUNK,
+    FRAME_DENIED, FRAME_ENCODING, FRAME_END, FRAME_ERROR, FRAME_ETAG, FRAME_NOT_FOUND,
+    FRAME_NOT_MODIFIED, FRAME_OK, FRAME_QUEUED,
+};
+
+// What a keep-alive session asked to get along with every single file
+// download, plain downloads get neither.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct DownloadExtras {
+    pub checksum: bool,
+    pub content_type: bool,
+    pub etag: bool,
+    pub gzip: bool,
+}
+
+pub fn write_frame_header(mut stream: &dyn Connection, status: u8, length: u64) -> io::Result<()> {
+    stream.write_all(&encode_frame_header(status, length))
+}
+
+// Reports a file a client asked for that could not be opened: FRAME_NOT_FOUND
+// when there is no such file, FRAME_DENIED when it is not served, the reason when its name is not allowed and a
+// generic error frame otherwise. OS error details only go to the server log.
+pub fn write_open_error_frame(
+    stream: &dyn Connection,
+    file_name: &str,
+    err: io::Error,
+) -> io::Result<()> {
+    match err.kind() {
+        ErrorKind::NotFound => write_not_found_frame(stream, file_name),
+        ErrorKind::PermissionDenied if is_denial(&err) => {
+            write_denied_frame(stream, err.to_string())
+        }
+        ErrorKind::InvalidInput => write_error_frame(stream, err.to_string()),
+        _ => {
+            println!(
+                "{}...Error opening {}: {err}",
+                log_prefix(stream),
+                file_name
+            );
+            write_error_frame(stream, format!("Server error opening {}", file_name))
+        }
+    }
+}
+
+pub fn write_denied_frame(mut stream: &dyn Connection, reason: String) -> io::Result<()> {
+    println!("{}...Reporting to client:{reason}", log_prefix(stream));
+    let reason = with_request_id(stream, reason);
+    write_frame_header(stream, FRAME_DENIED, reason.len() as u64)?;
+    stream.write_all(reason.as_bytes())
+}
+
+pub fn write_not_found_frame(mut stream: &dyn Connection, file_name: &str) -> io::Result<()> {
+    let reason = FileServerError::NotFound {
+        file: file_name.to_owned(),
+    }
+    .to_string();
+    println!("{}...Reporting to client:{reason}", log_prefix(stream));
+    let reason = with_request_id(stream, reason);
+    write_frame_header(stream, FRAME_NOT_FOUND, reason.len() as u64)?;
+    stream.write_all(reason.as_bytes())
+}
+
+// The reason goes out with the connection's request id, if it has one.
+pub fn write_error_frame(mut stream: &dyn Connection, err_string: String) -> io::Result<()> {
+    println!(
+        "{}...Error reporting to keep-alive client:{err_string}",
+        log_prefix(stream)
+    );
+    let err_string = with_request_id(stream, err_string);
+    write_frame_header(stream, FRAME_ERROR, err_string.len() as u64)?;
+    stream.write_all(err_string.as_bytes())
+}
+
+// how long a client that is hung up on gets to finish sending what it pipelined
+const CLOSING_DRAIN_TIMEOUT: time::Duration = time::Duration::from_millis(50);
+
+// Sends an error frame and closes, for a request whose rest is not worth
+// reading. Closing with bytes the client pipelined still unread would reset
+// the connection and could lose the frame, so a header's worth of them is
+// read and thrown away first.
+pub(crate) fn write_closing_error_frame(
+    stream: &dyn Connection,
+    err_string: String,
+) -> io::Result<()> {
+    write_error_frame(stream, err_string)?;
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(CLOSING_DRAIN_TIMEOUT));
+    let mut unread = [0u8; MAX_HEADER_BYTES];
+    let mut left = MAX_HEADER_BYTES;
+    while left > 0 {
+        match stream.read(&mut unread[..left]) {
+            Ok(n) if n > 0 => left -= n,
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+pub fn write_busy_frame(mut stream: &dyn Connection, retry_after_secs: u64) -> io::Result<()> {
+    write_frame_header(stream, FRAME_BUSY, 8)?;
+    stream.write_all(&retry_after_secs.to_be_bytes())
+}
+
+pub fn write_queued_frame(mut stream: &dyn Connection, position: u64) -> io::Result<()> {
+    write_frame_header(stream, FRAME_QUEUED, 8)?;
+    stream.write_all(&position.to_be_bytes())
+}
+
+impl FileServer {
+    // Serves commands on one connection until the client sends Quit, hangs up,
+    // or stays quiet for longer than the stream read timeout.
+    pub fn handle_keep_alive_session(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        // checksum, content type and ETag frames around downloads
+        let mut extras = DownloadExtras::default();
+        loop {
+            let mut client_command_byte: [u8; 1] = [0];
+            match stream.read(&mut client_command_byte) {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    println!(
+                        "{}...Closing idle keep-alive connection",
+                        log_prefix(stream)
+                    );
+                    return;
+                }
+                Err(_) => return,
+            }
+
+            let parsed = CommandType::try_from(client_command_byte[0]);
+            // a session is timed a command at a time, not as one long request
+            let timed = parsed.as_ref().ok().copied();
+            let started = time::Instant::now();
+            let result = match parsed {
+                Ok(CommandType::Quit) => return,
+                Ok(CommandType::Checksums) => {
+                    extras.checksum = true;
+                    write_frame_header(stream, FRAME_OK, 0)
+                }
+                Ok(CommandType::ContentTypes) => {
+                    extras.content_type = true;
+                    write_frame_header(stream, FRAME_OK, 0)
+                }
+                Ok(CommandType::ETags) => {
+                    extras.etag = true;
+                    write_frame_header(stream, FRAME_OK, 0)
+                }
+                Ok(CommandType::AcceptGzip) => {
+                    extras.gzip = true;
+                    write_frame_header(stream, FRAME_OK, 0)
+                }
+                Ok(CommandType::Download) => {
+                    Self::framed_download(stream, root_dir, &metrics_registry, extras)
+                }
+                Ok(CommandType::ConditionalDownload) => {
+                    Self::framed_conditional_download(stream, root_dir, &metrics_registry, extras)
+                }
+                Ok(CommandType::Upload) => Self::framed_upload(stream, root_dir, &metrics_registry),
+                Ok(CommandType::Delete) => Self::framed_delete(stream, root_dir, &metrics_registry),
+                Ok(CommandType::List) => Self::framed_list(stream, root_dir, &metrics_registry),
+                Ok(CommandType::ListPage) => {
+                    Self::framed_list_page(stream, root_dir, &metrics_registry)
+                }
+                Ok(CommandType::Transfers) => Self::framed_transfers(stream, &metrics_registry),
+                Ok(CommandType::BatchDownload) => {
+                    Self::framed_batch_download(stream, root_dir, &metrics_registry)
+                }
+                Ok(CommandType::Archive) => {
+                    Self::framed_archive(stream, root_dir, &metrics_registry)
+                }
+                Ok(CommandType::RangeDownload) => {
+                    Self::framed_range_download(stream, root_dir, &metrics_registry, extras)
+                }
+                Ok(CommandType::Delta) => Self::framed_delta(stream, root_dir, &metrics_registry),
+                Ok(CommandType::ReliableDownload) => {
+                    Self::framed_reliable_download(stream, root_dir, &metrics_registry)
+                }
+                Ok(CommandType::FlashDownload) => {
+                    Self::framed_flash_download(stream, root_dir, &metrics_registry)
+                }
+                Ok(CommandType::Stat) => Self::framed_stat(stream, root_dir, &metrics_registry),
+                Ok(CommandType::Locate) => Self::framed_locate(stream, &metrics_registry),
+                Ok(CommandType::Sync) => Self::framed_sync(stream, root_dir, &metrics_registry),
+                Ok(command) => write_error_frame(
+                    stream,
+                    format!("{:?} is not supported on a keep-alive connection", command),
+                ),
+                Err(err) => {
+                    // an unknown byte means we lost track of the framing, give up
+                    let _ = write_error_frame(stream, err.to_string());
+                    return;
+                }
+            };
+
+            if let Some(command) = timed {
+                metrics_registry.record_request(command, started.elapsed());
+            }
+            if result.is_err() {
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn framed_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+        extras: DownloadExtras,
+    ) -> io::Result<()> {
+        let file_name = match Self::read_file_request(stream, &metrics_registry.limits()) {
+            Ok(file_name) => file_name,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::Download, &file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        Self::send_framed_file(stream, &file_name, root_dir, metrics_registry, extras)
+    }
+
+    // Sends one file as an OK frame, or an error frame if it can not be opened.
+    pub(crate) fn send_framed_file(
+        stream: &dyn Connection,
+        file_name: &str,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+        extras: DownloadExtras,
+    ) -> io::Result<()> {
+        let file_reader = match Self::open_served_file(file_name, root_dir, metrics_registry) {
+            Ok(file_reader) => file_reader,
+            Err(err) => return write_open_error_frame(stream, file_name, err),
+        };
+        let (mut file_reader, encoding) = match extras.gzip {
+            true => match Self::open_gzip_sibling(stream, file_name, root_dir, metrics_registry) {
+                Some(sibling) => (sibling, Some("gzip")),
+                None => (file_reader, None),
+            },
+            false => (file_reader, None),
+        };
+        let length = file_reader.len();
+
+        metrics_registry.record_download(file_name.to_owned());
+
+        if extras.content_type {
+            Self::write_content_type_frame(stream, file_name, root_dir, metrics_registry)?;
+        }
+        if extras.etag {
+            let etag = Self::served_etag(file_name, root_dir, metrics_registry)?;
+            write_frame_header(stream, FRAME_ETAG, etag.len() as u64)?;
+            let mut stream = stream;
+            stream.write_all(etag.as_bytes())?;
+        }
+        if let Some(encoding) = encoding {
+            metrics_registry
+                .precompressed_downloads
+                .fetch_add(1, Ordering::Relaxed);
+            write_frame_header(stream, FRAME_ENCODING, encoding.len() as u64)?;
+            let mut stream = stream;
+            stream.write_all(encoding.as_bytes())?;
+        }
+        write_frame_header(stream, FRAME_OK, length)?;
+        let sent = Self::stream_frame_body(
+            &mut file_reader,
+            stream,
+            metrics_registry,
+            file_name,
+            extras.checksum,
+        )?;
+        if sent != length {
+            // the file changed under us, the frame length is now a lie
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "file size changed mid transfer",
+            ));
+        }
+        Ok(())
+    }
+
+    // The gzip precompressed sibling of `file_name`, `foo.txt.gz` next to
+    // `foo.txt`, when the server serves those. A sibling older than the file
+    // was left behind by an edit and is not used, nor is one the serve
+    // patterns deny.
+    fn open_gzip_sibling(
+        stream: &dyn Connection,
+        file_name: &str,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Option<FileSource> {
+        if !metrics_registry.serve_precompressed() {
+            return None;
+        }
+        let sibling = format!("{}.gz", file_name);
+        let compressed = Self::stored_file(&sibling, root_dir, metrics_registry).ok()?;
+        let original = Self::stored_file(file_name, root_dir, metrics_registry).ok()?;
+        if compressed.modified < original.modified {
+            println!(
+                "{}...{} is older than {}, sending the file itself",
+                log_prefix(stream),
+                sibling,
+                file_name
+            );
+            return None;
+        }
+        Self::open_served_file(&sibling, root_dir, metrics_registry).ok()
+    }
+
+    pub(crate) fn write_content_type_frame(
+        mut stream: &dyn Connection,
+        file_name: &str,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let content_type = Self::served_content_type(file_name, root_dir, metrics_registry);
+        write_frame_header(stream, FRAME_CONTENT_TYPE, content_type.len() as u64)?;
+        stream.write_all(content_type.as_bytes())
+    }
+
+    // Streams the body of an OK frame, followed by a checksum frame of what
+    // was sent when `checksum` is set.
+    pub(crate) fn stream_frame_body(
+        file_reader: &mut impl Read,
+        mut stream: &dyn Connection,
+        metrics_registry: &MetricsRegistry,
+        file_name: &str,
+        checksum: bool,
+    ) -> io::Result<u64> {
+        if !checksum {
+            return Self::stream_file(file_reader, stream, metrics_registry, file_name);
+        }
+        let mut hashing = Hashing::new(file_reader);
+        let sent = Self::stream_file(&mut hashing, stream, metrics_registry, file_name)?;
+        let digest = hashing.sha256_hex();
+        write_frame_header(stream, FRAME_CHECKSUM, digest.len() as u64)?;
+        stream.write_all(digest.as_bytes())?;
+        Ok(sent)
+    }
+}
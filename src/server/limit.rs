@@ -0,0 +1,210 @@
+use super::connection::Connection;
+use super::listener::ListenerPolicy;
+use super::request_id::RequestId;
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{Shutdown, SocketAddr},
+    sync::{Arc, Condvar, Mutex},
+    time,
+};
+
+// What the accept loop does with a new connection once `max_connections`
+// sockets are open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // stop accepting until a connection closes, clients wait in the OS backlog
+    #[default]
+    Queue,
+    // accept it, answer "too many connections" and hang up
+    Reject,
+}
+
+// Caps how many client sockets are open at once, whatever they are doing.
+// The worker pool only bounds the work in flight, stats subscribers and
+// connections waiting for a worker still hold a socket each.
+pub struct ConnectionLimit {
+    max: Option<usize>,
+    policy: OverflowPolicy,
+    // every counted connection by request id, with who is on the other end and since when
+    open: Mutex<BTreeMap<RequestId, (String, time::Instant)>>,
+    closed: Condvar,
+}
+
+// One connection counted against the limit, as listed by `connections`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub id: RequestId,
+    pub peer: String,
+    pub open_for: time::Duration,
+}
+
+// A connection counted against the limit until it is dropped. Connections
+// turned away over the limit are not counted (no `limit`) but get an id all
+// the same, their "too many connections" reply carries it.
+struct CountedConnection {
+    inner: Box<dyn Connection>,
+    id: RequestId,
+    limit: Option<Arc<ConnectionLimit>>,
+}
+
+impl Drop for CountedConnection {
+    fn drop(&mut self) {
+        if let Some(limit) = &self.limit {
+            limit.open.lock().unwrap().remove(&self.id);
+            limit.closed.notify_all();
+        }
+    }
+}
+
+impl Connection for CountedConnection {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        self.inner.read_timeout()
+    }
+
+    fn peer(&self) -> String {
+        self.inner.peer()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.inner.peer_identity()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn request_id(&self) -> Option<RequestId> {
+        Some(self.id)
+    }
+
+    fn policy(&self) -> ListenerPolicy {
+        self.inner.policy()
+    }
+}
+
+impl ConnectionLimit {
+    // None leaves the number of connections unbounded
+    pub fn new(max: Option<usize>, policy: OverflowPolicy) -> ConnectionLimit {
+        ConnectionLimit {
+            max,
+            policy,
+            open: Mutex::new(BTreeMap::new()),
+            closed: Condvar::new(),
+        }
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        self.max
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.lock().unwrap().len()
+    }
+
+    // Connections open right now, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.open
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, (peer, opened_at))| ConnectionInfo {
+                id: *id,
+                peer: peer.clone(),
+                open_for: opened_at.elapsed(),
+            })
+            .collect()
+    }
+
+    // Gives `stream` a request id and counts it as open until it is dropped.
+    // Over the limit it is handed back with its id but not counted.
+    pub fn admit(
+        self: &Arc<Self>,
+        stream: Box<dyn Connection>,
+    ) -> Result<Box<dyn Connection>, Box<dyn Connection>> {
+        // one turned away before keeps its id
+        let id = stream.request_id().unwrap_or_else(RequestId::next);
+        let mut open = self.open.lock().unwrap();
+        if self.max.is_some_and(|max| open.len() >= max) {
+            return Err(Box::new(CountedConnection {
+                inner: stream,
+                id,
+                limit: None,
+            }));
+        }
+        open.insert(id, (stream.peer(), time::Instant::now()));
+        Ok(Box::new(CountedConnection {
+            inner: stream,
+            id,
+            limit: Some(self.clone()),
+        }))
+    }
+
+    // Waits up to `timeout` for room under the limit, reports whether there is.
+    pub fn wait_for_room(&self, timeout: time::Duration) -> bool {
+        let Some(max) = self.max else {
+            return true;
+        };
+        let open = self.open.lock().unwrap();
+        let (open, _) = self
+            .closed
+            .wait_timeout_while(open, timeout, |open| open.len() >= max)
+            .unwrap();
+        open.len() < max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection::duplex;
+
+    #[test]
+    fn test_dropping_a_connection_frees_its_place() {
+        let limit = Arc::new(ConnectionLimit::new(Some(1), OverflowPolicy::Reject));
+        let (first, _first_peer) = duplex();
+        let (second, _second_peer) = duplex();
+
+        let admitted = limit.admit(Box::new(first)).ok().unwrap();
+        assert_eq!(1, limit.open());
+        assert_eq!(
+            vec![admitted.request_id().unwrap()],
+            limit.connections().iter().map(|c| c.id).collect::<Vec<_>>()
+        );
+        let second = limit.admit(Box::new(second)).err().unwrap();
+        let second_id = second.request_id();
+        assert!(second_id.is_some());
+        assert_ne!(admitted.request_id(), second_id);
+        assert!(!limit.wait_for_room(time::Duration::from_millis(10)));
+
+        drop(admitted);
+        assert!(limit.wait_for_room(time::Duration::from_millis(10)));
+        let second = limit.admit(second).ok().unwrap();
+        assert_eq!(second_id, second.request_id());
+        assert_eq!(1, limit.open());
+    }
+}
@@ -0,0 +1,253 @@
+use super::connection::Connection;
+use socket2::{Domain, SockRef, Socket, Type};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    io,
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs,
+    },
+    path::PathBuf,
+    time,
+};
+
+// same backlog std::net::TcpListener::bind uses
+const LISTEN_BACKLOG: i32 = 128;
+
+// A socket the server accepts connections on.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+// What connections that came in on a listener may do, e.g. a public port
+// that only serves downloads next to an internal one that takes uploads.
+// See FileServer::add_listener_with_policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ListenerPolicy {
+    // uploads are refused, like the server wide read-only mode
+    pub read_only: bool,
+}
+
+impl ListenerPolicy {
+    pub fn read_only() -> ListenerPolicy {
+        ListenerPolicy { read_only: true }
+    }
+}
+
+// A connection tagged with the policy of the listener it came in on.
+struct PolicedConnection {
+    inner: Box<dyn Connection>,
+    policy: ListenerPolicy,
+}
+
+impl Connection for PolicedConnection {
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn read_timeout(&self) -> io::Result<Option<time::Duration>> {
+        self.inner.read_timeout()
+    }
+
+    fn peer(&self) -> String {
+        self.inner.peer()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.inner.peer_identity()
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn policy(&self) -> ListenerPolicy {
+        self.policy
+    }
+}
+
+// Where a listener can be reached, used to wake a blocked accept().
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    // Opens and drops a connection to the listener.
+    pub fn poke(&self) {
+        match self {
+            ListenAddr::Tcp(addr) => {
+                // a wildcard address can be listened on but not connected to
+                let ip = match addr.ip() {
+                    IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    ip => ip,
+                };
+                let _ = TcpStream::connect(SocketAddr::new(ip, addr.port()));
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let _ = UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+impl Listener {
+    pub fn listen_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(ListenAddr::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+
+    // Makes a thread blocked in accept() on this listener give up, where a
+    // poke could land on a sibling bound to the same port. Linux fails the
+    // accept, other systems may not.
+    pub fn stop_accepting(&self) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => SockRef::from(listener).shutdown(Shutdown::Read),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => SockRef::from(listener).shutdown(Shutdown::Read),
+        }
+    }
+
+    // Waits for the next connection. It carries `policy`, the policy of this
+    // listener, unless that is the default.
+    pub fn accept(&self, policy: ListenerPolicy) -> io::Result<Box<dyn Connection>> {
+        let inner: Box<dyn Connection> = match self {
+            Listener::Tcp(listener) => Box::new(listener.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Box::new(listener.accept()?.0),
+        };
+        if policy == ListenerPolicy::default() {
+            return Ok(inner);
+        }
+        Ok(Box::new(PolicedConnection { inner, policy }))
+    }
+}
+
+// Turns an address and port into socket addresses. IP literals are taken as
+// they are, so IPv6 works with or without brackets ("::1", "[::1]"), anything
+// else is resolved as a host name.
+pub fn resolve(address: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let literal = address.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = literal.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    Ok((address, port).to_socket_addrs()?.collect())
+}
+
+// Binds a listener. An IPv6 listener is dual-stack, so `[::]` also takes IPv4
+// connections, unless `v6_only` is set because an IPv4 listener already holds
+// the port.
+pub fn bind(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    bind_socket(addr, v6_only, false)
+}
+
+// Binds `count` listeners to `addr` with SO_REUSEPORT. The kernel spreads new
+// connections over them, so each can get an accept thread of its own.
+#[cfg(target_os = "linux")]
+pub fn bind_reuse_port(addr: SocketAddr, count: usize) -> io::Result<Vec<TcpListener>> {
+    let first = bind_socket(addr, false, true)?;
+    // a port 0 address picked a port, the rest have to join that one
+    let addr = first.local_addr()?;
+    let mut listeners = vec![first];
+    for _ in 1..count {
+        listeners.push(bind_socket(addr, false, true)?);
+    }
+    Ok(listeners)
+}
+
+fn bind_socket(addr: SocketAddr, v6_only: bool, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(target_os = "linux")]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(target_os = "linux"))]
+    let _ = reuse_port;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+// Binds the first address that works, like TcpListener::bind does.
+pub fn bind_first(addrs: impl ToSocketAddrs) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for addr in addrs.to_socket_addrs()? {
+        match bind(addr, false) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+// Binds a Unix domain socket at `path`. A socket file left behind by a server
+// that is gone is replaced, one somebody still listens on is not.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    UnixListener::bind(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_ip_literals() {
+        assert_eq!(
+            vec!["[::1]:80".parse::<SocketAddr>().unwrap()],
+            resolve("::1", 80).unwrap()
+        );
+        assert_eq!(resolve("::1", 80).unwrap(), resolve("[::1]", 80).unwrap());
+        assert_eq!(
+            vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()],
+            resolve("127.0.0.1", 80).unwrap()
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use super::connection::Connection;
+use super::keep_alive::{write_error_frame, write_frame_header, FRAME_END, FRAME_OK};
+use super::metrics::MetricsRegistry;
+use super::server::{FileServer, FileServerError};
+use super::types::{CommandType, ListQuery, ListSort};
+use crate::protocol;
+use crate::reader::ServedFile;
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    io::{self, ErrorKind, Write},
+    sync::Arc,
+    time,
+};
+
+// files in a page when the request leaves the limit at 0, and the most a
+// page holds whatever the request asks for
+pub const DEFAULT_LIST_PAGE: usize = 1000;
+pub const MAX_LIST_PAGE: usize = 10_000;
+
+// Where a file sits in a listing: by its sort key (0 for everyone when sorting
+// by name), then by name. Page tokens are `<key>/<name>` of the last file sent.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    key: u128,
+    name: String,
+}
+
+impl Position {
+    fn of(file: &ServedFile, sort: ListSort) -> Position {
+        let key = match sort {
+            ListSort::Name => 0,
+            ListSort::Modified => file
+                .modified
+                .duration_since(time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos()),
+            ListSort::Size => file.size as u128,
+        };
+        Position {
+            key,
+            name: file.name.clone(),
+        }
+    }
+
+    fn token(&self) -> String {
+        format!("{}/{}", self.key, self.name)
+    }
+
+    fn parse_token(token: &str) -> Option<Position> {
+        let (key, name) = token.split_once('/')?;
+        Some(Position {
+            key: key.parse().ok()?,
+            name: name.to_owned(),
+        })
+    }
+}
+
+// A file ranked in the order the query asks for, greatest last.
+struct Ranked {
+    position: Position,
+    descending: bool,
+    size: u64,
+}
+
+impl Ranked {
+    fn order(&self, other: &Position) -> Ordering {
+        match self.descending {
+            false => self.position.cmp(other),
+            true => other.cmp(&self.position),
+        }
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Ranked) -> bool {
+        self.position == other.position
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Ranked) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Ranked) -> Ordering {
+        self.order(&other.position)
+    }
+}
+
+// One page of a listing, in order, and the token of the page after it.
+pub(crate) struct Page {
+    files: Vec<(String, u64)>,
+    next: Option<String>,
+}
+
+// Walks `files`, the directory, once keeping only the `limit` first files past
+// the page token, so a page costs memory for the page whatever the directory
+// holds.
+pub(crate) fn list_page(
+    files: impl Iterator<Item = io::Result<ServedFile>>,
+    query: &ListQuery,
+    listed: impl Fn(&str) -> bool,
+) -> Result<Page, FileServerError> {
+    let after = match &query.page {
+        Some(token) => Some(
+            Position::parse_token(token)
+                .ok_or_else(|| FileServerError::bad_frame(format!("invalid page {:?}", token)))?,
+        ),
+        None => None,
+    };
+    let limit = match query.limit {
+        0 => DEFAULT_LIST_PAGE,
+        limit => limit.min(MAX_LIST_PAGE),
+    };
+
+    // a max-heap, the last file of the page so far is on top
+    let mut page = BinaryHeap::with_capacity(limit + 1);
+    let mut more = false;
+    for file in files {
+        let file = file?;
+        if !file.name.starts_with(&query.prefix) || !listed(&file.name) {
+            continue;
+        }
+        let ranked = Ranked {
+            position: Position::of(&file, query.sort),
+            descending: query.descending,
+            size: file.size,
+        };
+        if after
+            .as_ref()
+            .is_some_and(|after| ranked.order(after) != Ordering::Greater)
+        {
+            continue;
+        }
+        page.push(ranked);
+        if page.len() > limit {
+            page.pop();
+            more = true;
+        }
+    }
+
+    let page = page.into_sorted_vec();
+    let next = match (more, page.last()) {
+        (true, Some(last)) => Some(last.position.token()),
+        _ => None,
+    };
+    Ok(Page {
+        files: page
+            .into_iter()
+            .map(|ranked| (ranked.position.name, ranked.size))
+            .collect(),
+        next,
+    })
+}
+
+impl FileServer {
+    // Request: prefix=<p>|sort=<[-]name|mtime|size>|limit=<n>|page=<token>|,
+    // see ListQuery. The token is empty for the first page.
+    // Reply: one OK frame per file holding `name\tsize`, then an END frame
+    // holding the token of the next page, empty after the last one.
+    pub fn handle_list_page(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_list_page(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_list_page(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let limits = metrics_registry.limits();
+        let mut segments: [Vec<u8>; 4] = Default::default();
+        let query = segments
+            .iter_mut()
+            .try_for_each(|segment| {
+                *segment = Self::read_request_segment(stream, &limits)?;
+                Ok(())
+            })
+            .and_then(|_| {
+                protocol::parse_list_query([&segments[0], &segments[1], &segments[2], &segments[3]])
+            });
+        let query = match query {
+            Ok(query) => query,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+
+        // listed files are authorized like a plain List
+        let listed = |name: &str| {
+            metrics_registry
+                .authorize(stream, CommandType::List, name)
+                .is_ok()
+        };
+        let page = match Self::stored_files(root_dir, metrics_registry)
+            .map_err(FileServerError::Io)
+            .and_then(|files| list_page(files, &query, listed))
+        {
+            Ok(page) => page,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        for (name, size) in page.files {
+            let line = format!("{}\t{}", name, metrics_registry.served_len(size));
+            write_frame_header(stream, FRAME_OK, line.len() as u64)?;
+            stream.write_all(line.as_bytes())?;
+        }
+        let next = page.next.unwrap_or_default();
+        write_frame_header(stream, FRAME_END, next.len() as u64)?;
+        stream.write_all(next.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{served_files, ServeDir};
+    use std::fs;
+
+    fn names(page: &Page) -> Vec<&str> {
+        page.files.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_pages_follow_the_sort_order() {
+        let root_dir = "temp_test_list_page_root_dir";
+        let dir = ServeDir::create(root_dir).unwrap();
+        for (name, size) in [("log-a", 3), ("log-b", 1), ("log-c", 2), ("notes", 9)] {
+            fs::write(dir.path().join(name), vec![b'x'; size]).unwrap();
+        }
+        fs::write(dir.path().join("log-d.part"), b"uploading").unwrap();
+
+        let mut query = ListQuery {
+            prefix: "log-".to_owned(),
+            limit: 2,
+            ..Default::default()
+        };
+        let first = list_page(served_files(root_dir).unwrap(), &query, |_| true).unwrap();
+        assert_eq!(vec!["log-a", "log-b"], names(&first));
+        query.page = first.next;
+        let second = list_page(served_files(root_dir).unwrap(), &query, |_| true).unwrap();
+        assert_eq!(vec!["log-c"], names(&second));
+        assert_eq!(None, second.next);
+
+        let by_size = ListQuery {
+            sort: ListSort::Size,
+            descending: true,
+            ..Default::default()
+        };
+        let page = list_page(served_files(root_dir).unwrap(), &by_size, |name| {
+            name != "notes"
+        })
+        .unwrap();
+        assert_eq!(vec!["log-a", "log-c", "log-b"], names(&page));
+
+        query.page = Some("garbage".to_owned());
+        assert!(list_page(served_files(root_dir).unwrap(), &query, |_| true).is_err());
+    }
+}
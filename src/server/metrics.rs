@@ -0,0 +1,917 @@
+use super::audit::AuditLog;
+use super::authorizer::{AuthRequest, Authorizer};
+use super::bandwidth::{IpBandwidth, IpUsage};
+use super::clock::{Clock, SharedClock};
+use super::cluster::Cluster;
+use super::connection::{describe_peer, Connection};
+use super::file_locks::FileLocks;
+use super::histogram::Histogram;
+use super::janitor::Janitor;
+use super::observer::{ErrorEvent, EventObserver, Observer, TransferEvent};
+use super::pool::{TransferClass, WorkerPool};
+use super::replication::Replication;
+use super::request_id::{log_prefix, RequestId};
+use super::ring::HashRing;
+use super::serve_policy::ServePolicy;
+use super::server::FileServerError;
+use super::threads::ThreadRegistry;
+use super::throttle::{Throttle, TokenBucket};
+use super::types::CommandType;
+use super::watch::WatchHub;
+use super::watchdog::Watchdog;
+use crate::archive::embedded::EmbeddedArchive;
+use crate::cache::{ETagCache, HotFileCache, SharedMappings};
+use crate::protocol::stats::{ActiveTransfer, PoolStats, QueueStats, StatsSnapshot, TransferStats};
+use crate::protocol::Limits;
+use crate::reader::{plaintext_len, served_directory_path, AtRestKey};
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    time,
+};
+
+// Shared counters every handler gets a handle to.
+// Plain counters are atomics so the hot download path never waits on a lock
+// just to bump a number.
+pub struct MetricsRegistry {
+    pub started_at: time::Instant,
+    pub file_stat: RwLock<HashMap<String, i64>>,
+    pub bytes_served: AtomicU64,
+    pub bytes_per_file: RwLock<HashMap<String, u64>>,
+    pub total_connections: AtomicU64,
+    pub active_transfers: AtomicU64,
+    pub transfer_progress: RwLock<HashMap<u64, TransferProgress>>,
+    pub handler_panics: AtomicU64,
+    // upload and sync manifest bodies clients sent, and their bytes
+    pub request_bodies: AtomicU64,
+    pub request_body_bytes: AtomicU64,
+    // connections turned away with a BUSY frame, see BusyPolicy::Shed
+    pub shed_connections: AtomicU64,
+    pub stats_subscribers: AtomicU64,
+    pub stats_subscribers_evicted: AtomicU64,
+    // ReliableDownload chunks sent again because the client asked or went quiet
+    pub chunks_resent: AtomicU64,
+    // bytes of Delta replies the client took from its own copy instead
+    pub delta_bytes_copied: AtomicU64,
+    // downloads served from a gzip precompressed sibling of the file
+    pub precompressed_downloads: AtomicU64,
+    // how long connections waited for a worker once their command was read
+    pub dispatch_wait: Histogram,
+    // the same for downloads sorted into a size class
+    small_dispatch_wait: Histogram,
+    large_dispatch_wait: Histogram,
+    // how long each command's requests took, keyed by the command
+    request_durations: RwLock<HashMap<CommandType, Histogram>>,
+    next_transfer_id: AtomicU64,
+    // transfers begun since start, flush leaves it, and its wake up for
+    // wait_for_transfers
+    transfers_begun: Mutex<u64>,
+    transfer_begun: Condvar,
+    // what the stats ticks, timeouts, throttles, watchdog and janitor keep
+    // time by, see set_clock
+    clock: SharedClock,
+    // not metrics, but the registry is the one thing every handler is handed
+    mmap_threshold: AtomicU64,
+    // download speed limits in bytes per second, 0 per transfer is unlimited
+    transfer_rate_limit: AtomicU64,
+    global_rate_limit: RwLock<Option<Arc<TokenBucket>>>,
+    progress_hook: RwLock<Option<ProgressHook>>,
+    observers: RwLock<Vec<Observer>>,
+    upload_hooks: RwLock<Vec<UploadHook>>,
+    authorizer: RwLock<Option<Authorizer>>,
+    // which files downloads may open
+    serve_policy: RwLock<Arc<ServePolicy>>,
+    ring: RwLock<Arc<HashRing>>,
+    // uploads and deletes are refused while set, toggled from the admin port
+    read_only: AtomicBool,
+    // new connections are turned away while in-flight ones finish, see
+    // shutdown::start_draining
+    draining: AtomicBool,
+    // how many files the stats' top files list holds
+    top_files: AtomicUsize,
+    // byte caps on what clients send, see protocol::Limits
+    limits: RwLock<Limits>,
+    // uploads are synced to disk, directory entry included, before they are acknowledged
+    durable_uploads: AtomicBool,
+    // sessions that accept gzip get `<file>.gz` in place of `<file>` when it is fresh
+    serve_precompressed: AtomicBool,
+    // uploads are encrypted with it and downloads decrypted while set
+    at_rest_key: RwLock<Option<Arc<AtRestKey>>>,
+    // served in place of the root dir while set
+    embedded_archive: RwLock<Option<EmbeddedArchive>>,
+    pub hot_files: HotFileCache,
+    pub shared_mappings: SharedMappings,
+    pub etags: ETagCache,
+    // bytes served per client IP, and the daily cap on them
+    pub bandwidth: IpBandwidth,
+    // orders opening a file for download against an upload replacing it
+    pub file_locks: FileLocks,
+    // per-command wall-clock budgets, and the connections that ran over them
+    pub watchdog: Watchdog,
+    // the trash deletes go to, and the thread purging it
+    pub janitor: Janitor,
+    pub replication: Replication,
+    pub cluster: Cluster,
+    // names the server's threads and lists the live ones
+    pub threads: ThreadRegistry,
+    pub watches: WatchHub,
+    pub audit: AuditLog,
+}
+
+// dispatch_wait bucket bounds in microseconds, 100µs up to 10s
+const DISPATCH_WAIT_BOUNDS: &[u64] = &[100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+// request duration bucket bounds in microseconds, 1-2.5-5 steps from 1ms to a
+// minute, finer where most requests land and coarse for the long transfers
+const REQUEST_DURATION_BOUNDS: &[u64] = &[
+    1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+    5_000_000, 10_000_000, 25_000_000, 60_000_000,
+];
+
+pub const DEFAULT_TOP_FILES: usize = 10;
+
+// mmap_threshold value meaning "never map"
+const MMAP_DISABLED: u64 = u64::MAX;
+
+// Live state of one file being streamed to a client.
+pub struct TransferProgress {
+    pub file_name: String,
+    pub peer: String,
+    pub started_at: time::Instant,
+    pub bytes_sent: Arc<AtomicU64>,
+    pub request_id: Option<RequestId>,
+    // set by `cancel_transfer`, the sending side gives up at the next chunk
+    pub cancelled: Arc<AtomicBool>,
+}
+
+// What a ProgressHook is told about a transfer, once per chunk sent and once
+// more with `finished` set when it ends, completed or not.
+#[derive(Debug)]
+pub struct TransferUpdate<'a> {
+    pub id: u64,
+    pub file_name: &'a str,
+    pub peer: &'a str,
+    pub bytes_sent: u64,
+    pub finished: bool,
+}
+
+// Runs on the thread sending the file after every chunk, keep it cheap.
+pub type ProgressHook = Arc<dyn Fn(&TransferUpdate) + Send + Sync>;
+
+// What an UploadHook is told about a file an upload just gave its name.
+#[derive(Debug)]
+pub struct UploadMeta<'a> {
+    pub file_name: &'a str,
+    // the root it went to, the user's home when the server has accounts
+    pub root_dir: &'a str,
+    // as downloads will serve it, smaller than the file on disk when files
+    // are encrypted at rest
+    pub size: u64,
+}
+
+// Runs on the worker that took the upload, after the file got its name and
+// before the client hears it went through. Keep it quick or hand the work
+// (a virus scan, thumbnails) to a thread of your own.
+pub type UploadHook = Arc<dyn Fn(&Path, &UploadMeta) + Send + Sync>;
+
+// Handed out by `begin_transfer`, counts bytes for one transfer and folds them
+// into the per file totals when dropped.
+pub struct TransferGuard<'a> {
+    metrics: &'a MetricsRegistry,
+    id: u64,
+    file_name: String,
+    peer: String,
+    request_id: Option<RequestId>,
+    bytes_sent: Arc<AtomicU64>,
+    cancelled: Arc<AtomicBool>,
+    // set by `finish`, observers only hear of transfers that got to the end
+    completed: bool,
+    // None when the peer is not an IP address
+    client: Option<Arc<IpUsage>>,
+    // taken once at the start so chunks do not go through the lock
+    progress_hook: Option<ProgressHook>,
+}
+
+impl TransferGuard<'_> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // Ends a transfer whose last byte went out, dropping the guard without
+    // it counts the transfer as broken off.
+    pub fn finish(mut self) {
+        self.completed = true;
+    }
+
+    // The client has used up its daily cap, see IpBandwidth.
+    pub fn is_over_daily_cap(&self) -> bool {
+        self.client
+            .as_ref()
+            .is_some_and(|client| self.metrics.bandwidth.exhausted(client))
+    }
+
+    pub fn record_bytes_sent(&self, bytes: u64) {
+        let sent = self.bytes_sent.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.metrics.record_bytes_sent(bytes);
+        if let Some(client) = &self.client {
+            client.record(bytes);
+        }
+        self.report_progress(sent, false);
+    }
+
+    fn report_progress(&self, bytes_sent: u64, finished: bool) {
+        if let Some(hook) = &self.progress_hook {
+            hook(&TransferUpdate {
+                id: self.id,
+                file_name: &self.file_name,
+                peer: &self.peer,
+                bytes_sent,
+                finished,
+            });
+        }
+    }
+}
+
+impl Drop for TransferGuard<'_> {
+    fn drop(&mut self) {
+        self.report_progress(self.bytes_sent.load(Ordering::Relaxed), true);
+        self.metrics
+            .transfer_progress
+            .write()
+            .unwrap()
+            .remove(&self.id);
+        let sent = self.bytes_sent.load(Ordering::Relaxed);
+        if self.completed {
+            self.metrics.notify(|observer| {
+                observer.on_transfer_complete(&TransferEvent {
+                    request_id: self.request_id,
+                    peer: &self.peer,
+                    file_name: &self.file_name,
+                    bytes: sent,
+                })
+            });
+        }
+        *self
+            .metrics
+            .bytes_per_file
+            .write()
+            .unwrap()
+            .entry(std::mem::take(&mut self.file_name))
+            .or_insert(0) += sent;
+    }
+}
+
+impl MetricsRegistry {
+    pub fn new() -> MetricsRegistry {
+        let threads = ThreadRegistry::default();
+        let clock = SharedClock::default();
+        MetricsRegistry {
+            started_at: time::Instant::now(),
+            file_stat: RwLock::new(HashMap::new()),
+            bytes_served: AtomicU64::new(0),
+            bytes_per_file: RwLock::new(HashMap::new()),
+            total_connections: AtomicU64::new(0),
+            active_transfers: AtomicU64::new(0),
+            transfer_progress: RwLock::new(HashMap::new()),
+            handler_panics: AtomicU64::new(0),
+            request_bodies: AtomicU64::new(0),
+            request_body_bytes: AtomicU64::new(0),
+            shed_connections: AtomicU64::new(0),
+            stats_subscribers: AtomicU64::new(0),
+            stats_subscribers_evicted: AtomicU64::new(0),
+            chunks_resent: AtomicU64::new(0),
+            delta_bytes_copied: AtomicU64::new(0),
+            precompressed_downloads: AtomicU64::new(0),
+            dispatch_wait: Histogram::new(DISPATCH_WAIT_BOUNDS),
+            small_dispatch_wait: Histogram::new(DISPATCH_WAIT_BOUNDS),
+            large_dispatch_wait: Histogram::new(DISPATCH_WAIT_BOUNDS),
+            request_durations: RwLock::new(HashMap::new()),
+            next_transfer_id: AtomicU64::new(0),
+            transfers_begun: Mutex::new(0),
+            transfer_begun: Condvar::new(),
+            clock: clock.clone(),
+            mmap_threshold: AtomicU64::new(MMAP_DISABLED),
+            transfer_rate_limit: AtomicU64::new(0),
+            global_rate_limit: RwLock::new(None),
+            progress_hook: RwLock::new(None),
+            observers: RwLock::new(Vec::new()),
+            upload_hooks: RwLock::new(Vec::new()),
+            authorizer: RwLock::new(None),
+            serve_policy: RwLock::new(Arc::new(ServePolicy::default())),
+            ring: RwLock::new(Arc::new(HashRing::default())),
+            read_only: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            durable_uploads: AtomicBool::new(false),
+            serve_precompressed: AtomicBool::new(false),
+            limits: RwLock::new(Limits::default()),
+            top_files: AtomicUsize::new(DEFAULT_TOP_FILES),
+            at_rest_key: RwLock::new(None),
+            embedded_archive: RwLock::new(None),
+            hot_files: HotFileCache::default(),
+            shared_mappings: SharedMappings::default(),
+            etags: ETagCache::default(),
+            bandwidth: IpBandwidth::default(),
+            file_locks: FileLocks::default(),
+            watchdog: Watchdog::with_threads(&threads, &clock),
+            janitor: Janitor::with_threads(&threads, &clock),
+            replication: Replication::with_threads(&threads),
+            cluster: Cluster::with_threads(&threads),
+            threads,
+            watches: WatchHub::default(),
+            audit: AuditLog::default(),
+        }
+    }
+
+    pub fn record_download(&self, file_name: String) {
+        let mut stats = self.file_stat.write().unwrap();
+        if let Some(x) = stats.get_mut(&file_name) {
+            *x += 1;
+        } else {
+            stats.insert(file_name, 1);
+        }
+    }
+
+    pub fn record_bytes_sent(&self, bytes: u64) {
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_connection(&self) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_stats_subscribers(&self, count: usize) {
+        self.stats_subscribers
+            .store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_stats_eviction(&self) {
+        self.stats_subscribers_evicted
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn transfer_started(&self) {
+        self.active_transfers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn transfer_finished(&self) {
+        self.active_transfers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // `peer` is whoever receives the file, see Connection::peer.
+    pub fn begin_transfer(
+        &self,
+        file_name: &str,
+        peer: &str,
+        request_id: Option<RequestId>,
+    ) -> TransferGuard<'_> {
+        let id = self.next_transfer_id.fetch_add(1, Ordering::Relaxed);
+        let bytes_sent = Arc::new(AtomicU64::new(0));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.transfer_progress.write().unwrap().insert(
+            id,
+            TransferProgress {
+                file_name: file_name.to_owned(),
+                peer: peer.to_owned(),
+                started_at: time::Instant::now(),
+                bytes_sent: bytes_sent.clone(),
+                request_id,
+                cancelled: cancelled.clone(),
+            },
+        );
+        *self.transfers_begun.lock().unwrap() += 1;
+        self.transfer_begun.notify_all();
+        TransferGuard {
+            metrics: self,
+            id,
+            file_name: file_name.to_owned(),
+            peer: peer.to_owned(),
+            request_id,
+            bytes_sent,
+            cancelled,
+            completed: false,
+            client: peer
+                .parse::<SocketAddr>()
+                .ok()
+                .map(|addr| self.bandwidth.client(addr.ip())),
+            progress_hook: self.progress_hook.read().unwrap().clone(),
+        }
+    }
+
+    // Asks the transfer with this id to stop, false if no such transfer is
+    // running. The client sees its connection dropped mid file.
+    pub fn cancel_transfer(&self, id: u64) -> bool {
+        match self.transfer_progress.read().unwrap().get(&id) {
+            Some(transfer) => {
+                transfer.cancelled.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        *self.limits.read().unwrap()
+    }
+
+    // A request body of `bytes` was accepted for reading.
+    pub fn record_request_body(&self, bytes: u64) {
+        self.request_bodies.fetch_add(1, Ordering::Relaxed);
+        self.request_body_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_serve_policy(&self, policy: ServePolicy) {
+        *self.serve_policy.write().unwrap() = Arc::new(policy);
+    }
+
+    pub fn serve_policy(&self) -> Arc<ServePolicy> {
+        self.serve_policy.read().unwrap().clone()
+    }
+
+    pub fn set_ring(&self, ring: HashRing) {
+        *self.ring.write().unwrap() = Arc::new(ring);
+    }
+
+    // The ring Locate answers from, empty unless one was set.
+    pub fn ring(&self) -> Arc<HashRing> {
+        self.ring.read().unwrap().clone()
+    }
+
+    pub fn set_authorizer(&self, authorizer: Option<Authorizer>) {
+        *self.authorizer.write().unwrap() = authorizer;
+    }
+
+    // Asks the authorizer, if there is one, whether the client on `stream` may
+    // run `command` on `file_name`.
+    pub(crate) fn authorize(
+        &self,
+        stream: &dyn Connection,
+        command: CommandType,
+        file_name: &str,
+    ) -> Result<(), FileServerError> {
+        let Some(authorizer) = self.authorizer.read().unwrap().clone() else {
+            return Ok(());
+        };
+        let identity = stream.peer_identity();
+        let request = AuthRequest {
+            peer: stream.remote_addr(),
+            identity: identity.as_deref(),
+            command,
+            file_name,
+        };
+        if authorizer(&request) {
+            return Ok(());
+        }
+        println!(
+            "{}Denied {:?} of {} to {}",
+            log_prefix(stream),
+            command,
+            file_name,
+            describe_peer(stream)
+        );
+        Err(FileServerError::Forbidden {
+            file: file_name.to_owned(),
+        })
+    }
+
+    // Like authorize, for a TFTP client, which is nothing but its address.
+    pub(crate) fn authorize_peer(
+        &self,
+        peer: SocketAddr,
+        command: CommandType,
+        file_name: &str,
+    ) -> Result<(), FileServerError> {
+        let Some(authorizer) = self.authorizer.read().unwrap().clone() else {
+            return Ok(());
+        };
+        let request = AuthRequest {
+            peer: Some(peer),
+            identity: None,
+            command,
+            file_name,
+        };
+        if authorizer(&request) {
+            return Ok(());
+        }
+        println!("Denied {:?} of {} to {}", command, file_name, peer);
+        Err(FileServerError::Forbidden {
+            file: file_name.to_owned(),
+        })
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    // always while serving an embedded archive, there is nowhere to write
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst) || self.embedded_archive.read().unwrap().is_some()
+    }
+
+    // false if the server was draining already
+    pub(crate) fn begin_draining(&self) -> bool {
+        !self.draining.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // Starts the counters over: downloads and bytes per file, bytes served,
+    // connections and panics. Gauges and transfers in flight are left alone.
+    pub fn flush(&self) {
+        self.file_stat.write().unwrap().clear();
+        self.bytes_per_file.write().unwrap().clear();
+        self.bytes_served.store(0, Ordering::Relaxed);
+        self.total_connections.store(0, Ordering::Relaxed);
+        self.handler_panics.store(0, Ordering::Relaxed);
+        self.request_bodies.store(0, Ordering::Relaxed);
+        self.request_body_bytes.store(0, Ordering::Relaxed);
+        self.shed_connections.store(0, Ordering::Relaxed);
+        self.stats_subscribers_evicted.store(0, Ordering::Relaxed);
+        self.chunks_resent.store(0, Ordering::Relaxed);
+        self.delta_bytes_copied.store(0, Ordering::Relaxed);
+        self.precompressed_downloads.store(0, Ordering::Relaxed);
+        self.bandwidth.clear_totals();
+    }
+
+    // Called as every transfer started from now on makes progress, None
+    // removes the hook.
+    pub fn set_progress_hook(&self, hook: Option<ProgressHook>) {
+        *self.progress_hook.write().unwrap() = hook;
+    }
+
+    // Tells `observer` about every connection and request from now on.
+    pub fn add_observer(&self, observer: Observer) {
+        self.observers.write().unwrap().push(observer);
+    }
+
+    // Runs `hook` after every upload committed from now on, see UploadHook.
+    pub fn add_upload_hook(&self, hook: UploadHook) {
+        self.upload_hooks.write().unwrap().push(hook);
+    }
+
+    // Tells the upload hooks about `file_name`, just committed under
+    // `root_dir`. A hook that panics is logged and the others still run, the
+    // upload went through either way.
+    pub(crate) fn notify_upload(&self, file_name: &str, root_dir: &str) {
+        let hooks = self.upload_hooks.read().unwrap().clone();
+        if hooks.is_empty() {
+            return;
+        }
+        let path = served_directory_path(root_dir).join(file_name);
+        let size = match fs::metadata(&path) {
+            Ok(metadata) => self.served_len(metadata.len()),
+            Err(err) => {
+                println!("...Upload hooks skipped, {} is gone:{err}", file_name);
+                return;
+            }
+        };
+        let meta = UploadMeta {
+            file_name,
+            root_dir,
+            size,
+        };
+        for hook in hooks {
+            if panic::catch_unwind(AssertUnwindSafe(|| hook(&path, &meta))).is_err() {
+                println!("...Upload hook panicked on {}", file_name);
+            }
+        }
+    }
+
+    pub(crate) fn notify(&self, event: impl Fn(&dyn EventObserver)) {
+        for observer in self.observers.read().unwrap().iter() {
+            event(&**observer);
+        }
+    }
+
+    pub(crate) fn notify_error(
+        &self,
+        stream: &dyn Connection,
+        command_type: Option<CommandType>,
+        message: &str,
+    ) {
+        let peer = stream.peer();
+        self.notify(|observer| {
+            observer.on_error(&ErrorEvent {
+                request_id: stream.request_id(),
+                peer: &peer,
+                command_type,
+                message,
+            })
+        });
+    }
+
+    // Caps each download at `per_transfer` and all of them together at `global`
+    // bytes per second, None leaves that side unlimited.
+    pub fn set_rate_limits(&self, per_transfer: Option<u64>, global: Option<u64>) {
+        self.transfer_rate_limit
+            .store(per_transfer.unwrap_or(0), Ordering::Relaxed);
+        *self.global_rate_limit.write().unwrap() =
+            global.map(|rate| Arc::new(TokenBucket::new(rate, self.clock())));
+    }
+
+    // Pacing for one new download under the current limits.
+    pub fn throttle(&self) -> Throttle {
+        let per_transfer = match self.transfer_rate_limit.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        };
+        Throttle::new(
+            per_transfer,
+            self.global_rate_limit.read().unwrap().clone(),
+            self.clock(),
+        )
+    }
+
+    // Swaps the clock for a MockClock, say. Set it before the server starts:
+    // threads already waiting finish on the old one, and so does the global
+    // rate limit until set_rate_limits runs again.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        self.clock.set(clock);
+    }
+
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.get()
+    }
+
+    // Files at least this big are served from a memory mapping, None turns it off.
+    pub fn set_mmap_threshold(&self, threshold: Option<u64>) {
+        self.mmap_threshold
+            .store(threshold.unwrap_or(MMAP_DISABLED), Ordering::Relaxed);
+    }
+
+    pub fn mmap_threshold(&self) -> Option<u64> {
+        match self.mmap_threshold.load(Ordering::Relaxed) {
+            MMAP_DISABLED => None,
+            threshold => Some(threshold),
+        }
+    }
+
+    // Counts one `command` request that took its handler `elapsed` to serve.
+    pub fn record_request(&self, command: CommandType, elapsed: time::Duration) {
+        if let Some(histogram) = self.request_durations.read().unwrap().get(&command) {
+            histogram.observe(elapsed);
+            return;
+        }
+        self.request_durations
+            .write()
+            .unwrap()
+            .entry(command)
+            .or_insert_with(|| Histogram::new(REQUEST_DURATION_BOUNDS))
+            .observe(elapsed);
+    }
+
+    pub fn set_top_files(&self, count: usize) {
+        self.top_files.store(count, Ordering::Relaxed);
+    }
+
+    pub fn set_durable_uploads(&self, durable: bool) {
+        self.durable_uploads.store(durable, Ordering::Relaxed);
+    }
+
+    pub fn durable_uploads(&self) -> bool {
+        self.durable_uploads.load(Ordering::Relaxed)
+    }
+
+    pub fn set_serve_precompressed(&self, serve: bool) {
+        self.serve_precompressed.store(serve, Ordering::Relaxed);
+    }
+
+    pub fn serve_precompressed(&self) -> bool {
+        self.serve_precompressed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_at_rest_key(&self, key: Option<AtRestKey>) {
+        *self.at_rest_key.write().unwrap() = key.map(Arc::new);
+    }
+
+    pub fn at_rest_key(&self) -> Option<Arc<AtRestKey>> {
+        self.at_rest_key.read().unwrap().clone()
+    }
+
+    pub fn set_embedded_archive(&self, archive: Option<EmbeddedArchive>) {
+        *self.embedded_archive.write().unwrap() = archive;
+    }
+
+    pub fn embedded_archive(&self) -> Option<EmbeddedArchive> {
+        self.embedded_archive.read().unwrap().clone()
+    }
+
+    // Size a client sees for a served file `disk_len` bytes long on disk,
+    // smaller than that once files are encrypted at rest. Files out of an
+    // embedded archive are never encrypted.
+    pub fn served_len(&self, disk_len: u64) -> u64 {
+        let encrypted = self.at_rest_key.read().unwrap().is_some()
+            && self.embedded_archive.read().unwrap().is_none();
+        match encrypted {
+            true => plaintext_len(disk_len),
+            false => disk_len,
+        }
+    }
+
+    // dispatch_wait of the downloads waiting in the queue of `class`
+    pub fn class_dispatch_wait(&self, class: TransferClass) -> &Histogram {
+        match class {
+            TransferClass::Small => &self.small_dispatch_wait,
+            TransferClass::Large => &self.large_dispatch_wait,
+        }
+    }
+
+    pub fn download_count(&self, file_name: &str) -> i64 {
+        *self.file_stat.read().unwrap().get(file_name).unwrap_or(&0)
+    }
+
+    pub fn file_stat_snapshot(&self) -> HashMap<String, i64> {
+        self.file_stat.read().unwrap().clone()
+    }
+
+    // Empties the download counts and hands back what they were, in one go
+    // so a download recorded meanwhile lands on one side or the other.
+    pub fn take_file_stat(&self) -> HashMap<String, i64> {
+        std::mem::take(&mut *self.file_stat.write().unwrap())
+    }
+
+    // Transfers still running, oldest first. Entries go away when the
+    // TransferGuard of a transfer is dropped.
+    pub fn active_transfers(&self) -> Vec<ActiveTransfer> {
+        let mut transfers: Vec<ActiveTransfer> = self
+            .transfer_progress
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, transfer)| ActiveTransfer {
+                id: *id,
+                file_name: transfer.file_name.clone(),
+                peer: transfer.peer.clone(),
+                bytes_sent: transfer.bytes_sent.load(Ordering::Relaxed),
+                elapsed: transfer.started_at.elapsed(),
+                request_id: transfer.request_id,
+            })
+            .collect();
+        transfers.sort_by_key(|t| t.id);
+        transfers
+    }
+
+    // Waits up to `timeout` until `count` transfers have begun since the
+    // server started, finished ones included, and reports whether they have.
+    // For tests to wait on a download reaching its handler instead of
+    // sleeping on it.
+    pub fn wait_for_transfers(&self, count: u64, timeout: time::Duration) -> bool {
+        let begun = self.transfers_begun.lock().unwrap();
+        let (begun, _) = self
+            .transfer_begun
+            .wait_timeout_while(begun, timeout, |begun| *begun < count)
+            .unwrap();
+        *begun >= count
+    }
+
+    pub fn snapshot(&self, pool: &WorkerPool) -> StatsSnapshot {
+        let mut snapshot = StatsSnapshot {
+            number_of_clients: pool.busy() as u32,
+            most_downloaded_file: String::from("no files"),
+            bytes_served: self.bytes_served.load(Ordering::Relaxed),
+            pool: PoolStats {
+                workers: pool.size() as u32,
+                waiting_connections: pool.waiting() as u32,
+                dispatch_wait: self.dispatch_wait.snapshot(),
+                queues: TransferClass::ALL
+                    .iter()
+                    .map(|class| QueueStats {
+                        class: class.name().to_owned(),
+                        waiting: pool.waiting_in(*class) as u32,
+                        dispatch_wait: self.class_dispatch_wait(*class).snapshot(),
+                    })
+                    .collect(),
+            },
+            timestamp_ms: time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            ..StatsSnapshot::default()
+        };
+
+        let mut downloads: Vec<(String, u64)> = self
+            .file_stat
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(file, count)| (file.clone(), *count as u64))
+            .collect();
+        downloads.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        if let Some((file, count)) = downloads.first() {
+            snapshot.most_downloaded_file = file.clone();
+            snapshot.file_downloaded_count = *count;
+        }
+        downloads.truncate(self.top_files.load(Ordering::Relaxed));
+        snapshot.top_files = downloads;
+
+        snapshot.request_durations = self
+            .request_durations
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(command, histogram)| (format!("{:?}", command), histogram.snapshot()))
+            .collect();
+        snapshot.request_durations.sort_by(|a, b| a.0.cmp(&b.0));
+
+        snapshot.bytes_per_file = self
+            .bytes_per_file
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(file, bytes)| (file.clone(), *bytes))
+            .collect();
+        snapshot.bytes_per_file.sort();
+
+        snapshot.bytes_per_ip = self
+            .bandwidth
+            .usage()
+            .into_iter()
+            .map(|row| (row.ip.to_string(), row.total))
+            .collect();
+
+        for (id, transfer) in self.transfer_progress.read().unwrap().iter() {
+            let bytes_sent = transfer.bytes_sent.load(Ordering::Relaxed);
+            let elapsed = transfer.started_at.elapsed().as_secs_f64();
+            snapshot.transfers.push(TransferStats {
+                id: *id,
+                file_name: transfer.file_name.clone(),
+                bytes_sent,
+                bytes_per_second: if elapsed > 0.0 {
+                    (bytes_sent as f64 / elapsed) as u64
+                } else {
+                    0
+                },
+            });
+        }
+        snapshot.transfers.sort_by_key(|t| t.id);
+
+        snapshot.cluster = self
+            .cluster
+            .stats(&snapshot, self.top_files.load(Ordering::Relaxed));
+        snapshot
+    }
+}
+
+impl Default for MetricsRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_ranks_top_files() {
+        let metrics = MetricsRegistry::new();
+        for file in ["b", "a", "c", "c", "b", "c"] {
+            metrics.record_download(file.to_owned());
+        }
+        metrics.set_top_files(2);
+
+        let snapshot = metrics.snapshot(&WorkerPool::new(1));
+        assert_eq!(
+            vec![("c".to_owned(), 3), ("b".to_owned(), 2)],
+            snapshot.top_files
+        );
+        assert_eq!("c", snapshot.most_downloaded_file);
+        assert_eq!(3, snapshot.file_downloaded_count);
+
+        metrics.set_top_files(0);
+        let snapshot = metrics.snapshot(&WorkerPool::new(1));
+        assert!(snapshot.top_files.is_empty());
+        assert_eq!("c", snapshot.most_downloaded_file);
+    }
+
+    #[test]
+    fn test_request_durations_are_kept_per_command() {
+        let metrics = MetricsRegistry::new();
+        metrics.record_request(CommandType::Upload, time::Duration::from_millis(3));
+        metrics.record_request(CommandType::Download, time::Duration::from_secs(2));
+        metrics.record_request(CommandType::Download, time::Duration::from_secs(90));
+
+        let durations = metrics.snapshot(&WorkerPool::new(1)).request_durations;
+        let names: Vec<&str> = durations.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(vec!["Download", "Upload"], names);
+        let download = &durations[0].1;
+        assert_eq!(2, download.count);
+        // past the last bucket, only in the count
+        assert_eq!(Some(&(60_000_000, 1)), download.buckets.last());
+    }
+}
@@ -1,2 +1,51 @@
+pub mod accounts;
+pub mod admin;
+pub mod archive;
+pub mod audit;
+pub mod authorizer;
+pub mod bandwidth;
+pub mod batch;
+pub mod builder;
+pub mod clock;
+pub mod cluster;
+pub mod conditional;
+pub mod connection;
+pub mod delta;
+pub mod file_locks;
+pub mod files;
+pub mod flash;
+pub mod header;
+pub mod health;
+pub mod histogram;
+pub mod http;
+pub mod janitor;
+pub mod keep_alive;
+pub mod limit;
+pub mod listener;
+pub mod listing;
+pub mod metrics;
+pub mod observer;
+pub mod pool;
+pub mod preflight;
+pub mod prometheus;
+pub mod range;
+pub mod reliable;
+pub mod replication;
+pub mod request_id;
+pub mod ring;
+pub mod router;
+pub mod serve_policy;
+#[allow(clippy::module_inception)]
 pub mod server;
+pub mod shutdown;
+pub mod stats_sink;
+pub mod sync;
+pub mod tail;
+pub mod tftp;
+pub mod threads;
+pub mod throttle;
+pub mod transfers;
 pub mod types;
+pub mod watch;
+pub mod watchdog;
+pub mod webdav;
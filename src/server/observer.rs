@@ -0,0 +1,251 @@
+use super::request_id::RequestId;
+use super::types::CommandType;
+use std::{
+    net::SocketAddr,
+    sync::{mpsc, Arc},
+    time,
+};
+
+// A connection the server just accepted.
+pub struct ConnectionEvent<'a> {
+    pub request_id: Option<RequestId>,
+    pub peer: &'a str,
+}
+
+// A request a handler is about to run, or just ran.
+pub struct RequestEvent<'a> {
+    pub request_id: Option<RequestId>,
+    pub peer: &'a str,
+    pub command: u8,
+    // None when the router does not speak the built-in protocol
+    pub command_type: Option<CommandType>,
+}
+
+// A request the server turned away or whose handler panicked, `message` is
+// what the client was told.
+pub struct ErrorEvent<'a> {
+    pub request_id: Option<RequestId>,
+    pub peer: &'a str,
+    // None when the command could not be read
+    pub command_type: Option<CommandType>,
+    pub message: &'a str,
+}
+
+// A file sent to the end, the last of its bytes written to the client.
+pub struct TransferEvent<'a> {
+    pub request_id: Option<RequestId>,
+    pub peer: &'a str,
+    pub file_name: &'a str,
+    pub bytes: u64,
+}
+
+// Lets embedders feed their own telemetry without going through the log lines
+// or the Prometheus endpoint. Every method does nothing by default, implement
+// the ones you need. They run on the accept loop or the worker serving the
+// request, keep them quick.
+pub trait EventObserver: Send + Sync {
+    // the accept loop is about to take connections on `addrs`
+    fn on_started(&self, _addrs: &[SocketAddr]) {}
+
+    fn on_connection(&self, _event: &ConnectionEvent) {}
+
+    fn on_request_start(&self, _event: &RequestEvent) {}
+
+    fn on_request_end(&self, _event: &RequestEvent, _elapsed: time::Duration) {}
+
+    fn on_error(&self, _event: &ErrorEvent) {}
+
+    fn on_transfer_complete(&self, _event: &TransferEvent) {}
+
+    // the accept loop stopped, in-flight requests are being drained
+    fn on_shutting_down(&self) {}
+}
+
+pub type Observer = Arc<dyn EventObserver>;
+
+// What FileServer::events hands out, the observer calls as owned values so
+// they can cross threads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerEvent {
+    Started {
+        addrs: Vec<SocketAddr>,
+    },
+    ConnectionAccepted {
+        request_id: Option<RequestId>,
+        peer: String,
+    },
+    TransferCompleted {
+        file: String,
+        bytes: u64,
+    },
+    Error {
+        request_id: Option<RequestId>,
+        command_type: Option<CommandType>,
+        message: String,
+    },
+    ShuttingDown,
+}
+
+// Forwards every event into a channel. Sends to a dropped receiver fail and
+// are ignored, the server never waits on an embedder.
+pub(crate) struct ChannelObserver(pub mpsc::Sender<ServerEvent>);
+
+impl EventObserver for ChannelObserver {
+    fn on_started(&self, addrs: &[SocketAddr]) {
+        let _ = self.0.send(ServerEvent::Started {
+            addrs: addrs.to_vec(),
+        });
+    }
+
+    fn on_connection(&self, event: &ConnectionEvent) {
+        let _ = self.0.send(ServerEvent::ConnectionAccepted {
+            request_id: event.request_id,
+            peer: event.peer.to_owned(),
+        });
+    }
+
+    fn on_error(&self, event: &ErrorEvent) {
+        let _ = self.0.send(ServerEvent::Error {
+            request_id: event.request_id,
+            command_type: event.command_type,
+            message: event.message.to_owned(),
+        });
+    }
+
+    fn on_transfer_complete(&self, event: &TransferEvent) {
+        let _ = self.0.send(ServerEvent::TransferCompleted {
+            file: event.file_name.to_owned(),
+            bytes: event.bytes,
+        });
+    }
+
+    fn on_shutting_down(&self) {
+        let _ = self.0.send(ServerEvent::ShuttingDown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::Connection;
+    use super::super::metrics::MetricsRegistry;
+    use super::super::router::Router;
+    use super::*;
+    use crate::{reader, FileServer};
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::Mutex,
+    };
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl EventObserver for Recorder {
+        fn on_connection(&self, _event: &ConnectionEvent) {
+            self.0.lock().unwrap().push("connection".to_owned());
+        }
+
+        fn on_request_start(&self, event: &RequestEvent) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("start {}", event.command));
+        }
+
+        fn on_request_end(&self, event: &RequestEvent, _elapsed: time::Duration) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("end {}", event.command));
+        }
+
+        fn on_error(&self, _event: &ErrorEvent) {
+            self.0.lock().unwrap().push("error".to_owned());
+        }
+    }
+
+    fn pong_handler(
+        mut stream: &dyn Connection,
+        _root_dir: &'static str,
+        _metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        stream.write_all(b"pong").unwrap();
+    }
+
+    fn request(command: u8) -> String {
+        let mut stream = TcpStream::connect("127.0.0.1:8199").unwrap();
+        stream.write_all(&[command]).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn test_observer_sees_requests_and_refusals() {
+        let root_dir = "temp_test_observer_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+
+        let recorder = Arc::new(Recorder::default());
+        let mut server = FileServer::new("127.0.0.1", "8199", 1, root_dir).unwrap();
+        server.set_router(Router::custom().route(42, pong_handler));
+        server.add_observer(recorder.clone());
+        let handle = server.spawn();
+
+        assert_eq!("pong", request(42));
+        // nothing is routed to 43
+        assert_ne!("pong", request(43));
+        handle.shutdown();
+        assert_eq!(
+            vec!["connection", "start 42", "end 42", "connection", "error"],
+            *recorder.0.lock().unwrap()
+        );
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_events_channel_follows_the_server_lifecycle() {
+        let root_dir = "temp_test_events_root_dir";
+        crate::testkit::setup_tmp_file(root_dir, "hello.txt", "hello");
+
+        let mut server = FileServer::new("127.0.0.1", "8289", 1, root_dir).unwrap();
+        server.register_handlers(&[(
+            CommandType::Download,
+            FileServer::handle_incomming_file_request,
+        )]);
+        let events = server.events();
+        let handle = server.spawn();
+        assert!(matches!(
+            events.recv().unwrap(),
+            ServerEvent::Started { addrs } if addrs[0].port() == 8289
+        ));
+
+        assert_eq!(
+            "hello",
+            crate::testkit::download_test_file("127.0.0.1", "8289", "hello.txt", None)
+        );
+        // a command nobody serves
+        let mut stream = TcpStream::connect("127.0.0.1:8289").unwrap();
+        stream.write_all(&[CommandType::Upload.into()]).unwrap();
+        stream.read_to_end(&mut Vec::new()).unwrap();
+        handle.shutdown();
+        handle.join().unwrap();
+
+        let events: Vec<ServerEvent> = events.try_iter().collect();
+        assert!(matches!(
+            events.as_slice(),
+            [
+                ServerEvent::ConnectionAccepted { .. },
+                ServerEvent::TransferCompleted { file, bytes: 5 },
+                ServerEvent::ConnectionAccepted { .. },
+                ServerEvent::Error {
+                    command_type: None,
+                    ..
+                },
+                ServerEvent::ShuttingDown,
+            ] if file == "hello.txt"
+        ));
+
+        reader::cleanup_server_file(root_dir);
+    }
+}
@@ -0,0 +1,511 @@
+use super::types::CommandType;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Condvar, Mutex},
+    time,
+};
+
+// What the accept loop does with a new connection while every worker is busy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BusyPolicy {
+    // accept it and let it wait on its own thread for a worker
+    #[default]
+    Queue,
+    // stop accepting until a worker is free, clients pile up in the OS backlog
+    // and get refused once that is full
+    Backpressure,
+    // accept it and answer "server busy, retry after N seconds" right away
+    Reject {
+        retry_after: time::Duration,
+    },
+    // queue it while fewer than `max_queue` connections wait for a worker,
+    // past that answer a BUSY frame whose retry after grows with the queue
+    // (see WorkerPool::retry_after) and hang up
+    Shed {
+        max_queue: usize,
+    },
+}
+
+// The queue a download waits in for a worker, by the size of its file, see
+// FileServer::set_small_transfer_bytes. A large transfer only takes a free
+// worker while no small one is waiting, so a burst of multi-gigabyte
+// downloads does not keep interactive clients waiting behind it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TransferClass {
+    Small,
+    Large,
+}
+
+impl TransferClass {
+    pub const ALL: [TransferClass; 2] = [TransferClass::Small, TransferClass::Large];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TransferClass::Small => "small",
+            TransferClass::Large => "large",
+        }
+    }
+}
+
+// Counts the workers handlers may use. Part of the pool can be reserved for a
+// command so a burst of one kind of request (downloads, usually) can not take
+// every worker. Reserved workers are a floor, not a ceiling, a command whose
+// reservation is used up competes for the shared workers like everyone else.
+pub struct WorkerPool {
+    state: Mutex<PoolState>,
+    slot_freed: Condvar,
+    // a worker was taken or handed back or a connection started waiting,
+    // for whoever watches the pool rather than waits in it
+    changed: Condvar,
+}
+
+struct PoolState {
+    shared: Partition,
+    reserved: HashMap<CommandType, Partition>,
+    waiting: usize,
+    // the part of `waiting` that was sorted into a class
+    waiting_small: usize,
+    waiting_large: usize,
+    // everyone waiting by the order they came in, with their class
+    queue: BTreeMap<u64, Option<TransferClass>>,
+    next_ticket: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Partition {
+    size: i32,
+    free: i32,
+}
+
+// A worker taken from the pool, handed back when dropped.
+pub struct WorkerSlot {
+    pool: Arc<WorkerPool>,
+    reserved_for: Option<CommandType>,
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        let mut state = self.pool.state.lock().unwrap();
+        match self.reserved_for.and_then(|c| state.reserved.get_mut(&c)) {
+            Some(partition) => partition.free += 1,
+            None => state.shared.free += 1,
+        }
+        self.pool.slot_freed.notify_all();
+        self.pool.changed.notify_all();
+    }
+}
+
+impl PoolState {
+    fn take(&mut self, command: Option<CommandType>) -> Option<Option<CommandType>> {
+        if let Some(command) = command {
+            if let Some(partition) = self.reserved.get_mut(&command) {
+                if partition.free > 0 {
+                    partition.free -= 1;
+                    return Some(Some(command));
+                }
+            }
+        }
+        if self.shared.free > 0 {
+            self.shared.free -= 1;
+            return Some(None);
+        }
+        None
+    }
+
+    fn waiting_in(&mut self, class: TransferClass) -> &mut usize {
+        match class {
+            TransferClass::Small => &mut self.waiting_small,
+            TransferClass::Large => &mut self.waiting_large,
+        }
+    }
+
+    // Where the waiter holding `ticket` stands, 1 for next in line: behind
+    // everyone who came before it, and behind every small transfer when it is
+    // a large one. Only an estimate when parts of the pool are reserved.
+    fn position(&self, ticket: u64, class: Option<TransferClass>) -> usize {
+        let ahead = self
+            .queue
+            .iter()
+            .filter(|(other, other_class)| match (class, **other_class) {
+                (Some(TransferClass::Large), Some(TransferClass::Small)) => true,
+                (Some(TransferClass::Small), Some(TransferClass::Large)) => false,
+                _ => **other < ticket,
+            })
+            .count();
+        1 + ahead
+    }
+
+    fn busy(&self) -> i32 {
+        let busy_reserved: i32 = self.reserved.values().map(|p| p.size - p.free).sum();
+        self.shared.size - self.shared.free + busy_reserved
+    }
+
+    fn has_idle_worker(&self) -> bool {
+        self.shared.free > 0 || self.reserved.values().any(|p| p.free > 0)
+    }
+}
+
+impl WorkerPool {
+    pub fn new(size: i32) -> WorkerPool {
+        WorkerPool {
+            state: Mutex::new(PoolState {
+                shared: Partition { size, free: size },
+                reserved: HashMap::new(),
+                waiting: 0,
+                waiting_small: 0,
+                waiting_large: 0,
+                queue: BTreeMap::new(),
+                next_ticket: 0,
+            }),
+            slot_freed: Condvar::new(),
+            changed: Condvar::new(),
+        }
+    }
+
+    // Sets aside `count` of the shared workers for `command`, replacing any
+    // earlier reservation for it. Only idle shared workers can be reserved.
+    pub fn reserve(&self, command: CommandType, count: i32) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        let previous = state
+            .reserved
+            .get(&command)
+            .copied()
+            .unwrap_or(Partition { size: 0, free: 0 });
+        if previous.free != previous.size {
+            return Err(format!("{:?} workers are busy", command));
+        }
+
+        let shared_free = state.shared.free + previous.size;
+        if count < 0 || count > shared_free {
+            return Err(format!(
+                "can not reserve {} workers for {:?}, {} are free",
+                count, command, shared_free
+            ));
+        }
+
+        state.shared.size += previous.size - count;
+        state.shared.free = shared_free - count;
+        if count == 0 {
+            state.reserved.remove(&command);
+        } else {
+            state.reserved.insert(
+                command,
+                Partition {
+                    size: count,
+                    free: count,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    pub fn try_acquire(self: &Arc<Self>, command: Option<CommandType>) -> Option<WorkerSlot> {
+        let reserved_for = self.state.lock().unwrap().take(command)?;
+        self.changed.notify_all();
+        Some(WorkerSlot {
+            pool: self.clone(),
+            reserved_for,
+        })
+    }
+
+    // Blocks until a worker `command` may use is free.
+    pub fn acquire(self: &Arc<Self>, command: Option<CommandType>) -> WorkerSlot {
+        self.acquire_as(command, None)
+    }
+
+    // acquire, waiting in the queue of `class`. Connections left unsorted
+    // (None) are neither ahead of nor behind either queue.
+    pub fn acquire_as(
+        self: &Arc<Self>,
+        command: Option<CommandType>,
+        class: Option<TransferClass>,
+    ) -> WorkerSlot {
+        self.wait_for_slot(command, class, None)
+    }
+
+    // acquire_as, calling `report` with the connection's place in the queue
+    // (see PoolState::position) as soon as it has to wait and every `every`
+    // after that until a worker is free. The pool is not locked while
+    // `report` runs, it may write to a slow client.
+    pub fn acquire_reporting(
+        self: &Arc<Self>,
+        command: Option<CommandType>,
+        class: Option<TransferClass>,
+        every: time::Duration,
+        mut report: impl FnMut(usize),
+    ) -> WorkerSlot {
+        self.wait_for_slot(command, class, Some((every, &mut report)))
+    }
+
+    fn wait_for_slot(
+        self: &Arc<Self>,
+        command: Option<CommandType>,
+        class: Option<TransferClass>,
+        mut report: Option<(time::Duration, &mut dyn FnMut(usize))>,
+    ) -> WorkerSlot {
+        let mut state = self.state.lock().unwrap();
+        state.waiting += 1;
+        if let Some(class) = class {
+            *state.waiting_in(class) += 1;
+        }
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.insert(ticket, class);
+        self.changed.notify_all();
+        let mut report_at = time::Instant::now();
+        let reserved_for = loop {
+            // large transfers let every waiting small one go first
+            let yields = class == Some(TransferClass::Large) && state.waiting_small > 0;
+            if !yields {
+                if let Some(reserved_for) = state.take(command) {
+                    break reserved_for;
+                }
+            }
+            let Some((every, report)) = &mut report else {
+                state = self.slot_freed.wait(state).unwrap();
+                continue;
+            };
+            let now = time::Instant::now();
+            if now >= report_at {
+                let position = state.position(ticket, class);
+                drop(state);
+                report(position);
+                report_at = now + *every;
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            state = self
+                .slot_freed
+                .wait_timeout(state, report_at - now)
+                .unwrap()
+                .0;
+        };
+        state.waiting -= 1;
+        if let Some(class) = class {
+            *state.waiting_in(class) -= 1;
+        }
+        state.queue.remove(&ticket);
+        self.changed.notify_all();
+        WorkerSlot {
+            pool: self.clone(),
+            reserved_for,
+        }
+    }
+
+    // Waits up to `timeout` for any worker to be idle, reports whether one is.
+    pub fn wait_for_idle_worker(&self, timeout: time::Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .slot_freed
+            .wait_timeout_while(state, timeout, |state| !state.has_idle_worker())
+            .unwrap();
+        state.has_idle_worker()
+    }
+
+    pub fn size(&self) -> i32 {
+        let state = self.state.lock().unwrap();
+        state.shared.size + state.reserved.values().map(|p| p.size).sum::<i32>()
+    }
+
+    pub fn busy(&self) -> i32 {
+        self.state.lock().unwrap().busy()
+    }
+
+    // Waits up to `timeout` for at least `count` workers to be busy, reports
+    // whether they are. Lets tests wait for a connection to be picked up
+    // instead of sleeping on it.
+    pub fn wait_for_busy(&self, count: i32, timeout: time::Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.busy() < count)
+            .unwrap();
+        state.busy() >= count
+    }
+
+    // The same for `count` connections waiting for a worker, in the queue of
+    // `class` or in any queue for None.
+    pub fn wait_for_waiting(
+        &self,
+        class: Option<TransferClass>,
+        count: usize,
+        timeout: time::Duration,
+    ) -> bool {
+        let waiting = |state: &mut PoolState| match class {
+            Some(class) => *state.waiting_in(class),
+            None => state.waiting,
+        };
+        let state = self.state.lock().unwrap();
+        let (mut state, _) = self
+            .changed
+            .wait_timeout_while(state, timeout, |state| waiting(state) < count)
+            .unwrap();
+        waiting(&mut state) >= count
+    }
+
+    // connections waiting for a worker
+    pub fn waiting(&self) -> usize {
+        self.state.lock().unwrap().waiting
+    }
+
+    // connections waiting for a worker in the queue of `class`
+    pub fn waiting_in(&self, class: TransferClass) -> usize {
+        *self.state.lock().unwrap().waiting_in(class)
+    }
+
+    // When a shed client should come back: a second, plus one for every
+    // round of the pool the connections already waiting need.
+    pub fn retry_after(&self) -> time::Duration {
+        let rounds = self.waiting() as u64 / self.size().max(1) as u64;
+        time::Duration::from_secs(1 + rounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserved_workers_survive_a_burst() {
+        let pool = Arc::new(WorkerPool::new(3));
+        pool.reserve(CommandType::Statistics, 1).unwrap();
+
+        let downloads: Vec<WorkerSlot> = (0..2)
+            .map(|_| pool.try_acquire(Some(CommandType::Download)).unwrap())
+            .collect();
+        assert!(pool.try_acquire(Some(CommandType::Download)).is_none());
+
+        let stats = pool.try_acquire(Some(CommandType::Statistics)).unwrap();
+        assert_eq!(3, pool.busy());
+
+        drop(stats);
+        drop(downloads);
+        assert_eq!(0, pool.busy());
+        assert_eq!(3, pool.size());
+    }
+
+    #[test]
+    fn test_reservation_overflows_into_shared_workers() {
+        let pool = Arc::new(WorkerPool::new(2));
+        pool.reserve(CommandType::Statistics, 1).unwrap();
+        assert!(pool.reserve(CommandType::Upload, 2).is_err());
+
+        let _first = pool.try_acquire(Some(CommandType::Statistics)).unwrap();
+        let _second = pool.try_acquire(Some(CommandType::Statistics)).unwrap();
+        assert!(pool.try_acquire(None).is_none());
+    }
+
+    #[test]
+    fn test_retry_after_grows_with_the_queue() {
+        let pool = Arc::new(WorkerPool::new(2));
+        assert_eq!(time::Duration::from_secs(1), pool.retry_after());
+        let _busy: Vec<WorkerSlot> = (0..2).map(|_| pool.try_acquire(None).unwrap()).collect();
+        let waiting: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || drop(pool.acquire(None)))
+            })
+            .collect();
+        assert!(pool.wait_for_waiting(None, 4, time::Duration::from_secs(5)));
+        assert_eq!(time::Duration::from_secs(3), pool.retry_after());
+        drop(_busy);
+        for waiter in waiting {
+            waiter.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_small_transfers_go_before_large_ones() {
+        let pool = Arc::new(WorkerPool::new(1));
+        let busy = pool.try_acquire(None).unwrap();
+        let (served, order) = std::sync::mpsc::channel();
+        let large = {
+            let (pool, served) = (pool.clone(), served.clone());
+            std::thread::spawn(move || {
+                let _slot = pool.acquire_as(None, Some(TransferClass::Large));
+                served.send(TransferClass::Large).unwrap();
+            })
+        };
+        assert!(pool.wait_for_waiting(Some(TransferClass::Large), 1, time::Duration::from_secs(5)));
+        // queued after the large one, served before it
+        let small = {
+            let pool = pool.clone();
+            std::thread::spawn(move || {
+                let _slot = pool.acquire_as(None, Some(TransferClass::Small));
+                served.send(TransferClass::Small).unwrap();
+            })
+        };
+        assert!(pool.wait_for_waiting(Some(TransferClass::Small), 1, time::Duration::from_secs(5)));
+        assert_eq!(2, pool.waiting());
+
+        drop(busy);
+        small.join().unwrap();
+        large.join().unwrap();
+        let order: Vec<TransferClass> = order.iter().collect();
+        assert_eq!(vec![TransferClass::Small, TransferClass::Large], order);
+    }
+
+    #[test]
+    fn test_waiters_hear_their_place_in_the_queue() {
+        let pool = Arc::new(WorkerPool::new(1));
+        let busy = pool.try_acquire(None).unwrap();
+        let wait = |class| {
+            let pool = pool.clone();
+            let (report, positions) = std::sync::mpsc::channel();
+            let waiter = std::thread::spawn(move || {
+                let every = time::Duration::from_millis(5);
+                drop(
+                    pool.acquire_reporting(None, Some(class), every, |position| {
+                        let _ = report.send(position);
+                    }),
+                );
+            });
+            (waiter, positions)
+        };
+        let (large, large_positions) = wait(TransferClass::Large);
+        assert_eq!(1, large_positions.recv().unwrap());
+        // queued after the large one, next in line all the same
+        let (small, small_positions) = wait(TransferClass::Small);
+        assert_eq!(1, small_positions.recv().unwrap());
+        assert!(large_positions.iter().any(|position| position == 2));
+
+        drop(busy);
+        small.join().unwrap();
+        large.join().unwrap();
+        assert_eq!(0, pool.waiting());
+    }
+
+    #[test]
+    fn test_wait_for_idle_worker() {
+        let pool = Arc::new(WorkerPool::new(1));
+        let slot = pool.try_acquire(None).unwrap();
+        assert!(!pool.wait_for_idle_worker(time::Duration::from_millis(10)));
+
+        let waiter = {
+            let pool = pool.clone();
+            std::thread::spawn(move || pool.wait_for_idle_worker(time::Duration::from_secs(5)))
+        };
+        drop(slot);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_wait_for_busy_wakes_on_acquire() {
+        let pool = Arc::new(WorkerPool::new(2));
+        assert!(!pool.wait_for_busy(1, time::Duration::from_millis(10)));
+
+        let (taken, take) = std::sync::mpsc::channel::<()>();
+        let holder = {
+            let pool = pool.clone();
+            std::thread::spawn(move || {
+                let _slot = pool.acquire(None);
+                let _ = take.recv();
+            })
+        };
+        assert!(pool.wait_for_busy(1, time::Duration::from_secs(5)));
+        assert_eq!(1, pool.busy());
+        drop(taken);
+        holder.join().unwrap();
+        assert!(!pool.wait_for_busy(1, time::Duration::from_millis(10)));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::reader::served_directory_path;
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+};
+
+// Everything that can be wrong with a server before it accepts its first client.
+// Checks do not stop at the first problem, the caller gets the full list so a
+// misconfigured deployment can be fixed in one go.
+#[derive(Debug)]
+pub enum PreflightError {
+    InvalidConfig(String),
+    RootDirMissing(String),
+    RootDirNotReadable(String),
+    RootDirNotWritable(String),
+    PortUnavailable(String),
+}
+
+impl fmt::Display for PreflightError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreflightError::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            PreflightError::RootDirMissing(path) => {
+                write!(f, "root directory {} does not exist", path)
+            }
+            PreflightError::RootDirNotReadable(reason) => {
+                write!(f, "root directory is not readable: {}", reason)
+            }
+            PreflightError::RootDirNotWritable(reason) => {
+                write!(f, "root directory is not writable: {}", reason)
+            }
+            PreflightError::PortUnavailable(reason) => {
+                write!(f, "port is unavailable: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PreflightError {}
+
+pub fn check_config(address: &str, port: &str, thread_count: i32) -> Vec<PreflightError> {
+    let mut errors = Vec::new();
+
+    if address.trim().is_empty() {
+        errors.push(PreflightError::InvalidConfig(
+            "address must not be empty".to_owned(),
+        ));
+    }
+
+    if port.parse::<u16>().is_err() {
+        errors.push(PreflightError::InvalidConfig(format!(
+            "port {:?} is not a number between 0 and 65535",
+            port
+        )));
+    }
+
+    errors.extend(check_thread_count(thread_count));
+    errors
+}
+
+pub fn check_thread_count(thread_count: i32) -> Vec<PreflightError> {
+    // the thread count doubles as the pool size, anything below 1 means no
+    // connection would ever be served
+    if thread_count < 1 {
+        return vec![PreflightError::InvalidConfig(format!(
+            "thread count must be at least 1, got {}",
+            thread_count
+        ))];
+    }
+    Vec::new()
+}
+
+pub fn check_root_dir(root_dir: &str) -> Vec<PreflightError> {
+    let path = served_directory_path(root_dir);
+    if !path.is_dir() {
+        return vec![PreflightError::RootDirMissing(path.display().to_string())];
+    }
+
+    let mut errors = Vec::new();
+    if let Err(err) = fs::read_dir(&path) {
+        errors.push(PreflightError::RootDirNotReadable(format!(
+            "{}: {}",
+            path.display(),
+            err
+        )));
+    }
+
+    // the only reliable way to know if we can write is to try it
+    let probe = path.join(".preflight_probe");
+    match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&probe)
+    {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+        }
+        Err(err) => {
+            errors.push(PreflightError::RootDirNotWritable(format!(
+                "{}: {}",
+                path.display(),
+                err
+            )));
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader;
+
+    #[test]
+    fn test_check_config_reports_every_problem() {
+        let errors = check_config("", "not_a_port", 0);
+        assert_eq!(3, errors.len());
+        assert!(check_config("127.0.0.1", "8080", 4).is_empty());
+    }
+
+    #[test]
+    fn test_check_root_dir() {
+        let root_dir = "temp_test_preflight_root_dir";
+        reader::cleanup_server_file(root_dir);
+        assert!(matches!(
+            check_root_dir(root_dir).as_slice(),
+            [PreflightError::RootDirMissing(_)]
+        ));
+
+        reader::configure_directory_to_serve_file(root_dir);
+        assert!(check_root_dir(root_dir).is_empty());
+
+        reader::cleanup_server_file(root_dir);
+    }
+}
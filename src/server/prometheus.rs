@@ -0,0 +1,554 @@
+use super::http::{spawn_http_listener, HttpResponse};
+use super::limit::ConnectionLimit;
+use super::metrics::MetricsRegistry;
+use super::server::{FileServer, FileServerError};
+use crate::protocol::stats::{HistogramSnapshot, StatsSnapshot};
+use std::{fmt::Write, sync::atomic::Ordering};
+
+impl FileServer {
+    // Serves the metrics registry in the Prometheus text format on
+    // http://address:port/metrics
+    pub fn start_prometheus_exporter(
+        &self,
+        address: &str,
+        port: &str,
+    ) -> Result<(), FileServerError> {
+        let metrics = self.metrics.clone();
+        let pool = self.pool.clone();
+        let connection_limit = self.connection_limit.clone();
+
+        spawn_http_listener(address, port, move |request_line| {
+            if request_line.split_whitespace().nth(1) != Some("/metrics") {
+                return HttpResponse {
+                    status: 404,
+                    reason: "Not Found",
+                    content_type: "text/plain",
+                    body: "metrics live under /metrics".to_owned(),
+                };
+            }
+
+            let snapshot = metrics.snapshot(&pool);
+            HttpResponse::ok(
+                "text/plain; version=0.0.4",
+                render(&snapshot, &metrics, &connection_limit),
+            )
+        })
+    }
+}
+
+pub fn render(
+    snapshot: &StatsSnapshot,
+    metrics: &MetricsRegistry,
+    connection_limit: &ConnectionLimit,
+) -> String {
+    let mut out = String::new();
+
+    metric_header(
+        &mut out,
+        "fileserver_busy_workers",
+        "gauge",
+        "Workers currently serving a client",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_busy_workers {}",
+        snapshot.number_of_clients
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_waiting_connections",
+        "gauge",
+        "Connections waiting for a free worker",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_waiting_connections {}",
+        snapshot.pool.waiting_connections
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_workers",
+        "gauge",
+        "Workers in the pool, thread_count",
+    );
+    let _ = writeln!(out, "fileserver_workers {}", snapshot.pool.workers);
+
+    metric_header(
+        &mut out,
+        "fileserver_dispatch_wait_seconds",
+        "histogram",
+        "Time from a connection's command being read to a worker picking it up",
+    );
+    histogram_lines(
+        &mut out,
+        "fileserver_dispatch_wait_seconds",
+        "",
+        &snapshot.pool.dispatch_wait,
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_queue_waiting_connections",
+        "gauge",
+        "Downloads waiting for a free worker, per size class",
+    );
+    for queue in &snapshot.pool.queues {
+        let _ = writeln!(
+            out,
+            "fileserver_queue_waiting_connections{{class=\"{}\"}} {}",
+            queue.class, queue.waiting
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "fileserver_queue_dispatch_wait_seconds",
+        "histogram",
+        "Time downloads waited for a worker, per size class",
+    );
+    for queue in &snapshot.pool.queues {
+        histogram_lines(
+            &mut out,
+            "fileserver_queue_dispatch_wait_seconds",
+            &format!("class=\"{}\",", queue.class),
+            &queue.dispatch_wait,
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "fileserver_request_duration_seconds",
+        "histogram",
+        "Time handlers took to serve a request, per command",
+    );
+    for (command, durations) in &snapshot.request_durations {
+        histogram_lines(
+            &mut out,
+            "fileserver_request_duration_seconds",
+            &format!("command=\"{}\",", escape_label(command)),
+            durations,
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "fileserver_open_connections",
+        "gauge",
+        "Client sockets open, stats subscribers included",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_open_connections {}",
+        connection_limit.open()
+    );
+    if let Some(max) = connection_limit.max() {
+        metric_header(
+            &mut out,
+            "fileserver_max_connections",
+            "gauge",
+            "Client sockets allowed open at once",
+        );
+        let _ = writeln!(out, "fileserver_max_connections {}", max);
+    }
+
+    metric_header(
+        &mut out,
+        "fileserver_connections_total",
+        "counter",
+        "Connections accepted",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_connections_total {}",
+        metrics.total_connections.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_bytes_served_total",
+        "counter",
+        "Bytes sent to clients",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_bytes_served_total {}",
+        snapshot.bytes_served
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_shed_connections_total",
+        "counter",
+        "Connections turned away with a BUSY frame while every worker was busy",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_shed_connections_total {}",
+        metrics.shed_connections.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_request_bodies_total",
+        "counter",
+        "Upload and sync manifest bodies read from clients",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_request_bodies_total {}",
+        metrics.request_bodies.load(Ordering::Relaxed)
+    );
+    metric_header(
+        &mut out,
+        "fileserver_request_body_bytes_total",
+        "counter",
+        "Bytes of upload and sync manifest bodies read from clients",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_request_body_bytes_total {}",
+        metrics.request_body_bytes.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_hot_cache_hits_total",
+        "counter",
+        "Downloads served from the hot file cache",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_hot_cache_hits_total {}",
+        metrics.hot_files.hits.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_hot_cache_misses_total",
+        "counter",
+        "Downloads that had to go to disk",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_hot_cache_misses_total {}",
+        metrics.hot_files.misses.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_shared_mapping_reuses_total",
+        "counter",
+        "Downloads that read a mapping another download of the file opened",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_shared_mapping_reuses_total {}",
+        metrics.shared_mappings.reuses.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_stats_subscribers",
+        "gauge",
+        "Clients following the statistics",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_stats_subscribers {}",
+        metrics.stats_subscribers.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_stats_subscribers_evicted_total",
+        "counter",
+        "Stats subscribers dropped after hanging up or missing heartbeats",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_stats_subscribers_evicted_total {}",
+        metrics.stats_subscribers_evicted.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_chunks_resent_total",
+        "counter",
+        "Reliable download chunks sent again after a negative or missing ack",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_chunks_resent_total {}",
+        metrics.chunks_resent.load(Ordering::Relaxed)
+    );
+    metric_header(
+        &mut out,
+        "fileserver_delta_copied_bytes_total",
+        "counter",
+        "Bytes of delta downloads clients took from their own copies instead",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_delta_copied_bytes_total {}",
+        metrics.delta_bytes_copied.load(Ordering::Relaxed)
+    );
+    metric_header(
+        &mut out,
+        "fileserver_precompressed_downloads_total",
+        "counter",
+        "Downloads served from a gzip precompressed sibling of the file",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_precompressed_downloads_total {}",
+        metrics.precompressed_downloads.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_watch_subscribers",
+        "gauge",
+        "Clients watching served files for changes",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_watch_subscribers {}",
+        metrics.watches.subscribers()
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_handler_panics_total",
+        "counter",
+        "Requests whose handler panicked",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_handler_panics_total {}",
+        metrics.handler_panics.load(Ordering::Relaxed)
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_thread_panics_total",
+        "counter",
+        "Server threads that ended in a panic",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_thread_panics_total {}",
+        metrics.threads.panics()
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_handler_timeouts_total",
+        "counter",
+        "Connections hung up on for running over their command's budget",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_handler_timeouts_total {}",
+        metrics.watchdog.timeouts()
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_janitor_removed_total",
+        "counter",
+        "Stale uploads, trashed files and empty directories the janitor removed",
+    );
+    let removed = metrics.janitor.counts();
+    for (kind, count) in [
+        ("partial", removed.partial_files),
+        ("trash", removed.trash_entries),
+        ("directory", removed.directories),
+    ] {
+        let _ = writeln!(
+            out,
+            "fileserver_janitor_removed_total{{kind=\"{}\"}} {}",
+            kind, count
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "fileserver_replication_sent_total",
+        "counter",
+        "Uploads and deletes replicas took",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_replication_sent_total {}",
+        metrics.replication.sent()
+    );
+    metric_header(
+        &mut out,
+        "fileserver_replication_failures_total",
+        "counter",
+        "Attempts to send a replica an upload or delete that failed",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_replication_failures_total {}",
+        metrics.replication.failures()
+    );
+    metric_header(
+        &mut out,
+        "fileserver_replication_pending",
+        "gauge",
+        "Uploads and deletes waiting for a replica",
+    );
+    let _ = writeln!(
+        out,
+        "fileserver_replication_pending {}",
+        metrics.replication.pending()
+    );
+
+    metric_header(
+        &mut out,
+        "fileserver_file_downloads_total",
+        "counter",
+        "Downloads per file",
+    );
+    let mut downloads: Vec<(String, i64)> = metrics.file_stat_snapshot().into_iter().collect();
+    downloads.sort();
+    for (file_name, count) in downloads {
+        let _ = writeln!(
+            out,
+            "fileserver_file_downloads_total{{file=\"{}\"}} {}",
+            escape_label(&file_name),
+            count
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "fileserver_file_bytes_total",
+        "counter",
+        "Bytes sent per file, counted when a transfer ends",
+    );
+    for (file_name, bytes) in &snapshot.bytes_per_file {
+        let _ = writeln!(
+            out,
+            "fileserver_file_bytes_total{{file=\"{}\"}} {}",
+            escape_label(file_name),
+            bytes
+        );
+    }
+
+    metric_header(
+        &mut out,
+        "fileserver_transfer_bytes_per_second",
+        "gauge",
+        "Average throughput of transfers in flight",
+    );
+    for transfer in &snapshot.transfers {
+        let _ = writeln!(
+            out,
+            "fileserver_transfer_bytes_per_second{{transfer=\"{}\",file=\"{}\"}} {}",
+            transfer.id,
+            escape_label(&transfer.file_name),
+            transfer.bytes_per_second
+        );
+    }
+
+    out
+}
+
+// The _bucket, _sum and _count lines of one histogram, in seconds. `labels`
+// is `name="value",` pairs put in front of `le`.
+fn histogram_lines(out: &mut String, name: &str, labels: &str, histogram: &HistogramSnapshot) {
+    for (bound, count) in &histogram.buckets {
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"{}\"}} {}",
+            name,
+            labels,
+            *bound as f64 / 1_000_000.0,
+            count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{}le=\"+Inf\"}} {}",
+        name, labels, histogram.count
+    );
+    let labels = labels.trim_end_matches(',');
+    let labels = match labels {
+        "" => String::new(),
+        labels => format!("{{{}}}", labels),
+    };
+    let _ = writeln!(
+        out,
+        "{}_sum{} {}",
+        name,
+        labels,
+        histogram.sum_micros as f64 / 1_000_000.0
+    );
+    let _ = writeln!(out, "{}_count{} {}", name, labels, histogram.count);
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::pool::WorkerPool;
+
+    #[test]
+    fn test_render_counts_downloads_and_bytes() {
+        let metrics = MetricsRegistry::new();
+        metrics.record_connection();
+        metrics.record_download("a\"b".to_owned());
+        {
+            let transfer = metrics.begin_transfer("a\"b", "127.0.0.1:1", None);
+            transfer.record_bytes_sent(42);
+        }
+
+        metrics
+            .dispatch_wait
+            .observe(std::time::Duration::from_millis(2));
+        metrics.record_request(
+            crate::CommandType::Download,
+            std::time::Duration::from_millis(30),
+        );
+
+        let text = render(
+            &metrics.snapshot(&WorkerPool::new(3)),
+            &metrics,
+            &ConnectionLimit::new(Some(4), Default::default()),
+        );
+
+        assert!(text.contains("fileserver_connections_total 1\n"));
+        assert!(text.contains("fileserver_bytes_served_total 42\n"));
+        assert!(text.contains("fileserver_max_connections 4\n"));
+        assert!(text.contains("fileserver_workers 3\n"));
+        assert!(text.contains("fileserver_dispatch_wait_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(text.contains("fileserver_dispatch_wait_seconds_bucket{le=\"0.01\"} 1\n"));
+        assert!(text.contains("fileserver_dispatch_wait_seconds_count 1\n"));
+        assert!(text.contains(
+            "fileserver_request_duration_seconds_bucket{command=\"Download\",le=\"0.025\"} 0\n"
+        ));
+        assert!(text.contains(
+            "fileserver_request_duration_seconds_bucket{command=\"Download\",le=\"0.05\"} 1\n"
+        ));
+        assert!(
+            text.contains("fileserver_request_duration_seconds_count{command=\"Download\"} 1\n")
+        );
+        assert!(text.contains("fileserver_file_downloads_total{file=\"a\\\"b\"} 1\n"));
+        assert!(text.contains("fileserver_file_bytes_total{file=\"a\\\"b\"} 42\n"));
+    }
+}
@@ -0,0 +1,202 @@
+use super::connection::Connection;
+use super::keep_alive::{
+    write_error_frame, write_frame_header, write_open_error_frame, DownloadExtras, FRAME_OK,
+};
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use super::types::CommandType;
+use crate::protocol;
+use crate::reader::validate_file_name;
+use std::{
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+    time,
+};
+
+// Big files can be fetched over several connections at once: Stat tells the
+// client how big the file is, then each connection asks for its own byte range.
+impl FileServer {
+    // Request: filename=a_file_name|
+    // Reply: an OK frame holding `<size>\t<modified, unix seconds>`.
+    pub fn handle_stat(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_stat(stream, root_dir, &metrics_registry);
+    }
+
+    // Request: filename=a_file_name|range=<start>-<end>|, end exclusive
+    // Reply: bytes start..end of the file in an OK frame.
+    pub fn handle_range_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_range_download(
+            stream,
+            root_dir,
+            &metrics_registry,
+            DownloadExtras::default(),
+        );
+    }
+
+    pub(crate) fn framed_stat(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let file_name = match Self::read_file_request(stream, &metrics_registry.limits()) {
+            Ok(file_name) => file_name,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::Stat, &file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        // a directory is no file to fetch either
+        let stored = match Self::stored_file(&file_name, root_dir, metrics_registry) {
+            Ok(stored) => stored,
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+
+        let modified = stored
+            .modified
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let size = metrics_registry.served_len(stored.size);
+        let etag = match Self::served_etag(&file_name, root_dir, metrics_registry) {
+            Ok(etag) => etag,
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+        let reply = format!("{}\t{}\t{}", size, modified, etag);
+        write_frame_header(stream, FRAME_OK, reply.len() as u64)?;
+        stream.write_all(reply.as_bytes())
+    }
+
+    pub(crate) fn framed_range_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+        extras: DownloadExtras,
+    ) -> io::Result<()> {
+        let request =
+            Self::read_file_request(stream, &metrics_registry.limits()).and_then(|file_name| {
+                let segment = Self::read_request_segment(stream, &metrics_registry.limits())?;
+                Ok((file_name, segment))
+            });
+        let (file_name, segment) = match request {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        let (start, end) = match protocol::parse_range(&segment) {
+            Ok(range) => range,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        if let Err(err) = validate_file_name(&file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::RangeDownload, &file_name)
+        {
+            return write_error_frame(stream, err.to_string());
+        }
+
+        let mut file_reader = match Self::open_served_file(&file_name, root_dir, metrics_registry) {
+            Ok(file_reader) => file_reader,
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+        if end > file_reader.len() {
+            return write_error_frame(
+                stream,
+                format!(
+                    "range {}-{} is past the end of {} ({} bytes)",
+                    start,
+                    end,
+                    file_name,
+                    file_reader.len()
+                ),
+            );
+        }
+        // a file fetched in pieces counts as one download, the first piece
+        if start == 0 {
+            metrics_registry.record_download(file_name.clone());
+        }
+
+        let length = end - start;
+        file_reader.seek(SeekFrom::Start(start))?;
+        if extras.content_type {
+            Self::write_content_type_frame(stream, &file_name, root_dir, metrics_registry)?;
+        }
+        write_frame_header(stream, FRAME_OK, length)?;
+        let sent = Self::stream_frame_body(
+            &mut file_reader.take(length),
+            stream,
+            metrics_registry,
+            &file_name,
+            extras.checksum,
+        )?;
+        if sent != length {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "file size changed mid transfer",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::*;
+    use crate::reader;
+    use std::fs;
+
+    fn read_reply(client: &dyn Connection) -> (u8, Vec<u8>) {
+        let mut client = client;
+        let mut header = [0u8; 9];
+        client.read_exact(&mut header).unwrap();
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&header[1..]);
+        let mut payload = vec![0; u64::from_be_bytes(length) as usize];
+        client.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+
+    #[test]
+    fn test_stat_and_range() {
+        let root_dir = "temp_test_range_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("digits"), b"0123456789").unwrap();
+        let metrics = MetricsRegistry::new();
+
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=digits|").unwrap();
+        FileServer::framed_stat(&server, root_dir, &metrics).unwrap();
+        let (status, payload) = read_reply(&client);
+        assert_eq!(FRAME_OK, status);
+        assert!(String::from_utf8(payload).unwrap().starts_with("10\t"));
+
+        client_end.write_all(b"filename=digits|range=3-7|").unwrap();
+        FileServer::framed_range_download(&server, root_dir, &metrics, DownloadExtras::default())
+            .unwrap();
+        assert_eq!((FRAME_OK, b"3456".to_vec()), read_reply(&client));
+        // only the piece starting at 0 counts as a download
+        assert_eq!(0, metrics.download_count("digits"));
+
+        client_end
+            .write_all(b"filename=digits|range=8-11|")
+            .unwrap();
+        FileServer::framed_range_download(&server, root_dir, &metrics, DownloadExtras::default())
+            .unwrap();
+        assert_ne!(FRAME_OK, read_reply(&client).0);
+
+        reader::cleanup_server_file(root_dir);
+    }
+}
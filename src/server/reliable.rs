@@ -0,0 +1,279 @@
+use super::connection::Connection;
+use super::keep_alive::{
+    write_error_frame, write_frame_header, write_open_error_frame, FRAME_CHUNK, FRAME_OK,
+};
+use super::metrics::MetricsRegistry;
+use super::request_id::log_prefix;
+use super::server::FileServer;
+use super::types::CommandType;
+use crate::protocol;
+use crate::reader::validate_file_name;
+use std::{
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    sync::{atomic::Ordering, Arc},
+    time,
+};
+
+// What a client answers after each window of chunks: the kind byte, then a
+// chunk sequence number as a u64 big endian.
+// every chunk before the number arrived, it is the next one expected
+pub const CHUNK_ACK: u8 = 0;
+// the numbered chunk was lost or mangled, send the window starting with it
+pub const CHUNK_NACK: u8 = 1;
+
+// how long the server waits for an ack before sending the window again
+const ACK_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+// windows sent again in one transfer before the server gives up on the client
+const MAX_RESENDS: u32 = 16;
+
+impl FileServer {
+    // TCP is not end to end when a transfer goes through relays that
+    // terminate it, a relay that drops or mangles data takes the download down
+    // with it. Here each chunk is numbered and the client acknowledges every
+    // window of them, so the server can go back to the first chunk that did
+    // not make it over the same connection.
+    //
+    // Request: filename=a_file_name|chunks=<chunk bytes>,<ack every>|
+    // Reply: an OK frame holding `<size>\t<chunk count>\t<etag>`, then the
+    // chunks a window of `ack every` at a time in FRAME_CHUNK frames. After
+    // each window the client sends CHUNK_ACK with the number of the chunk
+    // after it, or CHUNK_NACK with the first one it is missing. A window
+    // that goes unacknowledged for ACK_TIMEOUT is sent again.
+    pub fn handle_reliable_download(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_reliable_download(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_reliable_download(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let request =
+            Self::read_file_request(stream, &metrics_registry.limits()).and_then(|file_name| {
+                let segment = Self::read_request_segment(stream, &metrics_registry.limits())?;
+                Ok((file_name, segment))
+            });
+        let (file_name, segment) = match request {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        let (chunk_bytes, ack_every) = match protocol::parse_chunking(&segment) {
+            Ok(chunking) => chunking,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        if let Err(err) = validate_file_name(&file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        if let Err(err) =
+            metrics_registry.authorize(stream, CommandType::ReliableDownload, &file_name)
+        {
+            return write_error_frame(stream, err.to_string());
+        }
+
+        let mut file_reader = match Self::open_served_file(&file_name, root_dir, metrics_registry) {
+            Ok(file_reader) => file_reader,
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+        let etag = match Self::served_etag(&file_name, root_dir, metrics_registry) {
+            Ok(etag) => etag,
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+        let size = file_reader.len();
+        let chunks = size.div_ceil(chunk_bytes);
+        metrics_registry.record_download(file_name.clone());
+        let reply = format!("{}\t{}\t{}", size, chunks, etag);
+        write_frame_header(stream, FRAME_OK, reply.len() as u64)?;
+        stream.write_all(reply.as_bytes())?;
+
+        let previous_timeout = stream.read_timeout()?;
+        stream.set_read_timeout(Some(ACK_TIMEOUT))?;
+        let transfer =
+            metrics_registry.begin_transfer(&file_name, &stream.peer(), stream.request_id());
+        let throttle = metrics_registry.throttle();
+        let mut next = 0;
+        let mut resends = 0;
+        while next < chunks {
+            let window_end = (next + ack_every).min(chunks);
+            for seq in next..window_end {
+                if transfer.is_cancelled() {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        format!("transfer {} was cancelled", transfer.id()),
+                    ));
+                }
+                if transfer.is_over_daily_cap() {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        format!("daily transfer cap reached for {}", stream.peer()),
+                    ));
+                }
+                let length = chunk_bytes.min(size - seq * chunk_bytes);
+                let mut chunk = Vec::with_capacity(length as usize);
+                Read::by_ref(&mut file_reader)
+                    .take(length)
+                    .read_to_end(&mut chunk)?;
+                if chunk.len() as u64 != length {
+                    return Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "file size changed mid transfer",
+                    ));
+                }
+                throttle.pace(length);
+                write_frame_header(stream, FRAME_CHUNK, 8 + length)?;
+                stream.write_all(&seq.to_be_bytes())?;
+                stream.write_all(&chunk)?;
+                transfer.record_bytes_sent(length);
+            }
+
+            let resend_from = match read_ack(stream) {
+                Ok((CHUNK_ACK, acked)) if acked == window_end => {
+                    next = window_end;
+                    continue;
+                }
+                Ok((CHUNK_NACK, missing)) if (next..window_end).contains(&missing) => missing,
+                Ok((kind, seq)) => {
+                    let _ = write_error_frame(
+                        stream,
+                        format!(
+                            "unexpected acknowledgment {} {} for chunks {}-{}",
+                            kind, seq, next, window_end
+                        ),
+                    );
+                    return Err(io::Error::new(ErrorKind::InvalidData, "bad acknowledgment"));
+                }
+                // the ack or the end of the window got lost on the way
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    next
+                }
+                Err(err) => return Err(err),
+            };
+            resends += 1;
+            if resends > MAX_RESENDS {
+                return write_error_frame(
+                    stream,
+                    format!("gave up on {} after {} resends", file_name, MAX_RESENDS),
+                );
+            }
+            metrics_registry
+                .chunks_resent
+                .fetch_add(window_end - resend_from, Ordering::Relaxed);
+            println!(
+                "{}...Resending chunks {}-{} of {} to {}",
+                log_prefix(stream),
+                resend_from,
+                window_end,
+                file_name,
+                stream.peer()
+            );
+            next = resend_from;
+            file_reader.seek(SeekFrom::Start(next * chunk_bytes))?;
+        }
+        transfer.finish();
+        stream.set_read_timeout(previous_timeout)
+    }
+}
+
+// Reads one acknowledgment off the stream, its kind and chunk number.
+fn read_ack(mut stream: &dyn Connection) -> io::Result<(u8, u64)> {
+    let mut ack = [0u8; 9];
+    stream.read_exact(&mut ack)?;
+    let mut seq = [0u8; 8];
+    seq.copy_from_slice(&ack[1..]);
+    Ok((ack[0], u64::from_be_bytes(seq)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::*;
+    use crate::reader;
+    use std::{fs, thread};
+
+    fn read_reply(client: &dyn Connection) -> (u8, Vec<u8>) {
+        let mut client = client;
+        let mut header = [0u8; 9];
+        client.read_exact(&mut header).unwrap();
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&header[1..]);
+        let mut payload = vec![0; u64::from_be_bytes(length) as usize];
+        client.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+
+    fn read_chunk(client: &dyn Connection) -> (u64, Vec<u8>) {
+        let (status, payload) = read_reply(client);
+        assert_eq!(FRAME_CHUNK, status);
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&payload[..8]);
+        (u64::from_be_bytes(seq), payload[8..].to_vec())
+    }
+
+    fn ack(client: &dyn Connection, kind: u8, seq: u64) {
+        let mut client = client;
+        client.write_all(&[kind]).unwrap();
+        client.write_all(&seq.to_be_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_reliable_download_resends_after_a_nack() {
+        let root_dir = "temp_test_reliable_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("digits"), b"0123456789").unwrap();
+        let metrics = Arc::new(MetricsRegistry::new());
+
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end
+            .write_all(b"filename=digits|chunks=3,2|")
+            .unwrap();
+        let serving = metrics.clone();
+        let handler = thread::spawn(move || {
+            FileServer::framed_reliable_download(&server, root_dir, &serving)
+        });
+        let (status, header) = read_reply(&client);
+        assert_eq!(FRAME_OK, status);
+        assert!(String::from_utf8(header).unwrap().starts_with("10\t4\t"));
+
+        assert_eq!((0, b"012".to_vec()), read_chunk(&client));
+        assert_eq!((1, b"345".to_vec()), read_chunk(&client));
+        // pretend chunk 1 got mangled on the way
+        ack(&client, CHUNK_NACK, 1);
+        assert_eq!((1, b"345".to_vec()), read_chunk(&client));
+        assert_eq!((2, b"678".to_vec()), read_chunk(&client));
+        ack(&client, CHUNK_ACK, 3);
+        assert_eq!((3, b"9".to_vec()), read_chunk(&client));
+        ack(&client, CHUNK_ACK, 4);
+
+        handler.join().unwrap().unwrap();
+        assert_eq!(1, metrics.chunks_resent.load(Ordering::Relaxed));
+        assert_eq!(1, metrics.download_count("digits"));
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_reliable_download_refuses_an_ack_out_of_the_window() {
+        let root_dir = "temp_test_reliable_bad_ack_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("digits"), b"0123456789").unwrap();
+        let metrics = MetricsRegistry::new();
+
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end
+            .write_all(b"filename=digits|chunks=5,1|")
+            .unwrap();
+        ack(&client, CHUNK_ACK, 7);
+        assert!(FileServer::framed_reliable_download(&server, root_dir, &metrics).is_err());
+        assert_eq!(FRAME_OK, read_reply(&client).0);
+        read_chunk(&client);
+        assert_eq!(super::super::keep_alive::FRAME_ERROR, read_reply(&client).0);
+        reader::cleanup_server_file(root_dir);
+    }
+}
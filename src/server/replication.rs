@@ -0,0 +1,412 @@
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use super::threads::ThreadRegistry;
+use crate::client::{ClientError, FileClient};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
+    time,
+};
+
+// how long a replica that could not be reached is left alone
+const RETRY: time::Duration = time::Duration::from_secs(10);
+
+// A change to the served files that replicas are sent too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    // the file as it is when the replica gets it, not as it was uploaded
+    Upload(String),
+    Delete(String),
+}
+
+// A mutation a replica has not acknowledged yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pending {
+    // "host:port"
+    replica: String,
+    mutation: Mutation,
+}
+
+impl Pending {
+    // A line of the queue file: `<replica>\t<upload|delete>\t<file name>`,
+    // line breaks in the name escaped.
+    fn encode_line(&self) -> String {
+        let (operation, file_name) = match &self.mutation {
+            Mutation::Upload(file_name) => ("upload", file_name),
+            Mutation::Delete(file_name) => ("delete", file_name),
+        };
+        let file_name = file_name.replace('\n', "\\n").replace('\r', "\\r");
+        format!("{}\t{}\t{}\n", self.replica, operation, file_name)
+    }
+
+    fn parse_line(line: &str) -> Option<Pending> {
+        let mut fields = line.splitn(3, '\t');
+        let (replica, operation, file_name) = (fields.next()?, fields.next()?, fields.next()?);
+        let file_name = file_name.replace("\\n", "\n").replace("\\r", "\r");
+        let mutation = match operation {
+            "upload" => Mutation::Upload(file_name),
+            "delete" => Mutation::Delete(file_name),
+            _ => return None,
+        };
+        Some(Pending {
+            replica: replica.to_owned(),
+            mutation,
+        })
+    }
+}
+
+// Sends uploads and deletes on to other file servers with FileClient, in
+// the order they happened, from a thread of its own. What a replica could
+// not take waits in a queue, rewritten to disk on every change when there is
+// a queue file, and is tried again every few seconds and after a restart.
+// Only the root is replicated, files in user homes stay on this server.
+// Replicas should not replicate back, an upload would go round forever.
+#[derive(Default)]
+pub struct Replication {
+    state: Arc<ReplicationState>,
+    threads: ThreadRegistry,
+}
+
+#[derive(Default)]
+struct ReplicationState {
+    queue: Mutex<Queue>,
+    // rung on every new mutation
+    changed: Condvar,
+    started: AtomicBool,
+    sent: AtomicU64,
+    failures: AtomicU64,
+}
+
+#[derive(Default)]
+struct Queue {
+    replicas: Vec<String>,
+    root_dir: Option<&'static str>,
+    path: Option<PathBuf>,
+    pending: VecDeque<Pending>,
+    // replicas that failed are skipped until then
+    retry_at: HashMap<String, time::Instant>,
+    // set by record, so a mutation queued during a pass is not left waiting
+    changed: bool,
+}
+
+impl Queue {
+    // The whole queue goes to a temp file renamed over the old one, a crash
+    // leaves one or the other.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let lines: String = self.pending.iter().map(Pending::encode_line).collect();
+        let temp = path.with_extension("tmp");
+        if let Err(err) = fs::write(&temp, lines).and_then(|_| fs::rename(&temp, path)) {
+            println!("...Error saving the replication queue:{err}");
+        }
+    }
+}
+
+impl Replication {
+    // Replication whose thread is named and tracked by `threads`.
+    pub(crate) fn with_threads(threads: &ThreadRegistry) -> Replication {
+        Replication {
+            threads: threads.clone(),
+            ..Replication::default()
+        }
+    }
+
+    // Replicates mutations of `root_dir` to `replicas` from now on. With a
+    // `queue` file whatever it holds for these replicas is sent first,
+    // entries for replicas no longer configured are dropped.
+    pub(crate) fn configure(
+        &self,
+        root_dir: &'static str,
+        replicas: &[String],
+        queue: Option<&Path>,
+    ) -> io::Result<()> {
+        let mut pending = VecDeque::new();
+        if let Some(path) = queue {
+            match fs::read_to_string(path) {
+                Ok(lines) => {
+                    for line in lines.lines() {
+                        match Pending::parse_line(line) {
+                            Some(entry) if replicas.contains(&entry.replica) => {
+                                pending.push_back(entry)
+                            }
+                            Some(_) => {}
+                            None => println!("...Skipping replication queue line {:?}", line),
+                        }
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut state = self.state.queue.lock().unwrap();
+        state.replicas = replicas.to_vec();
+        state.root_dir = Some(root_dir);
+        state.path = queue.map(Path::to_path_buf);
+        state.pending = pending;
+        state.save();
+        Ok(())
+    }
+
+    pub fn replicas(&self) -> Vec<String> {
+        self.state.queue.lock().unwrap().replicas.clone()
+    }
+
+    // Mutations waiting for a replica, one per replica that still needs it.
+    pub fn pending(&self) -> usize {
+        self.state.queue.lock().unwrap().pending.len()
+    }
+
+    // Mutations replicas took since the server started.
+    pub fn sent(&self) -> u64 {
+        self.state.sent.load(Ordering::Relaxed)
+    }
+
+    // Attempts that failed since the server started, each one tried again later.
+    pub fn failures(&self) -> u64 {
+        self.state.failures.load(Ordering::Relaxed)
+    }
+
+    // Queues `mutation` of a file under `root_dir` for every replica. Only
+    // the configured root is replicated, anything else is ignored.
+    pub(crate) fn record(&self, root_dir: &str, mutation: Mutation) {
+        let mut queue = self.state.queue.lock().unwrap();
+        if queue.replicas.is_empty() || queue.root_dir != Some(root_dir) {
+            return;
+        }
+        // not folded into an upload still waiting, it may be on its way
+        // already with the content from before
+        for replica in queue.replicas.clone() {
+            queue.pending.push_back(Pending {
+                replica,
+                mutation: mutation.clone(),
+            });
+        }
+        queue.save();
+        queue.changed = true;
+        drop(queue);
+        self.state.changed.notify_all();
+    }
+
+    // Starts the thread sending what is queued, for as long as `metrics` is
+    // around.
+    pub(crate) fn start(&self, metrics: Weak<MetricsRegistry>) {
+        if self.state.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let state = Arc::downgrade(&self.state);
+        self.threads.spawn("replication", move || loop {
+            // ends with the registry replication belongs to
+            let Some(registry) = metrics.upgrade() else {
+                return;
+            };
+            registry.replication.send_pending(&registry);
+            drop(registry);
+
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            let mut queue = state.queue.lock().unwrap();
+            if !queue.changed {
+                // woken early by new mutations, failed replicas are still skipped
+                queue = state.changed.wait_timeout(queue, RETRY).unwrap().0;
+            }
+            queue.changed = false;
+        });
+    }
+
+    // One pass over the queue on the calling thread: every replica not
+    // waiting out a failure gets its mutations in order until one fails.
+    // Returns how many were sent.
+    pub(crate) fn send_pending(&self, metrics: &MetricsRegistry) -> usize {
+        let (replicas, root_dir) = {
+            let queue = self.state.queue.lock().unwrap();
+            let now = time::Instant::now();
+            let replicas: Vec<String> = queue
+                .replicas
+                .iter()
+                .filter(|replica| queue.retry_at.get(*replica).is_none_or(|at| *at <= now))
+                .cloned()
+                .collect();
+            match queue.root_dir {
+                Some(root_dir) => (replicas, root_dir),
+                None => return 0,
+            }
+        };
+
+        let mut sent = 0;
+        for replica in replicas {
+            let mut client = None;
+            loop {
+                let next = {
+                    let queue = self.state.queue.lock().unwrap();
+                    queue
+                        .pending
+                        .iter()
+                        .find(|entry| entry.replica == replica)
+                        .cloned()
+                };
+                let Some(entry) = next else {
+                    break;
+                };
+                let client = client.get_or_insert_with(|| replica_client(&replica));
+                let result = send(client, &entry.mutation, root_dir, metrics);
+
+                let mut queue = self.state.queue.lock().unwrap();
+                if let Err(err) = result {
+                    println!(
+                        "...Replica {} did not take {:?}, trying again later:{err}",
+                        replica, entry.mutation
+                    );
+                    self.state.failures.fetch_add(1, Ordering::Relaxed);
+                    queue
+                        .retry_at
+                        .insert(replica.clone(), time::Instant::now() + RETRY);
+                    break;
+                }
+                // taken out by position, more may have been queued meanwhile
+                if let Some(pos) = queue.pending.iter().position(|other| other == &entry) {
+                    queue.pending.remove(pos);
+                }
+                queue.retry_at.remove(&replica);
+                queue.save();
+                self.state.sent.fetch_add(1, Ordering::Relaxed);
+                sent += 1;
+            }
+        }
+        sent
+    }
+}
+
+// "host:port", the host may be a bracketed IPv6 address.
+fn replica_client(replica: &str) -> FileClient {
+    let (address, port) = replica.rsplit_once(':').unwrap_or((replica, ""));
+    FileClient::new(address, port)
+}
+
+// A file gone by the time it is sent, or already gone from the replica when
+// deleted, needs nothing more, the mutation after it says what became of it.
+fn send(
+    client: &mut FileClient,
+    mutation: &Mutation,
+    root_dir: &str,
+    metrics: &MetricsRegistry,
+) -> Result<(), ClientError> {
+    match mutation {
+        Mutation::Upload(file_name) => {
+            let source = match FileServer::open_stored_file(file_name, root_dir, metrics) {
+                Ok(source) => source,
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(ClientError::Io(err.to_string())),
+            };
+            let len = source.len();
+            client.upload_from_reader(file_name, source, len)
+        }
+        Mutation::Delete(file_name) => match client.delete(file_name) {
+            Err(ClientError::NotFound(_)) => Ok(()),
+            result => result,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::ServeDir;
+    use crate::testkit::TestServer;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_replicas_get_mutations_in_order_and_down_ones_wait() {
+        let root_dir = "temp_test_replication_root_dir";
+        let root = ServeDir::create(root_dir).unwrap();
+        let metrics = MetricsRegistry::new();
+        let replica = TestServer::start();
+        replica.add_file("gone.txt", "old");
+        // nothing listens there once the listener is dropped
+        let down = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let up = replica.addr().to_string();
+        let queue = std::env::temp_dir().join("fileserver_test_replication.queue");
+        let _ = fs::remove_file(&queue);
+
+        let replication = Replication::default();
+        let replicas = [up.clone(), down.clone()];
+        replication
+            .configure(root_dir, &replicas, Some(&queue))
+            .unwrap();
+        fs::write(root.path().join("new\nline.txt"), b"fresh").unwrap();
+        replication.record(root_dir, Mutation::Upload("new\nline.txt".to_owned()));
+        replication.record(root_dir, Mutation::Delete("gone.txt".to_owned()));
+        // a user's home is not replicated
+        replication.record("elsewhere", Mutation::Delete("gone.txt".to_owned()));
+        assert_eq!(4, replication.pending());
+
+        assert_eq!(2, replication.send_pending(&metrics));
+        assert_eq!(
+            b"fresh".to_vec(),
+            fs::read(replica.root().join("new\nline.txt")).unwrap()
+        );
+        assert!(!replica.root().join("gone.txt").exists());
+        assert_eq!((2, 1), (replication.sent(), replication.failures()));
+        // the down replica is left alone until its retry is due
+        assert_eq!(0, replication.send_pending(&metrics));
+        assert_eq!(1, replication.failures());
+
+        // what the down replica still needs outlives a restart
+        let restarted = Replication::default();
+        restarted
+            .configure(root_dir, &replicas, Some(&queue))
+            .unwrap();
+        assert_eq!(2, restarted.pending());
+        let lines = fs::read_to_string(&queue).unwrap();
+        assert_eq!(
+            format!("{down}\tupload\tnew\\nline.txt\n{down}\tdelete\tgone.txt\n"),
+            lines
+        );
+        // and is dropped once it is no replica any more
+        restarted.configure(root_dir, &[up], Some(&queue)).unwrap();
+        assert_eq!(0, restarted.pending());
+
+        let _ = fs::remove_file(&queue);
+    }
+
+    #[test]
+    fn test_uploads_and_deletes_reach_the_replica() {
+        let (uploaded, replicated) = std::sync::mpsc::channel();
+        let uploaded = Mutex::new(uploaded);
+        let replica = TestServer::start_with(|builder| {
+            builder.on_upload_complete(move |_, meta| {
+                let _ = uploaded.lock().unwrap().send(meta.file_name.to_owned());
+            })
+        });
+        let address = replica.addr().to_string();
+        let primary = TestServer::start_with(|builder| builder.replicas(&[&address]));
+        let mut client = primary.client();
+
+        client.upload("report.csv", b"a,b").unwrap();
+        client.delete("report.csv").unwrap();
+        client.upload("kept.csv", b"c,d").unwrap();
+
+        // replayed in order, the delete went out before the second upload
+        let timeout = time::Duration::from_secs(10);
+        assert_eq!("report.csv", replicated.recv_timeout(timeout).unwrap());
+        assert_eq!("kept.csv", replicated.recv_timeout(timeout).unwrap());
+        assert_eq!(
+            b"c,d".to_vec(),
+            fs::read(replica.root().join("kept.csv")).unwrap()
+        );
+        assert!(!replica.root().join("report.csv").exists());
+    }
+}
@@ -0,0 +1,85 @@
+use super::connection::Connection;
+use super::keep_alive::{write_frame_header, FRAME_OK};
+use std::{
+    fmt,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time,
+};
+
+// Tag of one accepted connection. It is in the server's log lines about the
+// connection, in the error frames the client gets and in the admin port's
+// connection and transfer lists, so a failure a user reports can be found in
+// the logs. Clients that want it up front send the RequestId prefix.
+//
+// The high half is picked when the process starts and the low half counts
+// connections, so ids do not repeat across restarts either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestId(u64);
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+static PROCESS: OnceLock<u64> = OnceLock::new();
+
+impl RequestId {
+    pub fn next() -> RequestId {
+        let process = *PROCESS.get_or_init(|| {
+            let since = time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .unwrap_or_default();
+            // nanoseconds mixed in so two servers started in the same second differ
+            (since.as_secs()
+                ^ u64::from(since.subsec_nanos()).rotate_left(17)
+                ^ std::process::id() as u64)
+                << 32
+        });
+        RequestId(process | (NEXT.fetch_add(1, Ordering::Relaxed) & 0xffff_ffff))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+// "[<id>] " for log lines about `stream`, nothing for connections without one.
+pub(crate) fn log_prefix(stream: &dyn Connection) -> String {
+    stream
+        .request_id()
+        .map_or(String::new(), |id| format!("[{}] ", id))
+}
+
+// What a client is told when something goes wrong, with the id to quote.
+pub(crate) fn with_request_id(stream: &dyn Connection, message: String) -> String {
+    match stream.request_id() {
+        Some(id) => format!("{} (request {})", message, id),
+        None => message,
+    }
+}
+
+// Reply to the RequestId prefix: an OK frame holding the id as text, empty for
+// connections without one.
+pub(crate) fn write_request_id(mut stream: &dyn Connection) -> io::Result<()> {
+    let id = stream
+        .request_id()
+        .map_or(String::new(), |id| id.to_string());
+    write_frame_header(stream, FRAME_OK, id.len() as u64)?;
+    stream.write_all(id.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_are_unique_and_ordered() {
+        let first = RequestId::next();
+        let second = RequestId::next();
+        assert!(first < second);
+        assert_eq!(16, first.to_string().len());
+        assert_eq!(first.0 >> 32, second.0 >> 32);
+    }
+}
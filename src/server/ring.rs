@@ -0,0 +1,165 @@
+use super::connection::Connection;
+use super::keep_alive::{write_error_frame, write_frame_header, FRAME_OK};
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use crate::reader::validate_file_name;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, ErrorKind, Write},
+    sync::Arc,
+};
+
+// points each node gets on the ring unless the config says otherwise, enough
+// to spread files within a few percent of evenly over a handful of nodes
+pub const DEFAULT_VNODES: u32 = 128;
+
+// The `[ring]` table of the config.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RingConfig {
+    // "host:port" of every shard, this server included if it holds files too
+    pub nodes: Vec<String>,
+    // points per node, more spread files more evenly
+    pub vnodes: u32,
+}
+
+impl Default for RingConfig {
+    fn default() -> Self {
+        RingConfig {
+            nodes: Vec::new(),
+            vnodes: DEFAULT_VNODES,
+        }
+    }
+}
+
+// Consistent hashing of file names onto shards, answered by the Locate
+// command. Each node is put on a ring of u64 hashes `vnodes` times and a file
+// belongs to the first node point at or after its own hash, so adding or
+// removing a node only moves the files next to its points. Hashes are the
+// first 8 bytes of SHA-256, every server and client with the same nodes
+// agrees on the placement.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HashRing {
+    nodes: Vec<String>,
+    // (hash, index into nodes), sorted by hash
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[String], vnodes: u32) -> HashRing {
+        let mut points: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(index, node)| {
+                (0..vnodes.max(1)).map(move |vnode| (hash(&format!("{}#{}", node, vnode)), index))
+            })
+            .collect();
+        // a tie is settled by node order, the same on every server
+        points.sort_unstable();
+        HashRing {
+            nodes: nodes.to_vec(),
+            points,
+        }
+    }
+
+    pub fn from_config(config: &RingConfig) -> HashRing {
+        HashRing::new(&config.nodes, config.vnodes)
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // The node `file_name` belongs to, None on an empty ring.
+    pub fn locate(&self, file_name: &str) -> Option<&str> {
+        let key = hash(file_name);
+        let at = self.points.partition_point(|(point, _)| *point < key);
+        // past the last point the ring wraps around to the first
+        let (_, index) = self.points.get(at).or_else(|| self.points.first())?;
+        Some(&self.nodes[*index])
+    }
+}
+
+impl FileServer {
+    // Request: filename=a_file_name|
+    // Reply: an OK frame holding the "host:port" of the node the file belongs
+    // to, an error frame when this server has no ring. Nothing is opened, the
+    // file need not exist yet, uploads are located the same way.
+    pub fn handle_locate(
+        stream: &dyn Connection,
+        _root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_locate(stream, &metrics_registry);
+    }
+
+    pub(crate) fn framed_locate(
+        mut stream: &dyn Connection,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let file_name = match Self::read_file_request(stream, &metrics_registry.limits()) {
+            Ok(file_name) => file_name,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        if let Err(err) = validate_file_name(&file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        let ring = metrics_registry.ring();
+        let Some(node) = ring.locate(&file_name) else {
+            return write_error_frame(stream, "this server has no hash ring".to_owned());
+        };
+        write_frame_header(stream, FRAME_OK, node.len() as u64)?;
+        stream.write_all(node.as_bytes())
+    }
+}
+
+fn hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    let mut first = [0u8; 8];
+    first.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(first)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate_is_stable_and_moves_little() {
+        let nodes: Vec<String> = (1..=3).map(|n| format!("10.0.0.{}:7878", n)).collect();
+        let ring = HashRing::new(&nodes, DEFAULT_VNODES);
+        assert_eq!(None, HashRing::default().locate("a.txt"));
+        assert_eq!(ring.locate("a.txt"), ring.locate("a.txt"));
+
+        let files: Vec<String> = (0..3000).map(|n| format!("file-{}.bin", n)).collect();
+        let mut per_node = [0; 3];
+        for file in &files {
+            let node = ring.locate(file).unwrap();
+            per_node[nodes.iter().position(|n| n == node).unwrap()] += 1;
+        }
+        // about a third each
+        assert!(per_node.iter().all(|count| (800..1200).contains(count)));
+
+        // a fourth node only takes files, none move between the other three
+        let mut grown = nodes.clone();
+        grown.push("10.0.0.4:7878".to_owned());
+        let grown = HashRing::new(&grown, DEFAULT_VNODES);
+        let mut moved = 0;
+        for file in &files {
+            let (before, after) = (ring.locate(file).unwrap(), grown.locate(file).unwrap());
+            if before != after {
+                assert_eq!("10.0.0.4:7878", after);
+                moved += 1;
+            }
+        }
+        assert!((500..1000).contains(&moved), "{} moved", moved);
+    }
+}
@@ -0,0 +1,387 @@
+use super::connection::{describe_peer, Connection};
+use super::metrics::MetricsRegistry;
+use super::observer::RequestEvent;
+use super::request_id::log_prefix;
+use super::server::{FileServer, Handler};
+use super::threads::Serving;
+use super::types::CommandType;
+use std::{
+    any::Any,
+    collections::HashMap,
+    net::SocketAddr,
+    panic::{self, AssertUnwindSafe},
+    sync::{atomic::Ordering, Arc},
+    time,
+};
+
+// What a middleware gets to see about the request it wraps.
+pub struct RequestContext<'a> {
+    pub stream: &'a dyn Connection,
+    pub command: u8,
+    // None when the router does not speak the built-in protocol
+    pub command_type: Option<CommandType>,
+    pub root_dir: &'static str,
+    pub metrics: &'a Arc<MetricsRegistry>,
+    // who sent it, see Connection::remote_addr and Connection::peer_identity
+    pub peer: Option<SocketAddr>,
+    pub peer_identity: Option<String>,
+}
+
+// Runs around a handler. Calling `next` hands the request to the rest of the
+// chain, not calling it short-circuits the request (auth, rate limiting...).
+pub type Middleware = Arc<dyn Fn(&RequestContext, &dyn Fn(&RequestContext)) + Send + Sync>;
+
+// Middleware printing every command with how long its handler took.
+pub fn request_logger() -> Middleware {
+    Arc::new(|ctx, next| {
+        let started = time::Instant::now();
+        next(ctx);
+        println!(
+            "{}{:?} (command {}) from {} took {:?}",
+            log_prefix(ctx.stream),
+            ctx.command_type,
+            ctx.command,
+            describe_peer(ctx.stream),
+            started.elapsed()
+        );
+    })
+}
+
+// Maps command bytes to handlers and wraps them in middleware.
+// FileServer dispatches every connection through one of these, embedders can
+// hand it their own to speak an entirely different command set while keeping
+// the pool, metrics and shutdown machinery.
+#[derive(Clone)]
+pub struct Router {
+    routes: HashMap<u8, Handler>,
+    middleware: Vec<Middleware>,
+    builtin_commands: bool,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Router {
+    // Router that understands the built-in protocol, Statistics subscribes,
+    // Ping is answered on the accept thread and so on.
+    pub fn new() -> Router {
+        Router {
+            routes: HashMap::new(),
+            middleware: Vec::new(),
+            builtin_commands: true,
+        }
+    }
+
+    // Router where every byte is just a command handled on a worker thread,
+    // no byte has a built-in meaning.
+    pub fn custom() -> Router {
+        Router {
+            builtin_commands: false,
+            ..Router::new()
+        }
+    }
+
+    pub fn route(mut self, command: u8, handler: Handler) -> Self {
+        if self.insert_route(command, handler).is_some() {
+            println!(
+                "...Warning: command {} was already routed, replacing its handler",
+                command
+            );
+        }
+        self
+    }
+
+    // Middleware runs in the order it was added, the first one is outermost.
+    pub fn middleware(mut self, middleware: Middleware) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub fn insert_route(&mut self, command: u8, handler: Handler) -> Option<Handler> {
+        self.routes.insert(command, handler)
+    }
+
+    pub fn push_middleware(&mut self, middleware: Middleware) {
+        self.middleware.push(middleware);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    // Every routed command byte, in no particular order.
+    pub fn commands(&self) -> Vec<u8> {
+        self.routes.keys().copied().collect()
+    }
+
+    pub fn handler(&self, command: u8) -> Option<Handler> {
+        self.routes.get(&command).copied()
+    }
+
+    pub fn uses_builtin_commands(&self) -> bool {
+        self.builtin_commands
+    }
+
+    pub fn dispatch(
+        &self,
+        command: u8,
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) -> bool {
+        match self.handler(command) {
+            None => false,
+            Some(handler) => {
+                // for the panic hook, Ping runs on the accept loop
+                let _serving = Serving::start(stream.request_id());
+                let ctx = RequestContext {
+                    stream,
+                    command,
+                    command_type: match self.builtin_commands {
+                        true => CommandType::try_from(command).ok(),
+                        false => None,
+                    },
+                    root_dir,
+                    metrics: &metrics_registry,
+                    peer: stream.remote_addr(),
+                    peer_identity: stream.peer_identity(),
+                };
+                // a panicking handler must not take the worker thread, or the
+                // accept loop for Ping, down with it. Whatever it already wrote
+                // stays written, the error goes after it.
+                let peer = stream.peer();
+                let event = RequestEvent {
+                    request_id: stream.request_id(),
+                    peer: &peer,
+                    command,
+                    command_type: ctx.command_type,
+                };
+                metrics_registry.notify(|observer| observer.on_request_start(&event));
+                let started = time::Instant::now();
+                let outcome =
+                    panic::catch_unwind(AssertUnwindSafe(|| self.run_chain(0, &ctx, handler)));
+                let elapsed = started.elapsed();
+                // keep-alive sessions time each command they serve themselves
+                if let Some(command) = ctx.command_type.filter(|c| *c != CommandType::KeepAlive) {
+                    metrics_registry.record_request(command, elapsed);
+                }
+                if let Err(payload) = outcome {
+                    metrics_registry
+                        .handler_panics
+                        .fetch_add(1, Ordering::Relaxed);
+                    println!(
+                        "{}...Handler for command {} panicked: {}",
+                        log_prefix(stream),
+                        command,
+                        panic_message(&*payload)
+                    );
+                    metrics_registry.notify_error(
+                        stream,
+                        ctx.command_type,
+                        "internal server error",
+                    );
+                    FileServer::report_error(
+                        stream,
+                        ctx.command_type,
+                        "internal server error".to_owned(),
+                    );
+                }
+                metrics_registry.notify(|observer| observer.on_request_end(&event, elapsed));
+                true
+            }
+        }
+    }
+
+    fn run_chain(&self, index: usize, ctx: &RequestContext, handler: Handler) {
+        match self.middleware.get(index) {
+            None => handler(ctx.stream, ctx.root_dir, ctx.metrics.clone()),
+            Some(middleware) => {
+                let next = |ctx: &RequestContext| self.run_chain(index + 1, ctx, handler);
+                middleware(ctx, &next);
+            }
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("non-string panic payload", String::as_str),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection::{duplex, MemoryConnection};
+    use crate::{reader, FileServer};
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    fn pong_handler(
+        mut stream: &dyn Connection,
+        _root_dir: &'static str,
+        _metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        stream.write_all(b"pong").unwrap();
+    }
+
+    fn request(port: &str, command: u8) -> String {
+        let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).unwrap();
+        stream.write_all(&[command]).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        reply
+    }
+
+    #[test]
+    fn test_custom_router_with_middleware() {
+        let root_dir = "temp_test_router_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+
+        let wrap: Middleware = Arc::new(|ctx, next| {
+            let mut stream = ctx.stream;
+            stream.write_all(b"[").unwrap();
+            next(ctx);
+            stream.write_all(b"]").unwrap();
+        });
+        let deny_three: Middleware = Arc::new(|ctx, next| {
+            assert_eq!(None, ctx.command_type);
+            if ctx.command != 3 {
+                next(ctx);
+            }
+        });
+        let router = Router::custom()
+            .route(42, pong_handler)
+            .route(3, pong_handler)
+            .middleware(wrap)
+            .middleware(deny_three);
+
+        let mut server = FileServer::new("127.0.0.1", "8029", 2, root_dir).unwrap();
+        server.set_router(router);
+        server.spawn();
+
+        assert_eq!("[pong]", request("8029", 42));
+        // 3 is Statistics in the built-in protocol, a custom router treats it
+        // like any other command
+        assert_eq!("[]", request("8029", 3));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_handlers_see_who_they_serve() {
+        fn authorized_handler(
+            mut stream: &dyn Connection,
+            _root_dir: &'static str,
+            metrics_registry: Arc<MetricsRegistry>,
+        ) {
+            let reply = match metrics_registry.authorize(stream, CommandType::Download, "report") {
+                Ok(()) => "ok",
+                Err(_) => "denied",
+            };
+            stream.write_all(reply.as_bytes()).unwrap();
+        }
+
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let record: Middleware = {
+            let seen = seen.clone();
+            Arc::new(move |ctx, next| {
+                seen.lock()
+                    .unwrap()
+                    .push((ctx.peer, ctx.peer_identity.clone()));
+                next(ctx);
+            })
+        };
+        let router = Router::custom()
+            .route(42, authorized_handler)
+            .middleware(record);
+        let metrics = Arc::new(MetricsRegistry::new());
+        metrics.set_authorizer(Some(Arc::new(|request| {
+            request.identity == Some("CN=alice")
+        })));
+
+        let reply = |client: MemoryConnection, server| {
+            router.dispatch(42, &server, "unused", metrics.clone());
+            drop(server);
+            let mut reply = String::new();
+            (&client as &dyn Connection)
+                .read_to_string(&mut reply)
+                .unwrap();
+            reply
+        };
+        let (client, server) = duplex();
+        assert_eq!("ok", reply(client, server.with_identity("CN=alice")));
+        let (client, server) = duplex();
+        assert_eq!("denied", reply(client, server));
+        assert_eq!(
+            vec![(None, Some("CN=alice".to_owned())), (None, None)],
+            *seen.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_panicking_handler_is_isolated() {
+        let root_dir = "temp_test_panicking_handler_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+
+        fn panicking_handler(
+            _stream: &dyn Connection,
+            _root_dir: &'static str,
+            _metrics_registry: Arc<MetricsRegistry>,
+        ) {
+            panic!("handler bug");
+        }
+        let mut server = FileServer::new("127.0.0.1", "8027", 1, root_dir).unwrap();
+        server.set_router(
+            Router::custom()
+                .route(42, pong_handler)
+                .route(43, panicking_handler),
+        );
+        let pool = server.pool.clone();
+        let metrics = server.metrics.clone();
+        server.spawn();
+
+        assert!(request("8027", 43).starts_with("internal server error (request "));
+        // the only worker came back
+        assert_eq!("pong", request("8027", 42));
+        assert_eq!(0, pool.busy());
+        assert_eq!(1, metrics.handler_panics.load(Ordering::Relaxed));
+        assert_eq!(0, metrics.active_transfers.load(Ordering::SeqCst));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_use_middleware_sees_builtin_commands() {
+        let root_dir = "temp_test_use_middleware_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+
+        let mut server = FileServer::new("127.0.0.1", "8028", 2, root_dir).unwrap();
+        server.register_handlers(&[
+            (CommandType::Download, pong_handler),
+            (CommandType::List, pong_handler),
+        ]);
+        server.use_middleware(Arc::new(|ctx, next| {
+            if ctx.command_type == Some(CommandType::Download) {
+                let mut stream = ctx.stream;
+                stream.write_all(b"denied").unwrap();
+                return;
+            }
+            next(ctx);
+        }));
+        server.spawn();
+
+        assert_eq!("denied", request("8028", 1));
+        assert_eq!("pong", request("8028", 7));
+
+        reader::cleanup_server_file(root_dir);
+    }
+}
@@ -0,0 +1,75 @@
+use super::server::FileServerError;
+use crate::reader::glob_matches;
+use std::io::{self, ErrorKind};
+
+// Which served files downloads may open, by glob (`*` and `?`, see
+// reader::glob_matches). A file matching a deny pattern is never served, and
+// once there is an allow pattern only files matching one are. Uploads are not
+// affected, a denied file can still be replaced, just not read back.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServePolicy {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl ServePolicy {
+    pub fn new(allow: Vec<String>, deny: Vec<String>) -> ServePolicy {
+        ServePolicy { allow, deny }
+    }
+
+    pub fn permits(&self, file_name: &str) -> bool {
+        !self
+            .deny
+            .iter()
+            .any(|pattern| glob_matches(pattern, file_name))
+            && (self.allow.is_empty()
+                || self
+                    .allow
+                    .iter()
+                    .any(|pattern| glob_matches(pattern, file_name)))
+    }
+
+    // A PermissionDenied error telling the client `file_name` is not served,
+    // see is_denial.
+    pub fn check(&self, file_name: &str) -> io::Result<()> {
+        match self.permits(file_name) {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                FileServerError::NotServed {
+                    file: file_name.to_owned(),
+                },
+            )),
+        }
+    }
+}
+
+// Whether `err` is a ServePolicy refusal rather than the filesystem's.
+pub fn is_denial(err: &io::Error) -> bool {
+    err.get_ref()
+        .and_then(|inner| inner.downcast_ref::<FileServerError>())
+        .is_some_and(|inner| matches!(inner, FileServerError::NotServed { .. }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let policy = ServePolicy::new(
+            vec!["*.tar.gz".to_owned()],
+            vec!["secret*".to_owned(), "*.key".to_owned()],
+        );
+        assert!(policy.permits("release.tar.gz"));
+        assert!(!policy.permits("secret.tar.gz"));
+        assert!(!policy.permits("notes.txt"));
+        assert!(is_denial(&policy.check("server.key").unwrap_err()));
+        // the filesystem's own permission errors are not the policy's
+        assert!(!is_denial(&io::Error::from(ErrorKind::PermissionDenied)));
+
+        let deny_only = ServePolicy::new(Vec::new(), vec!["*.key".to_owned()]);
+        assert!(deny_only.permits("notes.txt"));
+        assert!(ServePolicy::default().permits("server.key"));
+    }
+}
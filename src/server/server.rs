@@ -1,67 +1,316 @@
-use super::types::CommandType;
-use crate::reader::fetch_file_buffer;
-use core::panic;
-use once_cell::sync::Lazy;
-use regex::Regex;
+use super::accounts::{Accounts, UserAccount};
+use super::authorizer::Authorizer;
+use super::builder::FileServerBuilder;
+use super::clock::Clock;
+use super::connection::{Connection, PrefixedConnection};
+use super::header::HeaderReader;
+use super::health::SERVER_VERSION;
+use super::janitor::TrashPolicy;
+use super::keep_alive::{
+    write_busy_frame, write_error_frame, write_frame_header, write_queued_frame, DownloadExtras,
+    FRAME_OK,
+};
+use super::limit::{ConnectionLimit, OverflowPolicy};
+use super::listener::{self, Listener, ListenerPolicy};
+use super::metrics::{MetricsRegistry, ProgressHook, UploadMeta};
+use super::observer::{ChannelObserver, ConnectionEvent, Observer, ServerEvent};
+use super::pool::{BusyPolicy, TransferClass, WorkerPool, WorkerSlot};
+use super::preflight::{self, PreflightError};
+use super::replication::Mutation;
+use super::request_id::{log_prefix, with_request_id, write_request_id};
+use super::ring::HashRing;
+use super::router::{Middleware, Router};
+use super::serve_policy::ServePolicy;
+use super::shutdown::{ServerHandle, ShutdownHandle, ShutdownReport};
+use super::stats_sink::StatsSink;
+use super::types::{Capabilities, CommandType};
+use crate::archive::embedded::EmbeddedArchive;
+use crate::cache::cache_key;
+use crate::protocol::stats::{StatsFormat, StatsSnapshot};
+use crate::protocol::{self, Limit, Limits};
+use crate::reader::{
+    self, create_partial_file, discard_partial_file, open_encrypted_file_source, open_file_source,
+    EncryptingWriter, FileSource, PartialFile, ServedFile,
+};
 use std::{
     collections::HashMap,
     fmt,
-    io::{BufRead, BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex, RwLock},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, ToSocketAddrs},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        mpsc, Arc, Mutex, RwLock,
+    },
     thread, time,
 };
 
+pub type Handler =
+    fn(stream: &dyn Connection, root_dir: &'static str, metrics_registry: Arc<MetricsRegistry>);
+
+// A client following the statistics, with the frame layout it asked for and
+// the worker it holds until it goes away.
+pub struct StatsSubscriber {
+    stream: Box<dyn Connection>,
+    format: StatsFormat,
+    _slot: WorkerSlot,
+    // last time the subscriber sent anything, any byte counts as a heartbeat
+    last_heard: time::Instant,
+}
+
+impl StatsSubscriber {
+    // Picks up whatever the subscriber sent since the last tick. Returns false
+    // once it hung up or stayed quiet past `heartbeat_timeout`.
+    fn check_in(&mut self, heartbeat_timeout: Option<time::Duration>, clock: &dyn Clock) -> bool {
+        let _ = self
+            .stream
+            .set_read_timeout(Some(time::Duration::from_millis(HEARTBEAT_POLL_MS)));
+        let mut buf = [0u8; 64];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(_) => self.last_heard = clock.now(),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(_) => return false,
+            }
+        }
+        heartbeat_timeout
+            .is_none_or(|timeout| clock.now().saturating_duration_since(self.last_heard) < timeout)
+    }
+}
+
+// stats subscribers by connection id
+pub type StatsSubscribers = Arc<RwLock<HashMap<i64, StatsSubscriber>>>;
+
+// The stats subscribers as a sink, dropping the ones that hung up or went
+// quiet on the way.
+struct SubscriberSink {
+    subscribers: StatsSubscribers,
+    metrics: Arc<MetricsRegistry>,
+    heartbeat_timeout: Option<time::Duration>,
+}
+
+impl StatsSink for SubscriberSink {
+    fn describe(&self) -> String {
+        "stats subscribers".to_owned()
+    }
+
+    fn send(&mut self, snapshot: &StatsSnapshot) -> io::Result<()> {
+        let mut dead_connections: Vec<i64> = Vec::new();
+        let clock = self.metrics.clock();
+
+        for (id, subscriber) in self.subscribers.write().unwrap().iter_mut() {
+            if !subscriber.check_in(self.heartbeat_timeout, &*clock) {
+                println!(
+                    "{}Evicting silent stats subscriber connection_id:{}...",
+                    log_prefix(&*subscriber.stream),
+                    id
+                );
+                dead_connections.push(*id);
+                continue;
+            }
+
+            println!(
+                "{}sending metrics to connection_id:{}...",
+                log_prefix(&*subscriber.stream),
+                id
+            );
+
+            let mut conn: &dyn Connection = subscriber.stream.as_ref();
+            if conn.write_all(&snapshot.encode(subscriber.format)).is_err() {
+                dead_connections.push(*id);
+                continue;
+            }
+
+            println!(
+                "{}Successfully sent metrics to connection_id:{}...",
+                log_prefix(&*subscriber.stream),
+                id
+            );
+        }
+
+        let mut v = self.subscribers.write().unwrap();
+        let evicted: Vec<StatsSubscriber> = dead_connections
+            .iter()
+            .filter_map(|connection_id| v.remove(connection_id))
+            .collect();
+        self.metrics.set_stats_subscribers(v.len());
+        drop(v);
+        for subscriber in evicted {
+            self.metrics.record_stats_eviction();
+            let _ = subscriber.stream.shutdown(Shutdown::Both);
+        }
+        Ok(())
+    }
+}
+
 pub struct FileServer {
-    thread_pool: Arc<Mutex<i32>>,
-    listiner: TcpListener,
-    handlers: HashMap<
-        CommandType,
-        fn(
-            stream: &TcpStream,
-            root_dir: &'static str,
-            metrics_registry: Arc<RwLock<HashMap<String, i64>>>,
-        ),
-    >,
-    max_connections: i32,
-    next_id: i64,
-    stats_bound_connections: Arc<RwLock<HashMap<i64, TcpStream>>>,
-    root_dir: &'static str,
-    file_stat: Arc<RwLock<HashMap<String, i64>>>, // TODO: I pass this config to each handler function, I think this is a bit impure.
-                                                  // I would like to bootstrap the function in a closure somehow to refrence the config or use globabl configs somehow.
+    pub(crate) pool: Arc<WorkerPool>,
+    listeners: Vec<(Listener, ListenerPolicy)>,
+    // the first this many listeners share the main address with SO_REUSEPORT
+    acceptors: usize,
+    router: Arc<Router>,
+    next_id: AtomicI64,
+    stats_bound_connections: StatsSubscribers,
+    pub(crate) root_dir: &'static str,
+    keep_alive_timeout: time::Duration,
+    drain_timeout: time::Duration,
+    busy_policy: BusyPolicy,
+    // downloads of smaller files wait in the small queue, see TransferClass
+    small_transfer_bytes: Option<u64>,
+    max_stats_subscribers: Option<usize>,
+    stats_heartbeat_timeout: Option<time::Duration>,
+    stats_interval: time::Duration,
+    // where ticks go besides the subscribers, taken by start_metrics_report
+    stats_sinks: Mutex<Vec<Box<dyn StatsSink>>>,
+    // None serves everyone from root_dir without logging in
+    pub(crate) accounts: Option<Accounts>,
+    // what `auth` on the admin port is checked against, None refuses it
+    pub(crate) admin_password_sha256: Option<String>,
+    pub(crate) connection_limit: Arc<ConnectionLimit>,
+    pub(crate) shutdown_requested: Arc<AtomicBool>,
+    pub(crate) metrics: Arc<MetricsRegistry>, // TODO: I pass this config to each handler function, I think this is a bit impure.
+                                              // I would like to bootstrap the function in a closure somehow to refrence the config or use globabl configs somehow.
 }
 
-static FILE_MATCHER: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"filename=([^|]+)\|").unwrap()
-    // allowed filename: filename=a_file_name|
-});
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 30;
+const DEFAULT_DRAIN_SECS: u64 = 10;
+const DEFAULT_STATS_INTERVAL_MS: u64 = 1000;
+const ACCEPT_RETRY_DELAY_MS: u64 = 100;
+// how often a back-pressured accept loop checks for shutdown
+const BUSY_POLL_MS: u64 = 100;
+// how long a stats tick waits on each subscriber for a heartbeat
+const HEARTBEAT_POLL_MS: u64 = 1;
+// how often a queued client that asked for it hears its place in the queue
+const QUEUE_REPORT_MS: u64 = 1000;
+// What determine_request read off a connection.
+struct RequestHead {
+    command_byte: u8,
+    // None for commands outside the built-in protocol
+    command_type: Option<CommandType>,
+    // the root the command runs against, the user's home once logged in
+    root_dir: &'static str,
+    // the version Hello settled on, None without one
+    protocol: Option<u16>,
+    // how StatisticsV2 and StatsOnce want their reports
+    stats_format: Option<StatsFormat>,
+}
+// a server speaking the built-in protocol is useless without these, see validate
+const REQUIRED_COMMANDS: &[CommandType] = &[CommandType::Download];
 
+// Typed so library users can tell a busy port from a bad request from a
+// missing file. Display is what clients get to see in error replies.
 #[derive(Debug)]
 pub enum FileServerError {
-    FailedToInitFTPServer(String),
-    FailedToParseRequest(String),
-    FailedToParseCommand(String),
-    ServerReadError(String),
+    // a listener could not be set up, the main one or a side one (TFTP, WebDAV...)
+    Bind { address: String, source: io::Error },
+    InvalidPort { port: String },
+    // reading from or writing to a client failed
+    Io(io::Error),
+    // the client asked for a file that is not served
+    NotFound { file: String },
+    // the request bytes do not follow the protocol
+    BadFrame { reason: String },
+    UnknownCommand { byte: u8 },
+    PreflightFailed(Vec<PreflightError>),
+    AcceptFailed(io::Error),
+    // wrong password or no such user, deliberately not telling which
+    LoginFailed { user: String },
+    // the server has accounts and the client did not log in
+    LoginRequired,
+    // the server could not serve its own protocol with the handlers it has
+    InvalidHandlerConfig { reason: String },
+    // the authorizer turned the request down
+    Forbidden { file: String },
+    // the file exists but the allow/deny patterns keep it from being served
+    NotServed { file: String },
+    // the client sent more than one of the server's Limits allows
+    TooLarge { limit: Limit, max: u64 },
+}
+
+impl FileServerError {
+    // An error opening `file` for a client, a missing file gets its own variant.
+    pub fn opening(file: &str, err: io::Error) -> FileServerError {
+        match err.kind() {
+            io::ErrorKind::NotFound => FileServerError::NotFound {
+                file: file.to_owned(),
+            },
+            _ => FileServerError::Io(err),
+        }
+    }
+
+    pub(crate) fn bad_frame(reason: impl Into<String>) -> FileServerError {
+        FileServerError::BadFrame {
+            reason: reason.into(),
+        }
+    }
 }
 
 impl fmt::Display for FileServerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            FileServerError::FailedToInitFTPServer(reason) => {
-                write!(f, "Could not init FTPServer: {}", reason)
+            FileServerError::Bind { address, source } => {
+                write!(f, "Could not listen on {}: {}", address, source)
             }
-            FileServerError::FailedToParseRequest(reason) => {
-                write!(f, "Could not parse filename in request: {}", reason)
+            FileServerError::InvalidPort { port } => write!(f, "Invalid port {:?}", port),
+            FileServerError::Io(err) => write!(f, "Connection error: {}", err),
+            FileServerError::NotFound { file } => write!(f, "File not found: {}", file),
+            FileServerError::BadFrame { reason } => {
+                write!(f, "Could not parse request: {}", reason)
             }
-
-            FileServerError::FailedToParseCommand(reason) => {
-                write!(f, "Could not parse command in request: {}", reason)
+            FileServerError::UnknownCommand { byte } => {
+                write!(
+                    f,
+                    "Could not parse command in request: unknown command {}",
+                    byte
+                )
+            }
+            FileServerError::AcceptFailed(err) => {
+                write!(f, "Listener stopped accepting connections: {}", err)
+            }
+            FileServerError::LoginFailed { user } => write!(f, "Login failed for {}", user),
+            FileServerError::LoginRequired => {
+                write!(f, "This server has user accounts, log in first")
+            }
+            FileServerError::InvalidHandlerConfig { reason } => {
+                write!(f, "Invalid handler configuration: {}", reason)
+            }
+            FileServerError::Forbidden { file } => write!(f, "Access denied to {}", file),
+            FileServerError::NotServed { file } => write!(f, "{} is not served", file),
+            FileServerError::TooLarge { limit, max } => {
+                write!(f, "{} is over the {} byte limit", limit.as_str(), max)
+            }
+            FileServerError::PreflightFailed(errors) => {
+                let reasons: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "Preflight checks failed: {}", reasons.join("; "))
             }
-            FileServerError::ServerReadError(_) => write!(f, "Client read deadline"),
         }
     }
 }
 
+impl std::error::Error for FileServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileServerError::Bind { source, .. } => Some(source),
+            FileServerError::Io(err) | FileServerError::AcceptFailed(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FileServerError {
+    fn from(err: io::Error) -> Self {
+        FileServerError::Io(err)
+    }
+}
+
 impl FileServer {
     pub fn new(
         address: &str,
@@ -69,27 +318,253 @@ impl FileServer {
         thread_count: i32,
         root_dir: &'static str,
     ) -> Result<FileServer, FileServerError> {
-        let addr = format!("{}:{}", address, port);
-        let listener = TcpListener::bind(addr);
+        Self::open(address, port, thread_count, root_dir, true)
+    }
+
+    // Like `new`, without looking at root_dir unless `root_required`. A server
+    // with an embedded archive never serves from it.
+    pub(crate) fn open(
+        address: &str,
+        port: &str,
+        thread_count: i32,
+        root_dir: &'static str,
+        root_required: bool,
+    ) -> Result<FileServer, FileServerError> {
+        // run every check before giving up so the caller sees all problems at once
+        let mut errors = preflight::check_config(address, port, thread_count);
+        if root_required {
+            errors.extend(preflight::check_root_dir(root_dir));
+        }
+
+        let listener = match port.parse::<u16>() {
+            // already reported by check_config
+            Err(_) => None,
+            Ok(port) => match listener::resolve(address, port)
+                .and_then(|addrs| listener::bind_first(&addrs[..]))
+            {
+                Err(err) => {
+                    errors.push(PreflightError::PortUnavailable(err.to_string()));
+                    None
+                }
+                Ok(listener) => Some(listener),
+            },
+        };
+        let listener = listener.map(Listener::Tcp);
+        Self::from_listener(listener, errors, thread_count, root_dir, root_required)
+    }
+
+    // Like `new` but takes anything std can resolve, `"[::]:8089"`,
+    // `("::1", 8089)`, a SocketAddr... The first address that binds is used.
+    // An IPv6 wildcard listener is dual-stack and takes IPv4 clients too.
+    pub fn bind(
+        addrs: impl ToSocketAddrs,
+        thread_count: i32,
+        root_dir: &'static str,
+    ) -> Result<FileServer, FileServerError> {
+        let mut errors = preflight::check_thread_count(thread_count);
+        errors.extend(preflight::check_root_dir(root_dir));
+
+        let listener = match listener::bind_first(addrs) {
+            Err(err) => {
+                errors.push(PreflightError::PortUnavailable(err.to_string()));
+                None
+            }
+            Ok(listener) => Some(listener),
+        };
+        Self::from_listener(
+            listener.map(Listener::Tcp),
+            errors,
+            thread_count,
+            root_dir,
+            true,
+        )
+    }
+
+    // Serves the same protocol on a Unix domain socket at `path`, for clients
+    // on the same host such as sidecars. A stale socket file from an earlier
+    // run is replaced.
+    #[cfg(unix)]
+    pub fn bind_unix(
+        path: impl AsRef<std::path::Path>,
+        thread_count: i32,
+        root_dir: &'static str,
+    ) -> Result<FileServer, FileServerError> {
+        let path = path.as_ref();
+        let mut errors = preflight::check_thread_count(thread_count);
+        errors.extend(preflight::check_root_dir(root_dir));
+
+        let listener = match listener::bind_unix(path) {
+            Err(err) => {
+                errors.push(PreflightError::PortUnavailable(format!(
+                    "{}: {}",
+                    path.display(),
+                    err
+                )));
+                None
+            }
+            Ok(listener) => Some(Listener::Unix(listener, path.to_path_buf())),
+        };
+        Self::from_listener(listener, errors, thread_count, root_dir, true)
+    }
+
+    fn from_listener(
+        listener: Option<Listener>,
+        errors: Vec<PreflightError>,
+        thread_count: i32,
+        root_dir: &'static str,
+        root_required: bool,
+    ) -> Result<FileServer, FileServerError> {
+        if errors.is_empty() && root_required {
+            // uploads that were cut off by the last shutdown or a crash
+            match reader::remove_partial_files(root_dir) {
+                Ok(0) => {}
+                Ok(removed) => println!("Removed {} unfinished uploads", removed),
+                Err(err) => println!("...Error removing unfinished uploads:{err}"),
+            }
+        }
+
         match listener {
-            Err(err) => Err(FileServerError::FailedToInitFTPServer(err.to_string())),
-            Ok(listener) => Ok(FileServer {
-                thread_pool: Arc::new(Mutex::new(thread_count)),
-                listiner: listener,
-                handlers: HashMap::new(),
-                max_connections: thread_count,
+            Some(listener) if errors.is_empty() => Ok(FileServer {
+                pool: Arc::new(WorkerPool::new(thread_count)),
+                listeners: vec![(listener, ListenerPolicy::default())],
+                acceptors: 1,
+                router: Arc::new(Router::new()),
                 root_dir,
-                next_id: 0,
+                next_id: AtomicI64::new(0),
+                keep_alive_timeout: time::Duration::from_secs(DEFAULT_KEEP_ALIVE_SECS),
+                drain_timeout: time::Duration::from_secs(DEFAULT_DRAIN_SECS),
+                busy_policy: BusyPolicy::default(),
+                small_transfer_bytes: None,
+                max_stats_subscribers: None,
+                stats_heartbeat_timeout: None,
+                stats_interval: time::Duration::from_millis(DEFAULT_STATS_INTERVAL_MS),
+                stats_sinks: Mutex::new(Vec::new()),
+                accounts: None,
+                admin_password_sha256: None,
+                connection_limit: Arc::new(ConnectionLimit::new(None, OverflowPolicy::default())),
+                shutdown_requested: Arc::new(AtomicBool::new(false)),
                 stats_bound_connections: Arc::new(RwLock::new(HashMap::new())),
-                file_stat: Arc::new(RwLock::new(HashMap::new())),
+                metrics: Arc::new(MetricsRegistry::new()),
             }),
+            _ => Err(FileServerError::PreflightFailed(errors)),
+        }
+    }
+
+    pub fn builder() -> FileServerBuilder {
+        FileServerBuilder::new()
+    }
+
+    // Listens on one more address, connections from every listener share the
+    // pool and handlers. To serve IPv4 and IPv6 on separate sockets with the
+    // same port add the IPv4 listener first, an IPv6 wildcard listener added
+    // before it would already own the port for both.
+    pub fn add_listener(
+        &mut self,
+        addrs: impl ToSocketAddrs,
+    ) -> Result<SocketAddr, FileServerError> {
+        self.add_listener_with_policy(addrs, ListenerPolicy::default())
+    }
+
+    // Like add_listener, with limits on what its connections may do, e.g. a
+    // read-only public port next to the internal one the server was made with.
+    pub fn add_listener_with_policy(
+        &mut self,
+        addrs: impl ToSocketAddrs,
+        policy: ListenerPolicy,
+    ) -> Result<SocketAddr, FileServerError> {
+        let fail = |err: io::Error| {
+            FileServerError::PreflightFailed(vec![PreflightError::PortUnavailable(err.to_string())])
+        };
+        let mut last_error = None;
+        for addr in addrs.to_socket_addrs().map_err(fail)? {
+            let port_has_v4 = self
+                .local_addrs()
+                .iter()
+                .any(|local| local.is_ipv4() && local.port() == addr.port());
+            match listener::bind(addr, port_has_v4) {
+                Ok(listener) => {
+                    let local_addr = listener.local_addr().map_err(fail)?;
+                    self.listeners.push((Listener::Tcp(listener), policy));
+                    return Ok(local_addr);
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+        Err(fail(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            )
+        })))
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            // SO_REUSEPORT siblings of the main listener are one address
+            .skip(self.acceptors - 1)
+            .filter_map(|(listener, _)| listener.local_addr())
+            .collect()
+    }
+
+    // Accepts connections to the main address on `count` sockets bound with
+    // SO_REUSEPORT, each with an accept thread of its own, so a high
+    // connection rate is not held up by a single accept(). Each reads the
+    // heads of its own connections, they all share the worker pool. Linux only. The main listener is bound again for it,
+    // call this before the server starts.
+    pub fn set_acceptor_threads(&mut self, count: usize) -> Result<(), FileServerError> {
+        let fail = |reason: String| {
+            FileServerError::PreflightFailed(vec![PreflightError::InvalidConfig(reason)])
+        };
+        let count = count.max(1);
+        if count == self.acceptors {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let addr = self.listeners[0]
+                .0
+                .local_addr()
+                .ok_or_else(|| fail("the main listener is not a TCP socket".to_owned()))?;
+            // the first listener was bound without SO_REUSEPORT, nothing can
+            // join it, so the whole group is bound anew
+            let previous: Vec<_> = self.listeners.drain(..self.acceptors).collect();
+            // the group keeps whatever the main listener was allowed to do
+            let policy = previous[0].1;
+            drop(previous);
+            let listeners = match listener::bind_reuse_port(addr, count) {
+                Ok(listeners) => listeners,
+                Err(err) => {
+                    // keep serving the address the old way if it can be
+                    if let Ok(listener) = listener::bind(addr, false) {
+                        self.listeners.insert(0, (Listener::Tcp(listener), policy));
+                    }
+                    self.acceptors = 1;
+                    return Err(FileServerError::PreflightFailed(vec![
+                        PreflightError::PortUnavailable(err.to_string()),
+                    ]));
+                }
+            };
+            for (index, listener) in listeners.into_iter().enumerate() {
+                self.listeners
+                    .insert(index, (Listener::Tcp(listener), policy));
+            }
+            self.acceptors = count;
+            Ok(())
         }
+        #[cfg(not(target_os = "linux"))]
+        Err(fail(format!(
+            "{} acceptor threads need SO_REUSEPORT, which is only used on Linux",
+            count
+        )))
     }
 
-    pub fn report_error_to_client(mut stream: &TcpStream, err_string: String) {
-        println!("...Error reporting to client:{err_string}");
-        stream.write_all(err_string.as_bytes()).unwrap_or_else(|_| {
-            println!("...Error while reporting error to client:{err_string}");
+    pub fn report_error_to_client(mut stream: &dyn Connection, err_string: String) {
+        let log_prefix = log_prefix(stream);
+        println!("{log_prefix}...Error reporting to client:{err_string}");
+        let reply = with_request_id(stream, err_string);
+        stream.write_all(reply.as_bytes()).unwrap_or_else(|_| {
+            println!("{log_prefix}...Error while reporting error to client:{reply}");
         });
     }
 
@@ -97,310 +572,1606 @@ impl FileServer {
     // want to avoid gloabls, and creating an object when not ready
     // ideally the 2nd param would be a context with key-value relevant stuff
     // but not really needed right now :)
+    //
+    // Request: filename=a_file_name|
+    // Reply: the file in an OK frame, the same as inside a keep-alive session.
+    // The length up front lets a client tell a finished download from one the
+    // server never got to finish, both end with the socket closing.
     pub fn handle_incomming_file_request(
-        mut stream: &TcpStream,
+        stream: &dyn Connection,
         root_dir: &'static str,
-        metrics_registry: Arc<RwLock<HashMap<String, i64>>>,
+        metrics_registry: Arc<MetricsRegistry>,
     ) {
-        let mut buffer = Vec::new();
-        let mut reader = BufReader::new(stream);
-        if let Err(err) = reader.read_until(b'|', &mut buffer) {
-            Self::report_error_to_client(stream, err.to_string());
-            return;
+        let _ = Self::framed_download(
+            stream,
+            root_dir,
+            &metrics_registry,
+            DownloadExtras::default(),
+        );
+    }
+
+    // Opens a file for download, serving hot files from the in memory cache.
+    // A file is put in the cache once it has been downloaded often enough and
+    // is small enough, see HotFileCache::admits.
+    pub(crate) fn open_served_file(
+        file_name: &str,
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<FileSource, io::Error> {
+        // names come off the wire, a user must not step out of their home
+        reader::validate_file_name(file_name)?;
+        // before the cache too, a denied file may have been cached earlier
+        metrics_registry.serve_policy().check(file_name)?;
+        let cache = &metrics_registry.hot_files;
+        let key = cache_key(root_dir, file_name);
+        // the handle opened here keeps its version through a later commit,
+        // the lock keeps a commit from landing halfway through a cache fill
+        let _version = metrics_registry.file_locks.read(&key);
+        if let Some(bytes) = cache.get(&key) {
+            return Ok(FileSource::Cached(io::Cursor::new(bytes)));
+        }
+        if let Some(mapping) = metrics_registry.shared_mappings.get(&key) {
+            return Ok(FileSource::Mapped(io::Cursor::new(mapping)));
         }
 
-        // Check if the string matches the pattern
-        let caps = FILE_MATCHER.captures(std::str::from_utf8(&buffer).unwrap());
-        let result = match caps {
-            None => Err(FileServerError::FailedToParseRequest(
-                "file name not found".to_owned(),
-            )),
-            Some(capture) => capture.get(1).map_or(
-                Err(FileServerError::FailedToParseRequest(
-                    "file name not found".to_owned(),
-                )),
-                |v| Ok(v.as_str().to_owned()),
-            ),
-        };
+        let mut source = Self::open_stored_file(file_name, root_dir, metrics_registry)?;
+        if let Some(mapping) = source.mapping() {
+            metrics_registry.shared_mappings.insert(&key, mapping);
+        }
+        // this download is not recorded yet, count it
+        let downloads = metrics_registry.download_count(file_name) + 1;
+        if !cache.admits(source.len(), downloads) {
+            return Ok(source);
+        }
 
-        // report error if matching failed
-        if let Err(err) = result {
-            Self::report_error_to_client(stream, err.to_string());
-            return;
+        let mut bytes = Vec::with_capacity(source.len() as usize);
+        source.read_to_end(&mut bytes)?;
+        let bytes: Arc<[u8]> = bytes.into();
+        cache.insert(&key, bytes.clone());
+        Ok(FileSource::Cached(io::Cursor::new(bytes)))
+    }
+
+    // Opens a served file as it is stored: out of the embedded archive when
+    // there is one, else from disk, decrypting it when the server encrypts at
+    // rest. Skips the hot file cache, open_served_file is for downloads.
+    pub(crate) fn open_stored_file(
+        file_name: &str,
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<FileSource, io::Error> {
+        reader::validate_file_name(file_name)?;
+        if let Some(archive) = metrics_registry.embedded_archive() {
+            let bytes = archive.bytes(file_name)?;
+            return Ok(FileSource::Embedded(io::Cursor::new(bytes)));
         }
+        match metrics_registry.at_rest_key() {
+            Some(key) => open_encrypted_file_source(file_name, root_dir, key),
+            None => open_file_source(file_name, root_dir, metrics_registry.mmap_threshold()),
+        }
+    }
 
-        // fetch file buffer with content
-        let file_name = result.unwrap();
-        let mut file_reader = match fetch_file_buffer(file_name.as_str(), root_dir) {
-            Err(error) => {
-                Self::report_error_to_client(stream, error.to_string());
-                return;
+    // Stored size and modification time of a served file, NotFound for
+    // anything but a file. Out of the embedded archive when there is one.
+    pub(crate) fn stored_file(
+        file_name: &str,
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<ServedFile, io::Error> {
+        if let Some(archive) = metrics_registry.embedded_archive() {
+            return archive.file(file_name);
+        }
+        let metadata = reader::file_metadata(file_name, root_dir)?;
+        if !metadata.is_file() {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        Ok(ServedFile {
+            name: file_name.to_owned(),
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    // The files listings show, see reader::served_files. Out of the embedded
+    // archive when there is one.
+    pub(crate) fn stored_files(
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<Box<dyn Iterator<Item = io::Result<ServedFile>>>, io::Error> {
+        match metrics_registry.embedded_archive() {
+            Some(archive) => {
+                let files: Vec<_> = archive.files().map(Ok).collect();
+                Ok(Box::new(files.into_iter()))
             }
-            Ok(file_buffer) => file_buffer,
+            None => Ok(Box::new(reader::served_files(root_dir)?)),
+        }
+    }
+
+    // stored_files as names and stored sizes, see reader::list_files.
+    pub(crate) fn list_stored_files(
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<Vec<(String, u64)>, io::Error> {
+        match metrics_registry.embedded_archive() {
+            // the archive keeps its files sorted by name
+            Some(archive) => Ok(archive.files().map(|file| (file.name, file.size)).collect()),
+            None => reader::list_files(root_dir),
+        }
+    }
+
+    // The ETag of a served file, see ETagCache. Only hashed when it changed
+    // since the last time someone asked.
+    pub(crate) fn served_etag(
+        file_name: &str,
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<String, io::Error> {
+        let stored = Self::stored_file(file_name, root_dir, metrics_registry)?;
+        metrics_registry.etags.get_or_compute(
+            &cache_key(root_dir, file_name),
+            stored.size,
+            stored.modified,
+            || {
+                Self::open_stored_file(file_name, root_dir, metrics_registry)
+                    .and_then(reader::sha256_hex)
+            },
+        )
+    }
+
+    // The content type of a served file, by its extension or else by its first
+    // bytes, decrypted ones when the server encrypts at rest.
+    pub(crate) fn served_content_type(
+        file_name: &str,
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> &'static str {
+        if let Some(content_type) = reader::content_type_by_extension(file_name) {
+            return content_type;
+        }
+        let mut head = Vec::with_capacity(reader::SNIFF_BYTES);
+        match Self::open_stored_file(file_name, root_dir, metrics_registry) {
+            Ok(source) => match source
+                .take(reader::SNIFF_BYTES as u64)
+                .read_to_end(&mut head)
+            {
+                Ok(_) => reader::content_type_of_bytes(&head),
+                Err(_) => reader::OCTET_STREAM,
+            },
+            Err(_) => reader::OCTET_STREAM,
+        }
+    }
+
+    // Starts an upload of `file_name`, see create_partial_file. It is
+    // encrypted on its way to disk when the server encrypts at rest.
+    pub(crate) fn create_stored_file(
+        file_name: &str,
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<PartialFile, io::Error> {
+        let file = create_partial_file(file_name, root_dir)?;
+        let Some(key) = metrics_registry.at_rest_key() else {
+            return Ok(PartialFile::Plain(file));
         };
+        match EncryptingWriter::new(file, key) {
+            Ok(writer) => Ok(PartialFile::Encrypted(writer)),
+            Err(err) => {
+                discard_partial_file(file_name, root_dir);
+                Err(err)
+            }
+        }
+    }
 
-        let mut stats = metrics_registry.write().unwrap();
-        if let Some(x) = stats.get_mut(&file_name) {
-            *x += 1;
-        } else {
-            stats.insert(file_name, 1);
+    // Gives a fully received upload its real name. With durable uploads the
+    // bytes and then the new name are synced to disk first, a client told its
+    // upload succeeded never loses it to a crash.
+    pub(crate) fn commit_stored_file(
+        file: PartialFile,
+        file_name: &str,
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<(), io::Error> {
+        let durable = metrics_registry.durable_uploads();
+        let file = file.finish()?;
+        if durable {
+            file.sync_all()?;
         }
+        drop(file);
+        let key = cache_key(root_dir, file_name);
+        let writing = metrics_registry.file_locks.write(&key);
+        reader::commit_partial_file(file_name, root_dir)?;
+        metrics_registry.hot_files.invalidate(&key);
+        metrics_registry.shared_mappings.invalidate(&key);
+        metrics_registry.etags.invalidate(&key);
+        if durable {
+            reader::sync_directory(root_dir)?;
+        }
+        // let go first, a hook may want to read the file back
+        drop(writing);
+        metrics_registry.notify_upload(file_name, root_dir);
+        metrics_registry
+            .replication
+            .record(root_dir, Mutation::Upload(file_name.to_owned()));
+        Ok(())
+    }
+
+    // Removes a served file, into the trash while the server keeps one, under
+    // the same per-file write lock a commit takes.
+    pub(crate) fn remove_stored_file(
+        file_name: &str,
+        root_dir: &str,
+        metrics_registry: &MetricsRegistry,
+    ) -> Result<(), io::Error> {
+        let key = cache_key(root_dir, file_name);
+        let writing = metrics_registry.file_locks.write(&key);
+        match metrics_registry.janitor.trash() {
+            Some(_) => reader::trash_file(file_name, root_dir).map(|_| ())?,
+            None => reader::delete_file(file_name, root_dir)?,
+        }
+        metrics_registry.hot_files.invalidate(&key);
+        metrics_registry.shared_mappings.invalidate(&key);
+        metrics_registry.etags.invalidate(&key);
+        drop(writing);
+        metrics_registry
+            .replication
+            .record(root_dir, Mutation::Delete(file_name.to_owned()));
+        Ok(())
+    }
+
+    // Reads a `filename=a_file_name|` request off the stream.
+    // The stream is read one byte at a time on purpose, a buffered reader could
+    // swallow bytes of the next command on a keep-alive connection.
+    pub fn read_file_request(
+        stream: &dyn Connection,
+        limits: &Limits,
+    ) -> Result<String, FileServerError> {
+        let buffer = Self::read_request_segment(stream, limits)?;
+        limits.parse_file_name(&buffer)
+    }
+
+    // Reads up to and including the next `|`, or to EOF, see HeaderReader for
+    // the deadline.
+    pub(crate) fn read_request_segment(
+        stream: &dyn Connection,
+        limits: &Limits,
+    ) -> Result<Vec<u8>, FileServerError> {
+        HeaderReader::new(stream)?.read_segment(limits.max_header_bytes)
+    }
 
+    // Copies the file to the client and returns how many bytes were sent.
+    pub fn stream_file(
+        file_reader: &mut impl Read,
+        mut stream: &dyn Connection,
+        metrics_registry: &MetricsRegistry,
+        file_name: &str,
+    ) -> Result<u64, io::Error> {
+        let transfer =
+            metrics_registry.begin_transfer(file_name, &stream.peer(), stream.request_id());
+        let throttle = metrics_registry.throttle();
+        let mut sent = 0;
         loop {
             // read from the file 1KB at a time until EOF aka (0)
             let mut buf = vec![];
-            let read_op = { file_reader.by_ref().take(1024).read_to_end(&mut buf) };
-            match read_op {
-                Ok(read) => {
-                    if read == 0 {
-                        return;
-                    }
-                    stream.write_all(&buf).unwrap_or_else(|error| {
-                        Self::report_error_to_client(stream, error.to_string());
-                    });
-                }
-                Err(error) => {
-                    Self::report_error_to_client(stream, error.to_string());
-                    return;
-                }
+            let read = file_reader.by_ref().take(1024).read_to_end(&mut buf)?;
+            if read == 0 {
+                transfer.finish();
+                return Ok(sent);
+            }
+            if transfer.is_cancelled() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("transfer {} was cancelled", transfer.id()),
+                ));
             }
+            // cut off mid file, the cap is checked before each command too
+            if transfer.is_over_daily_cap() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    format!("daily transfer cap reached for {}", stream.peer()),
+                ));
+            }
+            throttle.pace(read as u64);
+            stream.write_all(&buf)?;
+            transfer.record_bytes_sent(read as u64);
+            sent += read as u64;
         }
     }
 
     pub fn no_op_handler(
-        _stream: &TcpStream,
+        _stream: &dyn Connection,
         _root_dir: &'static str,
-        _metrics_registry: Arc<RwLock<HashMap<String, i64>>>,
+        _metrics_registry: Arc<MetricsRegistry>,
     ) {
     }
 
-    fn determine_handler(
-        &self,
-        mut stream: &TcpStream,
-    ) -> Result<
-        (
-            fn(
-                stream: &TcpStream,
-                root_dir: &'static str,
-                metrics_registry: Arc<RwLock<HashMap<String, i64>>>,
-            ),
-            CommandType,
-        ),
-        FileServerError,
-    > {
-        let mut client_command_byte: [u8; 1] = [0];
-        if let Err(err) = stream.read(&mut client_command_byte) {
-            return Err(FileServerError::FailedToParseCommand(err.to_string()));
-        }
-
-        let command: CommandType;
-
-        match client_command_byte[0] {
-            1 => {
-                command = CommandType::Download;
+    // Reads the command, after answering the RequestId and Hello prefixes
+    // when the client sends them first and logging the client in when the
+    // server has accounts, and the format byte of StatisticsV2. All of it
+    // shares one HEADER_TIMEOUT, the command's own arguments are the
+    // handler's to read.
+    fn determine_request(&self, stream: &dyn Connection) -> Result<RequestHead, FileServerError> {
+        let mut head = HeaderReader::new(stream)?;
+        let limits = self.metrics.limits();
+        let (mut command_byte, mut command_type) = self.determine_handler(&mut head)?;
+        let mut protocol = None;
+        // in either order, each at most once
+        let mut answered = Vec::new();
+        while let Some(prefix @ (CommandType::RequestId | CommandType::Hello)) = command_type {
+            if answered.contains(&prefix) {
+                return Err(FileServerError::bad_frame(format!(
+                    "{:?} sent twice",
+                    prefix
+                )));
+            }
+            match prefix {
+                CommandType::Hello => {
+                    let segment = head.read_segment(limits.max_header_bytes)?;
+                    let version = protocol::parse_hello(&segment)?;
+                    let capabilities = self.capabilities(version);
+                    protocol = Some(capabilities.protocol);
+                    let capabilities = capabilities.encode();
+                    write_frame_header(stream, FRAME_OK, capabilities.len() as u64)?;
+                    let mut stream = stream;
+                    stream.write_all(capabilities.as_bytes())?;
+                }
+                _ => write_request_id(stream)?,
             }
-            2 => {
-                panic!("upload not implemented")
+            answered.push(prefix);
+            (command_byte, command_type) = self.determine_handler(&mut head)?;
+        }
+        let root_dir = match (&self.accounts, command_type) {
+            (None, _) => self.root_dir,
+            (Some(accounts), Some(CommandType::Login)) => {
+                let home = Self::read_login(stream, &mut head, accounts, &limits)?;
+                (command_byte, command_type) = self.determine_handler(&mut head)?;
+                home
             }
-            3 => {
-                command = CommandType::Statistics;
+            // health checks touch no files
+            (Some(_), Some(CommandType::Ping)) => self.root_dir,
+            (Some(_), _) => return Err(FileServerError::LoginRequired),
+        };
+        let stats_format = match command_type {
+            Some(CommandType::StatisticsV2) | Some(CommandType::StatsOnce) => {
+                Some(protocol::parse_stats_format(head.read_byte()?)?)
             }
-            _ => {
-                panic!("not implemented")
+            _ => None,
+        };
+        Ok(RequestHead {
+            command_byte,
+            command_type,
+            root_dir,
+            protocol,
+            stats_format,
+        })
+    }
+
+    // Reads the command byte and checks the router knows it. The parsed
+    // CommandType is None for commands outside the built-in protocol.
+    fn determine_handler(
+        &self,
+        head: &mut HeaderReader,
+    ) -> Result<(u8, Option<CommandType>), FileServerError> {
+        let command_byte = head.read_byte()?;
+        for prefix in [CommandType::RequestId, CommandType::Hello] {
+            if command_byte == u8::from(prefix) && self.router.uses_builtin_commands() {
+                return Ok((command_byte, Some(prefix)));
             }
         }
+        // Login is a prefix understood whenever accounts are set up, it has
+        // no handler of its own
+        if command_byte == u8::from(CommandType::Login)
+            && self.accounts.is_some()
+            && self.router.uses_builtin_commands()
+        {
+            return Ok((command_byte, Some(CommandType::Login)));
+        }
 
-        let handler = self.handlers.get(&command);
+        if self.router.handler(command_byte).is_none() {
+            return Err(FileServerError::UnknownCommand { byte: command_byte });
+        }
 
-        if handler.is_none() {
-            return Err(FileServerError::FailedToParseCommand(
-                "unsupported command type".to_owned(),
-            ));
+        if !self.router.uses_builtin_commands() {
+            return Ok((command_byte, None));
         }
+        Ok((command_byte, CommandType::try_from(command_byte).ok()))
+    }
 
-        Ok((*handler.unwrap(), command))
+    // What Hello answers a client speaking up to `client_version` with.
+    pub fn capabilities(&self, client_version: u16) -> Capabilities {
+        let mut commands = self.router.commands();
+        if self.router.uses_builtin_commands() {
+            commands.push(u8::from(CommandType::RequestId));
+            commands.push(u8::from(CommandType::Hello));
+            if self.accounts.is_some() {
+                commands.push(u8::from(CommandType::Login));
+            }
+        }
+        commands.sort_unstable();
+        commands.dedup();
+        Capabilities {
+            protocol: client_version.min(protocol::PROTOCOL_VERSION),
+            server_version: SERVER_VERSION.to_owned(),
+            commands,
+            max_upload_bytes: self.metrics.limits().max_upload_bytes,
+            compression: match self.metrics.serve_precompressed() {
+                true => vec!["gzip".to_owned()],
+                false => Vec::new(),
+            },
+        }
     }
 
     // Counting on main ending for this to be temrinated, has no cleanup since we expect it to live for the life of the app
     pub fn send_stats(
-        thread_pool_ref: Arc<Mutex<i32>>,
-        file_stat_ref: Arc<RwLock<HashMap<String, i64>>>,
-        stats_bound_connections_ref: Arc<RwLock<HashMap<i64, TcpStream>>>,
+        pool_ref: Arc<WorkerPool>,
+        metrics_ref: Arc<MetricsRegistry>,
+        mut sinks: Vec<Box<dyn StatsSink>>,
         interval: u64,
-        max_connections_allowed: i32,
     ) {
-        loop {
-            thread::sleep(time::Duration::from_millis(interval));
-            let pool_size = *thread_pool_ref.lock().unwrap();
-            let mut max_count = 0;
-            let mut most_demanded_file = String::from("no files");
-            for (file, count) in file_stat_ref.read().unwrap().iter() {
-                if *count > max_count {
-                    max_count = *count;
-                    most_demanded_file = file.clone();
+        for seq in 1.. {
+            metrics_ref
+                .clock()
+                .sleep(time::Duration::from_millis(interval));
+            let mut snapshot = metrics_ref.snapshot(&pool_ref);
+            snapshot.seq = seq;
+            for sink in sinks.iter_mut() {
+                if let Err(err) = sink.send(&snapshot) {
+                    println!("...Could not send stats to {}:{err}", sink.describe());
                 }
             }
+        }
+    }
 
-            let mut dead_connections: Vec<i64> = Vec::new();
-
-            for (id, mut conn) in stats_bound_connections_ref.write().unwrap().iter() {
-                // TODO: handle these errors and cleanup the cache if connections are bad
-                // start this call on it's own thread to do periodically
-                println!("sending metrics to connection_id:{}...", id);
-
-                if let Err(_) = conn.write(&[(max_connections_allowed - pool_size) as u8]) {
-                    dead_connections.push(id.clone());
-                    continue;
-                }
-
-                if let Err(_) = conn.write(&[most_demanded_file.len() as u8]) {
-                    dead_connections.push(id.clone());
-                    continue;
-                }
+    pub fn start_metrics_report(&self) {
+        let pool = self.pool.clone();
+        let metrics = self.metrics.clone();
+        let mut sinks: Vec<Box<dyn StatsSink>> = vec![Box::new(SubscriberSink {
+            subscribers: self.stats_bound_connections.clone(),
+            metrics: self.metrics.clone(),
+            heartbeat_timeout: self.stats_heartbeat_timeout,
+        })];
+        sinks.append(&mut self.stats_sinks.lock().unwrap());
+        let interval = self.stats_interval.as_millis() as u64;
 
-                if let Err(_) = conn.write(most_demanded_file.as_bytes()) {
-                    dead_connections.push(id.clone());
-                    continue;
-                }
+        let threads = &self.metrics.threads;
+        threads.spawn("stats", move || {
+            Self::send_stats(pool, metrics, sinks, interval)
+        });
+    }
 
-                if let Err(_) = conn.write(&[max_count as u8]) {
-                    dead_connections.push(id.clone());
-                    continue;
-                }
+    // Runs the accept loop on a thread of its own, for embedding the server
+    // in a program whose main thread has other things to do.
+    pub fn spawn(self) -> ServerHandle {
+        let shutdown = self.shutdown_handle();
+        let local_addr = self.local_addrs().first().copied();
+        let metrics = self.metrics.clone();
+        let pool = self.pool.clone();
+        let threads = self.metrics.threads.clone();
+        let accept_loop = threads.spawn("accept", move || self.handle_incomming_connections());
+        ServerHandle::new(accept_loop, shutdown, local_addr, metrics, pool)
+    }
 
-                println!("Successfully sent metrics to connection_id:{}...", id);
-            }
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::for_listener(
+            self.shutdown_requested.clone(),
+            self.listeners[0].0.listen_addr().unwrap(),
+        )
+    }
 
-            let mut v = stats_bound_connections_ref.write().unwrap();
-            for connection_id in dead_connections {
-                v.remove(&connection_id);
+    // Back-pressure: leave new connections in the backlog until a worker is
+    // idle. Returns false if shutdown was requested meanwhile.
+    fn wait_for_idle_worker(&self) -> bool {
+        while !self
+            .pool
+            .wait_for_idle_worker(time::Duration::from_millis(BUSY_POLL_MS))
+        {
+            if self.shutdown_requested.load(Ordering::SeqCst) {
+                return false;
             }
         }
+        true
     }
 
-    pub fn free_thread_barrier(&self, thread_lookup_interval: u64) {
-        // look for a free thread in 6 second intervals
-        loop {
-            let mut count = self.thread_pool.lock().unwrap();
-            if *count == 0 {
-                drop(count);
-                thread::sleep(time::Duration::from_millis(thread_lookup_interval));
-            } else {
-                *count -= 1;
-                break;
+    // Same for the connection limit, leave new connections in the backlog
+    // until one closes.
+    fn wait_for_connection_room(&self) -> bool {
+        while !self
+            .connection_limit
+            .wait_for_room(time::Duration::from_millis(BUSY_POLL_MS))
+        {
+            if self.shutdown_requested.load(Ordering::SeqCst) {
+                return false;
             }
         }
+        true
     }
 
-    pub fn start_metrics_report(&self) {
-        let thread_pool = self.thread_pool.clone();
-        let file_stats = self.file_stat.clone();
-        let stats_bound_connections = self.stats_bound_connections.clone();
-        let max_connections = self.max_connections;
+    // Reports an error in the reply format of the command, an error frame for
+    // the framed commands and plain text for the rest.
+    pub fn report_error(
+        stream: &dyn Connection,
+        command_type: Option<CommandType>,
+        err_string: String,
+    ) {
+        if Self::replies_framed(command_type) {
+            let _ = write_error_frame(stream, err_string);
+        } else {
+            Self::report_error_to_client(stream, err_string);
+        }
+    }
 
-        thread::spawn(move || {
-            Self::send_stats(
-                thread_pool,
-                file_stats,
-                stats_bound_connections,
-                1000,
-                max_connections,
-            )
-        });
+    // Whether `command_type` answers in keep-alive frames rather than plain text.
+    fn replies_framed(command_type: Option<CommandType>) -> bool {
+        matches!(
+            command_type,
+            Some(CommandType::Download)
+                | Some(CommandType::Upload)
+                | Some(CommandType::Delete)
+                | Some(CommandType::List)
+                | Some(CommandType::ConditionalDownload)
+                | Some(CommandType::Transfers)
+                | Some(CommandType::BatchDownload)
+                | Some(CommandType::Archive)
+                | Some(CommandType::RangeDownload)
+                | Some(CommandType::Stat)
+                | Some(CommandType::Locate)
+                | Some(CommandType::Watch)
+                | Some(CommandType::Tail)
+                | Some(CommandType::ListPage)
+                | Some(CommandType::ReliableDownload)
+                | Some(CommandType::FlashDownload)
+                | Some(CommandType::Delta)
+                | Some(CommandType::Sync)
+                | Some(CommandType::Checksums)
+                | Some(CommandType::ContentTypes)
+                | Some(CommandType::ETags)
+                | Some(CommandType::AcceptGzip)
+                | Some(CommandType::Login)
+                | Some(CommandType::RequestId)
+                | Some(CommandType::Hello)
+                | Some(CommandType::KeepAlive)
+        )
     }
 
-    pub fn handle_incomming_connections(&self) {
-        for stream in self.listiner.incoming() {
-            println!("Handling incoming connection .....");
-            self.free_thread_barrier(6000);
-
-            let mutex_ref = self.thread_pool.clone();
-            let mut managed_stream = stream.unwrap();
-
-            match self.determine_handler(&managed_stream) {
-                Ok((handler, command_type)) => match command_type {
-                    CommandType::Download => {
-                        let root_dir = self.root_dir;
-                        let merics_registry = self.file_stat.clone();
-                        thread::spawn(move || {
-                            managed_stream.set_read_timeout(None).unwrap();
-                            handler(&mut managed_stream, root_dir, merics_registry);
-                            let mut count = mutex_ref.lock().unwrap();
-                            *count += 1;
-                        });
-                    }
+    // report_error, with observers told about it too.
+    fn refuse(
+        metrics: &MetricsRegistry,
+        stream: &dyn Connection,
+        command_type: Option<CommandType>,
+        message: String,
+    ) {
+        metrics.notify_error(stream, command_type, &message);
+        Self::report_error(stream, command_type, message);
+    }
 
-                    CommandType::Statistics => {
-                        self.stats_bound_connections
-                            .write()
-                            .unwrap()
-                            .insert(self.next_id, managed_stream);
+    // The worker a connection ready since `ready_at` runs on, the one the
+    // reject policy already took for it or the next free one in the queue of
+    // `class`.
+    // Waits for a worker unless the busy policy already took one. A client
+    // that asked to hear its place in the queue is told while it waits and
+    // once more when it is picked up, see FRAME_QUEUED. One that went away
+    // meanwhile still gets its worker, the handler finds out it is gone.
+    fn take_worker(
+        pool: &Arc<WorkerPool>,
+        metrics: &MetricsRegistry,
+        slot: Option<WorkerSlot>,
+        command_type: Option<CommandType>,
+        class: Option<TransferClass>,
+        ready_at: time::Instant,
+        queued_to: Option<&dyn Connection>,
+    ) -> WorkerSlot {
+        let slot = slot.unwrap_or_else(|| match queued_to {
+            Some(stream) => {
+                let every = time::Duration::from_millis(QUEUE_REPORT_MS);
+                pool.acquire_reporting(command_type, class, every, |position| {
+                    let _ = write_queued_frame(stream, position as u64);
+                })
+            }
+            None => pool.acquire_as(command_type, class),
+        });
+        if let Some(stream) = queued_to {
+            let _ = write_queued_frame(stream, 0);
+        }
+        let waited = ready_at.elapsed();
+        metrics.dispatch_wait.observe(waited);
+        if let Some(class) = class {
+            metrics.class_dispatch_wait(class).observe(waited);
+        }
+        slot
+    }
 
-                        println!(
-                            "Client with connection_id:{} registered on metrics endpoint....",
-                            self.next_id
-                        );
-                    }
+    // downloads whose request starts with the file name, the ones queued by size
+    fn sorts_by_size(command_type: Option<CommandType>) -> bool {
+        matches!(
+            command_type,
+            Some(CommandType::Download)
+                | Some(CommandType::ConditionalDownload)
+                | Some(CommandType::RangeDownload)
+                | Some(CommandType::ReliableDownload)
+                | Some(CommandType::FlashDownload)
+                | Some(CommandType::Delta)
+        )
+    }
 
-                    CommandType::Upload => {
-                        panic!("upload should never be called!")
-                    }
+    // The queue a download waits in for a worker: small when its file is
+    // under `small_bytes` as Stat would report it, large otherwise. The file
+    // name is read off the connection for that and put back in front of it
+    // for the handler. Requests naming no served file are left unsorted,
+    // the handler has the error to report. None when the client is refused
+    // for a request that never came in whole.
+    fn classify_transfer(
+        stream: Box<dyn Connection>,
+        command_type: Option<CommandType>,
+        root_dir: &'static str,
+        metrics: &MetricsRegistry,
+        small_bytes: u64,
+    ) -> Option<(Box<dyn Connection>, Option<TransferClass>)> {
+        let limits = metrics.limits();
+        let segment = match Self::read_request_segment(&*stream, &limits) {
+            Ok(segment) => segment,
+            Err(error) => {
+                Self::refuse(metrics, &*stream, command_type, error.to_string());
+                return None;
+            }
+        };
+        let class = limits
+            .parse_file_name(&segment)
+            .ok()
+            .and_then(|file_name| Self::stored_file(&file_name, root_dir, metrics).ok())
+            .map(
+                |stored| match metrics.served_len(stored.size) < small_bytes {
+                    true => TransferClass::Small,
+                    false => TransferClass::Large,
                 },
+            );
+        Some((Box::new(PrefixedConnection::new(stream, segment)), class))
+    }
 
-                //TODO: standardize error report to client
-                Err(error) => {
-                    Self::report_error_to_client(&managed_stream, error.to_string());
-                    let mut count = mutex_ref.lock().unwrap();
-                    *count += 1;
-                }
-            }
-        }
+    // Tells the client to come back later, in the reply format of its command.
+    fn reject_busy(
+        metrics: &MetricsRegistry,
+        stream: Box<dyn Connection>,
+        command_type: Option<CommandType>,
+        message: String,
+    ) {
+        Self::refuse(metrics, &*stream, command_type, message);
+        Self::hang_up(stream);
     }
 
-    pub fn register_handlers(
-        &mut self,
-        handlers: &[(
-            CommandType,
-            fn(
-                stream: &TcpStream,
-                root_dir: &'static str,
-                metrics_registry: Arc<RwLock<HashMap<String, i64>>>,
-            ),
-        )],
+    // Load shedding: a BUSY frame with when to come back, or the reject
+    // policy's message for commands that do not answer in frames.
+    fn shed_busy(
+        metrics: &MetricsRegistry,
+        stream: Box<dyn Connection>,
+        command_type: Option<CommandType>,
+        retry_after: time::Duration,
     ) {
-        for (command, handler) in handlers {
-            println!("Registering {:?} handler...", command);
-            self.handlers.insert(*command, *handler);
+        metrics.shed_connections.fetch_add(1, Ordering::Relaxed);
+        let retry_after = retry_after.as_secs().max(1);
+        let message = format!("server busy, retry after {} seconds", retry_after);
+        if !Self::replies_framed(command_type) {
+            return Self::reject_busy(metrics, stream, command_type, message);
         }
+        println!("{}...Shedding connection:{message}", log_prefix(&*stream));
+        metrics.notify_error(&*stream, command_type, &message);
+        let _ = write_busy_frame(&*stream, retry_after);
+        Self::hang_up(stream);
     }
-}
 
-// Test Helpers
+    fn hang_up(stream: Box<dyn Connection>) {
+        // closing with the request still unread resets the connection, which
+        // can cost the client our reply, so read it off before hanging up
+        let _ = stream.shutdown(Shutdown::Write);
+        thread::spawn(move || {
+            let _ = stream.set_read_timeout(Some(time::Duration::from_secs(1)));
+            let _ = io::copy(&mut &*stream, &mut io::sink());
+        });
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::super::types::stats::Stats;
-    use super::*;
-    use crate::reader;
+    // Waits for in-flight transfers to finish, up to the drain timeout, and
+    // summarises the run. Whatever is still running by then counts as aborted.
+    fn drain(&self) -> ShutdownReport {
+        // watchers never finish on their own
+        self.metrics.watches.close();
+        let deadline = time::Instant::now() + self.drain_timeout;
+        while self.metrics.active_transfers.load(Ordering::SeqCst) > 0
+            && time::Instant::now() < deadline
+        {
+            thread::sleep(time::Duration::from_millis(50));
+        }
+
+        // stats followers would otherwise wait forever for the next report
+        for subscriber in self.stats_bound_connections.read().unwrap().values() {
+            let _ = subscriber.stream.shutdown(Shutdown::Both);
+        }
+
+        ShutdownReport {
+            uptime: self.metrics.started_at.elapsed(),
+            total_connections: self.metrics.total_connections.load(Ordering::Relaxed),
+            bytes_served: self.metrics.bytes_served.load(Ordering::Relaxed),
+            aborted_transfers: self.metrics.active_transfers.load(Ordering::SeqCst),
+            final_metrics: self.metrics.file_stat_snapshot(),
+        }
+    }
+
+    // Errors a busy server sees from accept() that say nothing about the
+    // listener itself, the loop keeps going after these.
+    fn is_transient_accept_error(err: &io::Error) -> bool {
+        // too many open files for the process or the system
+        #[cfg(unix)]
+        if matches!(err.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) {
+            return true;
+        }
+        matches!(
+            err.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+        )
+    }
+
+    // What the accept loop does about a failed accept(): transient errors are
+    // logged and retried after a pause, anything else stops the server.
+    fn retry_after_accept_error(err: io::Error) -> Result<(), FileServerError> {
+        if !Self::is_transient_accept_error(&err) {
+            return Err(FileServerError::AcceptFailed(err));
+        }
+        println!("...Error accepting connection, retrying:{err}");
+        // running out of descriptors clears up as connections finish,
+        // back off instead of spinning on accept
+        thread::sleep(time::Duration::from_millis(ACCEPT_RETRY_DELAY_MS));
+        Ok(())
+    }
+
+    // Reads the head of an accepted request and hands it to a worker, or
+    // answers it right away: pings, refusals and clients that are turned
+    // away. Runs on a thread of its own, never on the accept loop.
+    fn serve_connection(&self, managed_stream: Box<dyn Connection>, over_limit: bool) {
+        let RequestHead {
+            command_byte,
+            command_type,
+            root_dir,
+            protocol,
+            stats_format,
+        } = match self.determine_request(&*managed_stream) {
+            Ok(head) => head,
+            // clients that log in speak the framed protocol
+            Err(error @ (FileServerError::LoginFailed { .. } | FileServerError::LoginRequired)) => {
+                self.metrics
+                    .notify_error(&*managed_stream, None, &error.to_string());
+                Self::refuse_login(&*managed_stream, error);
+                return;
+            }
+            //TODO: standardize error report to client
+            Err(error) => {
+                Self::refuse(&self.metrics, &*managed_stream, None, error.to_string());
+                return;
+            }
+        };
+
+        // health checks are cheap and answered on this thread, they get
+        // through even when every place is taken
+        if over_limit && command_type != Some(CommandType::Ping) {
+            println!(
+                "{}...Too many connections, turning away {}",
+                log_prefix(&*managed_stream),
+                managed_stream.peer()
+            );
+            Self::reject_busy(
+                &self.metrics,
+                managed_stream,
+                command_type,
+                "too many connections, try again later".to_owned(),
+            );
+            return;
+        }
+
+        // a draining server only finishes what it has, health checks aside
+        if self.metrics.is_draining() && command_type != Some(CommandType::Ping) {
+            println!(
+                "{}...Draining, turning away {}",
+                log_prefix(&*managed_stream),
+                managed_stream.peer()
+            );
+            Self::reject_busy(
+                &self.metrics,
+                managed_stream,
+                command_type,
+                "draining, retry elsewhere".to_owned(),
+            );
+            return;
+        }
+        let capped = managed_stream
+            .remote_addr()
+            .is_some_and(|addr| self.metrics.bandwidth.is_capped(addr.ip()));
+        if capped && command_type != Some(CommandType::Ping) {
+            println!(
+                "{}...Daily transfer cap reached, turning away {}",
+                log_prefix(&*managed_stream),
+                managed_stream.peer()
+            );
+            Self::reject_busy(
+                &self.metrics,
+                managed_stream,
+                command_type,
+                "daily transfer cap reached, retry tomorrow".to_owned(),
+            );
+            return;
+        }
+
+        // health checks are answered right here so they still get through
+        // when every worker is busy
+        if command_type == Some(CommandType::Ping) {
+            self.router.dispatch(
+                command_byte,
+                &*managed_stream,
+                root_dir,
+                self.metrics.clone(),
+            );
+            return;
+        }
+
+        // dispatch wait is counted from here, slow clients are not the pool's fault
+        let ready_at = time::Instant::now();
+        let reports_queue =
+            protocol.is_some_and(|version| version >= protocol::QUEUE_POSITION_VERSION);
+
+        // nothing waits for a worker here, a connection that finds its part
+        // of the pool busy waits on the thread it is going to run on.
+        // Unless the policy says to turn it away instead.
+        let pool = self.pool.clone();
+        let slot = match self.busy_policy {
+            BusyPolicy::Reject { retry_after } => match pool.try_acquire(command_type) {
+                Some(slot) => Some(slot),
+                None => {
+                    let message = format!(
+                        "server busy, retry after {} seconds",
+                        retry_after.as_secs().max(1)
+                    );
+                    Self::reject_busy(&self.metrics, managed_stream, command_type, message);
+                    return;
+                }
+            },
+            BusyPolicy::Shed { max_queue } => match pool.try_acquire(command_type) {
+                Some(slot) => Some(slot),
+                None if pool.waiting() < max_queue => None,
+                None => {
+                    let retry_after = pool.retry_after();
+                    Self::shed_busy(&self.metrics, managed_stream, command_type, retry_after);
+                    return;
+                }
+            },
+            _ => None,
+        };
+
+        match command_type {
+            // anything outside the built-in protocol runs on a worker like downloads do
+            Some(CommandType::Download)
+            | Some(CommandType::Upload)
+            | Some(CommandType::Delete)
+            | Some(CommandType::List)
+            | Some(CommandType::ConditionalDownload)
+            | Some(CommandType::Transfers)
+            | Some(CommandType::BatchDownload)
+            | Some(CommandType::Archive)
+            | Some(CommandType::RangeDownload)
+            | Some(CommandType::Stat)
+            | Some(CommandType::Locate)
+            | Some(CommandType::Watch)
+            | Some(CommandType::Tail)
+            | Some(CommandType::ListPage)
+            | Some(CommandType::ReliableDownload)
+            | Some(CommandType::FlashDownload)
+            | Some(CommandType::Delta)
+            | Some(CommandType::Sync)
+            | Some(CommandType::Checksums)
+            | Some(CommandType::ContentTypes)
+            | Some(CommandType::ETags)
+            | Some(CommandType::AcceptGzip)
+            | Some(CommandType::KeepAlive)
+            | None => {
+                let merics_registry = self.metrics.clone();
+                let router = self.router.clone();
+                // keep-alive sessions use the read timeout as their idle timeout
+                let read_timeout = match command_type {
+                    Some(CommandType::KeepAlive) => Some(self.keep_alive_timeout),
+                    _ => None,
+                };
+                let small_transfer_bytes = self.small_transfer_bytes;
+                let threads = &self.metrics.threads;
+                threads.spawn_worker(managed_stream.request_id(), move || {
+                    // a worker the policy already took needs no queue
+                    let (managed_stream, class, ready_at) = match small_transfer_bytes {
+                        Some(small_bytes)
+                            if slot.is_none() && Self::sorts_by_size(command_type) =>
+                        {
+                            match Self::classify_transfer(
+                                managed_stream,
+                                command_type,
+                                root_dir,
+                                &merics_registry,
+                                small_bytes,
+                            ) {
+                                // the wait is counted once the file name is in
+                                Some((stream, class)) => (stream, class, time::Instant::now()),
+                                None => return,
+                            }
+                        }
+                        _ => (managed_stream, None, ready_at),
+                    };
+                    // shared with the watchdog, and declared before the slot
+                    // so the worker is free again before the client sees
+                    // the connection close
+                    let managed_stream: Arc<dyn Connection> = Arc::from(managed_stream);
+                    let _slot = Self::take_worker(
+                        &pool,
+                        &merics_registry,
+                        slot,
+                        command_type,
+                        class,
+                        ready_at,
+                        reports_queue.then_some(&*managed_stream),
+                    );
+                    managed_stream.set_read_timeout(read_timeout).unwrap();
+                    // the budget starts once a worker picked the connection up
+                    let _budget = command_type.and_then(|command| {
+                        merics_registry.watchdog.watch(command, &managed_stream)
+                    });
+                    merics_registry.transfer_started();
+                    router.dispatch(
+                        command_byte,
+                        &*managed_stream,
+                        root_dir,
+                        merics_registry.clone(),
+                    );
+                    merics_registry.transfer_finished();
+                });
+            }
+
+            Some(CommandType::Statistics) | Some(CommandType::StatisticsV2) => {
+                let format = stats_format.unwrap_or(StatsFormat::V1);
+
+                let connection_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                let stats_bound_connections = self.stats_bound_connections.clone();
+                let max_stats_subscribers = self.max_stats_subscribers;
+                let metrics = self.metrics.clone();
+                let log_prefix = log_prefix(&*managed_stream);
+                let threads = &self.metrics.threads;
+                threads.spawn_worker(managed_stream.request_id(), move || {
+                    let slot = Self::take_worker(
+                        &pool,
+                        &metrics,
+                        slot,
+                        command_type,
+                        None,
+                        ready_at,
+                        None,
+                    );
+                    let mut subscribers = stats_bound_connections.write().unwrap();
+                    if max_stats_subscribers.is_some_and(|max| subscribers.len() >= max) {
+                        drop(subscribers);
+                        drop(slot);
+                        Self::reject_busy(
+                            &metrics,
+                            managed_stream,
+                            command_type,
+                            "too many stats subscribers, try again later".to_owned(),
+                        );
+                        return;
+                    }
+                    subscribers.insert(
+                        connection_id,
+                        StatsSubscriber {
+                            stream: managed_stream,
+                            format,
+                            _slot: slot,
+                            last_heard: metrics.clock().now(),
+                        },
+                    );
+                    metrics.set_stats_subscribers(subscribers.len());
+
+                    println!(
+                        "{}Client with connection_id:{} registered on metrics endpoint....",
+                        log_prefix, connection_id
+                    );
+                });
+            }
+
+            // one report and hang up
+            Some(CommandType::StatsOnce) => {
+                let format = stats_format.unwrap_or(StatsFormat::V1);
+                let metrics = self.metrics.clone();
+                let threads = &self.metrics.threads;
+                threads.spawn_worker(managed_stream.request_id(), move || {
+                    let _slot = Self::take_worker(
+                        &pool,
+                        &metrics,
+                        slot,
+                        command_type,
+                        None,
+                        ready_at,
+                        None,
+                    );
+                    let snapshot = metrics.snapshot(&pool);
+                    let mut stream: &dyn Connection = &*managed_stream;
+                    if let Err(err) = stream.write_all(&snapshot.encode(format)) {
+                        println!(
+                            "{}...Error sending stats snapshot:{err}",
+                            log_prefix(stream)
+                        );
+                    }
+                });
+            }
+
+            // nothing to quit outside of a keep-alive session
+            Some(CommandType::Quit) => {}
+
+            Some(CommandType::Login) => Self::refuse(
+                &self.metrics,
+                &*managed_stream,
+                command_type,
+                "already logged in".to_owned(),
+            ),
+
+            Some(CommandType::Hello) => Self::refuse(
+                &self.metrics,
+                &*managed_stream,
+                command_type,
+                "Hello must come before the command".to_owned(),
+            ),
+
+            Some(CommandType::RequestId) => Self::refuse(
+                &self.metrics,
+                &*managed_stream,
+                command_type,
+                "RequestId must be the first command".to_owned(),
+            ),
+
+            Some(CommandType::Ping) => {
+                unreachable!("ping is answered before taking a worker")
+            }
+        }
+    }
+
+    pub fn handle_incomming_connections(&self) -> Result<ShutdownReport, FileServerError> {
+        let addrs = self.local_addrs();
+        println!(
+            "fileserver {} (protocol {}) listening on {:?}",
+            SERVER_VERSION,
+            protocol::PROTOCOL_VERSION,
+            addrs
+        );
+        self.metrics.notify(|observer| observer.on_started(&addrs));
+        // every listener gets an accept loop of its own, a head still coming
+        // in when they end is read before the server drains, which takes
+        // HEADER_TIMEOUT at most
+        let failure = Mutex::new(None);
+        let woken = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for (index, (listener, policy)) in self.listeners.iter().enumerate() {
+                let (failure, woken) = (&failure, &woken);
+                let role = format!("accept-{}", index);
+                self.metrics
+                    .threads
+                    .spawn_scoped(scope, None, &role, move || {
+                        if let Err(err) = self.accept_connections(scope, index, listener, *policy) {
+                            failure.lock().unwrap().get_or_insert(err);
+                            self.shutdown_requested.store(true, Ordering::SeqCst);
+                        }
+                        // the first loop to end wakes the others, they are parked
+                        // in accept() and would not notice otherwise
+                        if !woken.swap(true, Ordering::SeqCst) {
+                            self.stop_accepting();
+                        }
+                    });
+            }
+        });
+        if let Some(err) = failure.into_inner().unwrap() {
+            return Err(err);
+        }
+
+        println!("Stopped accepting connections, draining .....");
+        self.metrics.notify(|observer| observer.on_shutting_down());
+        let report = self.drain();
+        println!("Shutdown report: {}", report);
+        Ok(report)
+    }
+
+    // The accept loop of the listener at `index`, until shutdown or an
+    // accept error that is not worth retrying. Each connection's head is
+    // read on a thread of `scope`, named after this loop.
+    fn accept_connections<'scope>(
+        &'scope self,
+        scope: &'scope thread::Scope<'scope, '_>,
+        index: usize,
+        listener: &Listener,
+        policy: ListenerPolicy,
+    ) -> Result<(), FileServerError> {
+        let head_role = format!("head-{}", index);
+        loop {
+            if self.busy_policy == BusyPolicy::Backpressure && !self.wait_for_idle_worker() {
+                return Ok(());
+            }
+            if self.connection_limit.policy() == OverflowPolicy::Queue
+                && !self.wait_for_connection_room()
+            {
+                return Ok(());
+            }
+            let stream = listener.accept(policy);
+            if self.shutdown_requested.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+
+            let managed_stream = match stream {
+                Ok(managed_stream) => managed_stream,
+                Err(err) => {
+                    Self::retry_after_accept_error(err)?;
+                    continue;
+                }
+            };
+
+            self.metrics.record_connection();
+
+            // counted from here on, the place is given back when the last
+            // owner of the stream drops it, whichever thread that ends up being
+            let (managed_stream, over_limit) = match self.connection_limit.admit(managed_stream) {
+                Ok(stream) => (stream, false),
+                Err(stream) => (stream, true),
+            };
+            println!(
+                "{}Handling incoming connection from {} .....",
+                log_prefix(&*managed_stream),
+                managed_stream.peer()
+            );
+            let peer = managed_stream.peer();
+            self.metrics.notify(|observer| {
+                observer.on_connection(&ConnectionEvent {
+                    request_id: managed_stream.request_id(),
+                    peer: &peer,
+                })
+            });
+
+            // the head is read on a thread of its own, a client that is slow
+            // to send it holds up nobody else
+            let threads = &self.metrics.threads;
+            threads.spawn_scoped(scope, managed_stream.request_id(), &head_role, move || {
+                self.serve_connection(managed_stream, over_limit)
+            });
+        }
+    }
+
+    // Wakes every accept loop still parked in accept() so it sees the
+    // shutdown flag.
+    fn stop_accepting(&self) {
+        for (index, (listener, _)) in self.listeners.iter().enumerate() {
+            if index < self.acceptors && self.acceptors > 1 {
+                // a poke to the shared port lands on whichever sibling the
+                // kernel picks
+                let _ = listener.stop_accepting();
+            } else if let Ok(addr) = listener.listen_addr() {
+                addr.poke();
+            }
+        }
+    }
+
+    // how long a keep-alive connection may sit without sending a command
+    pub fn set_keep_alive_timeout(&mut self, timeout: time::Duration) {
+        self.keep_alive_timeout = timeout;
+    }
+
+    // Serve files of at least `threshold` bytes from a memory mapping instead
+    // of a BufReader, None (the default) turns mapping off.
+    // bytes per second one download, and all of them together, may use
+    pub fn set_rate_limits(&mut self, per_transfer: Option<u64>, global: Option<u64>) {
+        self.metrics.set_rate_limits(per_transfer, global);
+    }
+
+    // Bytes each client IP may download per UTC day, None (the default) is
+    // unlimited. Clients past it are turned away until midnight.
+    pub fn set_daily_ip_cap(&mut self, bytes: Option<u64>) {
+        self.metrics.bandwidth.set_daily_cap(bytes);
+    }
+
+    // Longest a connection may spend on `command`, counted once a worker
+    // picked it up. Past it the watchdog hangs up, freeing the worker, and
+    // counts a timeout. None (the default) lets the command run for as long
+    // as it takes; stats subscriptions are never timed out.
+    pub fn set_handler_timeout(&mut self, command: CommandType, budget: Option<time::Duration>) {
+        self.metrics.watchdog.set_budget(command, budget);
+    }
+
+    // Tells `observer` about every connection, request and refusal, see
+    // EventObserver.
+    pub fn add_observer(&mut self, observer: Observer) {
+        self.metrics.add_observer(observer);
+    }
+
+    // The same events as a channel, for embedders that would rather receive
+    // than implement EventObserver. Every call opens a channel of its own;
+    // dropping the receiver is fine, the server keeps going without it.
+    pub fn events(&self) -> mpsc::Receiver<ServerEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.metrics.add_observer(Arc::new(ChannelObserver(sender)));
+        receiver
+    }
+
+    // Globs for the files downloads may open, see ServePolicy. Both empty
+    // (the default) serves everything.
+    pub fn set_serve_patterns(&mut self, allow: Vec<String>, deny: Vec<String>) {
+        self.metrics.set_serve_policy(ServePolicy::new(allow, deny));
+    }
+
+    // Reports every download's progress to `hook`, see TransferUpdate.
+    pub fn set_progress_hook(&mut self, hook: ProgressHook) {
+        self.metrics.set_progress_hook(Some(hook));
+    }
+
+    // Calls `hook` with the path and details of every file an upload (over
+    // the protocol or WebDAV) committed, e.g. to scan it or replicate it, see
+    // UploadHook. Can be called more than once, every hook runs in turn.
+    pub fn on_upload_complete(
+        &mut self,
+        hook: impl Fn(&Path, &UploadMeta) + Send + Sync + 'static,
+    ) {
+        self.metrics.add_upload_hook(Arc::new(hook));
+    }
+
+    // Every request for a file is put to `authorizer` first, see Authorizer.
+    pub fn set_authorizer(&mut self, authorizer: Authorizer) {
+        self.metrics.set_authorizer(Some(authorizer));
+    }
+
+    // Threads started from now on are named `<prefix>-worker-3`,
+    // `<prefix>-stats` and so on, `fs` by default.
+    pub fn set_thread_name_prefix(&mut self, prefix: &str) {
+        self.metrics.threads.set_prefix(prefix);
+    }
+
+    // How many of the most downloaded files stats reports list, 10 by default.
+    pub fn set_top_files(&mut self, count: usize) {
+        self.metrics.set_top_files(count);
+    }
+
+    pub fn set_mmap_threshold(&mut self, threshold: Option<u64>) {
+        self.metrics.set_mmap_threshold(threshold);
+    }
+
+    // Keep up to `capacity_bytes` of hot files no bigger than `max_file_size`
+    // in memory. A capacity of 0 (the default) turns the cache off.
+    pub fn set_hot_cache(&mut self, capacity_bytes: u64, max_file_size: u64) {
+        self.metrics
+            .hot_files
+            .configure(capacity_bytes, max_file_size);
+    }
+
+    // Sets aside `count` workers that only `command` may use, so it stays
+    // responsive while other commands keep the rest of the pool busy.
+    pub fn reserve_workers(
+        &mut self,
+        command: CommandType,
+        count: i32,
+    ) -> Result<(), FileServerError> {
+        self.pool.reserve(command, count).map_err(|reason| {
+            FileServerError::PreflightFailed(vec![PreflightError::InvalidConfig(reason)])
+        })
+    }
+
+    // how long shutdown waits for in-flight transfers before giving up on them
+    pub fn set_drain_timeout(&mut self, timeout: time::Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    pub fn set_busy_policy(&mut self, policy: BusyPolicy) {
+        self.busy_policy = policy;
+    }
+
+    // Downloads of files under `bytes`, the size Stat reports, wait for a
+    // worker ahead of larger ones so a few big transfers do not hold up
+    // interactive clients. None, the default, serves downloads in the order
+    // they came.
+    pub fn set_small_transfer_bytes(&mut self, bytes: Option<u64>) {
+        self.small_transfer_bytes = bytes;
+    }
+
+    // caps the sockets open at once, stats subscribers included. None lifts the cap
+    pub fn set_connection_limit(&mut self, max: Option<usize>, policy: OverflowPolicy) {
+        self.connection_limit = Arc::new(ConnectionLimit::new(max, policy));
+    }
+
+    // Jails each user to root_dir/<name> and makes every client log in first,
+    // Ping aside. An empty list turns accounts off.
+    pub fn set_accounts(&mut self, users: &[UserAccount]) -> Result<(), FileServerError> {
+        self.accounts = match users {
+            [] => None,
+            users => Some(Accounts::new(users, self.root_dir)?),
+        };
+        // an empty home is still the user's
+        for home in self.accounts.iter().flat_map(Accounts::homes) {
+            self.metrics
+                .janitor
+                .keep_directory(reader::served_directory_path(home));
+        }
+        Ok(())
+    }
+
+    // The admin port's `metrics reset` and `metrics snapshot` only run on
+    // connections that sent `auth <password>` with a password hashing to
+    // `hex_hash` (SHA-256, like a user's password_sha256). None, the default,
+    // refuses them. Only admin ports started after this see the change.
+    pub fn set_admin_password_sha256(&mut self, hex_hash: Option<&str>) {
+        self.admin_password_sha256 = hex_hash.map(str::to_ascii_lowercase);
+    }
+
+    // Appends every upload and delete, refused ones too, to the file at
+    // `path`. The admin port's `audit` command follows the same entries.
+    pub fn set_audit_log(&mut self, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+        self.metrics.audit.open(path)
+    }
+
+    // Encrypts uploads at rest with AES-256-GCM under `hex_key` (64 hex
+    // digits) and decrypts served files on the way out, so nothing under the
+    // served directory is plaintext. The hot file cache still holds plaintext,
+    // in memory only. Files already there must have been stored with the same
+    // key, anything else fails to open.
+    pub fn set_encryption_key(&mut self, hex_key: &str) -> io::Result<()> {
+        let key = reader::AtRestKey::from_hex(hex_key)?;
+        self.metrics.set_at_rest_key(Some(key));
+        Ok(())
+    }
+
+    // Serves the files of `archive` in place of those in root_dir: every kind
+    // of download, Stat, List, ListPage, BatchDownload and Sync see the
+    // archive and nothing on disk, uploads and deletes are refused like in
+    // read-only mode. Archive and WebDAV serve it too, Watch and Tail are
+    // refused, nothing in it changes. Files in the archive are served as they
+    // are, never decrypted. FileServerBuilder::embedded_archive builds a
+    // server that does not need root_dir at all.
+    pub fn set_embedded_archive(&mut self, archive: EmbeddedArchive) {
+        println!("Serving {} files from the embedded archive", archive.len());
+        self.metrics.set_embedded_archive(Some(archive));
+    }
+
+    // Syncs every upload and the directory entry for its name to disk before
+    // acknowledging it. Slower, but an acknowledged upload survives a crash
+    // or power loss.
+    pub fn set_durable_uploads(&mut self, durable: bool) {
+        self.metrics.set_durable_uploads(durable);
+    }
+
+    // Sessions that sent AcceptGzip get `foo.txt.gz` in place of `foo.txt`
+    // when it exists and is no older than foo.txt, streamed as it is on disk
+    // instead of compressed on the fly. Advertised in Hello's compression.
+    pub fn set_serve_precompressed(&mut self, serve: bool) {
+        self.metrics.set_serve_precompressed(serve);
+    }
+
+    // Deletes move files to root_dir/.trash instead of unlinking them, and a
+    // janitor thread purges what `policy` no longer keeps. The admin port's
+    // `trash` and `restore` list and bring them back. None unlinks again.
+    pub fn set_trash(&mut self, policy: Option<TrashPolicy>) {
+        self.metrics.janitor.set_trash(self.root_dir, policy);
+    }
+
+    // Locate answers with the node of `ring` a file name maps to, so clients
+    // of a sharded deployment can go to the right server directly.
+    pub fn set_ring(&mut self, ring: HashRing) {
+        self.metrics.set_ring(ring);
+    }
+
+    // Follows the stats of the file servers at `peers` ("host:port") and adds
+    // them up with this server's into the cluster part of its reports, see
+    // Cluster. Empty stops following.
+    pub fn set_cluster_peers(&mut self, peers: &[String]) {
+        self.metrics.cluster.set_peers(peers);
+    }
+
+    // Sends every upload and delete in the root on to the file servers at
+    // `replicas` ("host:port"), see Replication. What they could not take yet
+    // is kept in `queue` when set, and sent first after a restart.
+    pub fn set_replicas(&mut self, replicas: &[String], queue: Option<&Path>) -> io::Result<()> {
+        self.metrics
+            .replication
+            .configure(self.root_dir, replicas, queue)?;
+        if !replicas.is_empty() {
+            self.metrics
+                .replication
+                .start(Arc::downgrade(&self.metrics));
+        }
+        Ok(())
+    }
+
+    // Every `interval` the janitor removes `.part` files no upload wrote to
+    // for an hour, purges the trash and removes empty directories below the
+    // root, user homes aside. None stops it, a trash still gets purged.
+    pub fn set_janitor_interval(&mut self, interval: Option<time::Duration>) {
+        self.metrics.janitor.set_interval(self.root_dir, interval);
+    }
+
+    // Caps file names, request headers, uploads and sync manifests, see
+    // Limits. A request over one gets an error frame naming it. Hello
+    // advertises the upload cap so clients can check before sending.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.metrics.set_limits(limits);
+    }
+
+    // Turns away stats subscribers past `max`. None lets any number follow.
+    pub fn set_max_stats_subscribers(&mut self, max: Option<usize>) {
+        self.max_stats_subscribers = max;
+    }
+
+    // Evicts stats subscribers that send nothing for `timeout`. Off by default
+    // since older clients never send heartbeats, those are only evicted once
+    // they hang up.
+    pub fn set_stats_heartbeat_timeout(&mut self, timeout: Option<time::Duration>) {
+        self.stats_heartbeat_timeout = timeout;
+    }
+
+    // What the stats ticks, stats heartbeat timeouts, rate limits, handler
+    // budgets and the janitor keep time by, the system clock unless set. Set
+    // it before anything starts, a MockClock lets tests step those intervals
+    // instead of waiting them out.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.metrics.set_clock(clock);
+    }
+
+    // How often start_metrics_report pushes a tick to stats subscribers, a
+    // second by default. Rounded up to a millisecond so the loop never spins.
+    pub fn set_stats_interval(&mut self, interval: time::Duration) {
+        self.stats_interval = interval.max(time::Duration::from_millis(1));
+    }
+
+    // Sends every stats tick to `sink` too, from the next start_metrics_report.
+    pub fn add_stats_sink(&mut self, sink: Box<dyn StatsSink>) {
+        self.stats_sinks.get_mut().unwrap().push(sink);
+    }
+
+    pub fn register_handlers(&mut self, handlers: &[(CommandType, Handler)]) {
+        let router = Arc::make_mut(&mut self.router);
+        for (command, handler) in handlers {
+            println!("Registering {:?} handler...", command);
+            if router.insert_route(u8::from(*command), *handler).is_some() {
+                println!(
+                    "...Warning: {:?} was already registered, replacing its handler",
+                    command
+                );
+            }
+        }
+    }
+
+    // Checks the handlers make a usable server, so a missing one shows up at
+    // startup rather than when the first client sends that command. Built-in
+    // routers need at least a Download handler, custom ones any route at all.
+    pub fn validate(&self) -> Result<(), FileServerError> {
+        if !self.router.uses_builtin_commands() {
+            if self.router.is_empty() {
+                return Err(FileServerError::InvalidHandlerConfig {
+                    reason: "the custom router has no routes".to_owned(),
+                });
+            }
+            return Ok(());
+        }
+
+        let missing: Vec<String> = REQUIRED_COMMANDS
+            .iter()
+            .filter(|command| self.router.handler(u8::from(**command)).is_none())
+            .map(|command| format!("{:?}", command))
+            .collect();
+        if !missing.is_empty() {
+            return Err(FileServerError::InvalidHandlerConfig {
+                reason: format!("no handler registered for {}", missing.join(", ")),
+            });
+        }
+        Ok(())
+    }
+
+    // Wraps every handler dispatched from now on. Statistics subscriptions are
+    // not handlers and never pass through middleware.
+    pub fn use_middleware(&mut self, middleware: Middleware) {
+        Arc::make_mut(&mut self.router).push_middleware(middleware);
+    }
+
+    // Replaces the whole dispatch table, handlers registered so far are dropped.
+    pub fn set_router(&mut self, router: Router) {
+        self.router = Arc::new(router);
+    }
+}
+
+// Test Helpers
+
+#[cfg(test)]
+mod tests {
+    use super::super::keep_alive::{FRAME_ERROR, FRAME_NOT_FOUND};
+    use super::*;
+    use crate::protocol::stats::{Stats, StatsSnapshot};
+    use crate::reader;
+    use crate::testkit::{download_test_file, hold_worker, setup_tmp_file, TestServer};
     use std::fs;
 
-    fn setup_tmp_file(root_dir: &str, filename: &str, file_content: &str) {
-        let path = reader::configure_directory_to_serve_file(root_dir);
-        fs::write(format!("{}/{}", path.as_str(), filename), file_content).unwrap();
+    // What `handler` answers to `request`, which must be an error frame.
+    fn refusal(
+        request: &[u8],
+        handler: fn(&dyn Connection, &'static str, &MetricsRegistry) -> io::Result<()>,
+        metrics: &MetricsRegistry,
+    ) -> String {
+        let (client, server) = super::super::connection::duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(request).unwrap();
+        let _ = handler(&server, "temp_test_limits_root_dir", metrics);
+        drop(server);
+        let mut reply = Vec::new();
+        client_end.read_to_end(&mut reply).unwrap();
+        assert_eq!(FRAME_ERROR, reply[0]);
+        String::from_utf8(reply[9..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_limits_refuse_with_error_frames() {
+        let metrics = MetricsRegistry::new();
+        metrics.set_limits(Limits {
+            max_file_name_bytes: 8,
+            max_header_bytes: 32,
+            max_upload_bytes: Some(4),
+            max_manifest_bytes: 16,
+        });
+
+        let long_name = b"filename=much_too_long.txt|\x00\x00\x00\x00\x00\x00\x00\x01x";
+        assert_eq!(
+            "file name is over the 8 byte limit",
+            refusal(long_name, FileServer::framed_upload, &metrics)
+        );
+        let long_header = [&b"filename="[..], &[b'a'; 40]].concat();
+        assert_eq!(
+            "request header is over the 32 byte limit",
+            refusal(&long_header, FileServer::framed_upload, &metrics)
+        );
+        let upload = [&b"filename=a.txt|"[..], &5u64.to_be_bytes(), b"12345"].concat();
+        assert_eq!(
+            "upload is over the 4 byte limit",
+            refusal(&upload, FileServer::framed_upload, &metrics)
+        );
+        assert_eq!(
+            "manifest is over the 16 byte limit",
+            refusal(&17u64.to_be_bytes(), FileServer::framed_sync, &metrics)
+        );
+        // refused bodies are not counted
+        assert_eq!(0, metrics.request_bodies.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_refused_upload_body_is_not_read() {
+        let server = TestServer::start_with(|builder| {
+            builder.limits(Limits {
+                max_upload_bytes: Some(4),
+                ..Limits::default()
+            })
+        });
+        // announces far more than it is ever going to send
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(&[u8::from(CommandType::Upload)]).unwrap();
+        stream.write_all(b"filename=huge.bin|").unwrap();
+        stream.write_all(&u64::MAX.to_be_bytes()).unwrap();
+        stream
+            .set_read_timeout(Some(protocol::HEADER_TIMEOUT))
+            .unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        assert_eq!(FRAME_ERROR, reply[0]);
+        assert!(reply[9..].starts_with(b"upload is over the 4 byte limit"));
+
+        // a client in the middle of a big body still gets the reason
+        let body = vec![0u8; 8 << 20];
+        match server.client().upload("big.bin", &body) {
+            Err(crate::ClientError::Server(reason)) => {
+                assert!(reason.starts_with("upload is over the 4 byte limit"))
+            }
+            other => panic!("expected the limit, got {:?}", other),
+        }
     }
 
     fn setup_file_server(
         addr: &str,
         port: &str,
         threads: i32,
-        handlers: &[(
-            CommandType,
-            fn(
-                stream: &TcpStream,
-                root_dir: &'static str,
-                metrics_registry: Arc<RwLock<HashMap<String, i64>>>,
-            ),
-        )],
+        handlers: &[(CommandType, Handler)],
         root_dir: &'static str,
     ) -> FileServer {
         let mut file_server = FileServer::new(addr, port, threads, root_dir).unwrap();
@@ -412,37 +2183,11 @@ mod tests {
         net::TcpStream,
     };
 
-    fn download_test_file(
-        addr: &'static str,
-        port: &'static str,
-        file_name: &'static str,
-        read_delay: Option<time::Duration>,
-    ) -> String {
-        let addr_with_port = format!("{}:{}", addr, port);
-
-        let mut stream = TcpStream::connect(addr_with_port).unwrap();
-        stream.write_all(&[1]).unwrap();
-
-        if let Some(delay) = read_delay {
-            thread::sleep(delay);
-        }
-        stream
-            .write_all(format!("filename={}|", file_name).as_bytes())
-            .unwrap();
-        stream.flush().unwrap();
-
-        let mut buffer = Vec::new();
-
-        stream.read_to_end(&mut buffer).unwrap();
-
-        return String::from_utf8_lossy(&buffer).to_string();
-    }
-
     fn connect_to_metrics_path(addr: &'static str, port: &'static str) -> TcpStream {
         let addr_with_port = format!("{}:{}", addr, port);
         let mut stream = TcpStream::connect(addr_with_port).unwrap();
         stream.write_all(&[3]).unwrap();
-        return stream;
+        stream
     }
 
     fn init_test_server(
@@ -451,7 +2196,7 @@ mod tests {
         content: &'static str,
         file_name: &'static str,
         root_dir: &'static str,
-    ) {
+    ) -> ServerHandle {
         setup_tmp_file(root_dir, file_name, content);
         let server = setup_file_server(
             addr,
@@ -463,14 +2208,18 @@ mod tests {
                     FileServer::handle_incomming_file_request,
                 ),
                 (CommandType::Statistics, FileServer::no_op_handler),
+                (CommandType::StatisticsV2, FileServer::no_op_handler),
+                (CommandType::StatsOnce, FileServer::no_op_handler),
+                (
+                    CommandType::KeepAlive,
+                    FileServer::handle_keep_alive_session,
+                ),
             ],
             root_dir,
         );
 
         server.start_metrics_report();
-        thread::spawn(move || {
-            server.handle_incomming_connections();
-        });
+        server.spawn()
     }
 
     #[test]
@@ -493,19 +2242,14 @@ mod tests {
         let port = "8079";
         let content = "hello_from_file_Server!";
         let file_name = "temp_test_file";
-        let root_dir = "temp_test_root_dir";
+        let root_dir = "temp_test_stats_root_dir";
 
-        init_test_server(addr, port, content, file_name, root_dir);
+        let handle = init_test_server(addr, port, content, file_name, root_dir);
 
-        // Simulate long running connection on downlaod path
-        thread::spawn(|| {
-            download_test_file(
-                addr,
-                port,
-                file_name,
-                Some(time::Duration::from_millis(1000000)),
-            );
-        });
+        // a long running connection on the download path, picked up before
+        // the stats are asked for
+        let held = hold_worker(addr, port);
+        assert!(handle.wait_for_busy_workers(1, time::Duration::from_secs(5)));
         download_test_file(addr, port, file_name, None);
         download_test_file(addr, port, file_name, None);
         download_test_file(addr, port, file_name, None);
@@ -517,6 +2261,1364 @@ mod tests {
         assert_eq!("temp_test_file", stats.most_downloaded_file);
         assert_eq!(3, stats.file_downloaded_count);
 
+        drop(held);
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_statistic_v2_reports_bytes() {
+        let addr = "127.0.0.1";
+        let port = "8019";
+        let content = "hello_from_stats_v2!";
+        let file_name = "temp_test_stats_v2_file";
+        let root_dir = "temp_test_stats_v2_root_dir";
+
+        init_test_server(addr, port, content, file_name, root_dir);
+        download_test_file(addr, port, file_name, None);
+        download_test_file(addr, port, file_name, None);
+
+        let mut metrics_stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        metrics_stream.write_all(&[8, 0]).unwrap();
+        let stats = StatsSnapshot::from_stream_v2(&mut metrics_stream).unwrap();
+
+        assert_eq!(file_name, stats.most_downloaded_file);
+        assert_eq!(2, stats.file_downloaded_count);
+        assert_eq!(2 * content.len() as u64, stats.bytes_served);
+        assert_eq!(
+            vec![(file_name.to_owned(), 2 * content.len() as u64)],
+            stats.bytes_per_file
+        );
+
+        // one report on demand, without waiting for a tick, then EOF
+        let mut once_stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        once_stream.write_all(&[18, 0]).unwrap();
+        let once = StatsSnapshot::from_stream_v2(&mut once_stream).unwrap();
+        assert_eq!(stats.bytes_per_file, once.bytes_per_file);
+        assert_eq!(0, once_stream.read(&mut [0u8; 1]).unwrap());
+
+        // the same tick as one JSON line
+        use std::io::BufRead;
+        let json_stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        (&json_stream).write_all(&[8, 1]).unwrap();
+        let mut line = String::new();
+        io::BufReader::new(json_stream)
+            .read_line(&mut line)
+            .unwrap();
+        // the v2 subscriber above holds a worker too, leave clients out
+        assert!(line.starts_with("{\"clients\":"));
+        assert!(line.contains(&format!(
+            "\"top_file\":\"{}\",\"count\":2,\"bytes_served\":40,",
+            file_name
+        )));
+        assert!(line.ends_with("}\n"));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_reserved_stats_worker_with_busy_pool() {
+        let addr = "127.0.0.1";
+        let port = "7999";
+        let file_name = "temp_test_reserved_file";
+        let root_dir = "temp_test_reserved_root_dir";
+
+        setup_tmp_file(root_dir, file_name, "reserved");
+        let mut server = setup_file_server(
+            addr,
+            port,
+            2,
+            &[
+                (
+                    CommandType::Download,
+                    FileServer::handle_incomming_file_request,
+                ),
+                (CommandType::Statistics, FileServer::no_op_handler),
+            ],
+            root_dir,
+        );
+        server.reserve_workers(CommandType::Statistics, 1).unwrap();
+        server.start_metrics_report();
+        let pool = server.pool.clone();
+        let handle = server.spawn();
+
+        // one download holds the only shared worker, the next one has to wait
+        let held = hold_worker(addr, port);
+        assert!(handle.wait_for_busy_workers(1, time::Duration::from_secs(5)));
+        let waiting = hold_worker(addr, port);
+        assert!(pool.wait_for_waiting(None, 1, time::Duration::from_secs(5)));
+
+        let mut metrics_stream = connect_to_metrics_path(addr, port);
+        let stats = Stats::stats_from_stream(&mut metrics_stream);
+        assert_eq!(2, stats.number_of_clients);
+
+        drop((held, waiting));
+        reader::cleanup_server_file(root_dir);
+    }
+
+    // index.html and app.js in a tar like the Archive command sends, made
+    // from a directory of `source_name`
+    fn embedded_test_archive(source_name: &str) -> EmbeddedArchive {
+        let source = reader::ServeDir::create(source_name).unwrap();
+        setup_tmp_file(source.name(), "index.html", "<h1>archived</h1>");
+        setup_tmp_file(source.name(), "app.js", "run()");
+        let mut tar = Vec::new();
+        for entry in crate::archive::collect_entries(source.path(), "").unwrap() {
+            tar.extend(entry.header);
+            tar.extend(fs::read(&entry.disk_path).unwrap());
+            tar.extend(vec![0; entry.padding()]);
+        }
+        tar.extend(crate::archive::END_OF_ARCHIVE);
+        EmbeddedArchive::from_bytes(tar).unwrap()
+    }
+
+    #[test]
+    fn test_serves_the_embedded_archive_in_place_of_the_root() {
+        let archive = embedded_test_archive("temp_test_embedded_archive_source");
+        let server =
+            crate::testkit::TestServer::start_with(|builder| builder.embedded_archive(archive));
+        server.add_file("on_disk.txt", "not served");
+
+        assert_eq!("<h1>archived</h1>", server.download("index.html"));
+        let mut client = server.client();
+        assert_eq!(5, client.stat("app.js").unwrap().size);
+        assert!(client.stat("on_disk.txt").is_err());
+        let names: Vec<String> = client
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(vec!["app.js", "index.html"], names);
+
+        assert!(server.client().upload("new.txt", b"refused").is_err());
+        assert!(!server.root().join("new.txt").exists());
+    }
+
+    #[test]
+    fn test_embedded_archive_needs_no_root_dir() {
+        let root_dir = "temp_test_embedded_archive_no_root_dir";
+        reader::cleanup_server_file(root_dir);
+        let archive = embedded_test_archive("temp_test_embedded_archive_no_root_source");
+        let server = FileServerBuilder::new()
+            .address("127.0.0.1")
+            .port("8231")
+            .root_dir(root_dir)
+            .embedded_archive(archive)
+            .handlers(&crate::testkit::builtin_handlers())
+            .build()
+            .unwrap();
+        server.start_webdav("127.0.0.1", "8232").unwrap();
+        let handle = server.spawn();
+
+        let mut client = crate::FileClient::new("127.0.0.1", "8231");
+        let mut tar = Vec::new();
+        let token = crate::CancellationToken::new();
+        client.download_archive(".", &mut tar, &token).unwrap();
+        let tar = EmbeddedArchive::from_bytes(tar).unwrap();
+        let names: Vec<String> = tar.files().map(|file| file.name).collect();
+        assert_eq!(vec!["app.js", "index.html"], names);
+        assert!(client
+            .download_archive("docs", &mut Vec::new(), &token)
+            .is_err());
+        // nothing in the archive changes, there is nothing to follow
+        assert!(client.watch("*").is_err());
+        let mut tail = client.tail("app.js", 10).unwrap();
+        assert!(tail.next_chunk(&token).is_err());
+
+        let dav = |head: &str| {
+            let mut stream = TcpStream::connect("127.0.0.1:8232").unwrap();
+            stream.write_all(head.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let get = dav("GET /app.js HTTP/1.1\r\n\r\n");
+        assert!(
+            get.starts_with("HTTP/1.1 200") && get.ends_with("run()"),
+            "{}",
+            get
+        );
+        let listing = dav("PROPFIND / HTTP/1.1\r\nDepth: 1\r\n\r\n");
+        assert!(
+            listing.contains("<D:href>/index.html</D:href>"),
+            "{}",
+            listing
+        );
+        let index = dav("GET / HTTP/1.1\r\n\r\n");
+        assert!(index.contains("app.js"), "{}", index);
+        assert!(dav("GET /docs/app.js HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+
+        // the keep-alive session would hold up the drain
+        drop((client, tail));
+        handle.shutdown();
+        handle.join().unwrap();
+        assert!(!reader::served_directory_path(root_dir).exists());
+    }
+
+    #[test]
+    fn test_stats_ticks_follow_the_clock() {
+        let clock = Arc::new(crate::MockClock::new());
+        let server = crate::testkit::TestServer::start_with(|builder| {
+            builder
+                .clock(clock.clone())
+                .stats_interval(time::Duration::from_secs(60))
+        });
+        server.add_file("ticked.txt", "tick");
+        server.download("ticked.txt");
+
+        let mut subscriber = TcpStream::connect(server.addr()).unwrap();
+        subscriber.write_all(&[8, 0]).unwrap();
+        assert!(server
+            .handle()
+            .wait_for_busy_workers(1, time::Duration::from_secs(5)));
+        subscriber
+            .set_read_timeout(Some(time::Duration::from_millis(100)))
+            .unwrap();
+        // a minute per round, each one over once the stats thread sleeps again
+        let mut rounds = 0;
+        let stats = loop {
+            assert!(clock.wait_for_sleepers(1, time::Duration::from_secs(5)));
+            let mut peek = [0u8; 1];
+            if subscriber.peek(&mut peek).is_ok() {
+                break StatsSnapshot::from_stream_v2(&mut subscriber).unwrap();
+            }
+            rounds += 1;
+            assert!(rounds < 50, "no tick after {} minutes", rounds);
+            clock.advance(time::Duration::from_secs(60));
+        };
+        assert!(rounds >= 1);
+        assert_eq!("ticked.txt", stats.most_downloaded_file);
+    }
+
+    fn read_keep_alive_frame(mut stream: impl Read) -> (u8, String) {
+        let mut status = [0u8; 1];
+        stream.read_exact(&mut status).unwrap();
+        let mut length = [0u8; 8];
+        stream.read_exact(&mut length).unwrap();
+        let mut payload = vec![0; u64::from_be_bytes(length) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        (status[0], String::from_utf8_lossy(&payload).to_string())
+    }
+
+    // The reason in an error reply, without the request id that closes it.
+    fn without_request_id(reply: String) -> String {
+        match reply.rsplit_once(" (request ") {
+            Some((reason, _)) => reason.to_owned(),
+            None => reply,
+        }
+    }
+
+    #[test]
+    fn test_hot_cache_serves_until_upload() {
+        let addr = "127.0.0.1";
+        let port = "8009";
+        let file_name = "temp_test_hot_file";
+        let root_dir = "temp_test_hot_cache_root_dir";
+
+        setup_tmp_file(root_dir, file_name, "first");
+        let mut server = setup_file_server(
+            addr,
+            port,
+            10,
+            &[
+                (
+                    CommandType::Download,
+                    FileServer::handle_incomming_file_request,
+                ),
+                (CommandType::Upload, FileServer::handle_upload),
+            ],
+            root_dir,
+        );
+        server.set_hot_cache(1024, 1024);
+        let metrics = server.metrics.clone();
+        server.spawn();
+
+        // the second download makes the file hot
+        assert_eq!("first", download_test_file(addr, port, file_name, None));
+        assert_eq!("first", download_test_file(addr, port, file_name, None));
+        assert_eq!(1, metrics.hot_files.len());
+
+        // changes behind the server's back are not seen while cached
+        setup_tmp_file(root_dir, file_name, "changed on disk");
+        assert_eq!("first", download_test_file(addr, port, file_name, None));
+
+        let mut stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        stream.write_all(&[2]).unwrap();
+        stream
+            .write_all(format!("filename={}|", file_name).as_bytes())
+            .unwrap();
+        stream.write_all(&6u64.to_be_bytes()).unwrap();
+        stream.write_all(b"second").unwrap();
+        assert_eq!((0, String::new()), read_keep_alive_frame(&mut stream));
+
+        assert_eq!("second", download_test_file(addr, port, file_name, None));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_concurrent_downloads_share_one_mapping() {
+        let addr = "127.0.0.1";
+        let port = "8189";
+        let file_name = "temp_test_shared_file";
+        let root_dir = "temp_test_shared_mapping_root_dir";
+        let content = "0123456789abcdef".repeat(16 * 1024);
+
+        setup_tmp_file(root_dir, file_name, &content);
+        let mut server = setup_file_server(
+            addr,
+            port,
+            10,
+            &[
+                (
+                    CommandType::Download,
+                    FileServer::handle_incomming_file_request,
+                ),
+                (CommandType::Upload, FileServer::handle_upload),
+            ],
+            root_dir,
+        );
+        server.set_mmap_threshold(Some(0));
+        let metrics = server.metrics.clone();
+        server.spawn();
+
+        let downloads: Vec<_> = (0..8)
+            .map(|_| thread::spawn(move || download_test_file(addr, port, file_name, None)))
+            .collect();
+        for download in downloads {
+            assert!(download.join().unwrap() == content);
+        }
+
+        // a second reader while the first is still at it gets the same mapping
+        let first = FileServer::open_served_file(file_name, root_dir, &metrics).unwrap();
+        let second = FileServer::open_served_file(file_name, root_dir, &metrics).unwrap();
+        assert!(first.mapping().unwrap().ptr_eq(second.mapping().unwrap()));
+        assert!(metrics.shared_mappings.reuses.load(Ordering::Relaxed) >= 1);
+
+        // an upload is never served out of the old mapping
+        let mut stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        stream.write_all(&[2]).unwrap();
+        stream
+            .write_all(format!("filename={}|", file_name).as_bytes())
+            .unwrap();
+        stream.write_all(&3u64.to_be_bytes()).unwrap();
+        stream.write_all(b"new").unwrap();
+        assert_eq!((0, String::new()), read_keep_alive_frame(&mut stream));
+        let mut third = FileServer::open_served_file(file_name, root_dir, &metrics).unwrap();
+        assert!(!first.mapping().unwrap().ptr_eq(third.mapping().unwrap()));
+        let mut read = String::new();
+        third.read_to_string(&mut read).unwrap();
+        assert_eq!("new", read);
+
+        drop((first, second, third));
+        assert!(metrics.shared_mappings.is_empty());
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_keep_alive_session() {
+        let addr = "127.0.0.1";
+        let port = "8069";
+        let content = "hello_from_keep_alive!";
+        let file_name = "temp_test_keep_alive_file";
+        let root_dir = "temp_test_keep_alive_root_dir";
+
+        init_test_server(addr, port, content, file_name, root_dir);
+
+        let mut stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        stream.write_all(&[4]).unwrap();
+        for _ in 0..2 {
+            stream.write_all(&[1]).unwrap();
+            stream
+                .write_all(format!("filename={}|", file_name).as_bytes())
+                .unwrap();
+            assert_eq!((0, content.to_owned()), read_keep_alive_frame(&mut stream));
+        }
+
+        stream.write_all(&[1]).unwrap();
+        stream.write_all(b"filename=missing_file|").unwrap();
+        assert_eq!(FRAME_NOT_FOUND, read_keep_alive_frame(&mut stream).0);
+
+        // quitting closes the connection from the server side
+        stream.write_all(&[5]).unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_shutdown_report() {
+        let addr = "127.0.0.1";
+        let port = "8059";
+        let content = "hello_from_shutdown!";
+        let file_name = "temp_test_shutdown_file";
+        let root_dir = "temp_test_shutdown_root_dir";
+
+        setup_tmp_file(root_dir, file_name, content);
+        let server = setup_file_server(
+            addr,
+            port,
+            2,
+            &[(
+                CommandType::Download,
+                FileServer::handle_incomming_file_request,
+            )],
+            root_dir,
+        );
+        let server = server.spawn();
+        assert_eq!(
+            Some(format!("{}:{}", addr, port).parse().unwrap()),
+            server.local_addr()
+        );
+
+        assert_eq!(content, download_test_file(addr, port, file_name, None));
+        server.shutdown();
+        let report = server.join().unwrap();
+
+        assert_eq!(1, report.total_connections);
+        assert_eq!(content.len() as u64, report.bytes_served);
+        assert_eq!(0, report.aborted_transfers);
+        assert_eq!(Some(&1), report.final_metrics.get(file_name));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_drain_finishes_sessions_then_shuts_down() {
+        let addr = "127.0.0.1";
+        let port = "8139";
+        let content = "hello_from_drain!";
+        let file_name = "temp_test_drain_file";
+        let root_dir = "temp_test_drain_root_dir";
+
+        setup_tmp_file(root_dir, file_name, content);
+        let server = setup_file_server(
+            addr,
+            port,
+            2,
+            &[
+                (
+                    CommandType::Download,
+                    FileServer::handle_incomming_file_request,
+                ),
+                (
+                    CommandType::KeepAlive,
+                    FileServer::handle_keep_alive_session,
+                ),
+            ],
+            root_dir,
+        );
+        let server = server.spawn();
+
+        let mut session = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        session.write_all(&[4]).unwrap();
+        session.write_all(&[1]).unwrap();
+        session
+            .write_all(format!("filename={}|", file_name).as_bytes())
+            .unwrap();
+        assert_eq!((0, content.to_owned()), read_keep_alive_frame(&mut session));
+
+        server.drain();
+        assert_eq!(
+            "draining, retry elsewhere",
+            without_request_id(download_test_file(addr, port, file_name, None))
+        );
+
+        // the session in flight is still served until it quits
+        session.write_all(&[1]).unwrap();
+        session
+            .write_all(format!("filename={}|", file_name).as_bytes())
+            .unwrap();
+        assert_eq!((0, content.to_owned()), read_keep_alive_frame(&mut session));
+        session.write_all(&[5]).unwrap();
+
+        let report = server.join().unwrap();
+        assert_eq!(2, report.total_connections);
+        assert_eq!(0, report.aborted_transfers);
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_ipv4_and_ipv6_listeners() {
+        let file_name = "temp_test_dual_stack_file";
+        let root_dir = "temp_test_dual_stack_root_dir";
+        setup_tmp_file(root_dir, file_name, "both stacks");
+
+        let mut server = FileServer::bind("127.0.0.1:7989", 2, root_dir).unwrap();
+        let v6_addr = server.add_listener(("::1", 7989)).unwrap();
+        assert_eq!(2, server.local_addrs().len());
+        server.register_handlers(&[(
+            CommandType::Download,
+            FileServer::handle_incomming_file_request,
+        )]);
+        server.spawn();
+
+        for addr in ["127.0.0.1:7989".parse().unwrap(), v6_addr] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&[1]).unwrap();
+            stream
+                .write_all(format!("filename={}|", file_name).as_bytes())
+                .unwrap();
+            assert_eq!(
+                (0, "both stacks".to_owned()),
+                read_keep_alive_frame(&mut stream)
+            );
+        }
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reuse_port_acceptors() {
+        // the head threads that answered a ping, named after their acceptor
+        static ANSWERED_ON: Mutex<std::collections::BTreeSet<String>> =
+            Mutex::new(std::collections::BTreeSet::new());
+        fn recording_ping(
+            stream: &dyn Connection,
+            root_dir: &'static str,
+            metrics_registry: Arc<MetricsRegistry>,
+        ) {
+            let name = thread::current().name().unwrap_or_default().to_owned();
+            ANSWERED_ON.lock().unwrap().insert(name);
+            FileServer::handle_ping(stream, root_dir, metrics_registry);
+        }
+        let root_dir = "temp_test_reuse_port_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+
+        let mut server = FileServer::bind("127.0.0.1:8229", 2, root_dir).unwrap();
+        server.listeners[0].1 = ListenerPolicy::read_only();
+        server.set_acceptor_threads(4).unwrap();
+        server.register_handlers(&[(CommandType::Ping, recording_ping)]);
+        assert_eq!(
+            vec!["127.0.0.1:8229".parse::<SocketAddr>().unwrap()],
+            server.local_addrs()
+        );
+        // every socket of the group keeps the main listener's policy
+        assert!(server
+            .listeners
+            .iter()
+            .all(|(_, policy)| *policy == ListenerPolicy::read_only()));
+        let handle = server.spawn();
+
+        let client = crate::FileClient::new("127.0.0.1", "8229");
+        for _ in 0..64 {
+            client.ping().unwrap();
+        }
+        // the kernel spread the connections, and more than one acceptor
+        // read heads and answered
+        let answered_on = ANSWERED_ON.lock().unwrap().clone();
+        assert!(answered_on.len() > 1, "{:?}", answered_on);
+        assert!(answered_on.iter().all(|name| name.starts_with("fs-head-")));
+        // every acceptor thread is woken up and lets go of the port
+        handle.shutdown();
+        handle.join().unwrap();
+        std::net::TcpListener::bind("127.0.0.1:8229").unwrap();
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_reject_policy_when_busy() {
+        let addr = "127.0.0.1";
+        let port = "7979";
+        let file_name = "temp_test_busy_file";
+        let root_dir = "temp_test_busy_root_dir";
+        setup_tmp_file(root_dir, file_name, "not now");
+
+        let mut server = setup_file_server(
+            addr,
+            port,
+            1,
+            &[(
+                CommandType::Download,
+                FileServer::handle_incomming_file_request,
+            )],
+            root_dir,
+        );
+        server.set_busy_policy(BusyPolicy::Reject {
+            retry_after: time::Duration::from_secs(2),
+        });
+        let busy_worker = server.pool.try_acquire(None).unwrap();
+        server.spawn();
+
+        assert_eq!(
+            "server busy, retry after 2 seconds",
+            without_request_id(download_test_file(addr, port, file_name, None))
+        );
+        drop(busy_worker);
+        assert_eq!("not now", download_test_file(addr, port, file_name, None));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_small_downloads_queue_ahead_of_large_ones() {
+        let addr = "127.0.0.1";
+        let port = "8279";
+        let root_dir = "temp_test_small_queue_root_dir";
+        setup_tmp_file(root_dir, "small", "quick");
+        setup_tmp_file(root_dir, "large", &"x".repeat(4096));
+
+        let mut server = setup_file_server(
+            addr,
+            port,
+            1,
+            &[(
+                CommandType::Download,
+                FileServer::handle_incomming_file_request,
+            )],
+            root_dir,
+        );
+        server.set_small_transfer_bytes(Some(1024));
+        let pool = server.pool.clone();
+        let metrics = server.metrics.clone();
+        let busy_worker = pool.try_acquire(None).unwrap();
+        server.spawn();
+
+        let large = thread::spawn(move || download_test_file(addr, port, "large", None));
+        let small = thread::spawn(move || download_test_file(addr, port, "small", None));
+        for class in TransferClass::ALL {
+            assert!(pool.wait_for_waiting(Some(class), 1, time::Duration::from_secs(5)));
+        }
+        drop(busy_worker);
+
+        // the handlers still see the file names read off to sort them
+        assert_eq!("quick", small.join().unwrap());
+        assert_eq!(4096, large.join().unwrap().len());
+        for class in TransferClass::ALL {
+            assert_eq!(1, metrics.class_dispatch_wait(class).snapshot().count);
+        }
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_shed_policy_sends_busy_and_client_comes_back() {
+        let root_dir = "temp_test_shed_root_dir";
+        setup_tmp_file(root_dir, "later", "served on retry");
+
+        let mut server = setup_file_server(
+            "127.0.0.1",
+            "8249",
+            1,
+            &[(
+                CommandType::KeepAlive,
+                FileServer::handle_keep_alive_session,
+            )],
+            root_dir,
+        );
+        server.set_busy_policy(BusyPolicy::Shed { max_queue: 0 });
+        let metrics = server.metrics.clone();
+        let events = server.events();
+        let busy_worker = server.pool.try_acquire(None).unwrap();
+        server.spawn();
+
+        let mut client = crate::FileClient::new("127.0.0.1", "8249");
+        client.set_busy_retries(0);
+        assert!(matches!(
+            client.download("later"),
+            Err(crate::ClientError::Busy(retry_after)) if retry_after == time::Duration::from_secs(1)
+        ));
+
+        // waits the second out and asks again, by then the worker is free
+        client.set_busy_retries(3);
+        let freed = thread::spawn(move || {
+            // frees the worker once the second ask was shed too
+            let shed = events
+                .iter()
+                .filter(|event| match event {
+                    ServerEvent::Error { message, .. } => message.starts_with("server busy"),
+                    _ => false,
+                })
+                .nth(1);
+            assert!(shed.is_some());
+            drop(busy_worker);
+        });
+        assert_eq!(
+            b"served on retry".to_vec(),
+            client.download("later").unwrap()
+        );
+        freed.join().unwrap();
+        assert_eq!(2, metrics.shed_connections.load(Ordering::Relaxed));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_connection_limit_counts_stats_subscribers() {
+        let addr = "127.0.0.1";
+        let port = "7949";
+        let file_name = "temp_test_limit_file";
+        let root_dir = "temp_test_limit_root_dir";
+        setup_tmp_file(root_dir, file_name, "room again");
+
+        let mut server = setup_file_server(
+            addr,
+            port,
+            4,
+            &[
+                (
+                    CommandType::Download,
+                    FileServer::handle_incomming_file_request,
+                ),
+                (CommandType::Statistics, FileServer::no_op_handler),
+            ],
+            root_dir,
+        );
+        server.set_connection_limit(Some(1), OverflowPolicy::Reject);
+        server.start_metrics_report();
+        let limit = server.connection_limit.clone();
+        server.spawn();
+
+        let subscriber = connect_to_metrics_path(addr, port);
+        assert_eq!(
+            "too many connections, try again later",
+            without_request_id(download_test_file(addr, port, file_name, None))
+        );
+
+        // the subscriber's place comes back once the stats tick notices it left
+        drop(subscriber);
+        assert!(limit.wait_for_room(time::Duration::from_secs(5)));
+        assert_eq!(
+            "room again",
+            download_test_file(addr, port, file_name, None)
+        );
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_stats_subscribers_are_capped_and_evicted() {
+        let addr = "127.0.0.1";
+        let port = "7939";
+        let root_dir = "temp_test_stats_subscribers_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+
+        let mut server = setup_file_server(
+            addr,
+            port,
+            4,
+            &[(CommandType::Statistics, FileServer::no_op_handler)],
+            root_dir,
+        );
+        server.set_max_stats_subscribers(Some(1));
+        server.set_stats_heartbeat_timeout(Some(time::Duration::from_millis(1500)));
+        server.start_metrics_report();
+        let metrics = server.metrics.clone();
+        server.spawn();
+
+        // the first report means it is registered
+        let mut silent = connect_to_metrics_path(addr, port);
+        silent
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        silent.peek(&mut [0u8; 1]).unwrap();
+
+        let mut turned_away = connect_to_metrics_path(addr, port);
+        let mut reply = String::new();
+        turned_away.read_to_string(&mut reply).unwrap();
+        assert_eq!(
+            "too many stats subscribers, try again later",
+            without_request_id(reply)
+        );
+
+        // never sends a heartbeat, so the server hangs up on it
+        let mut reports = Vec::new();
+        silent.read_to_end(&mut reports).unwrap();
+        assert_eq!(1, metrics.stats_subscribers_evicted.load(Ordering::Relaxed));
+        assert_eq!(0, metrics.stats_subscribers.load(Ordering::Relaxed));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_stats_ticks_reach_every_sink() {
+        struct ChannelSink(std::sync::mpsc::Sender<StatsSnapshot>);
+        impl StatsSink for ChannelSink {
+            fn describe(&self) -> String {
+                "channel".to_owned()
+            }
+            fn send(&mut self, snapshot: &StatsSnapshot) -> io::Result<()> {
+                self.0
+                    .send(snapshot.clone())
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+        }
+
+        let root_dir = "temp_test_stats_sinks_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+        let mut server = FileServer::new("127.0.0.1", "8269", 1, root_dir).unwrap();
+        server.set_stats_interval(time::Duration::from_millis(10));
+        let (first, first_ticks) = std::sync::mpsc::channel();
+        let (second, second_ticks) = std::sync::mpsc::channel();
+        server.add_stats_sink(Box::new(ChannelSink(first)));
+        server.add_stats_sink(Box::new(ChannelSink(second)));
+        // a sink that fails does not hold the others up
+        drop(first_ticks);
+        server.start_metrics_report();
+
+        let timeout = time::Duration::from_secs(5);
+        for _ in 0..2 {
+            assert_eq!(
+                0,
+                second_ticks
+                    .recv_timeout(timeout)
+                    .unwrap()
+                    .number_of_clients
+            );
+        }
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_download_handler_over_duplex() {
+        let file_name = "temp_test_duplex_file";
+        let root_dir = "temp_test_duplex_root_dir";
+        setup_tmp_file(root_dir, file_name, "no sockets needed");
+
+        let (client, server) = super::super::connection::duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end
+            .write_all(format!("filename={}|", file_name).as_bytes())
+            .unwrap();
+
+        let metrics = Arc::new(MetricsRegistry::new());
+        FileServer::handle_incomming_file_request(&server, root_dir, metrics.clone());
+        drop(server);
+
+        assert_eq!(
+            (0, "no sockets needed".to_owned()),
+            read_keep_alive_frame(&mut client_end)
+        );
+        assert_eq!(1, metrics.download_count(file_name));
+
+        // a missing file is a frame of its own, never mistaken for file content
+        let (client, server) = super::super::connection::duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=missing|").unwrap();
+        FileServer::handle_incomming_file_request(&server, root_dir, metrics.clone());
+        assert_eq!(FRAME_NOT_FOUND, read_keep_alive_frame(&mut client_end).0);
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_stream_file_respects_rate_limit() {
+        let (client, server) = super::super::connection::duplex();
+        let metrics = MetricsRegistry::new();
+        metrics.set_rate_limits(Some(4096), None);
+
+        let started = time::Instant::now();
+        // one second's worth goes out at once, the other 2KB takes half a second
+        let sent =
+            FileServer::stream_file(&mut &[7u8; 6144][..], &server, &metrics, "paced").unwrap();
+        assert_eq!(6144, sent);
+        assert!(started.elapsed() >= time::Duration::from_millis(450));
+        drop(client);
+    }
+
+    #[test]
+    fn test_progress_hook_sees_every_chunk() {
+        let (client, server) = super::super::connection::duplex();
+        let metrics = MetricsRegistry::new();
+        let updates = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = updates.clone();
+        metrics.set_progress_hook(Some(Arc::new(move |update| {
+            assert_eq!("tracked", update.file_name);
+            seen.lock()
+                .unwrap()
+                .push((update.bytes_sent, update.finished));
+        })));
+
+        FileServer::stream_file(&mut &[7u8; 2500][..], &server, &metrics, "tracked").unwrap();
+        assert_eq!(
+            vec![(1024, false), (2048, false), (2500, false), (2500, true)],
+            *updates.lock().unwrap()
+        );
+        drop(client);
+    }
+
+    #[test]
+    fn test_cancelled_transfer_stops_at_next_chunk() {
+        let (client, server) = super::super::connection::duplex();
+        let metrics = Arc::new(MetricsRegistry::new());
+        // cancel from the hook, the way the admin port would mid transfer
+        let registry = Arc::downgrade(&metrics);
+        metrics.set_progress_hook(Some(Arc::new(move |update| {
+            if let Some(metrics) = registry.upgrade() {
+                metrics.cancel_transfer(update.id);
+            }
+        })));
+
+        let err = FileServer::stream_file(&mut &[7u8; 4096][..], &server, &metrics, "killed")
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::ConnectionAborted, err.kind());
+        assert_eq!(1024, metrics.bytes_served.load(Ordering::Relaxed));
+        drop(client);
+    }
+
+    #[test]
+    fn test_read_only_refuses_uploads() {
+        let root_dir = "temp_test_read_only_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+        let metrics = Arc::new(MetricsRegistry::new());
+        metrics.set_read_only(true);
+
+        let (client, server) = super::super::connection::duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=refused|").unwrap();
+        client_end.write_all(&4u64.to_be_bytes()).unwrap();
+        client_end.write_all(b"data").unwrap();
+        FileServer::handle_upload(&server, root_dir, metrics.clone());
+
+        let (status, reason) = read_keep_alive_frame(&mut client_end);
+        assert_eq!((1, "server is read-only"), (status, reason.as_str()));
+        assert!(reader::file_metadata("refused", root_dir).is_err());
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_read_only_listener_refuses_uploads() {
+        let root_dir = "temp_test_read_only_listener_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+        let mut server = setup_file_server(
+            "127.0.0.1",
+            "8149",
+            2,
+            &[(CommandType::Upload, FileServer::handle_upload)],
+            root_dir,
+        );
+        server
+            .add_listener_with_policy("127.0.0.1:8148", ListenerPolicy::read_only())
+            .unwrap();
+        server.spawn();
+
+        let upload = |addr: &str, file_name: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&[2]).unwrap();
+            stream
+                .write_all(format!("filename={}|", file_name).as_bytes())
+                .unwrap();
+            stream.write_all(&4u64.to_be_bytes()).unwrap();
+            stream.write_all(b"data").unwrap();
+            read_keep_alive_frame(&mut stream)
+        };
+
+        let (status, reason) = upload("127.0.0.1:8148", "public");
+        assert_eq!(
+            (1, "server is read-only"),
+            (status, without_request_id(reason).as_str())
+        );
+        assert!(reader::file_metadata("public", root_dir).is_err());
+        assert_eq!(0, upload("127.0.0.1:8149", "internal").0);
+        assert!(reader::file_metadata("internal", root_dir).is_ok());
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_authorizer_denies_by_file_and_peer() {
+        let root_dir = "temp_test_authorizer_root_dir";
+        setup_tmp_file(root_dir, "keys.secret", "hunter2");
+        setup_tmp_file(root_dir, "notes.txt", "public");
+        let mut server = setup_file_server(
+            "127.0.0.1",
+            "8159",
+            2,
+            &[
+                (
+                    CommandType::Download,
+                    FileServer::handle_incomming_file_request,
+                ),
+                (CommandType::Upload, FileServer::handle_upload),
+                (CommandType::List, FileServer::handle_list),
+            ],
+            root_dir,
+        );
+        // only 10.0.0.0/8 may upload, nobody gets *.secret
+        server.set_authorizer(Arc::new(|request| {
+            let internal = matches!(request.peer, Some(std::net::SocketAddr::V4(addr)) if addr.ip().octets()[0] == 10);
+            !request.file_name.ends_with(".secret")
+                && (request.command != CommandType::Upload || internal)
+        }));
+        server.spawn();
+
+        let request = |command: u8, body: &[u8]| {
+            let mut stream = TcpStream::connect("127.0.0.1:8159").unwrap();
+            stream.write_all(&[command]).unwrap();
+            stream.write_all(body).unwrap();
+            let (status, reply) = read_keep_alive_frame(&mut stream);
+            (status, without_request_id(reply))
+        };
+
+        assert_eq!(
+            (1, "Access denied to keys.secret".to_owned()),
+            request(1, b"filename=keys.secret|")
+        );
+        assert_eq!((0, "public".to_owned()), request(1, b"filename=notes.txt|"));
+
+        let mut upload = b"filename=new.txt|".to_vec();
+        upload.extend_from_slice(&4u64.to_be_bytes());
+        upload.extend_from_slice(b"data");
+        assert_eq!(
+            (1, "Access denied to new.txt".to_owned()),
+            request(2, &upload)
+        );
+        assert!(reader::file_metadata("new.txt", root_dir).is_err());
+
+        let (status, listing) = request(7, b"");
+        assert_eq!((0, "notes.txt\t6\n"), (status, listing.as_str()));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_uploads_are_audited() {
+        let root_dir = "temp_test_audit_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+        let metrics = Arc::new(MetricsRegistry::new());
+        let entries = metrics.audit.follow();
+
+        let upload = |file_name: &str| {
+            let (client, server) = super::super::connection::duplex();
+            let mut client_end: &dyn Connection = &client;
+            client_end
+                .write_all(format!("filename={}|", file_name).as_bytes())
+                .unwrap();
+            client_end.write_all(&4u64.to_be_bytes()).unwrap();
+            client_end.write_all(b"data").unwrap();
+            FileServer::handle_upload(&server, root_dir, metrics.clone());
+        };
+        upload("kept");
+        metrics.set_read_only(true);
+        upload("refused");
+
+        let kept = entries.recv().unwrap();
+        assert!(kept.ends_with(&format!(
+            "\tin-memory peer\t{}\tupload\tkept\t4\tok\n",
+            root_dir
+        )));
+        let refused = entries.recv().unwrap();
+        assert!(refused.ends_with("\tupload\trefused\t0\terror: server is read-only\n"));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_durable_upload() {
+        let root_dir = "temp_test_durable_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        let mut server = FileServer::new("127.0.0.1", "0", 1, root_dir).unwrap();
+        server.set_durable_uploads(true);
+        let content = vec![9u8; 300_000];
+
+        let (client, server_end) = super::super::connection::duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=synced|").unwrap();
+        client_end
+            .write_all(&(content.len() as u64).to_be_bytes())
+            .unwrap();
+        client_end.write_all(&content).unwrap();
+        FileServer::handle_upload(&server_end, root_dir, server.metrics.clone());
+
+        assert_eq!((0, String::new()), read_keep_alive_frame(&mut client_end));
+        assert_eq!(content, fs::read(path.join("synced")).unwrap());
+        // no .part left behind
+        assert_eq!(1, fs::read_dir(&path).unwrap().count());
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_upload_hooks_run_after_commit() {
+        let root_dir = "temp_test_upload_hook_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        let mut server = FileServer::new("127.0.0.1", "0", 1, root_dir).unwrap();
+        let (seen_tx, seen) = mpsc::channel();
+        server.on_upload_complete(|_, _| panic!("a broken hook"));
+        server.on_upload_complete(move |path, meta| {
+            let on_disk = fs::read(path).unwrap();
+            seen_tx
+                .send((meta.file_name.to_string(), meta.size, on_disk))
+                .unwrap();
+        });
+
+        let (client, server_end) = super::super::connection::duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=hooked|").unwrap();
+        client_end.write_all(&4u64.to_be_bytes()).unwrap();
+        client_end.write_all(b"data").unwrap();
+        FileServer::handle_upload(&server_end, root_dir, server.metrics.clone());
+
+        // the panicking hook neither failed the upload nor stopped the next one
+        assert_eq!((0, String::new()), read_keep_alive_frame(&mut client_end));
+        assert_eq!(
+            ("hooked".to_string(), 4, b"data".to_vec()),
+            seen.try_recv().unwrap()
+        );
+        assert!(path.join("hooked").exists());
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_encrypted_at_rest() {
+        let root_dir = "temp_test_encrypted_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        let mut server = FileServer::new("127.0.0.1", "0", 1, root_dir).unwrap();
+        assert!(server.set_encryption_key("not a key").is_err());
+        server.set_encryption_key(&"ab".repeat(32)).unwrap();
+        let metrics = server.metrics.clone();
+        let content = "secret plans, ".repeat(10_000);
+
+        let (client, server_end) = super::super::connection::duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=plans|").unwrap();
+        client_end
+            .write_all(&(content.len() as u64).to_be_bytes())
+            .unwrap();
+        client_end.write_all(content.as_bytes()).unwrap();
+        FileServer::handle_upload(&server_end, root_dir, metrics.clone());
+        assert_eq!((0, String::new()), read_keep_alive_frame(&mut client_end));
+
+        let on_disk = fs::read(path.join("plans")).unwrap();
+        assert!(!on_disk.windows(6).any(|window| window == b"secret"));
+
+        client_end.write_all(b"filename=plans|").unwrap();
+        FileServer::handle_incomming_file_request(&server_end, root_dir, metrics.clone());
+        assert_eq!((0, content.clone()), read_keep_alive_frame(&mut client_end));
+
+        FileServer::handle_list(&server_end, root_dir, metrics.clone());
+        assert_eq!(
+            (0, format!("plans\t{}\n", content.len())),
+            read_keep_alive_frame(&mut client_end)
+        );
+
+        // put there behind the server's back, not encrypted
+        fs::write(path.join("plain"), "in the clear").unwrap();
+        client_end.write_all(b"filename=plain|").unwrap();
+        FileServer::handle_incomming_file_request(&server_end, root_dir, metrics);
+        assert_eq!(1, read_keep_alive_frame(&mut client_end).0);
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_build_requires_a_download_handler() {
+        let root_dir = "temp_test_handler_config_root_dir";
+        reader::configure_directory_to_serve_file(root_dir);
+        let builder = || {
+            FileServer::builder()
+                .address("127.0.0.1")
+                .port("8119")
+                .root_dir(root_dir)
+        };
+
+        let error = builder()
+            .handlers(&[(CommandType::Ping, FileServer::handle_ping)])
+            .build()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            error,
+            FileServerError::InvalidHandlerConfig { ref reason } if reason.contains("Download")
+        ));
+
+        // registering twice only warns, the later handler wins
+        let server = builder()
+            .handlers(&[
+                (CommandType::Download, FileServer::no_op_handler),
+                (
+                    CommandType::Download,
+                    FileServer::handle_incomming_file_request,
+                ),
+            ])
+            .build()
+            .unwrap();
+        assert!(server.validate().is_ok());
+        drop(server);
+
+        let custom = builder().router(Router::custom()).build();
+        assert!(matches!(
+            custom,
+            Err(FileServerError::InvalidHandlerConfig { .. })
+        ));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_request_id_is_echoed_and_quoted_in_errors() {
+        let addr = "127.0.0.1";
+        let port = "8109";
+        let root_dir = "temp_test_request_id_root_dir";
+        setup_tmp_file(root_dir, "present", "here");
+
+        let server = setup_file_server(
+            addr,
+            port,
+            2,
+            &[(
+                CommandType::Download,
+                FileServer::handle_incomming_file_request,
+            )],
+            root_dir,
+        );
+        server.spawn();
+
+        let mut stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        stream.write_all(b"\x14\x01filename=missing|").unwrap();
+        let (status, id) = read_keep_alive_frame(&mut stream);
+        assert_eq!(0, status);
+        assert_eq!(16, id.len());
+        let (status, reply) = read_keep_alive_frame(&mut stream);
+        assert_eq!(FRAME_NOT_FOUND, status);
+        assert!(reply.ends_with(&format!(" (request {})", id)));
+
+        // every connection gets its own
+        let mut stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+        stream.write_all(b"\x14\x01filename=present|").unwrap();
+        let (_, other_id) = read_keep_alive_frame(&mut stream);
+        assert_ne!(id, other_id);
+        assert_eq!((0, "here".to_owned()), read_keep_alive_frame(&mut stream));
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_a_silent_client_holds_up_nobody() {
+        let server = TestServer::start();
+        // connected, and then nothing, or a prefix and nothing
+        let silent = TcpStream::connect(server.addr()).unwrap();
+        let mut stalled = TcpStream::connect(server.addr()).unwrap();
+        stalled.write_all(&[u8::from(CommandType::Hello)]).unwrap();
+
+        let started = time::Instant::now();
+        server.add_file("ready", "right away");
+        assert_eq!("right away", server.download("ready"));
+        server.client().ping().unwrap();
+        assert!(started.elapsed() < protocol::HEADER_TIMEOUT);
+        drop((silent, stalled));
+    }
+
+    #[test]
+    fn test_transient_accept_errors_are_retried() {
+        for kind in [
+            io::ErrorKind::ConnectionAborted,
+            io::ErrorKind::ConnectionReset,
+            io::ErrorKind::Interrupted,
+        ] {
+            assert!(FileServer::retry_after_accept_error(io::Error::from(kind)).is_ok());
+        }
+        #[cfg(unix)]
+        for errno in [libc::EMFILE, libc::ENFILE] {
+            let err = io::Error::from_raw_os_error(errno);
+            assert!(FileServer::retry_after_accept_error(err).is_ok());
+        }
+        // anything else stops the accept loop
+        let err = io::Error::from(io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            FileServer::retry_after_accept_error(err),
+            Err(FileServerError::AcceptFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_accounts_jail_users_to_their_home() {
+        let addr = "127.0.0.1";
+        let port = "8099";
+        let root_dir = "temp_test_accounts_server_root_dir";
+        setup_tmp_file(root_dir, "shared", "not for alice");
+        setup_tmp_file(&format!("{}/alice", root_dir), "notes", "alice's notes");
+        setup_tmp_file(&format!("{}/bob", root_dir), "private", "bob's secret");
+
+        let mut server = setup_file_server(
+            addr,
+            port,
+            2,
+            &[(
+                CommandType::Download,
+                FileServer::handle_incomming_file_request,
+            )],
+            root_dir,
+        );
+        server
+            .set_accounts(&[UserAccount {
+                name: "alice".to_owned(),
+                password_sha256: reader::sha256_hex(&b"secret"[..]).unwrap(),
+            }])
+            .unwrap();
+        server.spawn();
+
+        let download_as = |login: &[u8], file_name: &str| {
+            let mut stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+            stream.write_all(login).unwrap();
+            stream.write_all(&[1]).unwrap();
+            stream
+                .write_all(format!("filename={}|", file_name).as_bytes())
+                .unwrap();
+            let (status, reply) = read_keep_alive_frame(&mut stream);
+            (status, without_request_id(reply))
+        };
+        let alice = b"\x13user=alice|password=secret|";
+        assert_eq!((0, "alice's notes".to_owned()), download_as(alice, "notes"));
+        assert_eq!(FRAME_NOT_FOUND, download_as(alice, "shared").0);
+        // no way out of the home, neither into another one nor above it
+        for escape in ["../bob/private", "../shared"] {
+            let (status, reply) = download_as(alice, escape);
+            assert_eq!(1, status, "{}", escape);
+            assert!(reply.contains("invalid file name"), "{}", reply);
+        }
+        assert_eq!(
+            (1, "Login failed for alice".to_owned()),
+            download_as(b"\x13user=alice|password=guess|", "notes")
+        );
+        assert_eq!(
+            (1, FileServerError::LoginRequired.to_string()),
+            download_as(b"", "notes")
+        );
+
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_listener() {
+        use std::os::unix::net::UnixStream;
+
+        let file_name = "temp_test_unix_file";
+        let root_dir = "temp_test_unix_root_dir";
+        let socket_path = std::env::temp_dir().join("fileserver_test.sock");
+        setup_tmp_file(root_dir, file_name, "over a unix socket");
+
+        let mut server = FileServer::bind_unix(&socket_path, 2, root_dir).unwrap();
+        server.register_handlers(&[(
+            CommandType::Download,
+            FileServer::handle_incomming_file_request,
+        )]);
+        let server = server.spawn();
+        assert_eq!(None, server.local_addr());
+
+        let mut stream = UnixStream::connect(&socket_path).unwrap();
+        stream.write_all(&[1]).unwrap();
+        stream
+            .write_all(format!("filename={}|", file_name).as_bytes())
+            .unwrap();
+        assert_eq!(
+            (0, "over a unix socket".to_owned()),
+            read_keep_alive_frame(&mut stream)
+        );
+
+        server.shutdown();
+        let report = server.join().unwrap();
+        assert_eq!(1, report.total_connections);
+
         reader::cleanup_server_file(root_dir);
     }
 }
@@ -0,0 +1,167 @@
+use super::listener::ListenAddr;
+use super::metrics::MetricsRegistry;
+use super::pool::WorkerPool;
+use super::server::FileServerError;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    panic,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+// Cloneable handle that asks a running server to stop accepting connections.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    wake_addr: ListenAddr,
+}
+
+impl ShutdownHandle {
+    pub fn new(requested: Arc<AtomicBool>, listen_addr: SocketAddr) -> ShutdownHandle {
+        Self::for_listener(requested, ListenAddr::Tcp(listen_addr))
+    }
+
+    pub(crate) fn for_listener(
+        requested: Arc<AtomicBool>,
+        wake_addr: ListenAddr,
+    ) -> ShutdownHandle {
+        ShutdownHandle {
+            requested,
+            wake_addr,
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        // the accept loop is blocked in accept(), poke it so it sees the flag
+        self.wake_addr.poke();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+// how often a draining server looks whether it has gone idle
+const DRAIN_POLL_MS: u64 = 50;
+
+// Puts the server in drain mode for a rolling restart: new connections are
+// turned away with a "draining" error while transfers in flight and stats
+// subscribers carry on, and once no transfer is left the server shuts down.
+// Returns false if it was draining already.
+pub(crate) fn start_draining(metrics: &Arc<MetricsRegistry>, shutdown: &ShutdownHandle) -> bool {
+    if !metrics.begin_draining() {
+        return false;
+    }
+    // watchers never finish on their own, they can follow another server
+    metrics.watches.close();
+
+    let metrics = metrics.clone();
+    let shutdown = shutdown.clone();
+    let threads = metrics.threads.clone();
+    threads.spawn("drain", move || {
+        while metrics.active_transfers.load(Ordering::SeqCst) > 0 {
+            thread::sleep(time::Duration::from_millis(DRAIN_POLL_MS));
+        }
+        println!("Drained, shutting down .....");
+        shutdown.shutdown();
+    });
+    true
+}
+
+// A server whose accept loop runs on a thread of its own, see
+// FileServer::spawn. Dropping the handle leaves the server running.
+pub struct ServerHandle {
+    accept_loop: thread::JoinHandle<Result<ShutdownReport, FileServerError>>,
+    shutdown: ShutdownHandle,
+    local_addr: Option<SocketAddr>,
+    metrics: Arc<MetricsRegistry>,
+    pool: Arc<WorkerPool>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        accept_loop: thread::JoinHandle<Result<ShutdownReport, FileServerError>>,
+        shutdown: ShutdownHandle,
+        local_addr: Option<SocketAddr>,
+        metrics: Arc<MetricsRegistry>,
+        pool: Arc<WorkerPool>,
+    ) -> ServerHandle {
+        ServerHandle {
+            accept_loop,
+            shutdown,
+            local_addr,
+            metrics,
+            pool,
+        }
+    }
+
+    // Address of the first TCP listener, with the port the OS picked when
+    // bound to port 0. None when the server only listens on a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown();
+    }
+
+    // Stops taking new connections and shuts down once in-flight transfers
+    // are done, join to wait for it. Same as the admin port's `drain`.
+    pub fn drain(&self) {
+        start_draining(&self.metrics, &self.shutdown);
+    }
+
+    // Waits up to `timeout` for `count` workers to be busy at once, so a
+    // test can know its connections were picked up without sleeping.
+    pub fn wait_for_busy_workers(&self, count: i32, timeout: time::Duration) -> bool {
+        self.pool.wait_for_busy(count, timeout)
+    }
+
+    // Waits up to `timeout` for `count` downloads to have begun sending, see
+    // MetricsRegistry::wait_for_transfers.
+    pub fn wait_for_transfers(&self, count: u64, timeout: time::Duration) -> bool {
+        self.metrics.wait_for_transfers(count, timeout)
+    }
+
+    // For stopping the server from somewhere the handle is not.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Waits for the accept loop to stop and the server to drain.
+    pub fn join(self) -> Result<ShutdownReport, FileServerError> {
+        self.accept_loop
+            .join()
+            .unwrap_or_else(|panic| panic::resume_unwind(panic))
+    }
+}
+
+// Summary of a server run, produced once the accept loop has stopped.
+#[derive(Debug, Clone)]
+pub struct ShutdownReport {
+    pub uptime: time::Duration,
+    pub total_connections: u64,
+    pub bytes_served: u64,
+    pub aborted_transfers: u64,
+    pub final_metrics: HashMap<String, i64>,
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "uptime: {:?}, connections: {}, bytes served: {}, aborted transfers: {}, downloads per file: {:?}",
+            self.uptime,
+            self.total_connections,
+            self.bytes_served,
+            self.aborted_transfers,
+            self.final_metrics
+        )
+    }
+}
@@ -0,0 +1,157 @@
+use crate::protocol::stats::{StatsFormat, StatsSnapshot};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+// Somewhere every stats tick goes. The TCP subscribers are one sink, the
+// ones below write ticks out locally. A sink that fails is asked again on the
+// next tick, it is up to the sink to give up on something for good.
+pub trait StatsSink: Send {
+    // what the sink writes to, for logs
+    fn describe(&self) -> String;
+    fn send(&mut self, snapshot: &StatsSnapshot) -> io::Result<()>;
+}
+
+// Appends every tick to a file, created if needed. Opened for each tick so
+// the file can be rotated or removed under it.
+pub struct FileSink {
+    path: PathBuf,
+    format: StatsFormat,
+}
+
+impl FileSink {
+    pub fn new(path: impl Into<PathBuf>, format: StatsFormat) -> FileSink {
+        FileSink {
+            path: path.into(),
+            format,
+        }
+    }
+}
+
+impl StatsSink for FileSink {
+    fn describe(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn send(&mut self, snapshot: &StatsSnapshot) -> io::Result<()> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&snapshot.encode(self.format))
+    }
+}
+
+// Writes every tick to whoever listens on a Unix socket. Connects on the
+// first tick and again on the tick after the connection broke.
+#[cfg(unix)]
+pub struct UnixSocketSink {
+    path: PathBuf,
+    format: StatsFormat,
+    stream: Option<UnixStream>,
+}
+
+#[cfg(unix)]
+impl UnixSocketSink {
+    pub fn new(path: impl Into<PathBuf>, format: StatsFormat) -> UnixSocketSink {
+        UnixSocketSink {
+            path: path.into(),
+            format,
+            stream: None,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl StatsSink for UnixSocketSink {
+    fn describe(&self) -> String {
+        format!("unix socket {}", self.path.display())
+    }
+
+    fn send(&mut self, snapshot: &StatsSnapshot) -> io::Result<()> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(UnixStream::connect(&self.path)?),
+        };
+        let written = stream.write_all(&snapshot.encode(self.format));
+        if written.is_err() {
+            self.stream = None;
+        }
+        written
+    }
+}
+
+// Prints every tick, for running under a supervisor that collects stdout.
+pub struct StdoutSink {
+    format: StatsFormat,
+}
+
+impl StdoutSink {
+    pub fn new(format: StatsFormat) -> StdoutSink {
+        StdoutSink { format }
+    }
+}
+
+impl StatsSink for StdoutSink {
+    fn describe(&self) -> String {
+        "stdout".to_owned()
+    }
+
+    fn send(&mut self, snapshot: &StatsSnapshot) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&snapshot.encode(self.format))?;
+        stdout.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_file_sink_appends_a_line_per_tick() {
+        let path = "temp_test_stats_sink.jsonl";
+        let _ = fs::remove_file(path);
+        let mut sink = FileSink::new(path, StatsFormat::Json);
+        let snapshot = StatsSnapshot {
+            number_of_clients: 3,
+            ..Default::default()
+        };
+        sink.send(&snapshot).unwrap();
+        sink.send(&snapshot).unwrap();
+
+        let written = fs::read_to_string(path).unwrap();
+        assert_eq!(2, written.lines().count());
+        assert!(written
+            .lines()
+            .all(|line| line == snapshot.encode_json().trim_end()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_sink_reconnects() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::net::UnixListener;
+
+        let path = "temp_test_stats_sink.sock";
+        let _ = fs::remove_file(path);
+        let mut sink = UnixSocketSink::new(path, StatsFormat::Json);
+        let snapshot = StatsSnapshot::default();
+        // nobody listening yet
+        assert!(sink.send(&snapshot).is_err());
+
+        let listener = UnixListener::bind(path).unwrap();
+        sink.send(&snapshot).unwrap();
+        let (reader, _) = listener.accept().unwrap();
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).unwrap();
+        assert_eq!(snapshot.encode_json(), line);
+        fs::remove_file(path).unwrap();
+    }
+}
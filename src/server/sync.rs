@@ -0,0 +1,155 @@
+use super::connection::Connection;
+use super::keep_alive::{write_error_frame, write_frame_header, FRAME_END};
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use super::types::{CommandType, ManifestEntry};
+use crate::protocol::{self, Limit};
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read},
+    sync::Arc,
+};
+
+impl FileServer {
+    // Request: [manifest length: u64 big endian][manifest], one
+    // `name\tsize\tsha256\n` line per file the client already has.
+    // Reply: like BatchDownload, one OK frame per served file the client is
+    // missing or has a different copy of, then an empty END frame. Files the
+    // client has and the server does not are left alone.
+    pub fn handle_sync(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_sync(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_sync(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let mut length = [0u8; 8];
+        stream.read_exact(&mut length)?;
+        let length = u64::from_be_bytes(length);
+        if let Err(err) = metrics_registry.limits().check(Limit::Manifest, length) {
+            // not worth reading that much just to stay in sync, drop the connection
+            let _ = write_error_frame(stream, err.to_string());
+            return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+        }
+        metrics_registry.record_request_body(length);
+        let mut manifest = vec![0; length as usize];
+        stream.read_exact(&mut manifest)?;
+        let manifest = match protocol::parse_manifest(&manifest) {
+            Ok(manifest) => manifest,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        let files = match Self::list_stored_files(root_dir, metrics_registry) {
+            Ok(files) => files,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+
+        let manifest: HashMap<String, ManifestEntry> = manifest
+            .into_iter()
+            .map(|entry| (entry.name.clone(), entry))
+            .collect();
+        for (file_name, size) in files {
+            let size = metrics_registry.served_len(size);
+            if Self::is_current(
+                manifest.get(&file_name),
+                &file_name,
+                size,
+                root_dir,
+                metrics_registry,
+            ) || metrics_registry
+                .authorize(stream, CommandType::Sync, &file_name)
+                .is_err()
+            {
+                continue;
+            }
+            Self::send_batch_entry(stream, &file_name, root_dir, metrics_registry)?;
+        }
+        write_frame_header(stream, FRAME_END, 0)
+    }
+
+    // Whether the client's copy matches the served file. Sizes are compared
+    // first so only files that could be the same get hashed, at most once
+    // per version, see ETagCache.
+    fn is_current(
+        entry: Option<&ManifestEntry>,
+        file_name: &str,
+        size: u64,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> bool {
+        let Some(entry) = entry.filter(|entry| entry.size == size) else {
+            return false;
+        };
+        Self::served_etag(file_name, root_dir, metrics_registry)
+            .is_ok_and(|etag| etag == entry.sha256)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::super::keep_alive::FRAME_OK;
+    use super::*;
+    use crate::reader;
+    use std::{fs, io::Write};
+
+    #[test]
+    fn test_sync_sends_only_what_differs() {
+        let root_dir = "temp_test_sync_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("same"), b"unchanged").unwrap();
+        fs::write(path.join("edited"), b"new text").unwrap();
+        fs::write(path.join("missing"), b"only here").unwrap();
+        let metrics = MetricsRegistry::new();
+
+        let manifest: String = [
+            ManifestEntry {
+                name: "same".to_owned(),
+                size: 9,
+                sha256: reader::sha256_hex(&b"unchanged"[..]).unwrap(),
+            },
+            ManifestEntry {
+                name: "edited".to_owned(),
+                size: 8,
+                sha256: reader::sha256_hex(&b"old text"[..]).unwrap(),
+            },
+            ManifestEntry {
+                name: "only_on_client".to_owned(),
+                size: 1,
+                sha256: "00".to_owned(),
+            },
+        ]
+        .iter()
+        .map(ManifestEntry::encode_line)
+        .collect();
+
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end
+            .write_all(&(manifest.len() as u64).to_be_bytes())
+            .unwrap();
+        client_end.write_all(manifest.as_bytes()).unwrap();
+        FileServer::framed_sync(&server, root_dir, &metrics).unwrap();
+        drop(server);
+
+        let mut reply = Vec::new();
+        client_end.read_to_end(&mut reply).unwrap();
+        let mut names = Vec::new();
+        let mut rest = &reply[..];
+        while rest[0] == FRAME_OK {
+            let length = u64::from_be_bytes(rest[1..9].try_into().unwrap()) as usize;
+            let name_length = u16::from_be_bytes(rest[9..11].try_into().unwrap()) as usize;
+            names.push(String::from_utf8(rest[11..11 + name_length].to_vec()).unwrap());
+            rest = &rest[9 + length..];
+        }
+        assert_eq!(FRAME_END, rest[0]);
+        assert_eq!(vec!["edited", "missing"], names);
+
+        reader::cleanup_server_file(root_dir);
+    }
+}
@@ -0,0 +1,236 @@
+use super::connection::Connection;
+use super::keep_alive::{write_error_frame, write_frame_header, write_open_error_frame, FRAME_OK};
+use super::metrics::MetricsRegistry;
+use super::request_id::log_prefix;
+use super::server::FileServer;
+use super::types::{ChangeKind, CommandType};
+use crate::protocol;
+use crate::reader::open_growing_file;
+use std::{
+    fs::File,
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    sync::{mpsc::RecvTimeoutError, Arc},
+    time,
+};
+
+// how long a quiet tail waits before looking at the file and its client again,
+// filesystem events can be coalesced or missed on some platforms
+const TAIL_POLL_MS: u64 = 500;
+
+// biggest frame of appended bytes, a burst of writes goes out as several
+const TAIL_CHUNK_BYTES: u64 = 64 * 1024;
+
+// The file a Tail follows and how far into it the client has been sent.
+struct Followed {
+    file: Option<File>,
+    offset: u64,
+}
+
+impl FileServer {
+    // Request: filename=a_file_name|tail=<bytes>|
+    // Reply: an OK frame with the last `bytes` bytes of the file (all of it
+    // when shorter), then an OK frame per batch of appended bytes for as long
+    // as the client stays connected. A file that is truncated, or replaced by
+    // an upload or a log rotation, is followed again from its start.
+    pub fn handle_tail(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_tail(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_tail(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let request =
+            Self::read_file_request(stream, &metrics_registry.limits()).and_then(|file_name| {
+                let segment = Self::read_request_segment(stream, &metrics_registry.limits())?;
+                Ok((file_name, segment))
+            });
+        let (file_name, segment) = match request {
+            Ok(request) => request,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        let last_bytes = match protocol::parse_tail(&segment) {
+            Ok(last_bytes) => last_bytes,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::Tail, &file_name) {
+            return write_error_frame(stream, err.to_string());
+        }
+        // appended bytes of an encrypted file are chunks of ciphertext
+        if metrics_registry.at_rest_key().is_some() {
+            return write_error_frame(
+                stream,
+                "tail is not available while files are encrypted at rest".to_owned(),
+            );
+        }
+        // nothing in it ever grows, and the root dir is not what is served
+        if metrics_registry.embedded_archive().is_some() {
+            return write_error_frame(
+                stream,
+                "tail is not available while serving an embedded archive".to_owned(),
+            );
+        }
+
+        // subscribed before the file is read, an append in between still wakes us
+        let events = match metrics_registry.watches.subscribe(root_dir, &file_name) {
+            Ok(events) => events,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        let opened = metrics_registry
+            .serve_policy()
+            .check(&file_name)
+            .and_then(|_| open_growing_file(&file_name, root_dir));
+        let mut followed = match opened {
+            Ok(file) => Followed {
+                file: Some(file),
+                offset: 0,
+            },
+            Err(err) => return write_open_error_frame(stream, &file_name, err),
+        };
+        let len = followed.len()?;
+        followed.offset = len.saturating_sub(last_bytes);
+        let sent = followed.send_appended(stream, u64::MAX)?;
+        if sent == 0 {
+            // the first frame is there even for an empty file
+            write_frame_header(stream, FRAME_OK, 0)?;
+        }
+        println!(
+            "{}{} is following {}",
+            log_prefix(stream),
+            stream.peer(),
+            file_name
+        );
+
+        loop {
+            match events.recv_timeout(time::Duration::from_millis(TAIL_POLL_MS)) {
+                // the pattern is the file name, which a `*` in it would widen
+                Ok(event) if event.file_name != file_name => continue,
+                Ok(event) => match event.kind {
+                    ChangeKind::Created => {
+                        followed.file = open_growing_file(&file_name, root_dir).ok();
+                        followed.offset = 0;
+                    }
+                    // the name may come back, the handle of the old file is kept
+                    // until it does
+                    ChangeKind::Deleted => continue,
+                    ChangeKind::Modified => {}
+                },
+                Err(RecvTimeoutError::Timeout) if Self::hung_up(stream) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => {}
+                // the hub was closed, the server is shutting down
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+            followed.send_appended(stream, TAIL_CHUNK_BYTES)?;
+        }
+    }
+}
+
+impl Followed {
+    fn len(&self) -> io::Result<u64> {
+        match &self.file {
+            Some(file) => Ok(file.metadata()?.len()),
+            None => Ok(0),
+        }
+    }
+
+    // Sends everything past `offset` in frames of at most `chunk` bytes.
+    fn send_appended(&mut self, mut stream: &dyn Connection, chunk: u64) -> io::Result<u64> {
+        let len = self.len()?;
+        if len < self.offset {
+            // truncated in place, start over like tail does
+            self.offset = 0;
+        }
+        let Some(file) = &mut self.file else {
+            return Ok(0);
+        };
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut sent = 0;
+        while self.offset < len {
+            let length = (len - self.offset).min(chunk);
+            let mut bytes = Vec::with_capacity(length as usize);
+            Read::by_ref(file).take(length).read_to_end(&mut bytes)?;
+            if bytes.is_empty() {
+                break;
+            }
+            write_frame_header(stream, FRAME_OK, bytes.len() as u64)?;
+            stream.write_all(&bytes)?;
+            self.offset += bytes.len() as u64;
+            sent += bytes.len() as u64;
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::*;
+    use crate::reader;
+    use std::{fs, fs::OpenOptions, thread};
+
+    fn read_chunk(client: &dyn Connection) -> (u8, Vec<u8>) {
+        let mut client = client;
+        let mut header = [0u8; 9];
+        client.read_exact(&mut header).unwrap();
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&header[1..]);
+        let mut payload = vec![0; u64::from_be_bytes(length) as usize];
+        client.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+
+    #[test]
+    fn test_tail_sends_the_end_then_follows_appends() {
+        let root_dir = "temp_test_tail_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("app.log"), b"first line\nsecond line\n").unwrap();
+        let metrics = Arc::new(MetricsRegistry::new());
+
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=app.log|tail=12|").unwrap();
+        let following = metrics.clone();
+        let handler = thread::spawn(move || FileServer::framed_tail(&server, root_dir, &following));
+        assert_eq!((FRAME_OK, b"second line\n".to_vec()), read_chunk(&client));
+
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(path.join("app.log"))
+            .unwrap();
+        log.write_all(b"third line\n").unwrap();
+        assert_eq!((FRAME_OK, b"third line\n".to_vec()), read_chunk(&client));
+
+        // rotated, the new file is followed from its start
+        fs::rename(path.join("app.log"), path.join("app.log.1")).unwrap();
+        fs::write(path.join("app.log"), b"fresh\n").unwrap();
+        let mut received = Vec::new();
+        while received.len() < b"fresh\n".len() {
+            let (status, payload) = read_chunk(&client);
+            assert_eq!(FRAME_OK, status);
+            received.extend(payload);
+        }
+        assert_eq!(b"fresh\n".to_vec(), received);
+
+        metrics.watches.close();
+        handler.join().unwrap().unwrap();
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_tail_of_a_missing_file_is_an_error() {
+        let metrics = MetricsRegistry::new();
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=nope.log|tail=10|").unwrap();
+        FileServer::framed_tail(&server, "temp_test_tail_missing_root_dir", &metrics).unwrap();
+        assert_ne!(FRAME_OK, read_chunk(&client).0);
+    }
+}
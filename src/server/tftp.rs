@@ -0,0 +1,433 @@
+use super::listener;
+use super::metrics::MetricsRegistry;
+use super::pool::WorkerPool;
+use super::request_id::RequestId;
+use super::serve_policy::is_denial;
+use super::server::{FileServer, FileServerError};
+use super::types::CommandType;
+use crate::reader::validate_file_name;
+use std::{
+    io::{self, ErrorKind, Read},
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+    time,
+};
+
+// Read-only TFTP (RFC 1350) next to the TCP protocol, for PXE boot roms and
+// network gear that only speak TFTP. Files come from the same root dir and
+// go through the same hot cache, metrics and worker pool as downloads.
+// The blksize (RFC 2348) and tsize (RFC 2349) options are supported, netascii
+// transfers are sent unchanged like octet ones.
+
+const OP_RRQ: u16 = 1;
+const OP_WRQ: u16 = 2;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERR_UNDEFINED: u16 = 0;
+const ERR_NOT_FOUND: u16 = 1;
+const ERR_ACCESS: u16 = 2;
+const ERR_ILLEGAL_OP: u16 = 4;
+const ERR_BAD_OPTION: u16 = 8;
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+const MAX_BLOCK_SIZE: usize = 65464;
+const ACK_TIMEOUT_MS: u64 = 1000;
+const MAX_RETRIES: u32 = 5;
+
+// A read request after option negotiation.
+#[derive(Debug, PartialEq)]
+struct ReadRequest {
+    file_name: String,
+    block_size: usize,
+    // whether to send an OACK first, and with which options
+    blksize_requested: bool,
+    tsize_requested: bool,
+}
+
+impl FileServer {
+    // Answers TFTP read requests on udp://address:port on a background thread,
+    // each transfer then runs on its own socket and thread like TFTP wants.
+    pub fn start_tftp(&self, address: &str, port: &str) -> Result<(), FileServerError> {
+        let port: u16 = port.parse().map_err(|_| FileServerError::InvalidPort {
+            port: port.to_owned(),
+        })?;
+        let socket = listener::resolve(address, port)
+            .and_then(|addrs| UdpSocket::bind(&addrs[..]))
+            .map_err(|source| FileServerError::Bind {
+                address: format!("{}:{}", address, port),
+                source,
+            })?;
+
+        let root_dir = self.root_dir;
+        let metrics = self.metrics.clone();
+        let pool = self.pool.clone();
+        self.metrics.threads.spawn("tftp", move || {
+            let mut packet = [0u8; 1024];
+            loop {
+                let (read, peer) = match socket.recv_from(&mut packet) {
+                    Ok(received) => received,
+                    Err(err) => {
+                        println!("...TFTP listener stopped: {err}");
+                        return;
+                    }
+                };
+                let local_ip = match socket.local_addr() {
+                    Ok(addr) => addr.ip(),
+                    Err(_) => continue,
+                };
+                let request = packet[..read].to_vec();
+                let metrics = metrics.clone();
+                let pool = pool.clone();
+                let threads = metrics.threads.clone();
+                threads.spawn_worker(None, move || {
+                    serve_request(&request, local_ip, peer, root_dir, &metrics, &pool);
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+fn serve_request(
+    request: &[u8],
+    local_ip: std::net::IpAddr,
+    peer: SocketAddr,
+    root_dir: &'static str,
+    metrics: &MetricsRegistry,
+    pool: &Arc<WorkerPool>,
+) {
+    // every transfer gets its own port, the transfer id in TFTP terms
+    let socket = match UdpSocket::bind((local_ip, 0)).and_then(|s| s.connect(peer).map(|_| s)) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("...TFTP could not open a transfer socket for {peer}: {err}");
+            return;
+        }
+    };
+
+    let request = match parse_request(request) {
+        Ok(request) => request,
+        Err((code, message)) => return send_error(&socket, code, &message),
+    };
+    if let Err(err) = validate_file_name(&request.file_name) {
+        return send_error(&socket, ERR_ACCESS, &err.to_string());
+    }
+    if let Err(err) = metrics.authorize_peer(peer, CommandType::Download, &request.file_name) {
+        return send_error(&socket, ERR_ACCESS, &err.to_string());
+    }
+    if metrics.bandwidth.is_capped(peer.ip()) {
+        return send_error(&socket, ERR_UNDEFINED, "daily transfer cap reached");
+    }
+    let _slot = match pool.try_acquire(Some(CommandType::Download)) {
+        Some(slot) => slot,
+        None => return send_error(&socket, ERR_UNDEFINED, "server busy, try again later"),
+    };
+    let mut file_reader = match FileServer::open_served_file(&request.file_name, root_dir, metrics)
+    {
+        Ok(file_reader) => file_reader,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return send_error(&socket, ERR_NOT_FOUND, "file not found")
+        }
+        Err(err) if is_denial(&err) => return send_error(&socket, ERR_ACCESS, &err.to_string()),
+        Err(err) => return send_error(&socket, ERR_UNDEFINED, &err.to_string()),
+    };
+
+    let request_id = RequestId::next();
+    println!(
+        "[{}] TFTP read of {} by {}",
+        request_id, request.file_name, peer
+    );
+    metrics.record_download(request.file_name.clone());
+    metrics.transfer_started();
+    let transfer = metrics.begin_transfer(&request.file_name, &peer.to_string(), Some(request_id));
+    let throttle = metrics.throttle();
+    let result = send_file(&socket, &request, &mut file_reader, |bytes| {
+        transfer.record_bytes_sent(bytes);
+        if transfer.is_cancelled() {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                format!("transfer {} was cancelled", transfer.id()),
+            ));
+        }
+        if transfer.is_over_daily_cap() {
+            return Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "daily transfer cap reached",
+            ));
+        }
+        throttle.pace(bytes);
+        Ok(())
+    });
+    match result {
+        Ok(_) => transfer.finish(),
+        Err(_) => drop(transfer),
+    }
+    metrics.transfer_finished();
+    if let Err(err) = result {
+        println!(
+            "[{}] ...TFTP transfer of {} failed: {err}",
+            request_id, request.file_name
+        );
+    }
+}
+
+fn send_file(
+    socket: &UdpSocket,
+    request: &ReadRequest,
+    file_reader: &mut crate::reader::FileSource,
+    record_bytes_sent: impl Fn(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    socket.set_read_timeout(Some(time::Duration::from_millis(ACK_TIMEOUT_MS)))?;
+
+    if request.blksize_requested || request.tsize_requested {
+        let mut oack = OP_OACK.to_be_bytes().to_vec();
+        if request.blksize_requested {
+            push_option(&mut oack, "blksize", &request.block_size.to_string());
+        }
+        if request.tsize_requested {
+            push_option(&mut oack, "tsize", &file_reader.len().to_string());
+        }
+        send_and_wait_for_ack(socket, &oack, 0)?;
+    }
+
+    let mut block: u16 = 1;
+    let mut data = vec![0u8; request.block_size];
+    loop {
+        let read = read_block(file_reader, &mut data)?;
+        let mut packet = OP_DATA.to_be_bytes().to_vec();
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(&data[..read]);
+        send_and_wait_for_ack(socket, &packet, block)?;
+        record_bytes_sent(read as u64)?;
+
+        // a short block, possibly empty, tells the client it has everything
+        if read < request.block_size {
+            return Ok(());
+        }
+        block = block.wrapping_add(1);
+    }
+}
+
+// Fills `data` unless the file ends first, returns how much was read.
+fn read_block(file_reader: &mut impl Read, data: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < data.len() {
+        match file_reader.read(&mut data[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+// Sends `packet` until the client acknowledges `block`. Duplicate acks of
+// earlier blocks are ignored rather than answered, answering them is how the
+// Sorcerer's Apprentice bug doubles every packet.
+fn send_and_wait_for_ack(socket: &UdpSocket, packet: &[u8], block: u16) -> io::Result<()> {
+    let mut reply = [0u8; 516];
+    for _ in 0..MAX_RETRIES {
+        socket.send(packet)?;
+        let deadline = time::Instant::now() + time::Duration::from_millis(ACK_TIMEOUT_MS);
+        while time::Instant::now() < deadline {
+            let read = match socket.recv(&mut reply) {
+                Ok(read) => read,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    break
+                }
+                Err(err) => return Err(err),
+            };
+            if read < 4 {
+                continue;
+            }
+            match u16::from_be_bytes([reply[0], reply[1]]) {
+                OP_ACK if u16::from_be_bytes([reply[2], reply[3]]) == block => return Ok(()),
+                OP_ERROR => {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        format!(
+                            "client gave up: {}",
+                            String::from_utf8_lossy(&reply[4..read]).trim_end_matches('\0')
+                        ),
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+    Err(io::Error::new(
+        ErrorKind::TimedOut,
+        format!("no ack for block {}", block),
+    ))
+}
+
+fn send_error(socket: &UdpSocket, code: u16, message: &str) {
+    println!("...TFTP error {code} reported to client: {message}");
+    let mut packet = OP_ERROR.to_be_bytes().to_vec();
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    let _ = socket.send(&packet);
+}
+
+fn push_option(packet: &mut Vec<u8>, name: &str, value: &str) {
+    packet.extend_from_slice(name.as_bytes());
+    packet.push(0);
+    packet.extend_from_slice(value.as_bytes());
+    packet.push(0);
+}
+
+// RRQ: [1][file name]\0[mode]\0 followed by optional [option]\0[value]\0 pairs.
+// Errors come back as the TFTP error code and message to send.
+fn parse_request(packet: &[u8]) -> Result<ReadRequest, (u16, String)> {
+    if packet.len() < 2 {
+        return Err((ERR_ILLEGAL_OP, "truncated request".to_owned()));
+    }
+    match u16::from_be_bytes([packet[0], packet[1]]) {
+        OP_RRQ => {}
+        OP_WRQ => return Err((ERR_ACCESS, "uploads are not accepted over TFTP".to_owned())),
+        other => return Err((ERR_ILLEGAL_OP, format!("unexpected opcode {}", other))),
+    }
+
+    let mut fields = packet[2..]
+        .split(|byte| *byte == 0)
+        .map(|field| String::from_utf8_lossy(field).to_string());
+    let file_name = fields.next().unwrap_or_default();
+    let mode = fields.next().unwrap_or_default().to_ascii_lowercase();
+    if file_name.is_empty() || !packet.ends_with(&[0]) {
+        return Err((ERR_ILLEGAL_OP, "malformed request".to_owned()));
+    }
+    if mode != "octet" && mode != "netascii" {
+        return Err((ERR_ILLEGAL_OP, format!("unsupported mode {:?}", mode)));
+    }
+
+    let mut request = ReadRequest {
+        file_name,
+        block_size: DEFAULT_BLOCK_SIZE,
+        blksize_requested: false,
+        tsize_requested: false,
+    };
+    // unknown options are skipped, the OACK only lists the ones we took
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        match name.to_ascii_lowercase().as_str() {
+            "blksize" => {
+                let size: usize = value
+                    .parse()
+                    .map_err(|_| (ERR_BAD_OPTION, format!("bad blksize {:?}", value)))?;
+                if size < 8 {
+                    return Err((ERR_BAD_OPTION, format!("blksize {} is too small", size)));
+                }
+                request.block_size = size.min(MAX_BLOCK_SIZE);
+                request.blksize_requested = true;
+            }
+            "tsize" => request.tsize_requested = true,
+            _ => {}
+        }
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::{cleanup_server_file, configure_directory_to_serve_file};
+    use std::fs;
+
+    fn rrq(file_name: &str, options: &[(&str, &str)]) -> Vec<u8> {
+        let mut packet = OP_RRQ.to_be_bytes().to_vec();
+        push_option(&mut packet, file_name, "octet");
+        for (name, value) in options {
+            push_option(&mut packet, name, value);
+        }
+        packet
+    }
+
+    #[test]
+    fn test_parse_request_options() {
+        let request =
+            parse_request(&rrq("boot.img", &[("blksize", "1428"), ("tsize", "0")])).unwrap();
+        assert_eq!("boot.img", request.file_name);
+        assert_eq!(1428, request.block_size);
+        assert!(request.blksize_requested && request.tsize_requested);
+
+        let mut wrq = rrq("boot.img", &[]);
+        wrq[1] = OP_WRQ as u8;
+        assert_eq!(ERR_ACCESS, parse_request(&wrq).unwrap_err().0);
+        assert!(parse_request(&[0, 1, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_tftp_read() {
+        let root_dir = "temp_test_tftp_root_dir";
+        let path = configure_directory_to_serve_file(root_dir);
+        // exactly two blocks, so the transfer ends with an empty one
+        let content: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        fs::write(path.join("boot.img"), &content).unwrap();
+
+        let server = FileServer::new("127.0.0.1", "7969", 2, root_dir).unwrap();
+        server.start_tftp("127.0.0.1", "7968").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        client
+            .send_to(&rrq("boot.img", &[]), "127.0.0.1:7968")
+            .unwrap();
+
+        let mut received = Vec::new();
+        let mut packet = [0u8; 516];
+        loop {
+            let (read, transfer_addr) = client.recv_from(&mut packet).unwrap();
+            assert_eq!(OP_DATA, u16::from_be_bytes([packet[0], packet[1]]));
+            received.extend_from_slice(&packet[4..read]);
+            let mut ack = OP_ACK.to_be_bytes().to_vec();
+            ack.extend_from_slice(&packet[2..4]);
+            client.send_to(&ack, transfer_addr).unwrap();
+            if read - 4 < DEFAULT_BLOCK_SIZE {
+                break;
+            }
+        }
+        assert_eq!(content, received);
+
+        client
+            .send_to(&rrq("missing", &[]), "127.0.0.1:7968")
+            .unwrap();
+        let (read, _) = client.recv_from(&mut packet).unwrap();
+        assert_eq!(OP_ERROR, u16::from_be_bytes([packet[0], packet[1]]));
+        assert_eq!(ERR_NOT_FOUND, u16::from_be_bytes([packet[2], packet[3]]));
+        assert!(read > 4);
+
+        cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_authorizer_denies_tftp_reads() {
+        let root_dir = "temp_test_tftp_authorizer_root_dir";
+        let path = configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("keys.secret"), b"hidden").unwrap();
+
+        let mut server = FileServer::new("127.0.0.1", "7967", 2, root_dir).unwrap();
+        server.set_authorizer(Arc::new(|request| {
+            request.peer.is_some() && !request.file_name.ends_with(".secret")
+        }));
+        server.start_tftp("127.0.0.1", "7966").unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        client
+            .send_to(&rrq("keys.secret", &[]), "127.0.0.1:7966")
+            .unwrap();
+        let mut packet = [0u8; 516];
+        let (read, _) = client.recv_from(&mut packet).unwrap();
+        assert_eq!(OP_ERROR, u16::from_be_bytes([packet[0], packet[1]]));
+        assert_eq!(ERR_ACCESS, u16::from_be_bytes([packet[2], packet[3]]));
+        assert!(String::from_utf8_lossy(&packet[4..read]).contains("Access denied"));
+
+        cleanup_server_file(root_dir);
+    }
+}
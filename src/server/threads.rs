@@ -0,0 +1,285 @@
+use super::request_id::RequestId;
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    panic,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Once, RwLock,
+    },
+    thread, time,
+};
+
+// what thread names start with until set_prefix says otherwise
+pub const DEFAULT_THREAD_PREFIX: &str = "fs";
+
+thread_local! {
+    // the request the current thread works on, for the panic hook
+    static SERVING: Cell<Option<RequestId>> = const { Cell::new(None) };
+}
+
+// Marks the current thread as working on `id` until dropped, when whatever
+// it worked on before is put back.
+pub(crate) struct Serving(Option<RequestId>);
+
+impl Serving {
+    pub(crate) fn start(id: Option<RequestId>) -> Serving {
+        Serving(SERVING.replace(id))
+    }
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        SERVING.set(self.0);
+    }
+}
+
+// Names the threads a server starts, `<prefix>-worker-3` or `<prefix>-stats`
+// say, and keeps track of the ones still running for the admin port. Worker
+// numbers are reused once their thread ends, so they stay below the number of
+// connections served at once. Cloning shares the registry.
+#[derive(Clone, Default)]
+pub struct ThreadRegistry {
+    state: Arc<RegistryState>,
+}
+
+#[derive(Default)]
+struct RegistryState {
+    prefix: RwLock<Option<String>>,
+    next_id: AtomicU64,
+    live: Mutex<BTreeMap<u64, LiveThread>>,
+    panics: AtomicU64,
+}
+
+struct LiveThread {
+    name: String,
+    worker: Option<u64>,
+    started_at: time::Instant,
+    request_id: Option<RequestId>,
+}
+
+// One row of ThreadRegistry::live.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadInfo {
+    pub name: String,
+    pub running_for: time::Duration,
+    // the connection a worker serves, None for the server's own threads
+    pub request_id: Option<RequestId>,
+}
+
+// Takes a thread off the live list when it ends, counting it if it panicked.
+struct Registration {
+    state: Arc<RegistryState>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.state.live.lock().unwrap().remove(&self.id);
+        if thread::panicking() {
+            self.state.panics.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl ThreadRegistry {
+    // Names threads started from now on `<prefix>-...`.
+    pub fn set_prefix(&self, prefix: &str) {
+        *self.state.prefix.write().unwrap() = Some(prefix.to_owned());
+    }
+
+    pub fn prefix(&self) -> String {
+        self.state
+            .prefix
+            .read()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| DEFAULT_THREAD_PREFIX.to_owned())
+    }
+
+    // Runs `f` on a thread named `<prefix>-<role>`.
+    pub fn spawn<T: Send + 'static>(
+        &self,
+        role: &str,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> thread::JoinHandle<T> {
+        self.spawn_registered(None, None, role, f)
+    }
+
+    // Runs `f` on a thread named `<prefix>-worker-<n>`, the lowest number no
+    // running worker has.
+    pub fn spawn_worker<T: Send + 'static>(
+        &self,
+        request_id: Option<RequestId>,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> thread::JoinHandle<T> {
+        let worker = {
+            let live = self.state.live.lock().unwrap();
+            let mut taken: Vec<u64> = live.values().filter_map(|t| t.worker).collect();
+            taken.sort_unstable();
+            taken
+                .iter()
+                .enumerate()
+                .find(|(n, worker)| *n as u64 != **worker)
+                .map_or(taken.len() as u64, |(n, _)| n as u64)
+        };
+        self.spawn_registered(Some(worker), request_id, &format!("worker-{}", worker), f)
+    }
+
+    // Like spawn, on a thread of `scope` so `f` may borrow from outside it.
+    pub fn spawn_scoped<'scope, 'env, T: Send + 'scope>(
+        &self,
+        scope: &'scope thread::Scope<'scope, 'env>,
+        request_id: Option<RequestId>,
+        role: &str,
+        f: impl FnOnce() -> T + Send + 'scope,
+    ) -> thread::ScopedJoinHandle<'scope, T> {
+        let (name, registration) = self.register(None, request_id, role);
+        // same as thread::spawn, failing to start a thread is not recoverable
+        thread::Builder::new()
+            .name(name)
+            .spawn_scoped(scope, move || {
+                let _registration = registration;
+                let _serving = Serving::start(request_id);
+                f()
+            })
+            .expect("failed to spawn thread")
+    }
+
+    fn spawn_registered<T: Send + 'static>(
+        &self,
+        worker: Option<u64>,
+        request_id: Option<RequestId>,
+        role: &str,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> thread::JoinHandle<T> {
+        let (name, registration) = self.register(worker, request_id, role);
+        // same as thread::spawn, failing to start a thread is not recoverable
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                let _registration = registration;
+                let _serving = Serving::start(request_id);
+                f()
+            })
+            .expect("failed to spawn thread")
+    }
+
+    // Puts a thread named `<prefix>-<role>` on the live list until the
+    // returned registration is dropped.
+    fn register(
+        &self,
+        worker: Option<u64>,
+        request_id: Option<RequestId>,
+        role: &str,
+    ) -> (String, Registration) {
+        let name = format!("{}-{}", self.prefix(), role);
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        self.state.live.lock().unwrap().insert(
+            id,
+            LiveThread {
+                name: name.clone(),
+                worker,
+                started_at: time::Instant::now(),
+                request_id,
+            },
+        );
+        let registration = Registration {
+            state: self.state.clone(),
+            id,
+        };
+        (name, registration)
+    }
+
+    // Every thread started through the registry that is still running, oldest
+    // first.
+    pub fn live(&self) -> Vec<ThreadInfo> {
+        self.state
+            .live
+            .lock()
+            .unwrap()
+            .values()
+            .map(|thread| ThreadInfo {
+                name: thread.name.clone(),
+                running_for: thread.started_at.elapsed(),
+                request_id: thread.request_id,
+            })
+            .collect()
+    }
+
+    // How many of them ended in a panic.
+    pub fn panics(&self) -> u64 {
+        self.state.panics.load(Ordering::Relaxed)
+    }
+}
+
+// Logs every panic with the name of the thread and the request it was
+// serving, then hands it on to the hook that was there before. Installing it
+// more than once does nothing.
+pub fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let prefix = SERVING
+                .get()
+                .map_or(String::new(), |id| format!("[{}] ", id));
+            let location = info
+                .location()
+                .map_or(String::new(), |at| format!(" at {}", at));
+            println!(
+                "{}...Thread {} panicked{}: {}",
+                prefix,
+                thread::current().name().unwrap_or("<unnamed>"),
+                location,
+                info.payload_as_str().unwrap_or("non-string panic payload")
+            );
+            previous(info);
+        }));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_threads_are_named_and_tracked() {
+        let threads = ThreadRegistry::default();
+        threads.set_prefix("t");
+        // a worker that runs until its sender is dropped
+        let blocked = || {
+            let (release, wait) = mpsc::channel::<()>();
+            let run = move || {
+                let _ = wait.recv();
+                thread::current().name().map(str::to_owned)
+            };
+            (release, run)
+        };
+
+        let id = RequestId::next();
+        let (release_first, run) = blocked();
+        let first = threads.spawn_worker(Some(id), run);
+        let (release_second, run) = blocked();
+        let second = threads.spawn_worker(None, run);
+        let names: Vec<String> = threads.live().into_iter().map(|t| t.name).collect();
+        assert_eq!(vec!["t-worker-0", "t-worker-1"], names);
+        assert_eq!(Some(id), threads.live()[0].request_id);
+
+        drop(release_first);
+        assert_eq!(Some("t-worker-0".to_owned()), first.join().unwrap());
+        // the lowest free number goes to the next worker
+        let (release_third, run) = blocked();
+        let third = threads.spawn_worker(None, run);
+        let names: Vec<String> = threads.live().into_iter().map(|t| t.name).collect();
+        assert_eq!(vec!["t-worker-1", "t-worker-0"], names);
+        drop((release_second, release_third));
+        second.join().unwrap();
+        third.join().unwrap();
+
+        assert!(threads.spawn("stats", || panic!("boom")).join().is_err());
+        assert_eq!(1, threads.panics());
+        assert!(threads.live().is_empty());
+    }
+}
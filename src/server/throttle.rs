@@ -0,0 +1,117 @@
+use super::clock::Clock;
+use std::{
+    sync::{Arc, Mutex},
+    time,
+};
+
+// Token bucket refilled at `rate` bytes per second, holding at most one
+// second's worth so an idle stretch does not buy an unlimited burst. Taking
+// more than is there puts the bucket in debt and sleeps the debt off, the
+// next caller then waits behind it.
+pub struct TokenBucket {
+    rate: u64,
+    state: Mutex<BucketState>,
+    clock: Arc<dyn Clock>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled_at: time::Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, clock: Arc<dyn Clock>) -> TokenBucket {
+        TokenBucket {
+            rate: rate.max(1),
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                refilled_at: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    // Blocks until `bytes` may go out.
+    pub fn take(&self, bytes: u64) {
+        let debt = {
+            let mut state = self.state.lock().unwrap();
+            let now = self.clock.now();
+            let refill = now.duration_since(state.refilled_at).as_secs_f64() * self.rate as f64;
+            state.tokens = (state.tokens + refill).min(self.rate as f64) - bytes as f64;
+            state.refilled_at = now;
+            -state.tokens
+        };
+        // sleep without the lock, others queue up behind the debt anyway
+        if debt > 0.0 {
+            self.clock
+                .sleep(time::Duration::from_secs_f64(debt / self.rate as f64));
+        }
+    }
+}
+
+// Paces one download against its own limit and the server wide one.
+pub struct Throttle {
+    transfer: Option<TokenBucket>,
+    global: Option<Arc<TokenBucket>>,
+}
+
+impl Throttle {
+    pub fn new(
+        per_transfer: Option<u64>,
+        global: Option<Arc<TokenBucket>>,
+        clock: Arc<dyn Clock>,
+    ) -> Throttle {
+        Throttle {
+            transfer: per_transfer.map(|rate| TokenBucket::new(rate, clock)),
+            global,
+        }
+    }
+
+    pub fn pace(&self, bytes: u64) {
+        if let Some(bucket) = &self.transfer {
+            bucket.take(bytes);
+        }
+        if let Some(bucket) = &self.global {
+            bucket.take(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::clock::{system_clock, MockClock};
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_bucket_paces_past_the_burst() {
+        let clock = Arc::new(MockClock::new());
+        let bucket = Arc::new(TokenBucket::new(10_000, clock.clone()));
+        let started = clock.now();
+        // a full second's worth goes out right away
+        bucket.take(10_000);
+        assert_eq!(0, clock.sleepers());
+
+        let taking = {
+            let bucket = bucket.clone();
+            thread::spawn(move || bucket.take(5_000))
+        };
+        assert!(clock.wait_for_sleepers(1, time::Duration::from_secs(5)));
+        clock.advance(time::Duration::from_millis(499));
+        assert_eq!(1, clock.sleepers());
+        clock.advance(time::Duration::from_millis(1));
+        taking.join().unwrap();
+        assert_eq!(time::Duration::from_millis(500), clock.now() - started);
+    }
+
+    #[test]
+    fn test_unlimited_throttle_never_waits() {
+        let started = time::Instant::now();
+        Throttle::new(None, None, system_clock()).pace(u64::MAX);
+        assert!(started.elapsed() < time::Duration::from_millis(100));
+    }
+}
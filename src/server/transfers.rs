@@ -0,0 +1,71 @@
+use super::connection::Connection;
+use super::keep_alive::{write_frame_header, FRAME_OK};
+use super::metrics::MetricsRegistry;
+use super::server::FileServer;
+use std::{
+    io::{self, Write},
+    sync::Arc,
+};
+
+impl FileServer {
+    // Reply: an OK frame holding one line per transfer in progress, see
+    // ActiveTransfer::encode_line.
+    pub fn handle_transfers(
+        stream: &dyn Connection,
+        _root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_transfers(stream, &metrics_registry);
+    }
+
+    pub(crate) fn framed_transfers(
+        mut stream: &dyn Connection,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let listing: String = metrics_registry
+            .active_transfers()
+            .iter()
+            .map(|transfer| transfer.encode_line())
+            .collect();
+        write_frame_header(stream, FRAME_OK, listing.len() as u64)?;
+        stream.write_all(listing.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::*;
+    use crate::protocol::stats::ActiveTransfer;
+    use std::io::Read;
+
+    #[test]
+    fn test_transfers_lists_running_transfers_only() {
+        let metrics = Arc::new(MetricsRegistry::new());
+        let finished = metrics.begin_transfer("old.bin", "10.0.0.1:4000", None);
+        drop(finished);
+        let running = metrics.begin_transfer("big.iso", "10.0.0.2:5000", None);
+        running.record_bytes_sent(42);
+
+        let (client, server) = duplex();
+        FileServer::handle_transfers(&server, "unused", metrics.clone());
+        drop(server);
+
+        let mut reply = Vec::new();
+        (&client as &dyn Connection)
+            .read_to_end(&mut reply)
+            .unwrap();
+        assert_eq!(FRAME_OK, reply[0]);
+        let listing = String::from_utf8(reply[9..].to_vec()).unwrap();
+        let transfers: Vec<ActiveTransfer> = listing
+            .lines()
+            .map(|line| ActiveTransfer::parse_line(line).unwrap())
+            .collect();
+
+        assert_eq!(1, transfers.len());
+        assert_eq!(running.id(), transfers[0].id);
+        assert_eq!("big.iso", transfers[0].file_name);
+        assert_eq!("10.0.0.2:5000", transfers[0].peer);
+        assert_eq!(42, transfers[0].bytes_sent);
+    }
+}
@@ -1,43 +1,305 @@
-#[derive(Eq, Hash, PartialEq, Clone, Copy, Debug)]
-pub enum CommandType {
-    Upload,
-    Download,
-    Statistics,
+use super::server::FileServerError;
+
+pub use crate::protocol::CommandType;
+
+// The command bytes on the wire, with the server's error for the ones it
+// does not know.
+impl TryFrom<u8> for CommandType {
+    type Error = FileServerError;
+
+    fn try_from(byte: u8) -> Result<CommandType, FileServerError> {
+        CommandType::from_byte(byte).ok_or(FileServerError::UnknownCommand { byte })
+    }
+}
+
+// Order of the files in a ListPage reply.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListSort {
+    #[default]
+    Name,
+    Modified,
+    Size,
+}
+
+impl ListSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListSort::Name => "name",
+            ListSort::Modified => "mtime",
+            ListSort::Size => "size",
+        }
+    }
+
+    pub fn parse(sort: &str) -> Option<ListSort> {
+        match sort {
+            "name" => Some(ListSort::Name),
+            "mtime" => Some(ListSort::Modified),
+            "size" => Some(ListSort::Size),
+            _ => None,
+        }
+    }
+}
+
+// A ListPage request: the files whose name starts with `prefix`, in `sort`
+// order (reversed when `descending`), at most `limit` of them (0 for the
+// server's default), after the page token the previous page ended with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListQuery {
+    pub prefix: String,
+    pub sort: ListSort,
+    pub descending: bool,
+    pub limit: usize,
+    pub page: Option<String>,
+}
+
+impl ListQuery {
+    // `prefix=<p>|sort=<[-]name|mtime|size>|limit=<n>|page=<token>|`, the
+    // token is empty for the first page.
+    pub fn encode(&self) -> String {
+        format!(
+            "prefix={}|sort={}{}|limit={}|page={}|",
+            self.prefix,
+            if self.descending { "-" } else { "" },
+            self.sort.as_str(),
+            self.limit,
+            self.page.as_deref().unwrap_or("")
+        )
+    }
+
+    // The four segments encode writes, in order.
+    pub fn parse(segments: [&str; 4]) -> Option<ListQuery> {
+        let field = |segment: &'_ str, key: &str| {
+            segment
+                .strip_prefix(key)
+                .and_then(|value| value.strip_suffix('|'))
+                .map(str::to_owned)
+        };
+        let sort = field(segments[1], "sort=")?;
+        let (descending, sort) = match sort.strip_prefix('-') {
+            Some(sort) => (true, sort),
+            None => (false, sort.as_str()),
+        };
+        let page = field(segments[3], "page=")?;
+        Some(ListQuery {
+            prefix: field(segments[0], "prefix=")?,
+            sort: ListSort::parse(sort)?,
+            descending,
+            limit: field(segments[2], "limit=")?.parse().ok()?,
+            page: (!page.is_empty()).then_some(page),
+        })
+    }
+}
+
+// Second segment of a ConditionalDownload request, after `filename=...|`:
+// `if-modified-since=<unix seconds>|` or `if-none-match=<sha256 hex>|`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadCondition {
+    ModifiedSince(std::time::SystemTime),
+    NoneMatch(String),
 }
 
-pub mod stats {
-    use std::{io::Read, net::TcpStream};
+impl DownloadCondition {
+    pub fn encode(&self) -> String {
+        match self {
+            DownloadCondition::ModifiedSince(time) => format!(
+                "if-modified-since={}|",
+                time.duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs())
+            ),
+            DownloadCondition::NoneMatch(hash) => format!("if-none-match={}|", hash),
+        }
+    }
 
-    pub struct Stats {
-        pub number_of_clients: u8,
-        pub most_downloaded_file: String,
-        pub file_downloaded_count: u8,
+    pub fn parse(segment: &str) -> Option<DownloadCondition> {
+        let (key, value) = segment.strip_suffix('|')?.split_once('=')?;
+        match key {
+            "if-modified-since" => Some(DownloadCondition::ModifiedSince(
+                std::time::UNIX_EPOCH + std::time::Duration::from_secs(value.parse().ok()?),
+            )),
+            "if-none-match" if !value.is_empty() => {
+                Some(DownloadCondition::NoneMatch(value.to_ascii_lowercase()))
+            }
+            _ => None,
+        }
     }
+}
+
+// What the client already has of one file, a Sync manifest holds one
+// `name\tsize\tsha256 hex\n` line per file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
 
-    impl Stats {
-        pub fn stats_from_stream(stream: &mut TcpStream) -> Stats {
-            let mut client_count: [u8; 1] = [11];
-            stream.read_exact(client_count.as_mut_slice()).unwrap();
+impl ManifestEntry {
+    pub fn encode_line(&self) -> String {
+        format!("{}\t{}\t{}\n", self.name, self.size, self.sha256)
+    }
 
-            let mut most_accessed_file_name_length: [u8; 1] = [1];
-            stream
-                .read_exact(most_accessed_file_name_length.as_mut_slice())
-                .unwrap();
+    pub fn parse_line(line: &str) -> Option<ManifestEntry> {
+        let mut fields = line.split('\t');
+        let entry = ManifestEntry {
+            name: fields.next().filter(|name| !name.is_empty())?.to_owned(),
+            size: fields.next()?.parse().ok()?,
+            sha256: fields.next()?.to_ascii_lowercase(),
+        };
+        fields.next().is_none().then_some(entry)
+    }
+}
 
-            let mut vec = vec![0; most_accessed_file_name_length[0] as usize];
-            let file_name: &mut [u8] = &mut vec[..];
-            stream.read_exact(file_name).unwrap();
+// What a server can do, sent to clients that open with Hello as the payload
+// of an OK frame, one `key=value` line each. Keys a client does not know are
+// skipped, so later versions can add some.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    // the protocol version both sides speak, the older of the two
+    pub protocol: u16,
+    pub server_version: String,
+    // command bytes the server answers, prefixes included
+    pub commands: Vec<u8>,
+    // biggest upload the server takes, None when there is no limit
+    pub max_upload_bytes: Option<u64>,
+    // compression algorithms downloads can be asked for
+    pub compression: Vec<String>,
+}
 
-            let mut file_downloaded_stat: [u8; 1] = [11];
-            stream
-                .read_exact(file_downloaded_stat.as_mut_slice())
-                .unwrap();
+impl Capabilities {
+    pub fn encode(&self) -> String {
+        let commands: Vec<String> = self.commands.iter().map(u8::to_string).collect();
+        let mut encoded = format!(
+            "protocol={}\nversion={}\ncommands={}\ncompression={}\n",
+            self.protocol,
+            self.server_version,
+            commands.join(","),
+            self.compression.join(",")
+        );
+        if let Some(max) = self.max_upload_bytes {
+            encoded.push_str(&format!("max_upload_bytes={}\n", max));
+        }
+        encoded
+    }
 
-            Stats {
-                number_of_clients: client_count[0],
-                most_downloaded_file: String::from_utf8_lossy(&file_name).to_string(),
-                file_downloaded_count: file_downloaded_stat[0],
+    pub fn parse(encoded: &str) -> Option<Capabilities> {
+        let (mut protocol, mut server_version, mut commands) = (None, None, None);
+        let mut capabilities = Capabilities {
+            protocol: 0,
+            server_version: String::new(),
+            commands: Vec::new(),
+            max_upload_bytes: None,
+            compression: Vec::new(),
+        };
+        for line in encoded.lines() {
+            let (key, value) = line.split_once('=')?;
+            match key {
+                "protocol" => protocol = Some(value.parse().ok()?),
+                "version" => server_version = Some(value.to_owned()),
+                "commands" => {
+                    commands = Some(
+                        list_items(value)
+                            .map(str::parse)
+                            .collect::<Result<_, _>>()
+                            .ok()?,
+                    )
+                }
+                "max_upload_bytes" => capabilities.max_upload_bytes = Some(value.parse().ok()?),
+                "compression" => {
+                    capabilities.compression = list_items(value).map(str::to_owned).collect()
+                }
+                _ => {}
             }
         }
+        capabilities.protocol = protocol?;
+        capabilities.server_version = server_version?;
+        capabilities.commands = commands?;
+        Some(capabilities)
+    }
+}
+
+// items of a comma separated capability, none for an empty value
+fn list_items(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').filter(|item| !item.is_empty())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Created => "created",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Deleted => "deleted",
+        }
+    }
+}
+
+// One change to a served file pushed to a Watch subscriber, sent as the
+// payload of an OK frame: `<kind>\t<file name>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub file_name: String,
+}
+
+impl ChangeEvent {
+    pub fn encode(&self) -> String {
+        format!("{}\t{}", self.kind.as_str(), self.file_name)
+    }
+
+    pub fn parse(payload: &str) -> Option<ChangeEvent> {
+        let (kind, file_name) = payload.split_once('\t')?;
+        let kind = match kind {
+            "created" => ChangeKind::Created,
+            "modified" => ChangeKind::Modified,
+            "deleted" => ChangeKind::Deleted,
+            _ => return None,
+        };
+        Some(ChangeEvent {
+            kind,
+            file_name: file_name.to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_command_bytes_round_trip() {
+        use super::CommandType;
+        let commands: Vec<CommandType> = (0..=u8::MAX)
+            .filter_map(|byte| CommandType::try_from(byte).ok())
+            .collect();
+        assert_eq!(31, commands.len());
+        for command in commands {
+            assert_eq!(command, CommandType::try_from(u8::from(command)).unwrap());
+        }
+        assert!(CommandType::try_from(0).is_err());
+        assert_eq!(6, u8::from(CommandType::Ping));
+    }
+
+    #[test]
+    fn test_capabilities_round_trip() {
+        let capabilities = super::Capabilities {
+            protocol: 1,
+            server_version: "0.1.0".to_owned(),
+            commands: vec![1, 2, 23],
+            max_upload_bytes: Some(1024),
+            compression: Vec::new(),
+        };
+        let encoded = capabilities.encode();
+        assert_eq!(Some(capabilities), super::Capabilities::parse(&encoded));
+        // keys from later versions are skipped, missing required ones are not
+        let later = format!("{}checksums=sha256\n", encoded);
+        assert!(super::Capabilities::parse(&later).is_some());
+        assert_eq!(
+            None,
+            super::Capabilities::parse("protocol=1\nversion=0.1.0\n")
+        );
     }
 }
@@ -0,0 +1,294 @@
+use super::connection::Connection;
+use super::keep_alive::{write_error_frame, write_frame_header, FRAME_OK};
+use super::metrics::MetricsRegistry;
+use super::request_id::log_prefix;
+use super::server::FileServer;
+use super::types::{ChangeEvent, ChangeKind, CommandType};
+use crate::reader::{glob_matches, served_directory_path, validate_file_name, validate_pattern};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Write},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time,
+};
+
+// how often a quiet watch checks whether its client is still there
+const WATCH_POLL_MS: u64 = 500;
+
+// (root, glob, where to send matching events) for every connected watcher
+type Watchers = Arc<Mutex<Vec<(String, String, Sender<ChangeEvent>)>>>;
+
+// One filesystem watcher per served directory, shared by every Watch
+// subscriber of that directory. There is more than one directory once users
+// have homes of their own. A watcher is only started when the first client
+// subscribes, servers nobody watches never pay for it.
+#[derive(Default)]
+pub struct WatchHub {
+    watchers: Watchers,
+    // by root dir
+    directories: Mutex<HashMap<String, RecommendedWatcher>>,
+}
+
+impl WatchHub {
+    // Events for served files matching `pattern` from now on.
+    pub fn subscribe(&self, root_dir: &str, pattern: &str) -> io::Result<Receiver<ChangeEvent>> {
+        let mut directories = self.directories.lock().unwrap();
+        if !directories.contains_key(root_dir) {
+            let watchers = self.watchers.clone();
+            let watched_root = root_dir.to_owned();
+            let mut started =
+                notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+                    Ok(event) => publish(&watchers, &watched_root, changes(&event)),
+                    Err(err) => println!("...Error watching served directory:{err}"),
+                })
+                .map_err(io::Error::other)?;
+            started
+                .watch(
+                    &served_directory_path(root_dir),
+                    RecursiveMode::NonRecursive,
+                )
+                .map_err(io::Error::other)?;
+            directories.insert(root_dir.to_owned(), started);
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        self.watchers
+            .lock()
+            .unwrap()
+            .push((root_dir.to_owned(), pattern.to_owned(), sender));
+        Ok(receiver)
+    }
+
+    pub fn subscribers(&self) -> usize {
+        self.watchers.lock().unwrap().len()
+    }
+
+    // Ends every subscription, their handlers return once they notice.
+    pub fn close(&self) {
+        self.watchers.lock().unwrap().clear();
+    }
+}
+
+fn publish(watchers: &Watchers, root_dir: &str, changes: Vec<ChangeEvent>) {
+    if changes.is_empty() {
+        return;
+    }
+    watchers.lock().unwrap().retain(|(root, pattern, sender)| {
+        // subscribers of other directories are kept as they are
+        root != root_dir
+            || changes
+                .iter()
+                .filter(|change| glob_matches(pattern, &change.file_name))
+                // a failed send means the subscriber is gone
+                .all(|change| sender.send(change.clone()).is_ok())
+    });
+}
+
+// What a filesystem event means for the served files. Renames show up as the
+// old name deleted and the new one created, that is also how a finished
+// upload appears since it is written under a partial name first.
+fn changes(event: &Event) -> Vec<ChangeEvent> {
+    let kinds: Vec<(ChangeKind, &PathBuf)> = match &event.kind {
+        EventKind::Create(_) => event
+            .paths
+            .iter()
+            .map(|path| (ChangeKind::Created, path))
+            .collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => event
+            .paths
+            .iter()
+            .map(|path| (ChangeKind::Deleted, path))
+            .collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => event
+            .paths
+            .iter()
+            .map(|path| (ChangeKind::Created, path))
+            .collect(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => event
+            .paths
+            .iter()
+            .zip([ChangeKind::Deleted, ChangeKind::Created])
+            .map(|(path, kind)| (kind, path))
+            .collect(),
+        EventKind::Modify(_) => event
+            .paths
+            .iter()
+            .map(|path| (ChangeKind::Modified, path))
+            .collect(),
+        EventKind::Remove(_) => event
+            .paths
+            .iter()
+            .map(|path| (ChangeKind::Deleted, path))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    kinds
+        .into_iter()
+        .filter_map(|(kind, path)| {
+            let file_name = path.file_name()?.to_str()?;
+            // partial uploads and anything else that is never served
+            validate_file_name(file_name).ok()?;
+            Some(ChangeEvent {
+                kind,
+                file_name: file_name.to_owned(),
+            })
+        })
+        .collect()
+}
+
+impl FileServer {
+    // Request: filename=<glob>|, e.g. filename=*.log|
+    // Reply: an empty OK frame once subscribed, then one OK frame per change
+    // holding a ChangeEvent, for as long as the client stays connected.
+    pub fn handle_watch(
+        stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: Arc<MetricsRegistry>,
+    ) {
+        let _ = Self::framed_watch(stream, root_dir, &metrics_registry);
+    }
+
+    pub(crate) fn framed_watch(
+        mut stream: &dyn Connection,
+        root_dir: &'static str,
+        metrics_registry: &MetricsRegistry,
+    ) -> io::Result<()> {
+        let pattern = match Self::read_file_request(stream, &metrics_registry.limits()) {
+            Ok(pattern) => pattern,
+            Err(err) => {
+                let _ = write_error_frame(stream, err.to_string());
+                return Err(io::Error::new(ErrorKind::InvalidData, err.to_string()));
+            }
+        };
+        if let Err(err) = validate_pattern(&pattern) {
+            return write_error_frame(stream, err.to_string());
+        }
+        if let Err(err) = metrics_registry.authorize(stream, CommandType::Watch, &pattern) {
+            return write_error_frame(stream, err.to_string());
+        }
+        // nothing in it ever changes, and the root dir is not what is served
+        if metrics_registry.embedded_archive().is_some() {
+            return write_error_frame(
+                stream,
+                "watch is not available while serving an embedded archive".to_owned(),
+            );
+        }
+        let events = match metrics_registry.watches.subscribe(root_dir, &pattern) {
+            Ok(events) => events,
+            Err(err) => return write_error_frame(stream, err.to_string()),
+        };
+        write_frame_header(stream, FRAME_OK, 0)?;
+        println!(
+            "{}{} is watching {}",
+            log_prefix(stream),
+            stream.peer(),
+            pattern
+        );
+
+        loop {
+            match events.recv_timeout(time::Duration::from_millis(WATCH_POLL_MS)) {
+                Ok(event) => {
+                    let payload = event.encode();
+                    write_frame_header(stream, FRAME_OK, payload.len() as u64)?;
+                    stream.write_all(payload.as_bytes())?;
+                }
+                Err(RecvTimeoutError::Timeout) if Self::hung_up(stream) => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => {}
+                // the hub was closed, the server is shutting down
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+
+    // Nothing is written to a watcher while the directory is quiet, so a
+    // client that went away is only noticed by reading. Anything it sends is
+    // ignored.
+    pub(crate) fn hung_up(stream: &dyn Connection) -> bool {
+        let _ = stream.set_read_timeout(Some(time::Duration::from_millis(1)));
+        let mut buf = [0u8; 64];
+        match stream.read(&mut buf) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(err) => !matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::connection::duplex;
+    use super::*;
+    use crate::reader;
+    use std::{fs, io::Read, thread};
+
+    fn read_event(client: &dyn Connection) -> (u8, String) {
+        let mut client = client;
+        let mut header = [0u8; 9];
+        client.read_exact(&mut header).unwrap();
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&header[1..]);
+        let mut payload = vec![0; u64::from_be_bytes(length) as usize];
+        client.read_exact(&mut payload).unwrap();
+        (header[0], String::from_utf8(payload).unwrap())
+    }
+
+    #[test]
+    fn test_watch_pushes_matching_changes() {
+        let root_dir = "temp_test_watch_root_dir";
+        let path = reader::configure_directory_to_serve_file(root_dir);
+        let metrics = Arc::new(MetricsRegistry::new());
+
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=*.log|").unwrap();
+        let watching = metrics.clone();
+        let handler = thread::spawn(move || FileServer::framed_watch(&server, root_dir, &watching));
+        assert_eq!((FRAME_OK, String::new()), read_event(&client));
+
+        fs::write(path.join("notes.txt"), b"ignored").unwrap();
+        fs::write(path.join("app.log.part"), b"not served yet").unwrap();
+        fs::write(path.join("app.log"), b"line").unwrap();
+        let (status, payload) = read_event(&client);
+        assert_eq!(FRAME_OK, status);
+        assert_eq!(
+            Some(ChangeEvent {
+                kind: ChangeKind::Created,
+                file_name: "app.log".to_owned(),
+            }),
+            ChangeEvent::parse(&payload)
+        );
+
+        fs::remove_file(path.join("app.log")).unwrap();
+        let mut deleted = false;
+        while !deleted {
+            // the write above may still be reported as a modification first
+            let event = ChangeEvent::parse(&read_event(&client).1).unwrap();
+            assert_eq!("app.log", event.file_name);
+            deleted = event.kind == ChangeKind::Deleted;
+        }
+
+        metrics.watches.close();
+        handler.join().unwrap().unwrap();
+        reader::cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_watch_rejects_patterns_with_paths() {
+        let metrics = MetricsRegistry::new();
+        let (client, server) = duplex();
+        let mut client_end: &dyn Connection = &client;
+        client_end.write_all(b"filename=../*|").unwrap();
+        FileServer::framed_watch(&server, "temp_test_watch_bad_root_dir", &metrics).unwrap();
+        assert_ne!(FRAME_OK, read_event(&client).0);
+        assert_eq!(0, metrics.watches.subscribers());
+    }
+}
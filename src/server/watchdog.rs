@@ -0,0 +1,181 @@
+use super::clock::SharedClock;
+use super::connection::Connection;
+use super::request_id::log_prefix;
+use super::threads::ThreadRegistry;
+use super::types::CommandType;
+use std::{
+    collections::HashMap,
+    net::Shutdown,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time,
+};
+
+// how often the watchdog looks for connections past their budget
+const WATCHDOG_TICK_MS: u64 = 100;
+
+// Wall-clock budgets per command. A connection still being served once its
+// command's budget is up is hung up on, the handler fails on its next read or
+// write and gives its worker back. Commands without a budget run as long as
+// they like, stats subscriptions always do.
+#[derive(Default)]
+pub struct Watchdog {
+    budgets: RwLock<HashMap<CommandType, time::Duration>>,
+    state: Arc<WatchdogState>,
+    threads: ThreadRegistry,
+}
+
+#[derive(Default)]
+struct WatchdogState {
+    watched: Mutex<HashMap<u64, Watched>>,
+    next_id: AtomicU64,
+    started: AtomicBool,
+    // connections hung up on for running over budget
+    timeouts: AtomicU64,
+    clock: SharedClock,
+}
+
+struct Watched {
+    command: CommandType,
+    deadline: time::Instant,
+    stream: Arc<dyn Connection>,
+}
+
+// Stops watching its connection when dropped, once the handler returned.
+pub struct WatchGuard {
+    state: Arc<WatchdogState>,
+    id: u64,
+}
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        self.state.watched.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl Watchdog {
+    // A watchdog whose thread is named and tracked by `threads` and keeps
+    // time by `clock`.
+    pub(crate) fn with_threads(threads: &ThreadRegistry, clock: &SharedClock) -> Watchdog {
+        Watchdog {
+            threads: threads.clone(),
+            state: Arc::new(WatchdogState {
+                clock: clock.clone(),
+                ..WatchdogState::default()
+            }),
+            ..Watchdog::default()
+        }
+    }
+
+    // None lifts the budget of `command`.
+    pub fn set_budget(&self, command: CommandType, budget: Option<time::Duration>) {
+        let mut budgets = self.budgets.write().unwrap();
+        match budget {
+            Some(budget) => budgets.insert(command, budget),
+            None => budgets.remove(&command),
+        };
+    }
+
+    pub fn budget(&self, command: CommandType) -> Option<time::Duration> {
+        self.budgets.read().unwrap().get(&command).copied()
+    }
+
+    pub fn timeouts(&self) -> u64 {
+        self.state.timeouts.load(Ordering::Relaxed)
+    }
+
+    // Starts the clock on `stream` serving `command`, None when the command
+    // has no budget. Keep the guard until the handler returns.
+    pub fn watch(&self, command: CommandType, stream: &Arc<dyn Connection>) -> Option<WatchGuard> {
+        let budget = self.budget(command)?;
+        let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+        self.state.watched.lock().unwrap().insert(
+            id,
+            Watched {
+                command,
+                deadline: self.state.clock.now() + budget,
+                stream: stream.clone(),
+            },
+        );
+        if !self.state.started.swap(true, Ordering::SeqCst) {
+            let state = Arc::downgrade(&self.state);
+            self.threads.spawn("watchdog", move || {
+                // ends with the registry the watchdog belongs to
+                while let Some(state) = state.upgrade() {
+                    state.hang_up_overdue();
+                    let clock = state.clock.clone();
+                    drop(state);
+                    clock.sleep(time::Duration::from_millis(WATCHDOG_TICK_MS));
+                }
+            });
+        }
+        Some(WatchGuard {
+            state: self.state.clone(),
+            id,
+        })
+    }
+}
+
+impl WatchdogState {
+    fn hang_up_overdue(&self) {
+        let now = self.clock.now();
+        let mut watched = self.watched.lock().unwrap();
+        watched.retain(|_, entry| {
+            if entry.deadline > now {
+                return true;
+            }
+            println!(
+                "{}...{:?} ran over its budget, hanging up on {}",
+                log_prefix(&*entry.stream),
+                entry.command,
+                entry.stream.peer()
+            );
+            // counted first, whoever sees the hang up may check the count
+            self.timeouts.fetch_add(1, Ordering::Relaxed);
+            let _ = entry.stream.shutdown(Shutdown::Both);
+            false
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::clock::MockClock;
+    use super::super::connection::duplex;
+    use super::*;
+
+    #[test]
+    fn test_hangs_up_once_the_budget_is_spent() {
+        let clock = Arc::new(MockClock::new());
+        let shared = SharedClock::default();
+        shared.set(clock.clone());
+        let watchdog = Watchdog::with_threads(&ThreadRegistry::default(), &shared);
+        watchdog.set_budget(CommandType::Download, Some(time::Duration::from_millis(50)));
+        let (client, server) = duplex();
+        let server: Arc<dyn Connection> = Arc::new(server);
+
+        assert!(watchdog.watch(CommandType::Upload, &server).is_none());
+        let _guard = watchdog.watch(CommandType::Download, &server).unwrap();
+        assert!(clock.wait_for_sleepers(1, time::Duration::from_secs(5)));
+        assert_eq!(0, watchdog.timeouts());
+        clock.advance(time::Duration::from_millis(WATCHDOG_TICK_MS));
+        // the client sees the server's end hang up
+        let mut client: &dyn Connection = &client;
+        let mut buf = [0u8; 1];
+        assert_eq!(0, std::io::Read::read(&mut client, &mut buf).unwrap());
+        assert_eq!(1, watchdog.timeouts());
+
+        // one that finishes in time is left alone
+        let (_client, server) = duplex();
+        let server: Arc<dyn Connection> = Arc::new(server);
+        drop(watchdog.watch(CommandType::Download, &server));
+        for _ in 0..2 {
+            assert!(clock.wait_for_sleepers(1, time::Duration::from_secs(5)));
+            clock.advance(time::Duration::from_millis(WATCHDOG_TICK_MS));
+        }
+        assert!(clock.wait_for_sleepers(1, time::Duration::from_secs(5)));
+        assert_eq!(1, watchdog.timeouts());
+    }
+}
@@ -0,0 +1,967 @@
+use super::accounts::Accounts;
+use super::audit::AuditEntry;
+use super::listener::{self, ListenerPolicy};
+use super::metrics::MetricsRegistry;
+use super::pool::WorkerPool;
+use super::serve_policy::is_denial;
+use super::server::{FileServer, FileServerError};
+use super::types::CommandType;
+use crate::reader::{
+    discard_partial_file, file_metadata, served_subdirectories, validate_directory_name,
+    validate_file_name, ServedFile,
+};
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+    time,
+};
+
+// WebDAV class 1 (RFC 4918) over the served directory so the file managers of
+// Windows, macOS and Linux can mount it as a network drive. The directory is
+// flat, so "/" is the only collection: PROPFIND lists it, GET/HEAD/PUT/DELETE
+// work on the files in it. Without LOCK support macOS mounts it read-only.
+// For browsers GET goes further: a path ending in `/` is a directory under the
+// root and gets an HTML index, and files in those directories can be fetched.
+// One request per connection, like the other side listeners. Nobody logs in
+// over WebDAV, so the home directories of user accounts are neither listed
+// nor served.
+
+// longest request line plus headers we are willing to read
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+const READ_TIMEOUT_SECS: u64 = 30;
+
+struct DavRequest {
+    method: String,
+    path: String,
+    depth: Option<String>,
+    content_length: Option<u64>,
+    chunked: bool,
+}
+
+struct DavResponse {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl DavResponse {
+    fn new(status: u16, reason: &'static str) -> DavResponse {
+        DavResponse {
+            status,
+            reason,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn text(status: u16, reason: &'static str, body: &str) -> DavResponse {
+        let mut response = DavResponse::new(status, reason);
+        response
+            .headers
+            .push(("Content-Type", "text/plain; charset=utf-8".to_owned()));
+        response.body = body.as_bytes().to_vec();
+        response
+    }
+
+    fn from_io_error(err: &io::Error) -> DavResponse {
+        match err.kind() {
+            ErrorKind::NotFound => DavResponse::text(404, "Not Found", "no such file"),
+            ErrorKind::InvalidInput => DavResponse::text(403, "Forbidden", &err.to_string()),
+            ErrorKind::PermissionDenied if is_denial(err) => {
+                DavResponse::text(403, "Forbidden", &err.to_string())
+            }
+            ErrorKind::AlreadyExists => DavResponse::text(423, "Locked", &err.to_string()),
+            _ => DavResponse::text(500, "Internal Server Error", &err.to_string()),
+        }
+    }
+
+    fn write_head(&self, stream: &mut TcpStream, content_length: u64) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, self.reason);
+        for (name, value) in &self.headers {
+            let _ = write!(head, "{}: {}\r\n", name, value);
+        }
+        let _ = write!(
+            head,
+            "DAV: 1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            content_length
+        );
+        stream.write_all(head.as_bytes())
+    }
+
+    fn send(&self, stream: &mut TcpStream) -> io::Result<()> {
+        self.write_head(stream, self.body.len() as u64)?;
+        stream.write_all(&self.body)
+    }
+}
+
+impl FileServer {
+    // Serves the root dir over WebDAV on http://address:port/ from a
+    // background thread. Requests take a worker from the pool like
+    // downloads and uploads do. Accounts set up after this are not hidden,
+    // set them first.
+    pub fn start_webdav(&self, address: &str, port: &str) -> Result<(), FileServerError> {
+        self.start_webdav_with_policy(address, port, ListenerPolicy::default())
+    }
+
+    // Like start_webdav, with limits on what its requests may do, see
+    // add_listener_with_policy. A read-only policy refuses PUT and DELETE.
+    pub fn start_webdav_with_policy(
+        &self,
+        address: &str,
+        port: &str,
+        policy: ListenerPolicy,
+    ) -> Result<(), FileServerError> {
+        let port: u16 = port.parse().map_err(|_| FileServerError::InvalidPort {
+            port: port.to_owned(),
+        })?;
+        let listener = listener::resolve(address, port)
+            .and_then(|addrs| listener::bind_first(&addrs[..]))
+            .map_err(|source| FileServerError::Bind {
+                address: format!("{}:{}", address, port),
+                source,
+            })?;
+
+        let root_dir = self.root_dir;
+        let metrics = self.metrics.clone();
+        let pool = self.pool.clone();
+        let homes: Arc<[String]> = self
+            .accounts
+            .iter()
+            .flat_map(Accounts::names)
+            .map(str::to_owned)
+            .collect();
+        self.metrics.threads.spawn("webdav", move || {
+            for stream in listener.incoming().flatten() {
+                let metrics = metrics.clone();
+                let pool = pool.clone();
+                let homes = homes.clone();
+                let threads = metrics.threads.clone();
+                threads.spawn_worker(None, move || {
+                    serve_connection(stream, root_dir, &homes, policy, &metrics, &pool)
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+fn serve_connection(
+    mut stream: TcpStream,
+    root_dir: &'static str,
+    homes: &[String],
+    policy: ListenerPolicy,
+    metrics: &MetricsRegistry,
+    pool: &Arc<WorkerPool>,
+) {
+    let _ = stream.set_read_timeout(Some(time::Duration::from_secs(READ_TIMEOUT_SECS)));
+    let read_half = match stream.try_clone() {
+        Ok(read_half) => read_half,
+        Err(_) => return,
+    };
+    // the body (PUT) is read through the same buffer the head was
+    let mut reader = BufReader::new(read_half);
+    let request = match read_request(&mut reader) {
+        Ok(request) => request,
+        Err(err) => {
+            let _ = DavResponse::text(400, "Bad Request", &err.to_string()).send(&mut stream);
+            return;
+        }
+    };
+
+    let _slot = pool.acquire(None);
+    metrics.record_connection();
+    println!("WebDAV {} {}", request.method, request.path);
+    let result = match request.method.as_str() {
+        "OPTIONS" => {
+            let mut response = DavResponse::new(200, "OK");
+            response.headers.push((
+                "Allow",
+                "OPTIONS, PROPFIND, GET, HEAD, PUT, DELETE".to_owned(),
+            ));
+            response.send(&mut stream)
+        }
+        "PROPFIND" => propfind(&mut stream, &request, root_dir, metrics),
+        "GET" | "HEAD" => get(&mut stream, &request, root_dir, homes, metrics),
+        "PUT" | "DELETE" if metrics.is_read_only() || policy.read_only => {
+            let operation = match request.method.as_str() {
+                "PUT" => "webdav-put",
+                _ => "webdav-delete",
+            };
+            let name = request.path.trim_start_matches('/');
+            audit(
+                &stream,
+                root_dir,
+                metrics,
+                operation,
+                name,
+                0,
+                Err("server is read-only".to_owned()),
+            );
+            DavResponse::text(403, "Forbidden", "server is read-only").send(&mut stream)
+        }
+        "PUT" => put(&mut stream, &mut reader, &request, root_dir, metrics),
+        "DELETE" => delete(&mut stream, &request, root_dir, metrics),
+        // the root is the only collection there is
+        "MKCOL" => DavResponse::text(405, "Method Not Allowed", "collections can not be created")
+            .send(&mut stream),
+        _ => DavResponse::text(405, "Method Not Allowed", "unsupported method").send(&mut stream),
+    };
+    if let Err(err) = result {
+        println!("...Error answering WebDAV client: {err}");
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> io::Result<DavRequest> {
+    let mut head = reader.take(MAX_HEAD_BYTES);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "bad request line")),
+    };
+
+    let mut request = DavRequest {
+        method,
+        // clients may send an absolute URI, only the path matters here
+        path: percent_decode(strip_origin(target.split('?').next().unwrap_or("/")))?,
+        depth: None,
+        content_length: None,
+        chunked: false,
+    };
+    loop {
+        line.clear();
+        if head.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(request);
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "depth" => request.depth = Some(value.to_owned()),
+            "content-length" => {
+                request.content_length =
+                    Some(value.parse().map_err(|_| {
+                        io::Error::new(ErrorKind::InvalidData, "bad Content-Length")
+                    })?)
+            }
+            "transfer-encoding" => request.chunked = value.eq_ignore_ascii_case("chunked"),
+            _ => {}
+        }
+    }
+}
+
+fn strip_origin(target: &str) -> &str {
+    match target.find("://") {
+        Some(scheme_end) => {
+            let rest = &target[scheme_end + 3..];
+            rest.find('/').map_or("/", |path_start| &rest[path_start..])
+        }
+        None => target,
+    }
+}
+
+// The file a path names, None for the root collection.
+fn file_in_path(path: &str) -> io::Result<Option<&str>> {
+    match path.trim_start_matches('/') {
+        "" => Ok(None),
+        name => {
+            validate_file_name(name)?;
+            Ok(Some(name))
+        }
+    }
+}
+
+fn propfind(
+    stream: &mut TcpStream,
+    request: &DavRequest,
+    root_dir: &'static str,
+    metrics: &MetricsRegistry,
+) -> io::Result<()> {
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    match file_in_path(&request.path) {
+        Err(err) => return DavResponse::from_io_error(&err).send(stream),
+        Ok(Some(name)) => match FileServer::stored_file(name, root_dir, metrics) {
+            Ok(file) => push_file_response(&mut body, &file, metrics),
+            Err(err) => return DavResponse::from_io_error(&err).send(stream),
+        },
+        Ok(None) => {
+            body.push_str(
+                "<D:response><D:href>/</D:href><D:propstat><D:prop>\
+                 <D:resourcetype><D:collection/></D:resourcetype>\
+                 <D:displayname></D:displayname>\
+                 </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+            );
+            // Depth: infinity is answered like 1, there is nothing deeper
+            if request.depth.as_deref() != Some("0") {
+                let mut files = match FileServer::stored_files(root_dir, metrics)
+                    .and_then(|files| files.collect::<io::Result<Vec<_>>>())
+                {
+                    Ok(files) => files,
+                    Err(err) => return DavResponse::from_io_error(&err).send(stream),
+                };
+                files.sort_by(|a, b| a.name.cmp(&b.name));
+                for file in files {
+                    if metrics
+                        .authorize(&*stream, CommandType::List, &file.name)
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    push_file_response(&mut body, &file, metrics);
+                }
+            }
+        }
+    }
+    body.push_str("</D:multistatus>\n");
+
+    let mut response = DavResponse::new(207, "Multi-Status");
+    response
+        .headers
+        .push(("Content-Type", "application/xml; charset=utf-8".to_owned()));
+    response.body = body.into_bytes();
+    response.send(stream)
+}
+
+fn push_file_response(body: &mut String, file: &ServedFile, metrics: &MetricsRegistry) {
+    let _ = writeln!(
+        body,
+        "<D:response><D:href>/{}</D:href><D:propstat><D:prop>\
+         <D:resourcetype/><D:displayname>{}</D:displayname>\
+         <D:getcontentlength>{}</D:getcontentlength>\
+         <D:getlastmodified>{}</D:getlastmodified>\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        percent_encode(&file.name),
+        xml_escape(&file.name),
+        metrics.served_len(file.size),
+        http_date(file.modified)
+    );
+}
+
+// Whether `name` is the home directory of a user account, case aside for
+// file systems that ignore it.
+fn is_home(name: &str, homes: &[String]) -> bool {
+    homes.iter().any(|home| home.eq_ignore_ascii_case(name))
+}
+
+fn get(
+    stream: &mut TcpStream,
+    request: &DavRequest,
+    root_dir: &'static str,
+    homes: &[String],
+    metrics: &MetricsRegistry,
+) -> io::Result<()> {
+    let name = request.path.trim_start_matches('/');
+    if name.is_empty() {
+        return directory_index(stream, request, "", root_dir, homes, metrics);
+    }
+    if let Err(err) = validate_directory_name(name) {
+        return DavResponse::from_io_error(&err).send(stream);
+    }
+    let top = name.split('/').next().unwrap_or(name);
+    if is_home(top, homes) {
+        return DavResponse::text(403, "Forbidden", "user homes are not served over WebDAV")
+            .send(stream);
+    }
+    if let Some(directory) = name.strip_suffix('/') {
+        return directory_index(stream, request, directory, root_dir, homes, metrics);
+    }
+    let embedded = metrics.embedded_archive().is_some();
+    // the embedded archive has files at the top and nothing below
+    if embedded && name.contains('/') {
+        return DavResponse::text(404, "Not Found", "no such file").send(stream);
+    }
+    // only the last part is a file, the rest is the directory it is in
+    let (listed_dir, file_name) = match name.rsplit_once('/') {
+        Some((directory, file_name)) => (format!("{}/{}", root_dir, directory), file_name),
+        None => (root_dir.to_owned(), name),
+    };
+    if let Err(err) = validate_file_name(file_name) {
+        return DavResponse::from_io_error(&err).send(stream);
+    }
+    let is_dir =
+        !embedded && file_metadata(file_name, &listed_dir).is_ok_and(|metadata| metadata.is_dir());
+    if is_dir {
+        // relative links in the index only work below a trailing slash
+        let mut response = DavResponse::new(301, "Moved Permanently");
+        let encoded: Vec<String> = name.split('/').map(percent_encode).collect();
+        response
+            .headers
+            .push(("Location", format!("/{}/", encoded.join("/"))));
+        return response.send(stream);
+    }
+    let modified =
+        FileServer::stored_file(file_name, &listed_dir, metrics).map(|file| file.modified);
+    // the policy sees the whole path, like the index does
+    if let Err(err) = metrics.serve_policy().check(name) {
+        return DavResponse::from_io_error(&err).send(stream);
+    }
+    if let Err(err) = metrics.authorize(&*stream, CommandType::Download, name) {
+        return DavResponse::text(403, "Forbidden", &err.to_string()).send(stream);
+    }
+    // turned away before the head, not cut off after it
+    if stream
+        .peer_addr()
+        .is_ok_and(|peer| metrics.bandwidth.is_capped(peer.ip()))
+    {
+        let mut response =
+            DavResponse::text(429, "Too Many Requests", "daily transfer cap reached");
+        response.headers.push((
+            "Retry-After",
+            metrics.bandwidth.until_reset().as_secs().to_string(),
+        ));
+        return response.send(stream);
+    }
+    let mut file_reader = match FileServer::open_served_file(file_name, &listed_dir, metrics) {
+        Ok(file_reader) => file_reader,
+        Err(err) => return DavResponse::from_io_error(&err).send(stream),
+    };
+
+    let mut response = DavResponse::new(200, "OK");
+    response.headers.push((
+        "Content-Type",
+        FileServer::served_content_type(file_name, &listed_dir, metrics).to_owned(),
+    ));
+    if let Ok(modified) = modified {
+        response
+            .headers
+            .push(("Last-Modified", http_date(modified)));
+    }
+    response.write_head(stream, file_reader.len())?;
+    if request.method == "HEAD" {
+        return Ok(());
+    }
+
+    metrics.record_download(name.to_owned());
+    metrics.transfer_started();
+    let result = FileServer::stream_file(&mut file_reader, &*stream, metrics, name);
+    metrics.transfer_finished();
+    result.map(|_| ())
+}
+
+// An HTML page listing `directory` under the root, "" being the root itself:
+// its directories first, then its files with their sizes and modification
+// times, each linked. User homes are left out of the root's.
+fn directory_index(
+    stream: &mut TcpStream,
+    request: &DavRequest,
+    directory: &str,
+    root_dir: &'static str,
+    homes: &[String],
+    metrics: &MetricsRegistry,
+) -> io::Result<()> {
+    let embedded = metrics.embedded_archive().is_some();
+    let listed_dir = match directory {
+        "" => root_dir.to_owned(),
+        // the embedded archive has no directories, only its files at the top
+        _ if embedded => {
+            return DavResponse::text(404, "Not Found", "no such directory").send(stream)
+        }
+        directory => format!("{}/{}", root_dir, directory),
+    };
+    let directories = match embedded {
+        true => Ok(Vec::new()),
+        false => served_subdirectories(&listed_dir),
+    };
+    let directories = match directories {
+        Ok(directories) => directories,
+        Err(err) => return DavResponse::from_io_error(&err).send(stream),
+    };
+    let mut files = match FileServer::stored_files(&listed_dir, metrics)
+        .and_then(|files| files.collect::<io::Result<Vec<_>>>())
+    {
+        Ok(files) => files,
+        Err(err) => return DavResponse::from_io_error(&err).send(stream),
+    };
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let title = xml_escape(&format!(
+        "Index of /{}",
+        match directory {
+            "" => String::new(),
+            directory => format!("{}/", directory),
+        }
+    ));
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+         <body><h1>{0}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n",
+        title
+    );
+    if !directory.is_empty() {
+        body.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for (name, modified) in directories {
+        if directory.is_empty() && is_home(&name, homes) {
+            continue;
+        }
+        let _ = writeln!(
+            body,
+            "<tr><td><a href=\"{}/\">{}/</a></td><td>-</td><td>{}</td></tr>",
+            percent_encode(&name),
+            xml_escape(&name),
+            http_date(modified)
+        );
+    }
+    for file in files {
+        let path = match directory {
+            "" => file.name.clone(),
+            directory => format!("{}/{}", directory, file.name),
+        };
+        if metrics.serve_policy().check(&path).is_err()
+            || metrics
+                .authorize(&*stream, CommandType::List, &path)
+                .is_err()
+        {
+            continue;
+        }
+        let _ = writeln!(
+            body,
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+            percent_encode(&file.name),
+            xml_escape(&file.name),
+            metrics.served_len(file.size),
+            http_date(file.modified)
+        );
+    }
+    body.push_str("</table></body></html>\n");
+
+    let mut response = DavResponse::new(200, "OK");
+    response
+        .headers
+        .push(("Content-Type", "text/html; charset=utf-8".to_owned()));
+    if request.method == "HEAD" {
+        return response.write_head(stream, body.len() as u64);
+    }
+    response.body = body.into_bytes();
+    response.send(stream)
+}
+
+fn put(
+    stream: &mut TcpStream,
+    body: &mut impl Read,
+    request: &DavRequest,
+    root_dir: &'static str,
+    metrics: &MetricsRegistry,
+) -> io::Result<()> {
+    let name = match file_in_path(&request.path) {
+        Ok(Some(name)) => name,
+        Ok(None) => return DavResponse::text(405, "Method Not Allowed", "not a file").send(stream),
+        Err(err) => return DavResponse::from_io_error(&err).send(stream),
+    };
+    if let Err(err) = metrics.authorize(&*stream, CommandType::Upload, name) {
+        audit(
+            stream,
+            root_dir,
+            metrics,
+            "webdav-put",
+            name,
+            0,
+            Err(err.to_string()),
+        );
+        return DavResponse::text(403, "Forbidden", &err.to_string()).send(stream);
+    }
+    let length = match (request.content_length, request.chunked) {
+        (Some(length), false) => length,
+        _ => {
+            return DavResponse::text(411, "Length Required", "send a Content-Length").send(stream)
+        }
+    };
+    let existed = file_metadata(name, root_dir).is_ok();
+
+    // same .part dance as Upload, a half sent PUT never replaces the file
+    let mut file = match FileServer::create_stored_file(name, root_dir, metrics) {
+        Ok(file) => file,
+        Err(err) => return DavResponse::from_io_error(&err).send(stream),
+    };
+    let received = io::copy(&mut body.take(length), &mut file);
+    let committed = match received {
+        Ok(received) if received == length => {
+            FileServer::commit_stored_file(file, name, root_dir, metrics)
+        }
+        Ok(_) => Err(io::Error::new(ErrorKind::UnexpectedEof, "short PUT body")),
+        Err(err) => Err(err),
+    };
+    audit(
+        stream,
+        root_dir,
+        metrics,
+        "webdav-put",
+        name,
+        if committed.is_ok() { length } else { 0 },
+        committed
+            .as_ref()
+            .map(|_| ())
+            .map_err(|err| err.to_string()),
+    );
+    if let Err(err) = committed {
+        discard_partial_file(name, root_dir);
+        return DavResponse::text(400, "Bad Request", &err.to_string()).send(stream);
+    }
+
+    println!("Received {} bytes for {} over WebDAV", length, name);
+    match existed {
+        true => DavResponse::new(204, "No Content").send(stream),
+        false => DavResponse::new(201, "Created").send(stream),
+    }
+}
+
+fn delete(
+    stream: &mut TcpStream,
+    request: &DavRequest,
+    root_dir: &'static str,
+    metrics: &MetricsRegistry,
+) -> io::Result<()> {
+    let name = match file_in_path(&request.path) {
+        Ok(Some(name)) => name,
+        Ok(None) => {
+            return DavResponse::text(403, "Forbidden", "the root can not be deleted").send(stream)
+        }
+        Err(err) => return DavResponse::from_io_error(&err).send(stream),
+    };
+    if let Err(err) = metrics.authorize(&*stream, CommandType::Delete, name) {
+        audit(
+            stream,
+            root_dir,
+            metrics,
+            "webdav-delete",
+            name,
+            0,
+            Err(err.to_string()),
+        );
+        return DavResponse::text(403, "Forbidden", &err.to_string()).send(stream);
+    }
+    let deleted = FileServer::remove_stored_file(name, root_dir, metrics);
+    audit(
+        stream,
+        root_dir,
+        metrics,
+        "webdav-delete",
+        name,
+        0,
+        deleted.as_ref().map(|_| ()).map_err(|err| err.to_string()),
+    );
+    match deleted {
+        Ok(()) => DavResponse::new(204, "No Content").send(stream),
+        Err(err) => DavResponse::from_io_error(&err).send(stream),
+    }
+}
+
+fn audit(
+    stream: &TcpStream,
+    root_dir: &str,
+    metrics: &MetricsRegistry,
+    operation: &'static str,
+    file_name: &str,
+    bytes: u64,
+    result: Result<(), String>,
+) {
+    let peer = stream
+        .peer_addr()
+        .map_or("unknown peer".to_owned(), |addr| addr.to_string());
+    metrics.audit.record(AuditEntry {
+        peer: &peer,
+        root_dir,
+        operation,
+        file_name,
+        bytes,
+        result,
+    });
+}
+
+fn percent_decode(path: &str) -> io::Result<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "bad percent escape"))?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "path is not valid utf-8"))
+}
+
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// RFC 1123 date, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+fn http_date(time: time::SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let days = secs / 86400;
+    let (hour, minute, second) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // days since the epoch to a civil date, Howard Hinnant's algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::accounts::UserAccount;
+    use super::*;
+    use crate::reader::{cleanup_server_file, configure_directory_to_serve_file, sha256_hex};
+    use std::fs;
+
+    fn request(port: u16, head: &str, body: &[u8]) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            http_date(time::UNIX_EPOCH + time::Duration::from_secs(784111777))
+        );
+    }
+
+    #[test]
+    fn test_webdav_round_trip() {
+        let root_dir = "temp_test_webdav_root_dir";
+        configure_directory_to_serve_file(root_dir);
+        let server = FileServer::new("127.0.0.1", "7959", 2, root_dir).unwrap();
+        server.start_webdav("127.0.0.1", "7958").unwrap();
+
+        let put = request(
+            7958,
+            "PUT /my%20notes.txt HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\n",
+            b"hello",
+        );
+        assert!(put.starts_with("HTTP/1.1 201"), "{}", put);
+
+        let listing = request(7958, "PROPFIND / HTTP/1.1\r\nDepth: 1\r\n\r\n", b"");
+        assert!(listing.starts_with("HTTP/1.1 207"), "{}", listing);
+        assert!(listing.contains("<D:href>/my%20notes.txt</D:href>"));
+        assert!(listing.contains("<D:getcontentlength>5</D:getcontentlength>"));
+
+        let get = request(7958, "GET /my%20notes.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(get.starts_with("HTTP/1.1 200") && get.ends_with("\r\n\r\nhello"));
+
+        let escape = request(7958, "GET /..%2Fetc%2Fpasswd HTTP/1.1\r\n\r\n", b"");
+        assert!(escape.starts_with("HTTP/1.1 403"), "{}", escape);
+
+        let delete = request(7958, "DELETE /my%20notes.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(delete.starts_with("HTTP/1.1 204"));
+        let gone = request(7958, "GET /my%20notes.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(gone.starts_with("HTTP/1.1 404"));
+
+        cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_browsers_get_an_html_index() {
+        let root_dir = "temp_test_webdav_index_root_dir";
+        let path = configure_directory_to_serve_file(root_dir);
+        fs::create_dir_all(path.join("docs")).unwrap();
+        fs::write(path.join("docs").join("a <b>.txt"), b"nested").unwrap();
+        fs::write(path.join("top.txt"), b"top").unwrap();
+        let server = FileServer::new("127.0.0.1", "7919", 2, root_dir).unwrap();
+        server.start_webdav("127.0.0.1", "7918").unwrap();
+
+        let root = request(7918, "GET / HTTP/1.1\r\n\r\n", b"");
+        assert!(root.starts_with("HTTP/1.1 200"), "{}", root);
+        assert!(root.contains("Content-Type: text/html"));
+        assert!(root.contains("<a href=\"docs/\">docs/</a>"));
+        assert!(root.contains("<a href=\"top.txt\">top.txt</a></td><td>3</td>"));
+        assert!(!root.contains("../"));
+
+        let moved = request(7918, "GET /docs HTTP/1.1\r\n\r\n", b"");
+        assert!(moved.starts_with("HTTP/1.1 301"), "{}", moved);
+        assert!(moved.contains("Location: /docs/\r\n"));
+
+        let docs = request(7918, "GET /docs/ HTTP/1.1\r\n\r\n", b"");
+        assert!(docs.contains("<title>Index of /docs/</title>"));
+        assert!(docs.contains("<a href=\"../\">../</a>"));
+        assert!(docs.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+        let nested = request(7918, "GET /docs/a%20%3Cb%3E.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(nested.ends_with("\r\n\r\nnested"), "{}", nested);
+
+        let escape = request(7918, "GET /docs/../../ HTTP/1.1\r\n\r\n", b"");
+        assert!(escape.starts_with("HTTP/1.1 403"), "{}", escape);
+        cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_user_homes_are_not_served() {
+        let root_dir = "temp_test_webdav_homes_root_dir";
+        let path = configure_directory_to_serve_file(root_dir);
+        fs::create_dir_all(path.join("bob")).unwrap();
+        fs::write(path.join("bob").join("private.txt"), b"bob's").unwrap();
+        fs::write(path.join("public.txt"), b"public").unwrap();
+        let mut server = FileServer::new("127.0.0.1", "7915", 2, root_dir).unwrap();
+        server
+            .set_accounts(&[UserAccount {
+                name: "bob".to_owned(),
+                password_sha256: sha256_hex(&b"secret"[..]).unwrap(),
+            }])
+            .unwrap();
+        server.start_webdav("127.0.0.1", "7914").unwrap();
+
+        let root = request(7914, "GET / HTTP/1.1\r\n\r\n", b"");
+        assert!(root.contains("public.txt"), "{}", root);
+        assert!(!root.contains("bob"), "{}", root);
+        for path in ["/bob/", "/bob/private.txt", "/BOB/private.txt", "/bob"] {
+            let response = request(7914, &format!("GET {} HTTP/1.1\r\n\r\n", path), b"");
+            assert!(
+                response.starts_with("HTTP/1.1 403"),
+                "{}: {}",
+                path,
+                response
+            );
+        }
+        cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_authorizer_is_asked_for_every_method() {
+        let root_dir = "temp_test_webdav_authorizer_root_dir";
+        let path = configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("keys.secret"), b"hidden").unwrap();
+        fs::write(path.join("open.txt"), b"open").unwrap();
+        let mut server = FileServer::new("127.0.0.1", "7913", 2, root_dir).unwrap();
+        // no uploads at all, and nothing secret
+        server.set_authorizer(Arc::new(|request| {
+            request.peer.is_some()
+                && request.command != CommandType::Upload
+                && !request.file_name.ends_with(".secret")
+        }));
+        server.start_webdav("127.0.0.1", "7912").unwrap();
+
+        let get = request(7912, "GET /keys.secret HTTP/1.1\r\n\r\n", b"");
+        assert!(get.starts_with("HTTP/1.1 403"), "{}", get);
+        let put = request(
+            7912,
+            "PUT /new.txt HTTP/1.1\r\nContent-Length: 3\r\n\r\n",
+            b"new",
+        );
+        assert!(put.starts_with("HTTP/1.1 403"), "{}", put);
+        assert!(!path.join("new.txt").exists());
+        let delete = request(7912, "DELETE /keys.secret HTTP/1.1\r\n\r\n", b"");
+        assert!(delete.starts_with("HTTP/1.1 403"), "{}", delete);
+        assert!(path.join("keys.secret").exists());
+
+        let index = request(7912, "GET / HTTP/1.1\r\n\r\n", b"");
+        assert!(index.contains("open.txt") && !index.contains("keys.secret"));
+        let get = request(7912, "GET /open.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(get.ends_with("\r\n\r\nopen"), "{}", get);
+
+        cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_capped_clients_get_429() {
+        let root_dir = "temp_test_webdav_cap_root_dir";
+        let path = configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("big.txt"), b"0123456789").unwrap();
+        let mut server = FileServer::new("127.0.0.1", "7911", 2, root_dir).unwrap();
+        server.set_daily_ip_cap(Some(4));
+        server.start_webdav("127.0.0.1", "7910").unwrap();
+
+        // under the cap when it starts, so this one goes through
+        let first = request(7910, "GET /big.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(first.starts_with("HTTP/1.1 200"), "{}", first);
+        let capped = request(7910, "GET /big.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(capped.starts_with("HTTP/1.1 429"), "{}", capped);
+        let retry_after: u64 = capped
+            .lines()
+            .find_map(|line| line.strip_prefix("Retry-After: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=86400).contains(&retry_after));
+
+        cleanup_server_file(root_dir);
+    }
+
+    #[test]
+    fn test_read_only_listener_refuses_writes() {
+        let root_dir = "temp_test_webdav_read_only_root_dir";
+        let path = configure_directory_to_serve_file(root_dir);
+        fs::write(path.join("kept.txt"), b"kept").unwrap();
+        let server = FileServer::new("127.0.0.1", "7909", 2, root_dir).unwrap();
+        server
+            .start_webdav_with_policy("127.0.0.1", "7908", ListenerPolicy::read_only())
+            .unwrap();
+
+        let put = request(
+            7908,
+            "PUT /new.txt HTTP/1.1\r\nContent-Length: 3\r\n\r\n",
+            b"new",
+        );
+        assert!(put.starts_with("HTTP/1.1 403"), "{}", put);
+        assert!(!path.join("new.txt").exists());
+        let delete = request(7908, "DELETE /kept.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(delete.starts_with("HTTP/1.1 403"), "{}", delete);
+        let get = request(7908, "GET /kept.txt HTTP/1.1\r\n\r\n", b"");
+        assert!(get.ends_with("\r\n\r\nkept"), "{}", get);
+
+        cleanup_server_file(root_dir);
+    }
+}
@@ -0,0 +1,237 @@
+// Helpers for integration tests, the crate's own and those of programs
+// embedding the server (the `testkit` feature). Everything here panics instead
+// of returning errors, a failed setup is a failed test.
+use crate::reader::{self, ServeDir};
+use crate::server::builder::FileServerBuilder;
+use crate::server::server::{FileServer, Handler};
+use crate::server::shutdown::ServerHandle;
+use crate::server::types::CommandType;
+use crate::FileClient;
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::Path,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    thread, time,
+};
+
+static NEXT_ROOT: AtomicU64 = AtomicU64::new(0);
+
+// Every built-in command with its handler, what the server binary registers.
+pub fn builtin_handlers() -> Vec<(CommandType, Handler)> {
+    vec![
+        (
+            CommandType::Download,
+            FileServer::handle_incomming_file_request,
+        ),
+        (
+            CommandType::ConditionalDownload,
+            FileServer::handle_conditional_download,
+        ),
+        (CommandType::Upload, FileServer::handle_upload),
+        (CommandType::Delete, FileServer::handle_delete),
+        (CommandType::List, FileServer::handle_list),
+        (CommandType::ListPage, FileServer::handle_list_page),
+        (CommandType::Transfers, FileServer::handle_transfers),
+        (
+            CommandType::BatchDownload,
+            FileServer::handle_batch_download,
+        ),
+        (CommandType::Archive, FileServer::handle_archive),
+        (
+            CommandType::RangeDownload,
+            FileServer::handle_range_download,
+        ),
+        (
+            CommandType::ReliableDownload,
+            FileServer::handle_reliable_download,
+        ),
+        (
+            CommandType::FlashDownload,
+            FileServer::handle_flash_download,
+        ),
+        (CommandType::Delta, FileServer::handle_delta),
+        (CommandType::Stat, FileServer::handle_stat),
+        (CommandType::Locate, FileServer::handle_locate),
+        (CommandType::Watch, FileServer::handle_watch),
+        (CommandType::Tail, FileServer::handle_tail),
+        (CommandType::Sync, FileServer::handle_sync),
+        (CommandType::Statistics, FileServer::no_op_handler),
+        (CommandType::StatisticsV2, FileServer::no_op_handler),
+        (CommandType::StatsOnce, FileServer::no_op_handler),
+        (
+            CommandType::KeepAlive,
+            FileServer::handle_keep_alive_session,
+        ),
+        (CommandType::Ping, FileServer::handle_ping),
+    ]
+}
+
+// Creates the served directory `root_dir` if needed and writes `filename`
+// into it.
+pub fn setup_tmp_file(root_dir: &str, filename: &str, file_content: &str) {
+    let path = reader::configure_directory_to_serve_file(root_dir);
+    fs::write(path.join(filename), file_content).unwrap();
+}
+
+// Downloads `file_name` over a connection of its own and returns the payload
+// of the reply frame, the file or the error message. With `read_delay` the
+// request is sent that long after the command byte, like a slow client.
+pub fn download_test_file(
+    addr: &str,
+    port: &str,
+    file_name: &str,
+    read_delay: Option<time::Duration>,
+) -> String {
+    let addr_with_port = format!("{}:{}", addr, port);
+
+    let mut stream = TcpStream::connect(addr_with_port).unwrap();
+    stream.write_all(&[CommandType::Download.into()]).unwrap();
+
+    if let Some(delay) = read_delay {
+        thread::sleep(delay);
+    }
+    stream
+        .write_all(format!("filename={}|", file_name).as_bytes())
+        .unwrap();
+    stream.flush().unwrap();
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).unwrap();
+    let mut length = [0u8; 8];
+    length.copy_from_slice(&reply[1..9]);
+    assert_eq!(9 + u64::from_be_bytes(length) as usize, reply.len());
+    String::from_utf8_lossy(&reply[9..]).to_string()
+}
+
+// Opens a download and stops after the command byte, so the worker that
+// picks it up waits on the file name until the returned stream is dropped,
+// or for HEADER_TIMEOUT (5s) at most, the worker then gives up on it. Pair it
+// with ServerHandle::wait_for_busy_workers to know it was picked up, and keep
+// whatever needs the worker held well inside those 5 seconds.
+pub fn hold_worker(addr: &str, port: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(format!("{}:{}", addr, port)).unwrap();
+    stream.write_all(&[CommandType::Download.into()]).unwrap();
+    stream
+}
+
+// A server on 127.0.0.1 and a port the OS picked, serving a directory of its
+// own, so tests can run side by side. Shut down and its directory removed
+// when dropped.
+pub struct TestServer {
+    handle: Option<ServerHandle>,
+    addr: SocketAddr,
+    root: ServeDir,
+}
+
+impl TestServer {
+    // Every built-in command, see builtin_handlers.
+    pub fn start() -> TestServer {
+        Self::start_with(|builder| builder)
+    }
+
+    // Like start, `configure` gets the builder first to set limits, accounts
+    // or more handlers say. Address, port, root and handlers are already set.
+    pub fn start_with(
+        configure: impl FnOnce(FileServerBuilder) -> FileServerBuilder,
+    ) -> TestServer {
+        let name = format!(
+            "temp_testkit_{}_{}",
+            process::id(),
+            NEXT_ROOT.fetch_add(1, Ordering::Relaxed)
+        );
+        let root = ServeDir::create(&name).unwrap();
+        let builder = FileServerBuilder::new()
+            .address("127.0.0.1")
+            .port("0")
+            .root_dir(&name)
+            .handlers(&builtin_handlers());
+        let server = configure(builder)
+            .build()
+            .expect("test server failed to start");
+        server.start_metrics_report();
+        let handle = server.spawn();
+        TestServer {
+            addr: handle.local_addr().expect("test server has no address"),
+            handle: Some(handle),
+            root,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> String {
+        self.addr.port().to_string()
+    }
+
+    // The served directory on disk.
+    pub fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    // What handlers know the served directory by.
+    pub fn root_dir(&self) -> &str {
+        self.root.name()
+    }
+
+    pub fn add_file(&self, name: &str, content: &str) {
+        setup_tmp_file(self.root.name(), name, content);
+    }
+
+    // A client for this server with the library's defaults.
+    pub fn client(&self) -> FileClient {
+        FileClient::new(&self.addr.ip().to_string(), &self.port())
+    }
+
+    // download_test_file against this server.
+    pub fn download(&self, file_name: &str) -> String {
+        download_test_file(&self.addr.ip().to_string(), &self.port(), file_name, None)
+    }
+
+    pub fn handle(&self) -> &ServerHandle {
+        self.handle.as_ref().unwrap()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.shutdown();
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_servers_run_side_by_side() {
+        let first = TestServer::start();
+        let second = TestServer::start_with(|builder| {
+            builder.serve_patterns(Vec::new(), vec!["*.txt".to_owned()])
+        });
+        assert_ne!(first.addr(), second.addr());
+
+        first.add_file("hello.txt", "from the first");
+        assert_eq!("from the first", first.download("hello.txt"));
+        assert!(first
+            .handle()
+            .wait_for_transfers(1, time::Duration::from_secs(5)));
+        assert_eq!(
+            b"from the first".to_vec(),
+            first.client().download("hello.txt").unwrap()
+        );
+        second.add_file("hello.txt", "from the second");
+        assert!(second.client().download("hello.txt").is_err());
+
+        let root = first.root().to_owned();
+        drop(first);
+        assert!(!root.exists());
+    }
+}